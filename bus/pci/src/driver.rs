@@ -36,9 +36,14 @@ pub struct PciBusDriver<'a> {
 impl<'a> PciBusDriver<'a> {
     const PCI_COMMAND_REG: usize = 0x04;
     const PCI_COMMAND_IO_ENABLE: u16 = 1 << 0;
+    const PCI_COMMAND_MEM_ENABLE: u16 = 1 << 1;
+    const PCI_COMMAND_BUS_MASTER: u16 = 1 << 2;
     const PCI_COMMAND_INTX_DISABLE: u16 = 1 << 10;
     const QEMU_PCI_VENDOR_ID: u16 = 0x1b36;
     const QEMU_PCI_SERIAL_DEVICE_ID: u16 = 0x0002;
+    const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+    /// BAR size reported when sizing fails.
+    const DEFAULT_BAR_SIZE: usize = 0x1000;
 
     pub fn new(
         endpoint: Endpoint,
@@ -134,6 +139,11 @@ impl<'a> PciBusDriver<'a> {
         ((self.read_u32(bus, dev, func, aligned) >> shift) & 0xff) as u8
     }
 
+    fn write_u32(&self, bus: u8, dev: u8, func: u8, reg: usize, val: u32) {
+        let addr = self.cfg_addr(bus, dev, func, reg);
+        unsafe { core::ptr::write_volatile(addr as *mut u32, val) }
+    }
+
     fn write_u16(&self, bus: u8, dev: u8, func: u8, reg: usize, val: u16) {
         let aligned = reg & !0x3;
         let shift = (reg & 0x2) * 8;
@@ -187,6 +197,74 @@ impl<'a> PciBusDriver<'a> {
         }
     }
 
+    /// Virtio devices need memory decode for their BARs and bus mastering for the rings.
+    fn ensure_virtio_enabled(&self, bus: u8, dev: u8, func: u8, vendor: u16) {
+        if vendor != Self::VIRTIO_PCI_VENDOR_ID {
+            return;
+        }
+
+        let before = self.read_u16(bus, dev, func, Self::PCI_COMMAND_REG);
+        let after = (before | Self::PCI_COMMAND_MEM_ENABLE | Self::PCI_COMMAND_BUS_MASTER)
+            & !Self::PCI_COMMAND_INTX_DISABLE;
+        if before != after {
+            self.write_u16(bus, dev, func, Self::PCI_COMMAND_REG, after);
+            log!(
+                "PCI virtio {:02x}:{:02x}.{} command update: {:#06x} -> {:#06x}",
+                bus,
+                dev,
+                func,
+                before,
+                after
+            );
+        }
+    }
+
+    /// Friendly compatible string for a virtio function, matching what virtio-mmio reports.
+    fn virtio_compat(device_id: u16) -> Option<&'static str> {
+        match virtio_common::virtio_device_id(device_id)? {
+            virtio_common::DEV_ID_NET => Some("virtio-net"),
+            virtio_common::DEV_ID_BLOCK => Some("virtio-block"),
+            virtio_common::DEV_ID_CONSOLE => Some("virtio-console"),
+            virtio_common::DEV_ID_ENTROPY => Some("virtio-rng"),
+            virtio_common::DEV_ID_GPU => Some("virtio-gpu"),
+            virtio_common::DEV_ID_INPUT => Some("virtio-input"),
+            _ => None,
+        }
+    }
+
+    /// Sizes a memory BAR by writing all ones, with decode disabled so the probe
+    /// address never goes live on the bus.
+    fn bar_size(&self, bus: u8, dev: u8, func: u8, off: usize, is_64: bool) -> usize {
+        let command = self.read_u16(bus, dev, func, Self::PCI_COMMAND_REG);
+        self.write_u16(
+            bus,
+            dev,
+            func,
+            Self::PCI_COMMAND_REG,
+            command & !(Self::PCI_COMMAND_IO_ENABLE | Self::PCI_COMMAND_MEM_ENABLE),
+        );
+
+        let lo = self.read_u32(bus, dev, func, off);
+        self.write_u32(bus, dev, func, off, 0xffff_ffff);
+        let mut mask = (self.read_u32(bus, dev, func, off) & 0xffff_fff0) as u64;
+        self.write_u32(bus, dev, func, off, lo);
+        if is_64 {
+            let hi = self.read_u32(bus, dev, func, off + 4);
+            self.write_u32(bus, dev, func, off + 4, 0xffff_ffff);
+            mask |= (self.read_u32(bus, dev, func, off + 4) as u64) << 32;
+            self.write_u32(bus, dev, func, off + 4, hi);
+        } else {
+            mask |= 0xffff_ffff_0000_0000;
+        }
+
+        self.write_u16(bus, dev, func, Self::PCI_COMMAND_REG, command);
+
+        match (!mask).wrapping_add(1) as usize {
+            0 => Self::DEFAULT_BAR_SIZE,
+            size => size,
+        }
+    }
+
     fn device_present(&self, bus: u8, dev: u8, func: u8) -> bool {
         let vendor = self.read_u16(bus, dev, func, 0x00);
         vendor != 0xffff
@@ -229,13 +307,15 @@ impl<'a> PciBusDriver<'a> {
                 let hi = self.read_u32(bus, dev, func, off + 4);
                 let base = (((hi as u64) << 32) | ((v as u64) & 0xffff_fff0)) as usize;
                 if base != 0 {
-                    out.push(MMIORegion { base_addr: base, size: 0x1000 });
+                    let size = self.bar_size(bus, dev, func, off, true);
+                    out.push(MMIORegion { base_addr: base, size });
                 }
                 bar += 2;
             } else {
                 let base = (v & 0xffff_fff0) as usize;
                 if base != 0 {
-                    out.push(MMIORegion { base_addr: base, size: 0x1000 });
+                    let size = self.bar_size(bus, dev, func, off, false);
+                    out.push(MMIORegion { base_addr: base, size });
                 }
                 bar += 1;
             }
@@ -261,6 +341,7 @@ impl<'a> PciBusDriver<'a> {
         let irq_pin = self.read_u8(bus, dev, func, 0x3d);
 
         self.ensure_qemu_serial_intx_enabled(bus, dev, func, vendor_id, device_id);
+        self.ensure_virtio_enabled(bus, dev, func, vendor_id);
 
         let mut compatible = Vec::new();
         compatible.push(alloc::format!("pciid:{:04x}:{:04x}", vendor_id, device_id));
@@ -271,6 +352,16 @@ impl<'a> PciBusDriver<'a> {
 
         let mut mmio = self.parse_bars(bus, dev, func, header_type);
 
+        // Virtio drivers locate their structures through vendor capabilities, so they get
+        // the function's config space as region 0 and the BARs after it.
+        if vendor_id == Self::VIRTIO_PCI_VENDOR_ID {
+            if let Some(compat) = Self::virtio_compat(device_id) {
+                compatible.insert(0, compat.to_string());
+            }
+            let cfg_base = self.ecam_phys + Self::cfg_off(bus, dev, func, 0);
+            mmio.insert(0, MMIORegion { base_addr: cfg_base, size: PGSIZE });
+        }
+
         // QEMU pci-serial 在无固件 BAR 分配场景下，I/O BAR 可能报告为 0x1（base=0）。
         // 这里给出平台已知 I/O window 起始地址回退，避免下游 ns16550a 获取 MMIO 时出现 InvalidArgs。
         if mmio.is_empty() && vendor_id == 0x1b36 && device_id == 0x0002 {
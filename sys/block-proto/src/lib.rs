@@ -0,0 +1,25 @@
+//! BLOCK_PROTO labels and flags beyond those libglenda defines, served by
+//! virtio-blk, ramdisk and loop alike.
+
+#![no_std]
+
+// BLOCK_PROTO labels for device properties beyond capacity and block size
+/// 1 if the caller may only read.
+pub const BLOCK_GET_READ_ONLY: usize = 0x100;
+/// 1 if writes may sit in a volatile cache until a flush, 0 for write-through.
+pub const BLOCK_GET_WRITEBACK: usize = 0x101;
+/// `cylinders << 16 | heads << 8 | sectors`, 0 if the device reports no geometry.
+pub const BLOCK_GET_GEOMETRY: usize = 0x102;
+/// Physical block size in bytes.
+pub const BLOCK_GET_PHYS_BLOCK_SIZE: usize = 0x103;
+/// Offset of the first aligned physical block, in logical blocks.
+pub const BLOCK_GET_ALIGNMENT_OFFSET: usize = 0x104;
+/// Minimum and optimal I/O sizes in bytes.
+pub const BLOCK_GET_MIN_IO_SIZE: usize = 0x105;
+pub const BLOCK_GET_OPT_IO_SIZE: usize = 0x106;
+/// Largest segment in bytes and segments per request; 0 means no limit.
+pub const BLOCK_GET_MAX_SEGMENT_SIZE: usize = 0x107;
+pub const BLOCK_GET_MAX_SEGMENTS: usize = 0x108;
+
+/// SETUP_RING flag (MR 2): the session may only read. Once set it stays set.
+pub const BLOCK_RING_READ_ONLY: usize = 1 << 0;
@@ -0,0 +1,288 @@
+//! gzip members (RFC 1952) and the DEFLATE streams inside them (RFC 1951).
+
+use crate::{byte, le16, le32, slice, DecompressError, Out};
+
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+const CM_DEFLATE: u8 = 8;
+
+const MAX_BITS: usize = 15;
+const MAX_LIT_CODES: usize = 286;
+const MAX_DIST_CODES: usize = 30;
+const FIXED_LIT_CODES: usize = 288;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] =
+    [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order code length code lengths are sent in.
+const CODE_LENGTH_ORDER: [usize; 19] =
+    [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Unpacks the gzip member at the start of `data` and returns its length.
+pub(crate) fn member(data: &[u8], out: &mut Out) -> Result<usize, DecompressError> {
+    if byte(data, 2)? != CM_DEFLATE {
+        return Err(DecompressError::Unsupported);
+    }
+    let flags = byte(data, 3)?;
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        pos += 2 + le16(data, pos).ok_or(DecompressError::Corrupt)? as usize;
+    }
+    for field in [FNAME, FCOMMENT] {
+        if flags & field != 0 {
+            let rest = data.get(pos..).ok_or(DecompressError::Corrupt)?;
+            pos += rest.iter().position(|&b| b == 0).ok_or(DecompressError::Corrupt)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+
+    let start = out.pos();
+    let mut bits = Bits { data, pos, buf: 0, count: 0 };
+    inflate(&mut bits, out, start)?;
+    let pos = bits.pos;
+    let crc = le32(data, pos).ok_or(DecompressError::Corrupt)?;
+    let size = le32(data, pos + 4).ok_or(DecompressError::Corrupt)?;
+    if size != (out.pos() - start) as u32 {
+        return Err(DecompressError::Corrupt);
+    }
+    if out.stores() && crc32(0, out.since(start)) != crc {
+        return Err(DecompressError::Checksum);
+    }
+    Ok(pos + 8)
+}
+
+/// LSB-first bit reader. Whole bytes are only taken when needed, so after
+/// `align` `pos` is the first byte past the stream.
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buf: u32,
+    count: u32,
+}
+
+impl Bits<'_> {
+    fn need(&mut self, n: u32) -> Result<u32, DecompressError> {
+        while self.count < n {
+            self.buf |= (byte(self.data, self.pos)? as u32) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let value = self.buf & ((1u32 << n) - 1);
+        self.buf >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        self.buf = 0;
+        self.count = 0;
+    }
+}
+
+/// Canonical Huffman code, decoded a bit at a time.
+struct Huffman {
+    /// Codes of each length.
+    counts: [u16; MAX_BITS + 1],
+    /// Symbols ordered by code.
+    symbols: [u16; FIXED_LIT_CODES],
+}
+
+impl Huffman {
+    /// Builds the code from per-symbol lengths. Incomplete codes are accepted;
+    /// their unused codes fail to decode.
+    fn new(lengths: &[u8]) -> Result<Self, DecompressError> {
+        let mut h = Huffman { counts: [0; MAX_BITS + 1], symbols: [0; FIXED_LIT_CODES] };
+        for &len in lengths {
+            h.counts[len as usize] += 1;
+        }
+        let mut left: i32 = 1;
+        for len in 1..=MAX_BITS {
+            left = (left << 1) - h.counts[len] as i32;
+            if left < 0 {
+                return Err(DecompressError::Corrupt);
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for len in 1..MAX_BITS {
+            offsets[len + 1] = offsets[len] + h.counts[len];
+        }
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                h.symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(h)
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, DecompressError> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for len in 1..=MAX_BITS {
+            code |= bits.need(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(DecompressError::Corrupt)
+    }
+}
+
+fn inflate(bits: &mut Bits, out: &mut Out, start: usize) -> Result<(), DecompressError> {
+    loop {
+        let last = bits.need(1)?;
+        match bits.need(2)? {
+            0 => stored(bits, out)?,
+            1 => {
+                let mut lengths = [0u8; FIXED_LIT_CODES];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let lit = Huffman::new(&lengths)?;
+                let dist = Huffman::new(&[5; MAX_DIST_CODES])?;
+                codes(bits, out, start, &lit, &dist)?;
+            }
+            2 => {
+                let (lit, dist) = dynamic_codes(bits)?;
+                codes(bits, out, start, &lit, &dist)?;
+            }
+            _ => return Err(DecompressError::Corrupt),
+        }
+        if last == 1 {
+            bits.align();
+            return Ok(());
+        }
+    }
+}
+
+fn stored(bits: &mut Bits, out: &mut Out) -> Result<(), DecompressError> {
+    bits.align();
+    let len = le16(bits.data, bits.pos).ok_or(DecompressError::Corrupt)?;
+    let nlen = le16(bits.data, bits.pos + 2).ok_or(DecompressError::Corrupt)?;
+    if len != !nlen {
+        return Err(DecompressError::Corrupt);
+    }
+    out.extend(slice(bits.data, bits.pos + 4, len as usize)?)?;
+    bits.pos += 4 + len as usize;
+    Ok(())
+}
+
+fn dynamic_codes(bits: &mut Bits) -> Result<(Huffman, Huffman), DecompressError> {
+    let nlit = bits.need(5)? as usize + 257;
+    let ndist = bits.need(5)? as usize + 1;
+    let ncode = bits.need(4)? as usize + 4;
+    if nlit > MAX_LIT_CODES || ndist > MAX_DIST_CODES {
+        return Err(DecompressError::Corrupt);
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &i in &CODE_LENGTH_ORDER[..ncode] {
+        code_lengths[i] = bits.need(3)? as u8;
+    }
+    let lencode = Huffman::new(&code_lengths)?;
+
+    let mut lengths = [0u8; MAX_LIT_CODES + MAX_DIST_CODES];
+    let mut i = 0;
+    while i < nlit + ndist {
+        let symbol = lencode.decode(bits)?;
+        let (len, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => match i {
+                0 => return Err(DecompressError::Corrupt),
+                _ => (lengths[i - 1], 3 + bits.need(2)? as usize),
+            },
+            17 => (0, 3 + bits.need(3)? as usize),
+            _ => (0, 11 + bits.need(7)? as usize),
+        };
+        if i + repeat > nlit + ndist {
+            return Err(DecompressError::Corrupt);
+        }
+        lengths[i..i + repeat].fill(len);
+        i += repeat;
+    }
+    // Without an end-of-block code the block can't end
+    if lengths[256] == 0 {
+        return Err(DecompressError::Corrupt);
+    }
+    Ok((Huffman::new(&lengths[..nlit])?, Huffman::new(&lengths[nlit..nlit + ndist])?))
+}
+
+fn codes(
+    bits: &mut Bits,
+    out: &mut Out,
+    start: usize,
+    lit: &Huffman,
+    dist: &Huffman,
+) -> Result<(), DecompressError> {
+    loop {
+        let symbol = lit.decode(bits)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8)?,
+            256 => return Ok(()),
+            _ => {
+                let code = symbol - 257;
+                if code >= LENGTH_BASE.len() {
+                    return Err(DecompressError::Corrupt);
+                }
+                let len =
+                    LENGTH_BASE[code] as usize + bits.need(LENGTH_EXTRA[code] as u32)? as usize;
+                let code = dist.decode(bits)? as usize;
+                if code >= DIST_BASE.len() {
+                    return Err(DecompressError::Corrupt);
+                }
+                let distance =
+                    DIST_BASE[code] as usize + bits.need(DIST_EXTRA[code] as u32)? as usize;
+                out.copy_match(distance, len, start)?;
+            }
+        }
+    }
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+            k += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 as used by gzip, continuing from `crc`.
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in data {
+        crc = CRC_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
@@ -0,0 +1,232 @@
+//! Decompressors for ramdisk images.
+//!
+//! Unpacks gzip, zstd and lz4 (frame and legacy) streams into a caller-provided
+//! buffer, checking the checksums each format carries. A stream may hold
+//! several members or frames back to back, as `cat a.gz b.gz` produces, and
+//! zstd/lz4 skippable frames are passed over. Anything after the last frame is
+//! ignored, since images usually sit in page-rounded regions.
+//!
+//! Matches are resolved against the output itself, so no window is kept.
+//! [`decompressed_size`] runs the same decoders without storing anything, for
+//! formats that don't record the size up front.
+
+#![no_std]
+
+extern crate alloc;
+
+mod inflate;
+mod lz4;
+mod xxhash;
+mod zstd;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecompressError {
+    /// The stream is malformed or ends early.
+    Corrupt,
+    /// A checksum doesn't match the data.
+    Checksum,
+    /// The stream uses a feature this implementation lacks, e.g. dictionaries.
+    Unsupported,
+    /// The output buffer is too small.
+    OutputFull,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Gzip,
+    Zstd,
+    Lz4,
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: u32 = 0xfd2f_b528;
+const LZ4_MAGIC: u32 = 0x184d_2204;
+const LZ4_LEGACY_MAGIC: u32 = 0x184c_2102;
+/// Skippable frames, shared by zstd and lz4, use magics 0x184d2a50..=0x184d2a5f.
+const SKIPPABLE_MAGIC: u32 = 0x184d_2a50;
+const SKIPPABLE_MASK: u32 = 0xffff_fff0;
+
+impl Format {
+    /// Recognises a stream by its leading magic, looking past skippable frames.
+    pub fn detect(data: &[u8]) -> Option<Format> {
+        let mut pos = 0;
+        loop {
+            match frame_at(data, pos)? {
+                Frame::Gzip => return Some(Format::Gzip),
+                Frame::Zstd => return Some(Format::Zstd),
+                Frame::Lz4 | Frame::Lz4Legacy => return Some(Format::Lz4),
+                Frame::Skippable(len) => pos += len,
+            }
+        }
+    }
+}
+
+/// Unpacks `data` into `out` and returns the number of bytes written.
+pub fn decompress(data: &[u8], out: &mut [u8]) -> Result<usize, DecompressError> {
+    let mut out = Out::new(out);
+    unpack(data, &mut out)?;
+    Ok(out.pos)
+}
+
+/// Size `data` unpacks to. Decodes the whole stream without storing it, so
+/// checksums over the content are not verified.
+pub fn decompressed_size(data: &[u8]) -> Result<usize, DecompressError> {
+    let mut out = Out::measure();
+    unpack(data, &mut out)?;
+    Ok(out.pos)
+}
+
+enum Frame {
+    Gzip,
+    Zstd,
+    Lz4,
+    Lz4Legacy,
+    /// Total length of the frame.
+    Skippable(usize),
+}
+
+fn frame_at(data: &[u8], pos: usize) -> Option<Frame> {
+    if data.get(pos..)?.starts_with(&GZIP_MAGIC) {
+        return Some(Frame::Gzip);
+    }
+    match le32(data, pos)? {
+        ZSTD_MAGIC => Some(Frame::Zstd),
+        LZ4_MAGIC => Some(Frame::Lz4),
+        LZ4_LEGACY_MAGIC => Some(Frame::Lz4Legacy),
+        m if m & SKIPPABLE_MASK == SKIPPABLE_MAGIC => {
+            Some(Frame::Skippable(8 + le32(data, pos + 4)? as usize))
+        }
+        _ => None,
+    }
+}
+
+fn unpack(data: &[u8], out: &mut Out) -> Result<(), DecompressError> {
+    let mut pos = 0;
+    let mut frames = 0;
+    while let Some(frame) = frame_at(data, pos) {
+        let src = &data[pos..];
+        pos += match frame {
+            Frame::Gzip => inflate::member(src, out)?,
+            Frame::Zstd => zstd::frame(src, out)?,
+            Frame::Lz4 => lz4::frame(src, out)?,
+            Frame::Lz4Legacy => lz4::legacy(src, out)?,
+            Frame::Skippable(len) if len <= src.len() => len,
+            Frame::Skippable(_) => return Err(DecompressError::Corrupt),
+        };
+        frames += 1;
+    }
+    if frames == 0 {
+        return Err(DecompressError::Corrupt);
+    }
+    Ok(())
+}
+
+/// Where decoders put their output: a buffer, or nowhere while measuring.
+pub(crate) struct Out<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+    measure: bool,
+}
+
+impl<'a> Out<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0, measure: false }
+    }
+
+    fn measure() -> Self {
+        Self { buf: &mut [], pos: 0, measure: true }
+    }
+
+    pub(crate) fn pos(&self) -> usize {
+        self.pos
+    }
+
+    /// Whether content is being stored, so checksums over it can be verified.
+    pub(crate) fn stores(&self) -> bool {
+        !self.measure
+    }
+
+    /// Output written since `start`; empty while measuring.
+    pub(crate) fn since(&self, start: usize) -> &[u8] {
+        match self.measure {
+            true => &[],
+            false => &self.buf[start..self.pos],
+        }
+    }
+
+    fn reserve(&mut self, len: usize) -> Result<usize, DecompressError> {
+        let end = self.pos.checked_add(len).ok_or(DecompressError::OutputFull)?;
+        if !self.measure && end > self.buf.len() {
+            return Err(DecompressError::OutputFull);
+        }
+        Ok(core::mem::replace(&mut self.pos, end))
+    }
+
+    pub(crate) fn push(&mut self, byte: u8) -> Result<(), DecompressError> {
+        let pos = self.reserve(1)?;
+        if !self.measure {
+            self.buf[pos] = byte;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn extend(&mut self, data: &[u8]) -> Result<(), DecompressError> {
+        let pos = self.reserve(data.len())?;
+        if !self.measure {
+            self.buf[pos..pos + data.len()].copy_from_slice(data);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn fill(&mut self, byte: u8, len: usize) -> Result<(), DecompressError> {
+        let pos = self.reserve(len)?;
+        if !self.measure {
+            self.buf[pos..pos + len].fill(byte);
+        }
+        Ok(())
+    }
+
+    /// Repeats `len` bytes from `distance` back; the source may overlap the
+    /// bytes being written but must not reach before `start`.
+    pub(crate) fn copy_match(
+        &mut self,
+        distance: usize,
+        len: usize,
+        start: usize,
+    ) -> Result<(), DecompressError> {
+        if distance == 0 || distance > self.pos - start {
+            return Err(DecompressError::Corrupt);
+        }
+        let pos = self.reserve(len)?;
+        if self.measure {
+            return Ok(());
+        }
+        let from = pos - distance;
+        if distance >= len {
+            self.buf.copy_within(from..from + len, pos);
+        } else {
+            for i in 0..len {
+                self.buf[pos + i] = self.buf[from + i];
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `len` bytes of `data` at `pos`.
+pub(crate) fn slice(data: &[u8], pos: usize, len: usize) -> Result<&[u8], DecompressError> {
+    let end = pos.checked_add(len).ok_or(DecompressError::Corrupt)?;
+    data.get(pos..end).ok_or(DecompressError::Corrupt)
+}
+
+pub(crate) fn byte(data: &[u8], pos: usize) -> Result<u8, DecompressError> {
+    data.get(pos).copied().ok_or(DecompressError::Corrupt)
+}
+
+pub(crate) fn le16(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(pos..pos + 2)?.try_into().unwrap()))
+}
+
+pub(crate) fn le32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(pos..pos + 4)?.try_into().unwrap()))
+}
@@ -0,0 +1,152 @@
+//! lz4 frames, and the legacy format the Linux kernel's initramfs uses.
+
+use crate::xxhash::xxh32;
+use crate::{byte, frame_at, le16, le32, slice, DecompressError, Out};
+
+const FLG_VERSION_MASK: u8 = 0b1100_0000;
+const FLG_VERSION: u8 = 0b0100_0000;
+const FLG_BLOCK_CHECKSUM: u8 = 1 << 4;
+const FLG_CONTENT_SIZE: u8 = 1 << 3;
+const FLG_CONTENT_CHECKSUM: u8 = 1 << 2;
+const FLG_DICT_ID: u8 = 1 << 0;
+
+/// Set in a block size for blocks stored without compression.
+const BLOCK_UNCOMPRESSED: u32 = 1 << 31;
+const LEGACY_BLOCK_SIZE: usize = 8 << 20;
+const MIN_MATCH: usize = 4;
+
+/// Unpacks the lz4 frame at the start of `data` and returns its length.
+pub(crate) fn frame(data: &[u8], out: &mut Out) -> Result<usize, DecompressError> {
+    let flg = byte(data, 4)?;
+    let bd = byte(data, 5)?;
+    if flg & FLG_VERSION_MASK != FLG_VERSION {
+        return Err(DecompressError::Unsupported);
+    }
+    if flg & FLG_DICT_ID != 0 {
+        return Err(DecompressError::Unsupported);
+    }
+    let block_max = match (bd >> 4) & 0x7 {
+        4 => 64 << 10,
+        5 => 256 << 10,
+        6 => 1 << 20,
+        7 => 4 << 20,
+        _ => return Err(DecompressError::Corrupt),
+    };
+    let mut pos = 6;
+    let content_size = match flg & FLG_CONTENT_SIZE {
+        0 => None,
+        _ => {
+            let size = u64::from_le_bytes(slice(data, pos, 8)?.try_into().unwrap());
+            pos += 8;
+            Some(size)
+        }
+    };
+    if byte(data, pos)? != (xxh32(&data[4..pos], 0) >> 8) as u8 {
+        return Err(DecompressError::Checksum);
+    }
+    pos += 1;
+
+    let start = out.pos();
+    loop {
+        let word = le32(data, pos).ok_or(DecompressError::Corrupt)?;
+        pos += 4;
+        if word == 0 {
+            break;
+        }
+        let len = (word & !BLOCK_UNCOMPRESSED) as usize;
+        if len > block_max {
+            return Err(DecompressError::Corrupt);
+        }
+        let block = slice(data, pos, len)?;
+        pos += len;
+        if flg & FLG_BLOCK_CHECKSUM != 0 {
+            let sum = le32(data, pos).ok_or(DecompressError::Corrupt)?;
+            if xxh32(block, 0) != sum {
+                return Err(DecompressError::Checksum);
+            }
+            pos += 4;
+        }
+        let block_start = out.pos();
+        match word & BLOCK_UNCOMPRESSED {
+            0 => self::block(block, out, start)?,
+            _ => out.extend(block)?,
+        }
+        if out.pos() - block_start > block_max {
+            return Err(DecompressError::Corrupt);
+        }
+    }
+
+    if content_size.is_some_and(|size| size != (out.pos() - start) as u64) {
+        return Err(DecompressError::Corrupt);
+    }
+    if flg & FLG_CONTENT_CHECKSUM != 0 {
+        let sum = le32(data, pos).ok_or(DecompressError::Corrupt)?;
+        if out.stores() && xxh32(out.since(start), 0) != sum {
+            return Err(DecompressError::Checksum);
+        }
+        pos += 4;
+    }
+    Ok(pos)
+}
+
+/// Unpacks a legacy stream, which has no end mark: it runs to the end of the
+/// input or the next frame's magic. Returns its length.
+pub(crate) fn legacy(data: &[u8], out: &mut Out) -> Result<usize, DecompressError> {
+    let start = out.pos();
+    let mut pos = 4;
+    while let Some(len) = le32(data, pos) {
+        if len == 0 || frame_at(data, pos).is_some() {
+            break;
+        }
+        pos += 4;
+        let block_start = out.pos();
+        self::block(slice(data, pos, len as usize)?, out, start)?;
+        pos += len as usize;
+        if out.pos() - block_start > LEGACY_BLOCK_SIZE {
+            return Err(DecompressError::Corrupt);
+        }
+    }
+    Ok(pos)
+}
+
+/// Decodes one compressed block. Matches may reach back to `start`, which
+/// covers frames whose blocks depend on earlier ones.
+fn block(data: &[u8], out: &mut Out, start: usize) -> Result<(), DecompressError> {
+    let mut pos = 0;
+    loop {
+        let token = byte(data, pos)?;
+        pos += 1;
+
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals += extension(data, &mut pos)?;
+        }
+        out.extend(slice(data, pos, literals)?)?;
+        pos += literals;
+        // The last sequence is literals only
+        if pos == data.len() {
+            return Ok(());
+        }
+
+        let distance = le16(data, pos).ok_or(DecompressError::Corrupt)? as usize;
+        pos += 2;
+        let mut len = (token & 0xf) as usize;
+        if len == 15 {
+            len += extension(data, &mut pos)?;
+        }
+        out.copy_match(distance, len + MIN_MATCH, start)?;
+    }
+}
+
+/// Length bytes added to a saturated token field, ending at one below 255.
+fn extension(data: &[u8], pos: &mut usize) -> Result<usize, DecompressError> {
+    let mut len = 0usize;
+    loop {
+        let b = byte(data, *pos)?;
+        *pos += 1;
+        len = len.checked_add(b as usize).ok_or(DecompressError::Corrupt)?;
+        if b != 255 {
+            return Ok(len);
+        }
+    }
+}
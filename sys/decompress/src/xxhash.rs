@@ -0,0 +1,129 @@
+//! xxHash32 and xxHash64, the checksums lz4 and zstd frames carry.
+
+const P32_1: u32 = 0x9e37_79b1;
+const P32_2: u32 = 0x85eb_ca77;
+const P32_3: u32 = 0xc2b2_ae3d;
+const P32_4: u32 = 0x27d4_eb2f;
+const P32_5: u32 = 0x1656_67b1;
+
+const P64_1: u64 = 0x9e37_79b1_85eb_ca87;
+const P64_2: u64 = 0xc2b2_ae3d_27d4_eb4f;
+const P64_3: u64 = 0x1656_67b1_9e37_79f9;
+const P64_4: u64 = 0x85eb_ca77_c2b2_ae63;
+const P64_5: u64 = 0x27d4_eb2f_1656_67c5;
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap())
+}
+
+fn round32(acc: u32, input: u32) -> u32 {
+    acc.wrapping_add(input.wrapping_mul(P32_2)).rotate_left(13).wrapping_mul(P32_1)
+}
+
+fn round64(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(P64_2)).rotate_left(31).wrapping_mul(P64_1)
+}
+
+fn merge64(acc: u64, val: u64) -> u64 {
+    (acc ^ round64(0, val)).wrapping_mul(P64_1).wrapping_add(P64_4)
+}
+
+pub(crate) fn xxh32(data: &[u8], seed: u32) -> u32 {
+    let mut pos = 0;
+    let mut h = if data.len() >= 16 {
+        let mut v = [
+            seed.wrapping_add(P32_1).wrapping_add(P32_2),
+            seed.wrapping_add(P32_2),
+            seed,
+            seed.wrapping_sub(P32_1),
+        ];
+        while pos + 16 <= data.len() {
+            for (i, lane) in v.iter_mut().enumerate() {
+                *lane = round32(*lane, u32_at(data, pos + i * 4));
+            }
+            pos += 16;
+        }
+        v[0].rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18))
+    } else {
+        seed.wrapping_add(P32_5)
+    };
+    h = h.wrapping_add(data.len() as u32);
+
+    while pos + 4 <= data.len() {
+        h = h
+            .wrapping_add(u32_at(data, pos).wrapping_mul(P32_3))
+            .rotate_left(17)
+            .wrapping_mul(P32_4);
+        pos += 4;
+    }
+    for &b in &data[pos..] {
+        h = h.wrapping_add((b as u32).wrapping_mul(P32_5)).rotate_left(11).wrapping_mul(P32_1);
+    }
+
+    h ^= h >> 15;
+    h = h.wrapping_mul(P32_2);
+    h ^= h >> 13;
+    h = h.wrapping_mul(P32_3);
+    h ^ (h >> 16)
+}
+
+pub(crate) fn xxh64(data: &[u8], seed: u64) -> u64 {
+    let mut pos = 0;
+    let mut h = if data.len() >= 32 {
+        let mut v = [
+            seed.wrapping_add(P64_1).wrapping_add(P64_2),
+            seed.wrapping_add(P64_2),
+            seed,
+            seed.wrapping_sub(P64_1),
+        ];
+        while pos + 32 <= data.len() {
+            for (i, lane) in v.iter_mut().enumerate() {
+                *lane = round64(*lane, u64_at(data, pos + i * 8));
+            }
+            pos += 32;
+        }
+        let mut h = v[0]
+            .rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18));
+        for lane in v {
+            h = merge64(h, lane);
+        }
+        h
+    } else {
+        seed.wrapping_add(P64_5)
+    };
+    h = h.wrapping_add(data.len() as u64);
+
+    while pos + 8 <= data.len() {
+        h = (h ^ round64(0, u64_at(data, pos)))
+            .rotate_left(27)
+            .wrapping_mul(P64_1)
+            .wrapping_add(P64_4);
+        pos += 8;
+    }
+    if pos + 4 <= data.len() {
+        h = (h ^ (u32_at(data, pos) as u64).wrapping_mul(P64_1))
+            .rotate_left(23)
+            .wrapping_mul(P64_2)
+            .wrapping_add(P64_3);
+        pos += 4;
+    }
+    for &b in &data[pos..] {
+        h = (h ^ (b as u64).wrapping_mul(P64_5)).rotate_left(11).wrapping_mul(P64_1);
+    }
+
+    h ^= h >> 33;
+    h = h.wrapping_mul(P64_2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(P64_3);
+    h ^ (h >> 32)
+}
@@ -0,0 +1,707 @@
+//! zstd frames (RFC 8878), without dictionary support.
+
+use crate::xxhash::xxh64;
+use crate::{byte, le16, le32, slice, DecompressError, Out};
+use alloc::vec;
+use alloc::vec::Vec;
+
+const FHD_CHECKSUM: u8 = 1 << 2;
+const FHD_RESERVED: u8 = 1 << 3;
+const FHD_SINGLE_SEGMENT: u8 = 1 << 5;
+
+const BLOCK_RAW: u32 = 0;
+const BLOCK_RLE: u32 = 1;
+const BLOCK_COMPRESSED: u32 = 2;
+const MAX_BLOCK_SIZE: usize = 128 << 10;
+
+const LITERALS_RAW: u8 = 0;
+const LITERALS_RLE: u8 = 1;
+const LITERALS_COMPRESSED: u8 = 2;
+
+const MODE_PREDEFINED: u8 = 0;
+const MODE_RLE: u8 = 1;
+const MODE_FSE: u8 = 2;
+
+const MAX_HUFFMAN_BITS: u32 = 11;
+const MAX_WEIGHT_LOG: u32 = 6;
+
+const LL_MAX_LOG: u32 = 9;
+const ML_MAX_LOG: u32 = 9;
+const OF_MAX_LOG: u32 = 8;
+const LL_MAX_SYMBOL: usize = 35;
+const ML_MAX_SYMBOL: usize = 52;
+const OF_MAX_SYMBOL: usize = 31;
+
+const LL_DEFAULT_LOG: u32 = 6;
+const ML_DEFAULT_LOG: u32 = 6;
+const OF_DEFAULT_LOG: u32 = 5;
+const LL_DEFAULT: [i16; 36] = [
+    4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1,
+    -1, -1, -1, -1,
+];
+const ML_DEFAULT: [i16; 53] = [
+    1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+];
+const OF_DEFAULT: [i16; 29] =
+    [1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1];
+
+/// Literals lengths for codes 16 and up; codes below are the length itself.
+const LL_BASE: [u32; 20] = [
+    16, 18, 20, 22, 24, 28, 32, 40, 48, 64, 128, 256, 512, 1024, 2048, 4096, 8192, 16384, 32768,
+    65536,
+];
+const LL_EXTRA: [u8; 20] = [1, 1, 1, 1, 2, 2, 3, 3, 4, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+/// Match lengths for codes 32 and up; codes below are the length less 3.
+const ML_BASE: [u32; 21] = [
+    35, 37, 39, 41, 43, 47, 51, 59, 67, 83, 99, 131, 259, 515, 1027, 2051, 4099, 8195, 16387,
+    32771, 65539,
+];
+const ML_EXTRA: [u8; 21] = [1, 1, 1, 1, 2, 2, 3, 3, 4, 4, 5, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+
+/// Unpacks the zstd frame at the start of `data` and returns its length.
+pub(crate) fn frame(data: &[u8], out: &mut Out) -> Result<usize, DecompressError> {
+    let fhd = byte(data, 4)?;
+    if fhd & FHD_RESERVED != 0 {
+        return Err(DecompressError::Corrupt);
+    }
+    let single_segment = fhd & FHD_SINGLE_SEGMENT != 0;
+    let mut pos = 5;
+    if !single_segment {
+        // Window descriptor: the output is flat, so any window fits
+        pos += 1;
+    }
+    let dict_len = [0, 1, 2, 4][(fhd & 0x3) as usize];
+    if slice(data, pos, dict_len)?.iter().any(|&b| b != 0) {
+        return Err(DecompressError::Unsupported);
+    }
+    pos += dict_len;
+    let content_size = match (fhd >> 6, single_segment) {
+        (0, false) => None,
+        (0, true) => Some(byte(data, pos)? as u64),
+        (1, _) => Some(le16(data, pos).ok_or(DecompressError::Corrupt)? as u64 + 256),
+        (2, _) => Some(le32(data, pos).ok_or(DecompressError::Corrupt)? as u64),
+        _ => Some(u64::from_le_bytes(slice(data, pos, 8)?.try_into().unwrap())),
+    };
+    pos += match (fhd >> 6, single_segment) {
+        (0, false) => 0,
+        (0, true) => 1,
+        (1, _) => 2,
+        (2, _) => 4,
+        _ => 8,
+    };
+
+    let start = out.pos();
+    let mut ctx = Context::new();
+    loop {
+        let header = slice(data, pos, 3)?;
+        let header = header[0] as u32 | (header[1] as u32) << 8 | (header[2] as u32) << 16;
+        pos += 3;
+        let last = header & 1 != 0;
+        let size = (header >> 3) as usize;
+        match (header >> 1) & 0x3 {
+            BLOCK_RAW => {
+                out.extend(slice(data, pos, size)?)?;
+                pos += size;
+            }
+            BLOCK_RLE => {
+                out.fill(byte(data, pos)?, size)?;
+                pos += 1;
+            }
+            BLOCK_COMPRESSED if size <= MAX_BLOCK_SIZE => {
+                ctx.block(slice(data, pos, size)?, out, start)?;
+                pos += size;
+            }
+            _ => return Err(DecompressError::Corrupt),
+        }
+        if last {
+            break;
+        }
+    }
+
+    if content_size.is_some_and(|size| size != (out.pos() - start) as u64) {
+        return Err(DecompressError::Corrupt);
+    }
+    if fhd & FHD_CHECKSUM != 0 {
+        let sum = le32(data, pos).ok_or(DecompressError::Corrupt)?;
+        if out.stores() && xxh64(out.since(start), 0) as u32 != sum {
+            return Err(DecompressError::Checksum);
+        }
+        pos += 4;
+    }
+    Ok(pos)
+}
+
+/// State carried from block to block within a frame.
+struct Context {
+    huffman: Option<Huffman>,
+    ll: Option<Fse>,
+    of: Option<Fse>,
+    ml: Option<Fse>,
+    reps: [usize; 3],
+    literals: Vec<u8>,
+}
+
+impl Context {
+    fn new() -> Self {
+        Self { huffman: None, ll: None, of: None, ml: None, reps: [1, 4, 8], literals: Vec::new() }
+    }
+
+    fn block(&mut self, data: &[u8], out: &mut Out, start: usize) -> Result<(), DecompressError> {
+        let pos = self.literals_section(data)?;
+        self.sequences_section(&data[pos..], out, start)
+    }
+
+    /// Decodes the literals into `self.literals` and returns the section's
+    /// length.
+    fn literals_section(&mut self, data: &[u8]) -> Result<usize, DecompressError> {
+        let b0 = byte(data, 0)?;
+        let kind = b0 & 0x3;
+        let format = (b0 >> 2) & 0x3;
+
+        if kind == LITERALS_RAW || kind == LITERALS_RLE {
+            let (header_len, size) = match format {
+                0 | 2 => (1, (b0 >> 3) as usize),
+                1 => (2, (b0 >> 4) as usize | (byte(data, 1)? as usize) << 4),
+                _ => (
+                    3,
+                    (b0 >> 4) as usize
+                        | (byte(data, 1)? as usize) << 4
+                        | (byte(data, 2)? as usize) << 12,
+                ),
+            };
+            if size > MAX_BLOCK_SIZE {
+                return Err(DecompressError::Corrupt);
+            }
+            self.literals.clear();
+            return match kind {
+                LITERALS_RAW => {
+                    self.literals.extend_from_slice(slice(data, header_len, size)?);
+                    Ok(header_len + size)
+                }
+                _ => {
+                    self.literals.resize(size, byte(data, header_len)?);
+                    Ok(header_len + 1)
+                }
+            };
+        }
+
+        let (header_len, field_bits, streams) = match format {
+            0 => (3, 10, 1),
+            1 => (3, 10, 4),
+            2 => (4, 14, 4),
+            _ => (5, 18, 4),
+        };
+        let mut header = 0u64;
+        for (i, &b) in slice(data, 0, header_len)?.iter().enumerate() {
+            header |= (b as u64) << (i * 8);
+        }
+        let mask = (1u64 << field_bits) - 1;
+        let regenerated = ((header >> 4) & mask) as usize;
+        let compressed = ((header >> (4 + field_bits)) & mask) as usize;
+        if regenerated > MAX_BLOCK_SIZE {
+            return Err(DecompressError::Corrupt);
+        }
+        let mut body = slice(data, header_len, compressed)?;
+        if kind == LITERALS_COMPRESSED {
+            let (huffman, len) = Huffman::read(body)?;
+            self.huffman = Some(huffman);
+            body = &body[len..];
+        }
+        let huffman = self.huffman.as_ref().ok_or(DecompressError::Corrupt)?;
+
+        self.literals.clear();
+        self.literals.resize(regenerated, 0);
+        if streams == 1 {
+            huffman.decode_stream(body, &mut self.literals)?;
+        } else {
+            let mut sizes = [0usize; 4];
+            for (i, size) in sizes[..3].iter_mut().enumerate() {
+                *size = le16(body, i * 2).ok_or(DecompressError::Corrupt)? as usize;
+            }
+            let total: usize = sizes[..3].iter().sum::<usize>() + 6;
+            sizes[3] = body.len().checked_sub(total).ok_or(DecompressError::Corrupt)?;
+            let segment = regenerated.div_ceil(4);
+            if regenerated < segment * 3 {
+                return Err(DecompressError::Corrupt);
+            }
+            let mut pos = 6;
+            for (i, size) in sizes.into_iter().enumerate() {
+                let from = i * segment;
+                let to = if i == 3 { regenerated } else { from + segment };
+                huffman.decode_stream(&body[pos..pos + size], &mut self.literals[from..to])?;
+                pos += size;
+            }
+        }
+        Ok(header_len + compressed)
+    }
+
+    fn sequences_section(
+        &mut self,
+        data: &[u8],
+        out: &mut Out,
+        start: usize,
+    ) -> Result<(), DecompressError> {
+        let b0 = byte(data, 0)? as usize;
+        let (count, mut pos) = match b0 {
+            0..128 => (b0, 1),
+            128..255 => (((b0 - 128) << 8) + byte(data, 1)? as usize, 2),
+            _ => (byte(data, 1)? as usize + ((byte(data, 2)? as usize) << 8) + 0x7f00, 3),
+        };
+        if count == 0 {
+            return out.extend(&self.literals);
+        }
+
+        let modes = byte(data, pos)?;
+        pos += 1;
+        if modes & 0x3 != 0 {
+            return Err(DecompressError::Corrupt);
+        }
+        pos += Fse::select(
+            &mut self.ll,
+            modes >> 6,
+            &data[pos..],
+            (&LL_DEFAULT, LL_DEFAULT_LOG),
+            LL_MAX_SYMBOL,
+            LL_MAX_LOG,
+        )?;
+        pos += Fse::select(
+            &mut self.of,
+            (modes >> 4) & 0x3,
+            &data[pos..],
+            (&OF_DEFAULT, OF_DEFAULT_LOG),
+            OF_MAX_SYMBOL,
+            OF_MAX_LOG,
+        )?;
+        pos += Fse::select(
+            &mut self.ml,
+            (modes >> 2) & 0x3,
+            &data[pos..],
+            (&ML_DEFAULT, ML_DEFAULT_LOG),
+            ML_MAX_SYMBOL,
+            ML_MAX_LOG,
+        )?;
+        let (ll, of, ml) = match (&self.ll, &self.of, &self.ml) {
+            (Some(ll), Some(of), Some(ml)) => (ll, of, ml),
+            _ => return Err(DecompressError::Corrupt),
+        };
+
+        let mut bits = ReverseBits::new(data.get(pos..).ok_or(DecompressError::Corrupt)?)?;
+        let mut ll_state = bits.read(ll.log) as usize;
+        let mut of_state = bits.read(of.log) as usize;
+        let mut ml_state = bits.read(ml.log) as usize;
+        let mut literal = 0;
+
+        for i in 0..count {
+            let of_code = of.table[of_state].symbol as u32;
+            let ml_code = ml.table[ml_state].symbol as usize;
+            let ll_code = ll.table[ll_state].symbol as usize;
+            if of_code > 31 {
+                return Err(DecompressError::Corrupt);
+            }
+            let offset_value = (1u64 << of_code) + bits.read(of_code);
+            let match_len = match ml_code {
+                0..32 => ml_code + 3,
+                _ => {
+                    let c = ml_code - 32;
+                    ML_BASE[c] as usize + bits.read(ML_EXTRA[c] as u32) as usize
+                }
+            };
+            let literal_len = match ll_code {
+                0..16 => ll_code,
+                _ => {
+                    let c = ll_code - 16;
+                    LL_BASE[c] as usize + bits.read(LL_EXTRA[c] as u32) as usize
+                }
+            };
+
+            if i + 1 < count {
+                ll_state = ll.update(ll_state, &mut bits);
+                ml_state = ml.update(ml_state, &mut bits);
+                of_state = of.update(of_state, &mut bits);
+            }
+            if bits.overflowed() {
+                return Err(DecompressError::Corrupt);
+            }
+
+            let distance = resolve_offset(&mut self.reps, offset_value as usize, literal_len)?;
+            let literals = self
+                .literals
+                .get(literal..literal + literal_len)
+                .ok_or(DecompressError::Corrupt)?;
+            out.extend(literals)?;
+            literal += literal_len;
+            out.copy_match(distance, match_len, start)?;
+        }
+        if !bits.finished() {
+            return Err(DecompressError::Corrupt);
+        }
+        out.extend(&self.literals[literal..])
+    }
+}
+
+/// Turns an offset value into a match distance, updating the repeat offsets.
+fn resolve_offset(
+    reps: &mut [usize; 3],
+    value: usize,
+    literal_len: usize,
+) -> Result<usize, DecompressError> {
+    let [r0, r1, r2] = *reps;
+    if value > 3 {
+        let distance = value - 3;
+        *reps = [distance, r0, r1];
+        return Ok(distance);
+    }
+    // Without literals the repeat codes shift by one
+    let index = if literal_len == 0 { value + 1 } else { value };
+    let (distance, updated) = match index {
+        1 => (r0, [r0, r1, r2]),
+        2 => (r1, [r1, r0, r2]),
+        3 => (r2, [r2, r0, r1]),
+        _ => {
+            let distance = r0.checked_sub(1).filter(|&d| d != 0).ok_or(DecompressError::Corrupt)?;
+            (distance, [distance, r0, r1])
+        }
+    };
+    *reps = updated;
+    Ok(distance)
+}
+
+/// Backward bitstream: read from the last byte's marker bit towards the
+/// first byte. Reads past the start give zeros and count as overflow.
+struct ReverseBits<'a> {
+    data: &'a [u8],
+    /// Bits not yet read.
+    left: isize,
+}
+
+impl<'a> ReverseBits<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, DecompressError> {
+        let last = *data.last().ok_or(DecompressError::Corrupt)?;
+        if last == 0 {
+            return Err(DecompressError::Corrupt);
+        }
+        let marker = 7 - last.leading_zeros() as isize;
+        Ok(Self { data, left: (data.len() as isize - 1) * 8 + marker })
+    }
+
+    fn peek(&self, n: u32) -> u64 {
+        let end = self.left;
+        let first = end - n as isize;
+        let from = first.max(0);
+        if n == 0 || end <= 0 {
+            return 0;
+        }
+        let lo = (from / 8) as usize;
+        let hi = ((end - 1) / 8) as usize;
+        let mut word = 0u64;
+        for (i, &b) in self.data[lo..=hi].iter().enumerate() {
+            word |= (b as u64) << (i * 8);
+        }
+        let len = (end - from) as u32;
+        let value = (word >> (from % 8)) & ((1u64 << len) - 1);
+        value << (from - first)
+    }
+
+    fn consume(&mut self, n: u32) {
+        self.left -= n as isize;
+    }
+
+    fn read(&mut self, n: u32) -> u64 {
+        let value = self.peek(n);
+        self.consume(n);
+        value
+    }
+
+    fn overflowed(&self) -> bool {
+        self.left < 0
+    }
+
+    fn finished(&self) -> bool {
+        self.left == 0
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct FseEntry {
+    symbol: u8,
+    bits: u8,
+    base: u16,
+}
+
+/// Finite state entropy decoding table.
+struct Fse {
+    log: u32,
+    table: Vec<FseEntry>,
+}
+
+impl Fse {
+    /// Sets `table` for a sequences section's compression `mode` and returns
+    /// the bytes its description took.
+    fn select(
+        table: &mut Option<Fse>,
+        mode: u8,
+        data: &[u8],
+        (default, default_log): (&[i16], u32),
+        max_symbol: usize,
+        max_log: u32,
+    ) -> Result<usize, DecompressError> {
+        match mode {
+            MODE_PREDEFINED => {
+                *table = Some(Fse::build(default, default_log)?);
+                Ok(0)
+            }
+            MODE_RLE => {
+                let symbol = byte(data, 0)?;
+                if symbol as usize > max_symbol {
+                    return Err(DecompressError::Corrupt);
+                }
+                *table = Some(Fse { log: 0, table: vec![FseEntry { symbol, bits: 0, base: 0 }] });
+                Ok(1)
+            }
+            MODE_FSE => {
+                let (fse, len) = Fse::read(data, max_symbol, max_log)?;
+                *table = Some(fse);
+                Ok(len)
+            }
+            // Repeat: keep the previous block's table
+            _ => table.as_ref().map(|_| 0).ok_or(DecompressError::Corrupt),
+        }
+    }
+
+    /// Reads a table description and returns the table and its length.
+    fn read(data: &[u8], max_symbol: usize, max_log: u32) -> Result<(Fse, usize), DecompressError> {
+        let mut bit = 0usize;
+        let mut take = |n: u32, consume: bool| -> Result<u32, DecompressError> {
+            let mut value = 0u32;
+            for i in 0..n as usize {
+                let pos = bit + i;
+                let b = data.get(pos / 8).copied().unwrap_or(0);
+                value |= (((b >> (pos % 8)) & 1) as u32) << i;
+            }
+            if consume {
+                bit += n as usize;
+                if bit.div_ceil(8) > data.len() {
+                    return Err(DecompressError::Corrupt);
+                }
+            }
+            Ok(value)
+        };
+
+        let log = take(4, true)? + 5;
+        if log > max_log {
+            return Err(DecompressError::Corrupt);
+        }
+        let mut probs = [0i16; 256];
+        let mut remaining = (1i32 << log) + 1;
+        let mut threshold = 1i32 << log;
+        let mut nbits = log + 1;
+        let mut symbol = 0usize;
+        let mut previous_zero = false;
+
+        while remaining > 1 && symbol <= max_symbol {
+            if previous_zero {
+                loop {
+                    let repeat = take(2, true)? as usize;
+                    symbol += repeat;
+                    if repeat != 3 {
+                        break;
+                    }
+                }
+                if symbol > max_symbol {
+                    return Err(DecompressError::Corrupt);
+                }
+            }
+            let max = (2 * threshold - 1) - remaining;
+            let low = take(nbits - 1, false)? as i32;
+            let value = if low < max {
+                take(nbits - 1, true)?;
+                low
+            } else {
+                let mut value = take(nbits, true)? as i32;
+                if value >= threshold {
+                    value -= max;
+                }
+                value
+            };
+            let prob = value - 1;
+            remaining -= prob.abs();
+            probs[symbol] = prob as i16;
+            symbol += 1;
+            previous_zero = prob == 0;
+            while remaining < threshold {
+                nbits -= 1;
+                threshold >>= 1;
+            }
+        }
+        if remaining != 1 {
+            return Err(DecompressError::Corrupt);
+        }
+        Ok((Fse::build(&probs[..symbol], log)?, bit.div_ceil(8)))
+    }
+
+    fn build(probs: &[i16], log: u32) -> Result<Fse, DecompressError> {
+        let size = 1usize << log;
+        let mut table = vec![FseEntry::default(); size];
+        let mut next = [0u16; 256];
+        let mut high = size - 1;
+
+        for (symbol, &prob) in probs.iter().enumerate() {
+            if prob == -1 {
+                table[high].symbol = symbol as u8;
+                high = high.wrapping_sub(1);
+                next[symbol] = 1;
+            } else {
+                next[symbol] = prob.max(0) as u16;
+            }
+        }
+
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mask = size - 1;
+        let mut position = 0;
+        for (symbol, &prob) in probs.iter().enumerate() {
+            for _ in 0..prob.max(0) {
+                table[position].symbol = symbol as u8;
+                loop {
+                    position = (position + step) & mask;
+                    if position <= high {
+                        break;
+                    }
+                }
+            }
+        }
+        if position != 0 {
+            return Err(DecompressError::Corrupt);
+        }
+
+        for entry in table.iter_mut() {
+            let state = next[entry.symbol as usize];
+            next[entry.symbol as usize] += 1;
+            if state == 0 {
+                return Err(DecompressError::Corrupt);
+            }
+            let bits = log - (15 - state.leading_zeros());
+            entry.bits = bits as u8;
+            entry.base = ((state as usize) << bits).wrapping_sub(size) as u16;
+        }
+        Ok(Fse { log, table })
+    }
+
+    fn update(&self, state: usize, bits: &mut ReverseBits) -> usize {
+        let entry = self.table[state];
+        entry.base as usize + bits.read(entry.bits as u32) as usize
+    }
+}
+
+/// Huffman decoding table for literals, indexed by the next `max_bits` bits.
+struct Huffman {
+    max_bits: u32,
+    /// (symbol, code length) per index.
+    table: Vec<(u8, u8)>,
+}
+
+impl Huffman {
+    /// Reads a tree description and returns the table and its length.
+    fn read(data: &[u8]) -> Result<(Huffman, usize), DecompressError> {
+        let header = byte(data, 0)? as usize;
+        let mut weights = [0u8; 256];
+        let (count, len) = if header < 128 {
+            let body = slice(data, 1, header)?;
+            (Self::fse_weights(body, &mut weights)?, 1 + header)
+        } else {
+            let count = header - 127;
+            let body = slice(data, 1, count.div_ceil(2))?;
+            for i in 0..count {
+                let b = body[i / 2];
+                weights[i] = if i % 2 == 0 { b >> 4 } else { b & 0xf };
+            }
+            (count, 1 + count.div_ceil(2))
+        };
+        Ok((Self::build(&mut weights, count)?, len))
+    }
+
+    /// Decodes FSE-compressed weights, two interleaved states sharing one
+    /// table, and returns how many there are.
+    fn fse_weights(data: &[u8], weights: &mut [u8; 256]) -> Result<usize, DecompressError> {
+        let (fse, len) = Fse::read(data, 255, MAX_WEIGHT_LOG)?;
+        let mut bits = ReverseBits::new(&data[len..])?;
+        let mut states = [bits.read(fse.log) as usize, bits.read(fse.log) as usize];
+        let mut count = 0;
+        loop {
+            for i in 0..2 {
+                if count == 255 {
+                    return Err(DecompressError::Corrupt);
+                }
+                weights[count] = fse.table[states[i]].symbol;
+                count += 1;
+                states[i] = fse.update(states[i], &mut bits);
+                if bits.overflowed() {
+                    if count == 255 {
+                        return Err(DecompressError::Corrupt);
+                    }
+                    weights[count] = fse.table[states[1 - i]].symbol;
+                    return Ok(count + 1);
+                }
+            }
+        }
+    }
+
+    /// Builds the table from the first `count` weights, deriving the weight
+    /// of the last symbol from the others.
+    fn build(weights: &mut [u8; 256], count: usize) -> Result<Huffman, DecompressError> {
+        let mut total = 0u32;
+        for &w in &weights[..count] {
+            if w as u32 > MAX_HUFFMAN_BITS {
+                return Err(DecompressError::Corrupt);
+            }
+            if w > 0 {
+                total += 1 << (w - 1);
+            }
+        }
+        if total == 0 {
+            return Err(DecompressError::Corrupt);
+        }
+        let max_bits = 32 - total.leading_zeros();
+        let left = (1u32 << max_bits) - total;
+        if max_bits > MAX_HUFFMAN_BITS || !left.is_power_of_two() {
+            return Err(DecompressError::Corrupt);
+        }
+        weights[count] = (left.trailing_zeros() + 1) as u8;
+        let symbols = count + 1;
+
+        let mut starts = [0u32; MAX_HUFFMAN_BITS as usize + 2];
+        for &w in &weights[..symbols] {
+            if w > 0 {
+                starts[w as usize + 1] += 1 << (w - 1);
+            }
+        }
+        for w in 1..starts.len() {
+            starts[w] += starts[w - 1];
+        }
+        let mut table = vec![(0u8, 0u8); 1 << max_bits];
+        for (symbol, &w) in weights[..symbols].iter().enumerate() {
+            if w == 0 {
+                continue;
+            }
+            let from = starts[w as usize] as usize;
+            let len = 1usize << (w - 1);
+            table[from..from + len].fill((symbol as u8, (max_bits + 1 - w as u32) as u8));
+            starts[w as usize] += len as u32;
+        }
+        Ok(Huffman { max_bits, table })
+    }
+
+    /// Fills `out` from one stream, which must be used up exactly.
+    fn decode_stream(&self, data: &[u8], out: &mut [u8]) -> Result<(), DecompressError> {
+        let mut bits = ReverseBits::new(data)?;
+        for b in out.iter_mut() {
+            let (symbol, len) = self.table[bits.peek(self.max_bits) as usize];
+            *b = symbol;
+            bits.consume(len as u32);
+        }
+        match bits.finished() {
+            true => Ok(()),
+            false => Err(DecompressError::Corrupt),
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! Unpacks streams made by the gzip, zstd and lz4 command line tools when they
+//! are installed.
+
+use decompress::*;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Text with repeats near and far, a stretch of noise and a long run of zeros,
+/// so each decoder sees literals, matches and multiple blocks.
+fn sample() -> Vec<u8> {
+    const WORDS: [&str; 12] = [
+        "block",
+        "device",
+        "ramdisk",
+        "page",
+        "the",
+        "of",
+        "initrd",
+        "sector",
+        "map",
+        "glenda",
+        "zero",
+        "capability",
+    ];
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = || {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (state >> 33) as usize
+    };
+    let mut data = Vec::new();
+    for _ in 0..8000 {
+        data.extend_from_slice(WORDS[next() % WORDS.len()].as_bytes());
+        data.push(if next() % 13 == 0 { b'\n' } else { b' ' });
+    }
+    data.extend((0..16 << 10).map(|_| next() as u8));
+    data.extend(std::iter::repeat_n(0, 200 << 10));
+    data.extend_from_within(1000..40_000);
+    data
+}
+
+/// Pipes `input` through a compressor, `None` if it is missing or fails.
+fn compress(tool: &str, args: &[&str], input: &[u8]) -> Option<Vec<u8>> {
+    let mut child = Command::new(tool)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    let mut stdin = child.stdin.take()?;
+    let input = input.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output().ok()?;
+    writer.join().ok()?.ok()?;
+    output.status.success().then_some(output.stdout)
+}
+
+fn unpack(packed: &[u8], len: usize) -> Vec<u8> {
+    let mut out = vec![0; len];
+    let n = decompress(packed, &mut out).unwrap();
+    out.truncate(n);
+    out
+}
+
+/// Checks each tool invocation round-trips `sample()`, in both passes.
+fn check(format: Format, tool: &str, variants: &[&[&str]]) {
+    let data = sample();
+    for args in variants {
+        let Some(packed) = compress(tool, args, &data) else {
+            eprintln!("{} not found, skipping", tool);
+            return;
+        };
+        assert_eq!(Format::detect(&packed), Some(format), "{} {:?}", tool, args);
+        assert_eq!(decompressed_size(&packed), Ok(data.len()), "{} {:?}", tool, args);
+        assert!(unpack(&packed, data.len()) == data, "{} {:?} mismatch", tool, args);
+    }
+}
+
+#[test]
+fn gzip() {
+    check(Format::Gzip, "gzip", &[&["-c", "-9"], &["-c", "-1"], &["-c", "-9", "--no-name"]]);
+}
+
+#[test]
+fn zstd() {
+    check(
+        Format::Zstd,
+        "zstd",
+        &[&["-c", "-19"], &["-c", "-1", "--no-check"], &["-c", "-3", "--no-content-size"]],
+    );
+}
+
+#[test]
+fn lz4() {
+    check(
+        Format::Lz4,
+        "lz4",
+        &[&["-c", "-9"], &["-c", "-1", "-BD", "-B4", "-BX", "--content-size"], &["-c", "-l"]],
+    );
+}
+
+#[test]
+fn concatenated_and_padded() {
+    let data = sample();
+    let (first, second) = data.split_at(100_000);
+    let Some(mut packed) = compress("zstd", &["-c"], first) else {
+        return;
+    };
+    let Some(gz) = compress("gzip", &["-c"], second) else {
+        return;
+    };
+    // A skippable frame between, and page-rounding zeros after
+    packed.extend_from_slice(&[0x5e, 0x2a, 0x4d, 0x18, 3, 0, 0, 0, 1, 2, 3]);
+    packed.extend_from_slice(&gz);
+    packed.resize(packed.len().next_multiple_of(4096), 0);
+
+    assert_eq!(decompressed_size(&packed), Ok(data.len()));
+    assert!(unpack(&packed, data.len()) == data);
+}
+
+#[test]
+fn detects_damage() {
+    let data = sample();
+    // Where each format's content checksum sits, from the end
+    for (tool, sum) in [("gzip", 8), ("zstd", 4), ("lz4", 4)] {
+        let Some(mut packed) = compress(tool, &["-c"], &data) else {
+            continue;
+        };
+        let mut out = vec![0; data.len() - 1];
+        assert_eq!(decompress(&packed, &mut out), Err(DecompressError::OutputFull), "{}", tool);
+
+        let at = packed.len() - sum;
+        packed[at] ^= 0x40;
+        let mut out = vec![0; data.len()];
+        assert_eq!(decompress(&packed, &mut out), Err(DecompressError::Checksum), "{}", tool);
+
+        assert!(decompress(&packed[..packed.len() / 2], &mut out).is_err(), "{}", tool);
+    }
+}
+
+#[test]
+fn rejects_unknown_data() {
+    assert_eq!(Format::detect(b"070701"), None);
+    assert_eq!(decompressed_size(&[0; 512]), Err(DecompressError::Corrupt));
+    assert_eq!(decompress(&[], &mut []), Err(DecompressError::Corrupt));
+}
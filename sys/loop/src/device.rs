@@ -0,0 +1,493 @@
+use crate::file::BadgedFileClient;
+use crate::image::Image;
+use crate::layout::{BUFFER_STRIDE, BUFFER_VA, RING_VA};
+use crate::overlay::Overlay;
+use alloc::string::String;
+use alloc::vec::Vec;
+use glenda::arch::mem::PGSIZE;
+use glenda::cap::{CSPACE_CAP, CapPtr, CapType, Endpoint, Page};
+use glenda::client::ResourceClient;
+use glenda::error::Error;
+use glenda::interface::{CSpaceService, ResourceService, VSpaceService};
+use glenda::io::uring::{IOURING_OP_READ, IOURING_OP_SYNC, IOURING_OP_WRITE, IoUringSqe};
+use glenda::io::uring::{IoUringBuffer as IoUring, IoUringServer};
+use glenda::ipc::Badge;
+use glenda::mem::Perms;
+use glenda::mem::shm::SharedMemory;
+use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+
+/// Upper bound on client sessions (one per badge), across all instances.
+pub const MAX_SESSIONS: usize = 16;
+/// Upper bound on attached loop instances.
+pub const MAX_INSTANCES: usize = 8;
+
+/// Bits of a client badge selecting the instance: n + 1 for `loop{n}`. Unbadged
+/// callers address loop0.
+pub const LOOP_BADGE_SHIFT: usize = 16;
+pub const LOOP_BADGE_MASK: usize = 0xff << LOOP_BADGE_SHIFT;
+
+/// Badge bits addressing instance `n`.
+pub fn loop_badge(n: usize) -> usize {
+    ((n + 1) << LOOP_BADGE_SHIFT) & LOOP_BADGE_MASK
+}
+
+/// Instance a badge addresses.
+pub fn badge_instance(badge: usize) -> usize {
+    ((badge & LOOP_BADGE_MASK) >> LOOP_BADGE_SHIFT).saturating_sub(1)
+}
+
+/// What to serve and how to slice it.
+#[derive(Debug, Clone)]
+pub struct LoopConfig {
+    /// Backing file on the VFS, raw or qcow2.
+    pub path: String,
+    /// Block size reported to clients; SQE offsets count these.
+    pub block_size: u32,
+    /// Byte offset of block 0 within the image.
+    pub offset: usize,
+    /// Largest number of bytes served from `offset`, `None` for up to the end
+    /// of the image.
+    pub size_limit: Option<usize>,
+    /// Delta file taking this instance's writes; `path` is then only read.
+    pub overlay: Option<String>,
+    /// With an overlay, all-zero writes take no space in the delta.
+    pub sparse: bool,
+}
+
+impl Default for LoopConfig {
+    fn default() -> Self {
+        Self {
+            path: String::from("/disk.img"),
+            block_size: 512,
+            offset: 0,
+            size_limit: None,
+            overlay: None,
+            sparse: false,
+        }
+    }
+}
+
+/// Per-client state, keyed by the client's badge. Sessions end with their
+/// instance: a badge names whatever gets attached in its place next.
+struct Session {
+    badge: usize,
+    instance: usize,
+    ring: Option<IoUringServer>,
+    buffer: Option<SharedMemory>,
+    /// Cspace slots of the ring frame, notify endpoint and buffer frame.
+    caps: Vec<CapPtr>,
+    /// Writes are refused.
+    read_only: bool,
+}
+
+/// A block device backed by a file on the VFS.
+pub struct LoopDevice {
+    image: Image,
+    /// Copy-on-write delta over `image`.
+    overlay: Option<Overlay>,
+    config: LoopConfig,
+    /// Size in blocks, recomputed whenever the layout changes.
+    capacity: usize,
+    /// The backing file was opened read-only.
+    file_read_only: bool,
+    /// Writes are refused; always set for a read-only file.
+    read_only: bool,
+}
+
+impl LoopDevice {
+    /// Serves `image` as laid out by `config`, with writes going to `delta` if
+    /// given; `read_only` says whichever of the two takes writes was opened
+    /// read-only. The block size must be a power of two no smaller than 512.
+    /// On failure `image` and `delta` are closed.
+    pub fn new(
+        image: Image,
+        delta: Option<BadgedFileClient>,
+        config: LoopConfig,
+        read_only: bool,
+    ) -> Result<Self, Error> {
+        let mut dev = Self {
+            image,
+            overlay: None,
+            config,
+            capacity: 0,
+            file_read_only: read_only,
+            read_only,
+        };
+        if let Err(e) = dev.configure(dev.config.clone(), read_only) {
+            if let Some(delta) = delta {
+                let _ = delta.close();
+            }
+            let _ = dev.close();
+            return Err(e);
+        }
+        if let Some(delta) = delta {
+            let block_size = dev.config.block_size as usize;
+            match Overlay::open(delta, block_size, dev.capacity, dev.config.sparse) {
+                Ok(overlay) => dev.overlay = Some(overlay),
+                Err(e) => {
+                    let _ = dev.close();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(dev)
+    }
+
+    /// Changes block size, offset, size limit and read-only mode. A file opened
+    /// read-only can't be made writable, and an overlay's layout is fixed by its
+    /// delta.
+    pub fn configure(&mut self, config: LoopConfig, read_only: bool) -> Result<(), Error> {
+        if config.block_size < 512 || !config.block_size.is_power_of_two() {
+            return Err(Error::InvalidArgs);
+        }
+        if self.file_read_only && !read_only {
+            return Err(Error::PermissionDenied);
+        }
+        if self.overlay.is_some()
+            && (config.block_size != self.config.block_size
+                || config.offset != self.config.offset
+                || config.size_limit != self.config.size_limit)
+        {
+            return Err(Error::InvalidArgs);
+        }
+        // An overlay's table was sized when it was opened
+        if self.overlay.is_none() {
+            let mut bytes = self.image.size()?.saturating_sub(config.offset);
+            if let Some(limit) = config.size_limit {
+                bytes = core::cmp::min(bytes, limit);
+            }
+            self.capacity = bytes / config.block_size as usize;
+        }
+        self.config = config;
+        self.read_only = read_only;
+        log!(
+            "{}: {} blocks of {} bytes from offset {:#x}{}",
+            self.config.path,
+            self.capacity,
+            self.config.block_size,
+            self.config.offset,
+            if read_only { ", read-only" } else { "" }
+        );
+        Ok(())
+    }
+
+    pub fn config(&self) -> &LoopConfig {
+        &self.config
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.config.block_size
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn is_qcow2(&self) -> bool {
+        self.image.is_qcow2()
+    }
+
+    /// Closes the backing file and delta.
+    pub fn close(self) -> Result<(), Error> {
+        if let Some(overlay) = self.overlay {
+            overlay.close()?;
+        }
+        self.image.close()
+    }
+
+    /// Moves `buf.len()` bytes between the image and `buf`, starting at `block`.
+    fn transfer(&mut self, write: bool, block: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        if write && self.read_only {
+            return Err(Error::PermissionDenied);
+        }
+        let block_size = self.config.block_size as usize;
+        let len = buf.len();
+        if len % block_size != 0 {
+            error!("Request length not aligned to block size ({}): len={}", block_size, len);
+            return Err(Error::InvalidArgs);
+        }
+        let end = block.checked_add(len / block_size);
+        if end.is_none_or(|end| end > self.capacity) {
+            error!("Request beyond end of device: block={}, len={}", block, len);
+            return Err(Error::InvalidArgs);
+        }
+
+        if let Some(overlay) = self.overlay.as_mut() {
+            if write {
+                overlay.write(block, buf)?;
+            } else {
+                overlay.read(&mut self.image, self.config.offset, block, buf)?;
+            }
+            return Ok(len);
+        }
+
+        let pos = self.config.offset + block * block_size;
+        if write {
+            self.image.write_at(pos, buf)?;
+        } else {
+            self.image.read_at(pos, buf)?;
+        }
+        Ok(len)
+    }
+}
+
+/// All loop instances and the clients using them.
+#[derive(Default)]
+pub struct LoopDriver {
+    instances: [Option<LoopDevice>; MAX_INSTANCES],
+    /// Open sessions; a session's index fixes where its ring and buffer are mapped.
+    sessions: Vec<Option<Session>>,
+}
+
+impl LoopDriver {
+    /// Lowest instance with nothing attached.
+    pub fn free_instance(&self) -> Result<usize, Error> {
+        self.instances.iter().position(|i| i.is_none()).ok_or(Error::OutOfMemory)
+    }
+
+    /// Puts `device` in the lowest free instance and returns its number.
+    pub fn attach(&mut self, device: LoopDevice) -> Result<usize, Error> {
+        let n = self.free_instance()?;
+        log!("loop{}: attached {}", n, device.config().path);
+        self.instances[n] = Some(device);
+        Ok(n)
+    }
+
+    /// Removes instance `n` and closes its sessions, unmapping their rings and
+    /// buffers and freeing their slots; its clients get `NotFound` until it's
+    /// attached again.
+    pub fn detach(
+        &mut self,
+        vspace_mgr: &mut VSpaceManager,
+        cspace_mgr: &mut CSpaceManager,
+        n: usize,
+    ) -> Result<LoopDevice, Error> {
+        let device = self.instances.get_mut(n).and_then(|i| i.take()).ok_or(Error::NotFound)?;
+        for (idx, slot) in self.sessions.iter_mut().enumerate() {
+            if slot.as_ref().is_none_or(|s| s.instance != n) {
+                continue;
+            }
+            let session = slot.take().unwrap();
+            if session.ring.is_some() {
+                let _ = vspace_mgr.unmap(RING_VA + idx * PGSIZE, 1);
+            }
+            if let Some(shm) = session.buffer {
+                let _ = vspace_mgr.unmap(BUFFER_VA + idx * BUFFER_STRIDE, shm.size() / PGSIZE);
+            }
+            for cap in session.caps {
+                let _ = CSPACE_CAP.delete(cap);
+                cspace_mgr.free(cap);
+            }
+            log!("Session {} closed", idx);
+        }
+        log!("loop{}: detached {}", n, device.config().path);
+        Ok(device)
+    }
+
+    pub fn instance(&self, n: usize) -> Result<&LoopDevice, Error> {
+        self.instances.get(n).and_then(|i| i.as_ref()).ok_or(Error::NotFound)
+    }
+
+    pub fn instance_mut(&mut self, n: usize) -> Result<&mut LoopDevice, Error> {
+        self.instances.get_mut(n).and_then(|i| i.as_mut()).ok_or(Error::NotFound)
+    }
+
+    /// Index of the session for `badge`, opening one on first contact.
+    fn session(&mut self, badge: usize) -> Result<usize, Error> {
+        let open = |s: &Option<Session>| s.as_ref().is_some_and(|s| s.badge == badge);
+        if let Some(idx) = self.sessions.iter().position(open) {
+            return Ok(idx);
+        }
+        // Slots of closed sessions are taken first
+        let idx = match self.sessions.iter().position(Option::is_none) {
+            Some(idx) => idx,
+            None if self.sessions.len() < MAX_SESSIONS => {
+                self.sessions.push(None);
+                self.sessions.len() - 1
+            }
+            None => return Err(Error::OutOfMemory),
+        };
+        let instance = badge_instance(badge);
+        self.sessions[idx] = Some(Session {
+            badge,
+            instance,
+            ring: None,
+            buffer: None,
+            caps: Vec::new(),
+            read_only: false,
+        });
+        log!("Session {} opened for badge {:#x} on loop{}", idx, badge, instance);
+        Ok(idx)
+    }
+
+    fn session_mut(&mut self, idx: usize) -> &mut Session {
+        self.sessions[idx].as_mut().expect("session is open")
+    }
+
+    /// Capacity seen by the client `badge`; 0 if its instance isn't attached.
+    pub fn capacity_of(&self, badge: usize) -> usize {
+        self.instance(badge_instance(badge)).map_or(0, |d| d.capacity())
+    }
+
+    pub fn block_size_of(&self, badge: usize) -> u32 {
+        self.instance(badge_instance(badge)).map_or(0, |d| d.block_size())
+    }
+
+    /// Makes the session of `badge` read-only; there is no way back.
+    pub fn set_read_only(&mut self, badge: usize) -> Result<(), Error> {
+        let idx = self.session(badge)?;
+        self.session_mut(idx).read_only = true;
+        log!("Session {} is read-only", idx);
+        Ok(())
+    }
+
+    /// Whether the client `badge` may only read, by instance or session.
+    pub fn is_read_only_for(&self, badge: usize) -> bool {
+        let instance = self.instance(badge_instance(badge)).is_ok_and(|d| d.is_read_only());
+        instance || self.sessions.iter().flatten().any(|s| s.badge == badge && s.read_only)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn setup_buffer(
+        &mut self,
+        res: &mut ResourceClient,
+        vspace_mgr: &mut VSpaceManager,
+        cspace_mgr: &mut CSpaceManager,
+        badge: usize,
+        frame: Page,
+        client_vaddr: usize,
+        size: usize,
+        paddr: usize,
+    ) -> Result<(), Error> {
+        let idx = self.session(badge)?;
+        let pages = size.div_ceil(PGSIZE);
+        if pages * PGSIZE > BUFFER_STRIDE {
+            return Err(Error::InvalidArgs);
+        }
+        // Each session's window gets its own slice of the address space
+        let buffer_va = BUFFER_VA + idx * BUFFER_STRIDE;
+        vspace_mgr.map_page(
+            frame.clone(),
+            buffer_va,
+            Perms::READ | Perms::WRITE,
+            pages,
+            res,
+            cspace_mgr,
+        )?;
+
+        let slot = frame.cap();
+        let mut shm = SharedMemory::from_frame(frame, buffer_va, pages * PGSIZE);
+        shm.set_client_vaddr(client_vaddr);
+        shm.set_paddr(paddr);
+        let session = self.session_mut(idx);
+        session.buffer = Some(shm);
+        session.caps.push(slot);
+        log!(
+            "Session {} SHM buffer setup: client_vaddr={:#x}, driver_vaddr={:#x}, size={}",
+            idx,
+            client_vaddr,
+            buffer_va,
+            size
+        );
+        Ok(())
+    }
+
+    pub fn setup_ring(
+        &mut self,
+        res: &mut ResourceClient,
+        vspace_mgr: &mut VSpaceManager,
+        cspace_mgr: &mut CSpaceManager,
+        badge: usize,
+        sq_entries: u32,
+        cq_entries: u32,
+        endpoint: Endpoint,
+    ) -> Result<Page, Error> {
+        let idx = self.session(badge)?;
+        // The ring page sits at a fixed VA per session and can't be mapped twice
+        if self.session_mut(idx).ring.is_some() {
+            return Err(Error::InvalidArgs);
+        }
+        log!("Setting up ring for session {}: SQ={}, CQ={}", idx, sq_entries, cq_entries);
+        let ring_va = RING_VA + idx * PGSIZE;
+        let slot = cspace_mgr.alloc(res)?;
+        let frame = Page::from(res.alloc(Badge::null(), CapType::Page, 1, slot)?);
+        vspace_mgr.map_page(
+            frame.clone(),
+            ring_va,
+            Perms::READ | Perms::WRITE,
+            1,
+            res,
+            cspace_mgr,
+        )?;
+
+        let ring = unsafe { IoUring::new(ring_va as *mut u8, PGSIZE, sq_entries, cq_entries) };
+        let mut server = IoUringServer::new(ring);
+        server.set_client_notify(endpoint);
+        let session = self.session_mut(idx);
+        session.ring = Some(server);
+        session.caps.extend([slot, endpoint.cap()]);
+        Ok(frame)
+    }
+
+    pub fn handle_io(&mut self) -> Result<(), Error> {
+        for idx in 0..self.sessions.len() {
+            if self.sessions[idx].is_some() {
+                self.handle_session_io(idx)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_session_io(&mut self, idx: usize) -> Result<(), Error> {
+        loop {
+            let Some(sqe) = self.session_mut(idx).ring.as_mut().and_then(|r| r.next_request())
+            else {
+                break;
+            };
+            let result = match self.process_sqe(idx, &sqe) {
+                Ok(len) => len as i32,
+                Err(e) => -(e as i32),
+            };
+            if let Some(ring) = self.session_mut(idx).ring.as_mut() {
+                ring.complete(sqe.user_data, result)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs one request against the session's instance; returns the bytes moved.
+    fn process_sqe(&mut self, idx: usize, sqe: &IoUringSqe) -> Result<usize, Error> {
+        if sqe.opcode == IOURING_OP_SYNC {
+            // Every write has reached the VFS by the time its CQE is posted
+            return Ok(0);
+        }
+        if sqe.opcode != IOURING_OP_READ && sqe.opcode != IOURING_OP_WRITE {
+            return Err(Error::NotSupported);
+        }
+        let write = sqe.opcode == IOURING_OP_WRITE;
+        let session = self.sessions[idx].as_ref().ok_or(Error::NotFound)?;
+        if write && session.read_only {
+            return Err(Error::PermissionDenied);
+        }
+
+        let len = sqe.len as usize;
+        let shm = session.buffer.as_ref().ok_or(Error::NotInitialized)?;
+        let offset = sqe
+            .addr
+            .checked_sub(shm.client_vaddr())
+            .filter(|off| off.checked_add(len).is_some_and(|end| end <= shm.size()));
+        let Some(offset) = offset else {
+            error!("Client address {:#x} out of SHM boundary", sqe.addr);
+            return Err(Error::InvalidArgs);
+        };
+        let local = (shm.vaddr() + offset) as *mut u8;
+        let buf = unsafe { core::slice::from_raw_parts_mut(local, len) };
+
+        let instance = session.instance;
+        self.instance_mut(instance)?.transfer(write, sqe.off, buf)
+    }
+}
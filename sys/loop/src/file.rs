@@ -0,0 +1,80 @@
+use glenda::cap::Endpoint;
+use glenda::error::Error;
+use glenda::ipc::{IPC_BUFFER_SIZE, MsgFlags, MsgTag, UTCB};
+use glenda::protocol::{FS_PROTO, fs};
+
+/// An open file on the VFS, addressed by the badge `open` returned.
+pub struct BadgedFileClient {
+    endpoint: Endpoint,
+    badge: usize,
+}
+
+impl BadgedFileClient {
+    pub fn new(endpoint: Endpoint, badge: usize) -> Self {
+        Self { endpoint, badge }
+    }
+
+    pub fn stat(&self) -> Result<glenda::protocol::fs::Stat, Error> {
+        let mut utcb = unsafe { UTCB::new() };
+        let tag = MsgTag::new(FS_PROTO, fs::STAT, MsgFlags::NONE);
+        utcb.set_msg_tag(tag);
+        utcb.set_mr(3, self.badge);
+
+        self.endpoint.call(&mut utcb)?;
+        let size = utcb.get_mr(0) as usize;
+        let mode = utcb.get_mr(1) as u32;
+        Ok(glenda::protocol::fs::Stat { size, mode, ..Default::default() })
+    }
+
+    pub fn close(&self) -> Result<(), Error> {
+        let mut utcb = unsafe { UTCB::new() };
+        utcb.set_msg_tag(MsgTag::new(FS_PROTO, fs::CLOSE, MsgFlags::NONE));
+        utcb.set_mr(3, self.badge);
+        self.endpoint.call(&mut utcb)?;
+        Ok(())
+    }
+
+    /// Fills `buf` from `offset`, one IPC buffer at a time. A short read means
+    /// the file ended.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut done = 0;
+        for chunk in buf.chunks_mut(IPC_BUFFER_SIZE) {
+            let mut utcb = unsafe { UTCB::new() };
+            utcb.set_msg_tag(MsgTag::new(FS_PROTO, fs::READ, MsgFlags::NONE));
+            utcb.set_mr(0, chunk.len());
+            utcb.set_mr(1, offset + done);
+            utcb.set_mr(3, self.badge);
+
+            self.endpoint.call(&mut utcb)?;
+            let count = core::cmp::min(utcb.get_mr(0), chunk.len());
+            chunk[..count].copy_from_slice(&utcb.ipc_buffer()[..count]);
+            done += count;
+            if count < chunk.len() {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Writes `data` at `offset`, one IPC buffer at a time.
+    pub fn write_at(&self, offset: usize, data: &[u8]) -> Result<usize, Error> {
+        let mut done = 0;
+        for chunk in data.chunks(IPC_BUFFER_SIZE) {
+            let mut utcb = unsafe { UTCB::new() };
+            utcb.set_msg_tag(MsgTag::new(FS_PROTO, fs::WRITE, MsgFlags::NONE));
+            utcb.ipc_buffer()[..chunk.len()].copy_from_slice(chunk);
+            utcb.set_size(chunk.len());
+            utcb.set_mr(0, chunk.len());
+            utcb.set_mr(1, offset + done);
+            utcb.set_mr(3, self.badge);
+
+            self.endpoint.call(&mut utcb)?;
+            let count = core::cmp::min(utcb.get_mr(0), chunk.len());
+            done += count;
+            if count < chunk.len() {
+                break;
+            }
+        }
+        Ok(done)
+    }
+}
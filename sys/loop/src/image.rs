@@ -0,0 +1,122 @@
+//! Disk images an instance can serve: a raw file, or a qcow2 image and its
+//! backing chain.
+
+use crate::file::BadgedFileClient;
+use alloc::boxed::Box;
+use alloc::string::String;
+use glenda::error::Error;
+use qcow2::{ImageFile, Qcow2, QcowError};
+
+/// Bound on qcow2 backing chains, so a chain that loops can't hang attach.
+pub const MAX_BACKING_DEPTH: usize = 8;
+
+pub enum Image {
+    Raw(BadgedFileClient),
+    Qcow2(Qcow2<BadgedFileClient>),
+}
+
+impl Image {
+    /// Size of the disk in bytes.
+    pub fn size(&mut self) -> Result<usize, Error> {
+        match self {
+            Image::Raw(file) => Ok(file.stat()?.size),
+            Image::Qcow2(image) => Ok(image.virtual_size() as usize),
+        }
+    }
+
+    /// A qcow2 image that is dirty or corrupt can only be read.
+    pub fn is_writable(&self) -> bool {
+        match self {
+            Image::Raw(_) => true,
+            Image::Qcow2(image) => image.is_writable(),
+        }
+    }
+
+    pub fn is_qcow2(&self) -> bool {
+        matches!(self, Image::Qcow2(_))
+    }
+
+    /// Fills `buf` from byte `pos` of the disk.
+    pub fn read_at(&mut self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        match self {
+            Image::Raw(file) => ImageFile::read_at(file, pos as u64, buf),
+            Image::Qcow2(image) => image.read_at(pos as u64, buf),
+        }
+        .map_err(qcow_error)
+    }
+
+    /// Writes `data` at byte `pos` of the disk.
+    pub fn write_at(&mut self, pos: usize, data: &[u8]) -> Result<(), Error> {
+        match self {
+            Image::Raw(file) => ImageFile::write_at(file, pos as u64, data),
+            Image::Qcow2(image) => image.write_at(pos as u64, data),
+        }
+        .map_err(qcow_error)
+    }
+
+    /// For a qcow2 image to read unallocated clusters from.
+    pub fn into_backing(self) -> Box<dyn ImageFile> {
+        match self {
+            Image::Raw(file) => Box::new(file),
+            Image::Qcow2(image) => Box::new(image),
+        }
+    }
+
+    /// Closes the file, and for qcow2 its backing chain.
+    pub fn close(self) -> Result<(), Error> {
+        match self {
+            Image::Raw(file) => file.close(),
+            Image::Qcow2(mut image) => image.close().map_err(qcow_error),
+        }
+    }
+}
+
+/// Lets a file back a qcow2 image. Transfers are all-or-nothing.
+impl ImageFile for BadgedFileClient {
+    fn size(&mut self) -> Result<u64, QcowError> {
+        Ok(self.stat().map_err(|_| QcowError::Io)?.size as u64)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), QcowError> {
+        match BadgedFileClient::read_at(self, offset as usize, buf) {
+            Ok(n) if n == buf.len() => Ok(()),
+            Ok(n) => {
+                error!("Short read at {:#x}: {} of {} bytes", offset, n, buf.len());
+                Err(QcowError::Io)
+            }
+            Err(_) => Err(QcowError::Io),
+        }
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), QcowError> {
+        match BadgedFileClient::write_at(self, offset as usize, data) {
+            Ok(n) if n == data.len() => Ok(()),
+            Ok(n) => {
+                error!("Short write at {:#x}: {} of {} bytes", offset, n, data.len());
+                Err(QcowError::Io)
+            }
+            Err(_) => Err(QcowError::Io),
+        }
+    }
+
+    fn close(&mut self) -> Result<(), QcowError> {
+        BadgedFileClient::close(self).map_err(|_| QcowError::Io)
+    }
+}
+
+/// Resolves a backing file name against the directory of the image naming it.
+pub fn backing_path(image: &str, name: &str) -> String {
+    match image.rfind('/') {
+        Some(i) if !name.starts_with('/') => alloc::format!("{}{}", &image[..=i], name),
+        _ => String::from(name),
+    }
+}
+
+pub fn qcow_error(e: QcowError) -> Error {
+    match e {
+        QcowError::Io => Error::IoError,
+        QcowError::BadHeader | QcowError::Corrupt | QcowError::OutOfRange => Error::InvalidArgs,
+        QcowError::Unsupported => Error::NotSupported,
+        QcowError::ReadOnly => Error::PermissionDenied,
+    }
+}
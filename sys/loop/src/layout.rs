@@ -0,0 +1,8 @@
+use glenda::cap::CapPtr;
+
+pub const DEVICE_SLOT: CapPtr = CapPtr::from(9);
+
+pub const RING_VA: usize = 0x5000_0000;
+pub const BUFFER_VA: usize = 0x4000_0000;
+/// Each session's buffer is mapped at BUFFER_VA + session * BUFFER_STRIDE.
+pub const BUFFER_STRIDE: usize = 0x0100_0000;
@@ -5,13 +5,21 @@
 extern crate glenda;
 extern crate alloc;
 
-use glenda::cap::{CapPtr, CapType, ENDPOINT_SLOT, MONITOR_CAP};
-use glenda::client::{FsClient, ProcessClient, ResourceClient};
-use glenda::interface::{FileSystemService, ResourceService};
+use crate::layout::DEVICE_SLOT;
+use glenda::cap::{CSPACE_CAP, CapType, ENDPOINT_SLOT, Endpoint, MONITOR_CAP, VSPACE_CAP};
+use glenda::client::{DeviceClient, ProcessClient, ResourceClient};
+use glenda::interface::ResourceService;
 use glenda::ipc::Badge;
-use glenda::protocol::fs::OpenFlags;
-
+use glenda::protocol::resource::{DEVICE_ENDPOINT, ResourceType};
+use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+
+mod device;
+mod file;
+mod image;
+mod layout;
+mod overlay;
 mod server;
+use device::LoopConfig;
 use server::LoopBlockServer;
 
 #[unsafe(no_mangle)]
@@ -21,6 +29,13 @@ fn main() -> usize {
 
     let mut _proc_client = ProcessClient::new(MONITOR_CAP);
     let mut res_client = ResourceClient::new(MONITOR_CAP);
+    let mut vspace_mgr = VSpaceManager::new(VSPACE_CAP.into(), 0x1000_0000, 0x1000_0000);
+    let mut cspace_mgr = CSpaceManager::new(CSPACE_CAP, 16);
+
+    res_client
+        .get_cap(Badge::null(), ResourceType::Endpoint, DEVICE_ENDPOINT, DEVICE_SLOT)
+        .expect("Failed to get device endpoint cap");
+    let mut dev_client = DeviceClient::new(Endpoint::from(DEVICE_SLOT));
 
     // 1. Allocate Endpoint for Block Service
     if let Err(e) = res_client.alloc(Badge::null(), CapType::Endpoint, 0, ENDPOINT_SLOT) {
@@ -28,27 +43,17 @@ fn main() -> usize {
         return 1;
     }
 
-    // 2. Open backing file from VFS (Monitor)
-    // We use temporary FsClient to open, then use the returned badge for LoopBlockServer
-    let mut vfs_client = FsClient::new(MONITOR_CAP);
-    log!("Opening /disk.img...");
-
-    let file_badge =
-        match vfs_client.open(Badge::null(), "/disk.img", OpenFlags::O_RDWR, 0, CapPtr::null()) {
-            Ok(b) => b,
-            Err(e) => {
-                log!("Failed to open /disk.img: {:?}", e);
-                // Fallback for testing: maybe just assume 0 if open fails? No, return.
-                return 1;
-            }
-        };
-    log!("Opened /disk.img, handle: {}", file_badge);
-
-    // 3. Create Server
+    // 2. Serve /disk.img as loop0; more files can be attached at runtime
+    let mut server =
+        LoopBlockServer::new(&mut dev_client, &mut res_client, &mut vspace_mgr, &mut cspace_mgr);
+    let config = LoopConfig::default();
+    let path = config.path.clone();
+    match server.attach(config, false) {
+        Ok(n) => log!("Serving {} as loop{}", path, n),
+        Err(e) => warn!("Failed to attach {}: {:?}", path, e),
+    }
 
     log!("Starting LoopBlockServer loop...");
-    let mut server = LoopBlockServer::new(MONITOR_CAP, file_badge);
-
     if let Err(e) = server.run() {
         log!("LoopBlockServer failed: {:?}", e);
         return 1;
@@ -0,0 +1,268 @@
+//! Copy-on-write delta images.
+//!
+//! A delta file starts with a header, followed by a table of one little-endian
+//! `u32` per block and then the data area. A table entry of 0 means the block
+//! was never written and reads come from the base file, `ZERO_BLOCK` means the
+//! block was written with zeros and holds no data, and any other value `n`
+//! places the block in data slot `n - 1`. Slots
+//! are appended in the order blocks are first written, so the delta only grows
+//! with what the instance changes.
+//!
+//! Only the table chunks in recent use are kept in memory, so the size of the
+//! base image doesn't bound what the driver allocates.
+
+use crate::file::BadgedFileClient;
+use crate::image::Image;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use glenda::error::Error;
+
+const MAGIC: &[u8; 8] = b"GLOOPCOW";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 512;
+const ENTRY_SIZE: usize = core::mem::size_of::<u32>();
+/// Table entry for a block written as zeros in sparse mode.
+const ZERO_BLOCK: u32 = u32::MAX;
+/// Table bytes read from the delta at a time.
+const TABLE_CHUNK: usize = 4096;
+const CHUNK_ENTRIES: usize = TABLE_CHUNK / ENTRY_SIZE;
+/// Table chunks kept in memory.
+const TABLE_CACHE_CHUNKS: usize = 16;
+
+/// A cached chunk of the table.
+struct TableChunk {
+    index: usize,
+    entries: Box<[u32; CHUNK_ENTRIES]>,
+    /// Access stamp; the least recent chunk is evicted first.
+    used: u64,
+}
+
+pub struct Overlay {
+    delta: BadgedFileClient,
+    block_size: usize,
+    blocks: usize,
+    /// Recently used table chunks. Entries are written through, so none is dirty.
+    cache: Vec<TableChunk>,
+    clock: u64,
+    /// Data slots in use.
+    slots: u32,
+    data_offset: usize,
+    /// Record all-zero writes in the table instead of allocating a slot.
+    sparse: bool,
+}
+
+impl Overlay {
+    /// Opens `delta` for an image of `blocks` blocks, formatting it if empty.
+    /// An existing delta must have been made with the same geometry; on failure
+    /// `delta` is closed.
+    pub fn open(
+        delta: BadgedFileClient,
+        block_size: usize,
+        blocks: usize,
+        sparse: bool,
+    ) -> Result<Self, Error> {
+        // Every block may take a slot, and slot numbers stop short of ZERO_BLOCK
+        if blocks >= ZERO_BLOCK as usize {
+            error!("Image of {} blocks is too large for an overlay", blocks);
+            let _ = delta.close();
+            return Err(Error::NotSupported);
+        }
+        let table_bytes = blocks * ENTRY_SIZE;
+        let data_offset = (HEADER_SIZE + table_bytes).next_multiple_of(block_size);
+        let mut overlay = Self {
+            delta,
+            block_size,
+            blocks,
+            cache: Vec::new(),
+            clock: 0,
+            slots: 0,
+            data_offset,
+            sparse,
+        };
+        match overlay.load(blocks) {
+            Ok(()) => Ok(overlay),
+            Err(e) => {
+                let _ = overlay.delta.close();
+                Err(e)
+            }
+        }
+    }
+
+    /// Formats an empty delta, or checks the header and scans the table.
+    fn load(&mut self, blocks: usize) -> Result<(), Error> {
+        if self.delta.stat()?.size == 0 {
+            return self.format(blocks);
+        }
+
+        let mut header = [0u8; HEADER_SIZE];
+        self.read_exact(0, &mut header)?;
+        let field = |off: usize| u64::from_le_bytes(header[off..off + 8].try_into().unwrap());
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let header_block_size = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        if &header[0..8] != MAGIC || version != VERSION {
+            error!("Delta has no valid header");
+            return Err(Error::InvalidArgs);
+        }
+        if header_block_size as usize != self.block_size
+            || field(16) as usize != blocks
+            || field(24) as usize != HEADER_SIZE
+            || field(32) as usize != self.data_offset
+        {
+            error!("Delta geometry does not match the base image");
+            return Err(Error::InvalidArgs);
+        }
+
+        // The slots in use follow the highest one the table points at
+        let mut entries = [0u32; CHUNK_ENTRIES];
+        for index in 0..blocks.div_ceil(CHUNK_ENTRIES) {
+            self.read_chunk(index, &mut entries)?;
+            let top = entries.iter().filter(|&&e| e != 0 && e != ZERO_BLOCK).max();
+            self.slots = self.slots.max(top.copied().unwrap_or(0));
+        }
+        log!("Delta: {} of {} blocks written", self.slots, blocks);
+        Ok(())
+    }
+
+    /// Writes the header and an empty table.
+    fn format(&mut self, blocks: usize) -> Result<(), Error> {
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&(self.block_size as u32).to_le_bytes());
+        header[16..24].copy_from_slice(&(blocks as u64).to_le_bytes());
+        header[24..32].copy_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+        header[32..40].copy_from_slice(&(self.data_offset as u64).to_le_bytes());
+        self.write_exact(0, &header)?;
+
+        let zeros = [0u8; HEADER_SIZE];
+        let mut pos = HEADER_SIZE;
+        while pos < self.data_offset {
+            let len = core::cmp::min(zeros.len(), self.data_offset - pos);
+            self.write_exact(pos, &zeros[..len])?;
+            pos += len;
+        }
+        log!("Formatted delta for {} blocks", blocks);
+        Ok(())
+    }
+
+    /// Fills `buf` from `block` onwards; blocks never written come from `base`,
+    /// whose block 0 is at byte `base_offset`.
+    pub fn read(
+        &mut self,
+        base: &mut Image,
+        base_offset: usize,
+        block: usize,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        for (i, chunk) in buf.chunks_mut(self.block_size).enumerate() {
+            let b = block + i;
+            match self.entry(b)? {
+                0 => base.read_at(base_offset + b * self.block_size, chunk)?,
+                ZERO_BLOCK => chunk.fill(0),
+                slot => self.read_exact(self.slot_offset(slot), chunk)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `data` from `block` onwards into the delta.
+    pub fn write(&mut self, block: usize, data: &[u8]) -> Result<(), Error> {
+        for (i, chunk) in data.chunks(self.block_size).enumerate() {
+            let b = block + i;
+            let entry = self.entry(b)?;
+            if entry != 0 && entry != ZERO_BLOCK {
+                self.write_exact(self.slot_offset(entry), chunk)?;
+                continue;
+            }
+            if self.sparse && chunk.iter().all(|&byte| byte == 0) {
+                if entry != ZERO_BLOCK {
+                    self.set_entry(b, ZERO_BLOCK)?;
+                }
+                continue;
+            }
+            // Data first, so a torn update leaves the block unwritten rather
+            // than pointing at garbage
+            let slot = self.slots + 1;
+            if slot == ZERO_BLOCK {
+                error!("Delta has no data slots left");
+                return Err(Error::OutOfMemory);
+            }
+            self.write_exact(self.slot_offset(slot), chunk)?;
+            self.slots = slot;
+            self.set_entry(b, slot)?;
+        }
+        Ok(())
+    }
+
+    pub fn close(self) -> Result<(), Error> {
+        self.delta.close()
+    }
+
+    fn slot_offset(&self, slot: u32) -> usize {
+        self.data_offset + (slot as usize - 1) * self.block_size
+    }
+
+    fn entry(&mut self, block: usize) -> Result<u32, Error> {
+        Ok(self.chunk(block / CHUNK_ENTRIES)?[block % CHUNK_ENTRIES])
+    }
+
+    fn set_entry(&mut self, block: usize, entry: u32) -> Result<(), Error> {
+        self.write_exact(HEADER_SIZE + block * ENTRY_SIZE, &entry.to_le_bytes())?;
+        self.chunk(block / CHUNK_ENTRIES)?[block % CHUNK_ENTRIES] = entry;
+        Ok(())
+    }
+
+    /// Table chunk `index`, read in if it isn't cached.
+    fn chunk(&mut self, index: usize) -> Result<&mut [u32; CHUNK_ENTRIES], Error> {
+        self.clock += 1;
+        let pos = match self.cache.iter().position(|c| c.index == index) {
+            Some(pos) => pos,
+            None => {
+                let mut entries = Box::new([0u32; CHUNK_ENTRIES]);
+                self.read_chunk(index, &mut entries)?;
+                let chunk = TableChunk { index, entries, used: 0 };
+                if self.cache.len() < TABLE_CACHE_CHUNKS {
+                    self.cache.push(chunk);
+                    self.cache.len() - 1
+                } else {
+                    let (lru, _) =
+                        self.cache.iter().enumerate().min_by_key(|(_, c)| c.used).unwrap();
+                    self.cache[lru] = chunk;
+                    lru
+                }
+            }
+        };
+        self.cache[pos].used = self.clock;
+        Ok(&mut self.cache[pos].entries)
+    }
+
+    /// Reads table chunk `index` from the delta; entries past the last block are 0.
+    fn read_chunk(&self, index: usize, entries: &mut [u32; CHUNK_ENTRIES]) -> Result<(), Error> {
+        let first = index * CHUNK_ENTRIES;
+        let count = core::cmp::min(CHUNK_ENTRIES, self.blocks - first);
+        let mut raw = [0u8; TABLE_CHUNK];
+        let raw = &mut raw[..count * ENTRY_SIZE];
+        self.read_exact(HEADER_SIZE + first * ENTRY_SIZE, raw)?;
+        entries.fill(0);
+        for (entry, bytes) in entries.iter_mut().zip(raw.chunks_exact(ENTRY_SIZE)) {
+            *entry = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        Ok(())
+    }
+
+    fn read_exact(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        if self.delta.read_at(pos, buf)? != buf.len() {
+            error!("Short read from delta at {:#x}", pos);
+            return Err(Error::IoError);
+        }
+        Ok(())
+    }
+
+    fn write_exact(&self, pos: usize, data: &[u8]) -> Result<(), Error> {
+        if self.delta.write_at(pos, data)? != data.len() {
+            error!("Short write to delta at {:#x}", pos);
+            return Err(Error::IoError);
+        }
+        Ok(())
+    }
+}
@@ -1,39 +1,270 @@
-use glenda::cap::{ENDPOINT_CAP, Endpoint, RECV_SLOT, REPLY_CAP, REPLY_SLOT};
+use crate::device::{
+    LoopConfig, LoopDevice, LoopDriver, MAX_INSTANCES, badge_instance, loop_badge,
+};
+use crate::file::BadgedFileClient;
+use crate::image::{Image, MAX_BACKING_DEPTH, backing_path, qcow_error};
+use alloc::string::String;
+use block_proto::{BLOCK_GET_READ_ONLY, BLOCK_RING_READ_ONLY};
+use glenda::cap::{
+    CSPACE_CAP, CapPtr, ENDPOINT_CAP, ENDPOINT_SLOT, Endpoint, MONITOR_CAP, Page, RECV_SLOT,
+    REPLY_CAP, REPLY_SLOT,
+};
+use glenda::client::{DeviceClient, FsClient, ResourceClient};
 use glenda::drivers::protocol::{BLOCK_PROTO, block};
 use glenda::error::Error;
-use glenda::ipc::{MsgFlags, MsgTag, UTCB};
-use glenda::protocol::{FS_PROTO, fs};
+use glenda::interface::{CSpaceService, DeviceService, FileSystemService};
+use glenda::ipc::server::{handle_call, handle_cap_call, handle_notify};
+use glenda::ipc::{Badge, IPC_BUFFER_SIZE, MsgTag, UTCB};
+use glenda::protocol::device::{LogicDeviceDesc, LogicDeviceType};
+use glenda::protocol::fs::OpenFlags;
+use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+use qcow2::{Qcow2, QcowError};
 
-pub struct BadgedFileClient {
-    endpoint: Endpoint,
-    badge: usize,
+// BLOCK_PROTO control labels, in the manner of LOOP_CONFIGURE / LOOP_CLR_FD /
+// LOOP_GET_STATUS / LOOP_SET_STATUS. All but LOOP_GET_STATUS are served on the
+// unbadged control endpoint only.
+/// Attaches the file named in the IPC buffer, optionally followed by a NUL and
+/// the path of a delta file to overlay it with. MR 0 offset, MR 1 size limit
+/// (0 for none), MR 2 block size (0 for 512), MR 3 flags. Returns the instance.
+pub const LOOP_ATTACH: usize = 0x200;
+/// Detaches instance MR 0 and closes its file.
+pub const LOOP_DETACH: usize = 0x201;
+/// Reports instance MR 0: MR 0 offset, MR 1 size limit, MR 2 block size,
+/// MR 3 flags, MR 4 capacity in blocks, MR 5 path length; the path goes in the
+/// IPC buffer as for LOOP_ATTACH.
+pub const LOOP_GET_STATUS: usize = 0x202;
+/// Reconfigures instance MR 0: MR 1 offset, MR 2 size limit, MR 3 block size
+/// (0 to keep it), MR 4 flags. An overlaid instance can only change flags.
+pub const LOOP_SET_STATUS: usize = 0x203;
+/// LOOP_* flag: serve the file read-only.
+pub const LOOP_FLAG_READ_ONLY: usize = 1 << 0;
+/// LOOP_* flag: don't store all-zero blocks in the delta.
+pub const LOOP_FLAG_SPARSE: usize = 1 << 1;
+/// LOOP_GET_STATUS flag: the instance has a delta.
+pub const LOOP_FLAG_OVERLAY: usize = 1 << 2;
+/// LOOP_GET_STATUS flag: the file is a qcow2 image.
+pub const LOOP_FLAG_QCOW2: usize = 1 << 3;
+
+/// Badge of the driver's own endpoint. Instance clients reach it badged through
+/// the device manager, so only the driver's owner calls in unbadged.
+const CONTROL_BADGE: usize = 0;
+
+/// Attaching, detaching and reconfiguring are reserved for the control endpoint.
+fn require_control(badge: usize) -> Result<(), Error> {
+    if badge != CONTROL_BADGE {
+        return Err(Error::PermissionDenied);
+    }
+    Ok(())
 }
 
-impl BadgedFileClient {
-    pub fn new(endpoint: Endpoint, badge: usize) -> Self {
-        Self { endpoint, badge }
+pub struct LoopBlockServer<'a> {
+    driver: LoopDriver,
+    /// Instances already known to the device manager; a re-attached instance
+    /// keeps its registration.
+    registered: [bool; MAX_INSTANCES],
+    vfs: FsClient,
+    dev: &'a mut DeviceClient,
+    res: &'a mut ResourceClient,
+    vspace_mgr: &'a mut VSpaceManager,
+    cspace_mgr: &'a mut CSpaceManager,
+}
+
+impl<'a> LoopBlockServer<'a> {
+    pub fn new(
+        dev: &'a mut DeviceClient,
+        res: &'a mut ResourceClient,
+        vspace_mgr: &'a mut VSpaceManager,
+        cspace_mgr: &'a mut CSpaceManager,
+    ) -> Self {
+        Self {
+            driver: LoopDriver::default(),
+            registered: [false; MAX_INSTANCES],
+            vfs: FsClient::new(MONITOR_CAP),
+            dev,
+            res,
+            vspace_mgr,
+            cspace_mgr,
+        }
     }
 
-    pub fn stat(&self) -> Result<glenda::protocol::fs::Stat, Error> {
-        let mut utcb = unsafe { UTCB::new() };
-        let tag = MsgTag::new(FS_PROTO, fs::STAT, MsgFlags::NONE);
-        utcb.set_msg_tag(tag);
-        utcb.set_mr(3, self.badge);
+    /// Opens `config.path` and serves it as a new instance, registered with the
+    /// device manager as `loop{n}`. A file that can't be opened for writing is
+    /// served read-only. qcow2 images are recognised by their header and served
+    /// as the disk they describe. With an overlay the file is only read and
+    /// writes go to the delta, which is created if missing.
+    pub fn attach(&mut self, config: LoopConfig, read_only: bool) -> Result<usize, Error> {
+        // A device with nowhere to go would take its open files with it
+        self.driver.free_instance()?;
+        let vfs = &mut self.vfs;
+        let mut open = |path: &str, flags| vfs.open(Badge::null(), path, flags, 0, CapPtr::null());
+        let writable = match config.overlay {
+            Some(_) => OpenFlags::O_RDWR | OpenFlags::O_CREAT,
+            None => OpenFlags::O_RDWR,
+        };
+        let target = config.overlay.as_deref().unwrap_or(&config.path);
+        let (target_badge, file_read_only) = if read_only {
+            (open(target, OpenFlags::O_RDONLY)?, true)
+        } else {
+            match open(target, writable) {
+                Ok(b) => (b, false),
+                Err(e) => {
+                    log!("Cannot open {} read-write ({:?}), trying read-only", target, e);
+                    (open(target, OpenFlags::O_RDONLY)?, true)
+                }
+            }
+        };
+        let (file_badge, delta_badge) = match config.overlay {
+            Some(_) => match open(&config.path, OpenFlags::O_RDONLY) {
+                Ok(b) => (b, Some(target_badge)),
+                Err(e) => {
+                    let _ = BadgedFileClient::new(MONITOR_CAP, target_badge).close();
+                    return Err(e);
+                }
+            },
+            None => (target_badge, None),
+        };
+
+        let writable = !file_read_only && delta_badge.is_none();
+        let image = match self.open_image(&config.path, file_badge, writable, 0) {
+            Ok(image) => image,
+            Err(e) => {
+                if let Some(b) = delta_badge {
+                    let _ = BadgedFileClient::new(MONITOR_CAP, b).close();
+                }
+                return Err(e);
+            }
+        };
+        // A dirty qcow2 image comes back read-only
+        let file_read_only = file_read_only || (writable && !image.is_writable());
+        let delta = delta_badge.map(|b| BadgedFileClient::new(MONITOR_CAP, b));
+        let device = LoopDevice::new(image, delta, config, file_read_only)?;
+        let n = self.driver.attach(device)?;
 
-        self.endpoint.call(&mut utcb)?;
-        let size = utcb.get_mr(0) as usize;
-        let mode = utcb.get_mr(1) as u32;
-        Ok(glenda::protocol::fs::Stat { size, mode, ..Default::default() })
+        if !self.registered[n] {
+            let desc = LogicDeviceDesc {
+                name: alloc::format!("loop{}", n),
+                parent_name: String::from("loop"),
+                dev_type: LogicDeviceType::Block,
+                badge: Some(loop_badge(n)),
+            };
+            if let Err(e) = self.dev.register_logic(Badge::null(), desc, ENDPOINT_SLOT) {
+                if let Ok(device) = self.driver.detach(self.vspace_mgr, self.cspace_mgr, n) {
+                    let _ = device.close();
+                }
+                return Err(e);
+            }
+            self.registered[n] = true;
+        }
+        Ok(n)
     }
-}
 
-pub struct LoopBlockServer {
-    file: BadgedFileClient,
-}
+    /// Wraps the open file `badge` in the format it holds, opening a qcow2
+    /// image's backing chain read-only. On failure the file is closed.
+    fn open_image(
+        &mut self,
+        path: &str,
+        badge: usize,
+        writable: bool,
+        depth: usize,
+    ) -> Result<Image, Error> {
+        let mut file = BadgedFileClient::new(MONITOR_CAP, badge);
+        match qcow2::probe(&mut file) {
+            Ok(false) => return Ok(Image::Raw(file)),
+            Ok(true) => {}
+            Err(e) => {
+                let _ = file.close();
+                return Err(qcow_error(e));
+            }
+        }
+
+        let opened = match Qcow2::open(file, writable) {
+            Err(QcowError::ReadOnly) if writable => {
+                warn!("{}: qcow2 image is dirty or corrupt, serving it read-only", path);
+                Qcow2::open(BadgedFileClient::new(MONITOR_CAP, badge), false)
+            }
+            opened => opened,
+        };
+        let mut image = match opened {
+            Ok(image) => image,
+            Err(e) => {
+                error!("{}: cannot open qcow2 image: {:?}", path, e);
+                let _ = BadgedFileClient::new(MONITOR_CAP, badge).close();
+                return Err(qcow_error(e));
+            }
+        };
+
+        if let Some(name) = image.backing_file() {
+            let backing = backing_path(path, name);
+            let result = if depth + 1 >= MAX_BACKING_DEPTH {
+                error!("{}: backing chain deeper than {}", path, MAX_BACKING_DEPTH);
+                Err(Error::NotSupported)
+            } else {
+                self.vfs
+                    .open(Badge::null(), &backing, OpenFlags::O_RDONLY, 0, CapPtr::null())
+                    .and_then(|b| self.open_image(&backing, b, false, depth + 1))
+            };
+            let result =
+                result.and_then(|b| image.set_backing(b.into_backing()).map_err(qcow_error));
+            if let Err(e) = result {
+                let _ = Image::Qcow2(image).close();
+                return Err(e);
+            }
+            log!("{}: backed by {}", path, backing);
+        }
+        Ok(Image::Qcow2(image))
+    }
+
+    pub fn detach(&mut self, n: usize) -> Result<(), Error> {
+        self.driver.detach(self.vspace_mgr, self.cspace_mgr, n)?.close()
+    }
+
+    /// Reports an instance; its own clients may ask, other badges may not.
+    fn get_status(&self, badge: usize, u: &mut UTCB) -> Result<(), Error> {
+        let n = u.get_mr(0);
+        if badge != CONTROL_BADGE && badge_instance(badge) != n {
+            return Err(Error::PermissionDenied);
+        }
+        let device = self.driver.instance(n)?;
+        let config = device.config();
+        let mut paths = config.path.clone();
+        if let Some(delta) = config.overlay.as_ref() {
+            paths.push('\0');
+            paths.push_str(delta);
+        }
+        let len = core::cmp::min(paths.len(), IPC_BUFFER_SIZE);
+        u.ipc_buffer()[..len].copy_from_slice(&paths.as_bytes()[..len]);
 
-impl LoopBlockServer {
-    pub fn new(file_ep: Endpoint, badge: usize) -> Self {
-        Self { file: BadgedFileClient::new(file_ep, badge) }
+        let mut flags = 0;
+        if device.is_read_only() {
+            flags |= LOOP_FLAG_READ_ONLY;
+        }
+        if config.sparse {
+            flags |= LOOP_FLAG_SPARSE;
+        }
+        if config.overlay.is_some() {
+            flags |= LOOP_FLAG_OVERLAY;
+        }
+        if device.is_qcow2() {
+            flags |= LOOP_FLAG_QCOW2;
+        }
+        u.set_mr(0, config.offset);
+        u.set_mr(1, config.size_limit.unwrap_or(0));
+        u.set_mr(2, config.block_size as usize);
+        u.set_mr(3, flags);
+        u.set_mr(4, device.capacity());
+        u.set_mr(5, len);
+        Ok(())
+    }
+
+    fn set_status(&mut self, u: &mut UTCB) -> Result<(), Error> {
+        let device = self.driver.instance_mut(u.get_mr(0))?;
+        let mut config = device.config().clone();
+        config.offset = u.get_mr(1);
+        config.size_limit = Some(u.get_mr(2)).filter(|&limit| limit != 0);
+        if u.get_mr(3) != 0 {
+            config.block_size = u.get_mr(3) as u32;
+        }
+        device.configure(config, u.get_mr(4) & LOOP_FLAG_READ_ONLY != 0)
     }
 
     pub fn run(&mut self) -> Result<(), Error> {
@@ -42,29 +273,24 @@ impl LoopBlockServer {
             utcb.clear();
             utcb.set_reply_window(REPLY_SLOT);
             utcb.set_recv_window(RECV_SLOT);
-            if let Err(_) = ENDPOINT_CAP.recv(&mut utcb) {
+            if ENDPOINT_CAP.recv(&mut utcb).is_err() {
                 continue;
             }
 
-            let res = glenda::ipc_dispatch! {
-                self, utcb,
-                (BLOCK_PROTO, block::GET_CAPACITY) => |s: &mut Self, u: &mut UTCB| {
-                    s.dispatch_capacity(u)
+            if let Err(e) = self.dispatch(&mut utcb) {
+                if e == Error::Success {
+                    continue;
                 }
-            };
-
-            let tag = utcb.get_msg_tag();
-            let reply_tag = match res {
-                Ok(val) => {
-                    utcb.set_mr(0, val);
-                    MsgTag::new(tag.proto(), tag.label(), MsgFlags::NONE)
-                }
-                Err(e) => {
-                    utcb.set_mr(0, e as usize);
-                    MsgTag::new(tag.proto(), tag.label(), MsgFlags::NONE)
-                }
-            };
-            utcb.set_msg_tag(reply_tag);
+                let tag = utcb.get_msg_tag();
+                error!(
+                    "loop: dispatch failed: {:?}, proto={:#x}, label={:#x}",
+                    e,
+                    tag.proto(),
+                    tag.label()
+                );
+                utcb.set_msg_tag(MsgTag::err());
+                utcb.set_mr(0, e as usize);
+            }
             if let Err(e) = REPLY_CAP.reply(&mut utcb)
                 && e != Error::InvalidCapability
             {
@@ -73,8 +299,98 @@ impl LoopBlockServer {
         }
     }
 
-    fn dispatch_capacity(&mut self, _utcb: &mut UTCB) -> Result<usize, Error> {
-        let stat = self.file.stat()?;
-        Ok((stat.size / 512) as usize)
+    fn dispatch(&mut self, utcb: &mut UTCB) -> Result<(), Error> {
+        let badge = utcb.get_badge().bits();
+
+        glenda::ipc_dispatch! {
+            self, utcb,
+            (glenda::protocol::KERNEL_PROTO, glenda::protocol::kernel::NOTIFY) => |s: &mut Self, u: &mut UTCB| {
+                handle_notify(u, |_| s.driver.handle_io())
+            },
+            (BLOCK_PROTO, block::GET_CAPACITY) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_| Ok(s.driver.capacity_of(badge)))
+            },
+            (BLOCK_PROTO, block::GET_BLOCK_SIZE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_| Ok(s.driver.block_size_of(badge) as usize))
+            },
+            (BLOCK_PROTO, BLOCK_GET_READ_ONLY) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_| Ok(s.driver.is_read_only_for(badge) as usize))
+            },
+            (BLOCK_PROTO, block::SETUP_BUFFER) => |s: &mut Self, u: &mut UTCB| {
+                let client_vaddr = u.get_mr(0);
+                let size = u.get_mr(1);
+                let paddr = u.get_mr(2);
+
+                // Move capabilities after reading registers
+                let slot = s.cspace_mgr.alloc(s.res)?;
+                CSPACE_CAP.transfer_self(RECV_SLOT, slot)?;
+                let frame = Page::from(slot);
+
+                handle_call(u, |_| {
+                    s.driver.setup_buffer(s.res, s.vspace_mgr, s.cspace_mgr, badge, frame, client_vaddr, size, paddr)?;
+                    Ok(0usize)
+                })
+            },
+            (BLOCK_PROTO, block::SETUP_RING) => |s: &mut Self, u: &mut UTCB| {
+                let sq = u.get_mr(0) as u32;
+                let cq = u.get_mr(1) as u32;
+                let flags = u.get_mr(2);
+
+                // Move capabilities after reading registers
+                let slot = s.cspace_mgr.alloc(s.res)?;
+                CSPACE_CAP.transfer_self(RECV_SLOT, slot)?;
+                let notify_ep = Endpoint::from(slot);
+
+                handle_cap_call(u, |_| {
+                    if flags & BLOCK_RING_READ_ONLY != 0 {
+                        s.driver.set_read_only(badge)?;
+                    }
+                    let frame = s.driver.setup_ring(s.res, s.vspace_mgr, s.cspace_mgr, badge, sq, cq, notify_ep)?;
+                    Ok(frame.cap())
+                })
+            },
+            (BLOCK_PROTO, LOOP_ATTACH) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u| {
+                    require_control(badge)?;
+                    let len = core::cmp::min(u.get_size(), IPC_BUFFER_SIZE);
+                    let paths = core::str::from_utf8(&u.ipc_buffer()[..len]).map_err(|_| Error::InvalidArgs)?;
+                    let (path, overlay) = match paths.split_once('\0') {
+                        Some((path, delta)) => (path, Some(String::from(delta))),
+                        None => (paths, None),
+                    };
+                    let flags = u.get_mr(3);
+                    let config = LoopConfig {
+                        path: String::from(path),
+                        block_size: match u.get_mr(2) {
+                            0 => 512,
+                            size => size as u32,
+                        },
+                        offset: u.get_mr(0),
+                        size_limit: Some(u.get_mr(1)).filter(|&limit| limit != 0),
+                        overlay,
+                        sparse: flags & LOOP_FLAG_SPARSE != 0,
+                    };
+                    s.attach(config, flags & LOOP_FLAG_READ_ONLY != 0)
+                })
+            },
+            (BLOCK_PROTO, LOOP_DETACH) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u| {
+                    require_control(badge)?;
+                    s.detach(u.get_mr(0))
+                })
+            },
+            (BLOCK_PROTO, LOOP_GET_STATUS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u| s.get_status(badge, u))
+            },
+            (BLOCK_PROTO, LOOP_SET_STATUS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u| {
+                    require_control(badge)?;
+                    s.set_status(u)
+                })
+            },
+            (_, _) => |_, _| {
+                Err(Error::NotSupported)
+            }
+        }
     }
 }
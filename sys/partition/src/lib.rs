@@ -0,0 +1,330 @@
+//! Partition table scanner shared by the block drivers.
+//!
+//! Reads LBA 0 and, for disks with a protective MBR, the GPT header and entry
+//! array through a [`SectorReader`], validating signatures and CRCs. The GPT
+//! backup header at the end of the disk is used if the primary is damaged.
+//! MBR disks yield the four primary entries plus any logical partitions chained
+//! from an extended partition.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Bits of a client badge that carry the partition number; 0 is the whole disk.
+pub const PARTITION_BADGE_SHIFT: usize = 16;
+pub const PARTITION_BADGE_MASK: usize = 0xff << PARTITION_BADGE_SHIFT;
+/// Highest partition number a badge can address; `scan` leaves out higher ones.
+pub const MAX_PARTITION: u32 = (PARTITION_BADGE_MASK >> PARTITION_BADGE_SHIFT) as u32;
+
+const MBR_SIGNATURE: u16 = 0xaa55;
+const MBR_TABLE_OFFSET: usize = 446;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+const MBR_EXTENDED_TYPES: [u8; 3] = [0x05, 0x0f, 0x85];
+/// Logical partitions are numbered after the four primary slots.
+const MBR_FIRST_LOGICAL: u32 = 5;
+/// Bound on the EBR chain so a looping chain can't hang the scan.
+const MBR_MAX_LOGICAL: usize = 64;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_MIN_SIZE: usize = 92;
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+/// Bound on the entry array; the spec minimum is 128 entries of 128 bytes.
+const GPT_MAX_ENTRIES: u32 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionError {
+    /// The reader failed.
+    Io,
+    /// LBA 0 carries no MBR signature.
+    NoTable,
+    /// Both GPT headers are missing or fail their CRC.
+    BadHeader,
+    /// The GPT entry array fails its CRC.
+    BadEntries,
+}
+
+/// Synchronous sector access to the disk being scanned.
+pub trait SectorReader {
+    /// Logical sector size in bytes; LBAs in the tables count these.
+    fn sector_size(&self) -> usize;
+    /// Disk size in sectors.
+    fn num_sectors(&self) -> u64;
+    /// Reads the sector at `lba` into `buf`, which is `sector_size` bytes long.
+    fn read_sector(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), PartitionError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// MBR partition type byte.
+    Mbr(u8),
+    Gpt {
+        type_guid: [u8; 16],
+        unique_guid: [u8; 16],
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    /// 1-based, as in the table (MBR logical partitions start at 5).
+    pub number: u32,
+    pub extent: Extent,
+    pub kind: PartitionKind,
+}
+
+/// A run of sectors on the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub start: u64,
+    pub len: u64,
+}
+
+impl Extent {
+    /// Maps `count` sectors at `offset` inside the extent to a disk offset, or
+    /// `None` if any of them fall outside it.
+    pub fn translate(&self, offset: u64, count: u64) -> Option<u64> {
+        let end = offset.checked_add(count)?;
+        if end > self.len {
+            return None;
+        }
+        Some(self.start + offset)
+    }
+}
+
+/// Badge bits addressing partition `number`, which must be at most `MAX_PARTITION`;
+/// wider numbers would alias other partitions or the whole disk.
+pub fn partition_badge(number: u32) -> usize {
+    debug_assert!(number <= MAX_PARTITION);
+    ((number as usize) << PARTITION_BADGE_SHIFT) & PARTITION_BADGE_MASK
+}
+
+/// Partition number a badge addresses, `None` for the whole disk.
+pub fn badge_partition(badge: usize) -> Option<u32> {
+    match (badge & PARTITION_BADGE_MASK) >> PARTITION_BADGE_SHIFT {
+        0 => None,
+        number => Some(number as u32),
+    }
+}
+
+/// Reads the partition table. A disk without an MBR signature has no table and
+/// yields `NoTable`; entries that don't fit on the disk or are numbered above
+/// `MAX_PARTITION` are skipped.
+pub fn scan(disk: &mut dyn SectorReader) -> Result<Vec<Partition>, PartitionError> {
+    let mut sector = vec![0u8; disk.sector_size()];
+    disk.read_sector(0, &mut sector)?;
+    if le16(&sector, 510) != MBR_SIGNATURE {
+        return Err(PartitionError::NoTable);
+    }
+
+    let entries = mbr_entries(&sector);
+    if entries.iter().any(|e| e.type_ == MBR_TYPE_GPT_PROTECTIVE) {
+        return scan_gpt(disk);
+    }
+    scan_mbr(disk, &entries)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MbrEntry {
+    type_: u8,
+    start: u64,
+    len: u64,
+}
+
+fn mbr_entries(sector: &[u8]) -> [MbrEntry; 4] {
+    core::array::from_fn(|i| {
+        let off = MBR_TABLE_OFFSET + i * 16;
+        MbrEntry {
+            type_: sector[off + 4],
+            start: le32(sector, off + 8) as u64,
+            len: le32(sector, off + 12) as u64,
+        }
+    })
+}
+
+fn scan_mbr(
+    disk: &mut dyn SectorReader,
+    entries: &[MbrEntry; 4],
+) -> Result<Vec<Partition>, PartitionError> {
+    let disk_len = disk.num_sectors();
+    let mut parts = Vec::new();
+    let mut push = |number: u32, type_: u8, start: u64, len: u64| {
+        if len == 0 || start == 0 || start.saturating_add(len) > disk_len || number > MAX_PARTITION
+        {
+            return;
+        }
+        let extent = Extent { start, len };
+        parts.push(Partition { number, extent, kind: PartitionKind::Mbr(type_) });
+    };
+
+    let mut extended = None;
+    for (i, e) in entries.iter().enumerate() {
+        if e.type_ == 0 {
+            continue;
+        }
+        if MBR_EXTENDED_TYPES.contains(&e.type_) {
+            extended.get_or_insert(e.start);
+            continue;
+        }
+        push(i as u32 + 1, e.type_, e.start, e.len);
+    }
+
+    // Each EBR holds one logical partition (relative to the EBR) and a link to
+    // the next EBR (relative to the start of the extended partition).
+    if let Some(base) = extended {
+        let mut sector = vec![0u8; disk.sector_size()];
+        let mut ebr = base;
+        for n in 0..MBR_MAX_LOGICAL {
+            if ebr == 0 || ebr >= disk_len {
+                break;
+            }
+            disk.read_sector(ebr, &mut sector)?;
+            if le16(&sector, 510) != MBR_SIGNATURE {
+                break;
+            }
+            let [logical, next, ..] = mbr_entries(&sector);
+            if logical.type_ != 0 {
+                push(MBR_FIRST_LOGICAL + n as u32, logical.type_, ebr + logical.start, logical.len);
+            }
+            if next.type_ == 0 || next.start == 0 {
+                break;
+            }
+            ebr = base + next.start;
+        }
+    }
+
+    Ok(parts)
+}
+
+struct GptHeader {
+    first_usable: u64,
+    last_usable: u64,
+    entries_lba: u64,
+    num_entries: u32,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+fn read_gpt_header(
+    disk: &mut dyn SectorReader,
+    lba: u64,
+) -> Result<Option<GptHeader>, PartitionError> {
+    let mut sector = vec![0u8; disk.sector_size()];
+    disk.read_sector(lba, &mut sector)?;
+    if &sector[0..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+    let header_size = le32(&sector, 12) as usize;
+    if !(GPT_HEADER_MIN_SIZE..=sector.len()).contains(&header_size) {
+        return Ok(None);
+    }
+    let stored_crc = le32(&sector, 16);
+    sector[16..20].fill(0);
+    if crc32(0, &sector[..header_size]) != stored_crc || le64(&sector, 24) != lba {
+        return Ok(None);
+    }
+
+    let entry_size = le32(&sector, 84) as usize;
+    let num_entries = le32(&sector, 80);
+    if entry_size < GPT_ENTRY_MIN_SIZE
+        || !entry_size.is_multiple_of(8)
+        || num_entries > GPT_MAX_ENTRIES
+    {
+        return Ok(None);
+    }
+    let header = GptHeader {
+        first_usable: le64(&sector, 40),
+        last_usable: le64(&sector, 48),
+        entries_lba: le64(&sector, 72),
+        num_entries,
+        entry_size,
+        entries_crc: le32(&sector, 88),
+    };
+
+    // The usable range and the entry array must lie on the disk
+    let disk_len = disk.num_sectors();
+    let entries_len = (num_entries as usize * entry_size).div_ceil(sector.len()) as u64;
+    if header.first_usable > header.last_usable
+        || header.last_usable >= disk_len
+        || header.entries_lba.checked_add(entries_len).is_none_or(|end| end > disk_len)
+    {
+        return Ok(None);
+    }
+    Ok(Some(header))
+}
+
+fn scan_gpt(disk: &mut dyn SectorReader) -> Result<Vec<Partition>, PartitionError> {
+    let last_lba = disk.num_sectors().saturating_sub(1);
+    let mut result = Err(PartitionError::BadHeader);
+    for lba in [1, last_lba] {
+        if let Some(header) = read_gpt_header(disk, lba)? {
+            result = read_gpt_entries(disk, &header);
+            if result.is_ok() {
+                break;
+            }
+        }
+    }
+    result
+}
+
+fn read_gpt_entries(
+    disk: &mut dyn SectorReader,
+    header: &GptHeader,
+) -> Result<Vec<Partition>, PartitionError> {
+    let sector_size = disk.sector_size();
+    let total = header.num_entries as usize * header.entry_size;
+    let mut entries = vec![0u8; total.div_ceil(sector_size) * sector_size];
+    for (i, chunk) in entries.chunks_mut(sector_size).enumerate() {
+        disk.read_sector(header.entries_lba + i as u64, chunk)?;
+    }
+    let entries = &entries[..total];
+    if crc32(0, entries) != header.entries_crc {
+        return Err(PartitionError::BadEntries);
+    }
+
+    let mut parts = Vec::new();
+    // Entries past MAX_PARTITION have no badge to address them by
+    let numbered = entries.chunks(header.entry_size).take(MAX_PARTITION as usize);
+    for (i, entry) in numbered.enumerate() {
+        let type_guid: [u8; 16] = entry[0..16].try_into().unwrap();
+        if type_guid == [0; 16] {
+            continue;
+        }
+        let first = le64(entry, 32);
+        let last = le64(entry, 40);
+        if first < header.first_usable || last > header.last_usable || last < first {
+            continue;
+        }
+        parts.push(Partition {
+            number: i as u32 + 1,
+            extent: Extent { start: first, len: last - first + 1 },
+            kind: PartitionKind::Gpt { type_guid, unique_guid: entry[16..32].try_into().unwrap() },
+        });
+    }
+    Ok(parts)
+}
+
+/// CRC-32 (IEEE 802.3, reflected), as used by GPT. Pass 0 to start.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn le16(buf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([buf[off], buf[off + 1]])
+}
+
+fn le32(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..off + 4].try_into().unwrap())
+}
+
+fn le64(buf: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(buf[off..off + 8].try_into().unwrap())
+}
@@ -0,0 +1,175 @@
+//! Scans hand-built MBR and GPT images held in memory.
+
+use partition::*;
+
+const SECTOR: usize = 512;
+
+struct MemDisk(Vec<u8>);
+
+impl MemDisk {
+    fn new(sectors: usize) -> Self {
+        Self(vec![0; sectors * SECTOR])
+    }
+
+    fn sector(&mut self, lba: u64) -> &mut [u8] {
+        let off = lba as usize * SECTOR;
+        &mut self.0[off..off + SECTOR]
+    }
+
+    fn mbr_entry(&mut self, lba: u64, slot: usize, type_: u8, start: u32, len: u32) {
+        let sector = self.sector(lba);
+        let off = 446 + slot * 16;
+        sector[off + 4] = type_;
+        sector[off + 8..off + 12].copy_from_slice(&start.to_le_bytes());
+        sector[off + 12..off + 16].copy_from_slice(&len.to_le_bytes());
+        sector[510] = 0x55;
+        sector[511] = 0xaa;
+    }
+}
+
+impl SectorReader for MemDisk {
+    fn sector_size(&self) -> usize {
+        SECTOR
+    }
+
+    fn num_sectors(&self) -> u64 {
+        (self.0.len() / SECTOR) as u64
+    }
+
+    fn read_sector(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), PartitionError> {
+        let data = self.0.get(lba as usize * SECTOR..(lba as usize + 1) * SECTOR);
+        buf.copy_from_slice(data.ok_or(PartitionError::Io)?);
+        Ok(())
+    }
+}
+
+/// Writes a GPT header at `lba` describing 128 entries at `entries_lba`.
+fn gpt_header(disk: &mut MemDisk, lba: u64, entries_lba: u64, last_usable: u64) {
+    gpt_header_with(disk, lba, entries_lba, last_usable, 128);
+}
+
+/// Writes a GPT header at `lba` describing `num_entries` entries at `entries_lba`.
+fn gpt_header_with(
+    disk: &mut MemDisk,
+    lba: u64,
+    entries_lba: u64,
+    last_usable: u64,
+    num_entries: u32,
+) {
+    let entries_crc = {
+        let off = entries_lba as usize * SECTOR;
+        crc32(0, &disk.0[off..off + num_entries as usize * 128])
+    };
+    let hdr = disk.sector(lba);
+    hdr[0..8].copy_from_slice(b"EFI PART");
+    hdr[12..16].copy_from_slice(&92u32.to_le_bytes());
+    hdr[24..32].copy_from_slice(&lba.to_le_bytes());
+    hdr[40..48].copy_from_slice(&34u64.to_le_bytes());
+    hdr[48..56].copy_from_slice(&last_usable.to_le_bytes());
+    hdr[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+    hdr[80..84].copy_from_slice(&num_entries.to_le_bytes());
+    hdr[84..88].copy_from_slice(&128u32.to_le_bytes());
+    hdr[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+    let crc = crc32(0, &hdr[..92]);
+    hdr[16..20].copy_from_slice(&crc.to_le_bytes());
+}
+
+fn gpt_disk() -> MemDisk {
+    let mut disk = MemDisk::new(4096);
+    disk.mbr_entry(0, 0, 0xee, 1, 4095);
+    for (i, (first, last)) in [(2048u64, 2559u64), (2560, 4000)].into_iter().enumerate() {
+        for entries_lba in [2u64, 4095 - 32] {
+            let off = entries_lba as usize * SECTOR + i * 128;
+            disk.0[off] = 0xaf;
+            disk.0[off + 16] = i as u8 + 1;
+            disk.0[off + 32..off + 40].copy_from_slice(&first.to_le_bytes());
+            disk.0[off + 40..off + 48].copy_from_slice(&last.to_le_bytes());
+        }
+    }
+    gpt_header(&mut disk, 1, 2, 4095 - 34);
+    gpt_header(&mut disk, 4095, 4095 - 32, 4095 - 34);
+    disk
+}
+
+#[test]
+fn crc32_matches_reference() {
+    assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+}
+
+#[test]
+fn mbr_primary_and_logical() {
+    let mut disk = MemDisk::new(1024);
+    disk.mbr_entry(0, 0, 0x83, 64, 100);
+    disk.mbr_entry(0, 1, 0x05, 200, 500);
+    // First EBR: logical at +10, next EBR at extended+100
+    disk.mbr_entry(200, 0, 0x83, 10, 50);
+    disk.mbr_entry(200, 1, 0x05, 100, 200);
+    disk.mbr_entry(300, 0, 0x0c, 5, 20);
+    // An entry running off the end of the disk is dropped
+    disk.mbr_entry(0, 3, 0x83, 1000, 100);
+
+    let parts = scan(&mut disk).unwrap();
+    let found: Vec<_> = parts.iter().map(|p| (p.number, p.extent.start, p.extent.len)).collect();
+    assert_eq!(found, [(1, 64, 100), (5, 210, 50), (6, 305, 20)]);
+    assert_eq!(parts[2].kind, PartitionKind::Mbr(0x0c));
+}
+
+#[test]
+fn gpt_entries_are_read() {
+    let mut disk = gpt_disk();
+    let parts = scan(&mut disk).unwrap();
+    assert_eq!(parts.len(), 2);
+    assert_eq!(parts[1].number, 2);
+    assert_eq!(parts[1].extent, Extent { start: 2560, len: 1441 });
+}
+
+#[test]
+fn gpt_falls_back_to_backup_header() {
+    let mut disk = gpt_disk();
+    // Corrupt the primary entry array; its CRC no longer matches
+    disk.sector(2)[40] ^= 0xff;
+    let parts = scan(&mut disk).unwrap();
+    assert_eq!(parts[0].extent, Extent { start: 2048, len: 512 });
+
+    disk.sector(4095)[0] = 0;
+    assert_eq!(scan(&mut disk), Err(PartitionError::BadEntries));
+}
+
+#[test]
+fn gpt_entries_beyond_badge_range_are_skipped() {
+    let mut disk = MemDisk::new(4096);
+    disk.mbr_entry(0, 0, 0xee, 1, 4095);
+    // Entries 1 and 257: the latter would alias the whole-disk badge
+    for (index, first) in [(0usize, 2048u64), (256, 3072)] {
+        let off = 2 * SECTOR + index * 128;
+        disk.0[off] = 0xaf;
+        disk.0[off + 32..off + 40].copy_from_slice(&first.to_le_bytes());
+        disk.0[off + 40..off + 48].copy_from_slice(&(first + 511).to_le_bytes());
+    }
+    gpt_header_with(&mut disk, 1, 2, 4095 - 34, 512);
+
+    let parts = scan(&mut disk).unwrap();
+    assert_eq!(parts.len(), 1);
+    assert_eq!(parts[0].number, 1);
+    assert!(parts.iter().all(|p| p.number <= MAX_PARTITION));
+}
+
+#[test]
+fn gpt_usable_range_must_fit_the_disk() {
+    let mut disk = gpt_disk();
+    gpt_header(&mut disk, 1, 2, 8191);
+    gpt_header(&mut disk, 4095, 4095 - 32, 8191);
+    assert_eq!(scan(&mut disk), Err(PartitionError::BadHeader));
+}
+
+#[test]
+fn extent_translation_enforces_bounds() {
+    let extent = Extent { start: 2048, len: 512 };
+    assert_eq!(extent.translate(0, 8), Some(2048));
+    assert_eq!(extent.translate(504, 8), Some(2552));
+    assert_eq!(extent.translate(505, 8), None);
+    assert_eq!(extent.translate(u64::MAX, 1), None);
+
+    assert_eq!(badge_partition(partition_badge(2) | 0x7), Some(2));
+    assert_eq!(badge_partition(0x7), None);
+}
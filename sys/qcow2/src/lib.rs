@@ -0,0 +1,634 @@
+//! qcow2 image reader and writer.
+//!
+//! Serves the guest-visible disk of a version 2 or 3 qcow2 file through an
+//! [`ImageFile`], walking the L1 and L2 tables on every access. Unallocated
+//! clusters read from the backing image, if one is attached, or as zeros.
+//! Writes allocate clusters at the end of the file and keep refcounts exact,
+//! copying clusters and L2 tables that internal snapshots still share.
+//! Compressed clusters, encryption, external data files and extended L2
+//! entries are not supported.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// "QFI\xfb"
+const MAGIC: u32 = 0x5146_49fb;
+const V2_HEADER_LEN: usize = 72;
+const V3_HEADER_LEN: usize = 104;
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+const MAX_REFCOUNT_ORDER: u32 = 6;
+/// Same limits as qemu, so a bad header can't make us allocate wildly.
+const MAX_BACKING_NAME: usize = 1023;
+const MAX_L1_BYTES: u64 = 32 << 20;
+const MAX_REFCOUNT_TABLE_BYTES: u64 = 8 << 20;
+
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+const INCOMPAT_CORRUPT: u64 = 1 << 1;
+const INCOMPAT_COMPRESSION_TYPE: u64 = 1 << 3;
+/// Incompatible features we can open an image with; dirty and corrupt images
+/// are only read.
+const INCOMPAT_KNOWN: u64 = INCOMPAT_DIRTY | INCOMPAT_CORRUPT | INCOMPAT_COMPRESSION_TYPE;
+
+const L1_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const REFTABLE_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+/// L1/L2 entry flag: the refcount is exactly 1, so the cluster may be written
+/// in place.
+const COPIED: u64 = 1 << 63;
+const COMPRESSED: u64 = 1 << 62;
+/// Version 3 L2 entry flag: the cluster reads as zeros.
+const ZERO: u64 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QcowError {
+    /// The file failed.
+    Io,
+    /// Not a qcow2 file, or a header field is out of range.
+    BadHeader,
+    /// The image uses a feature this implementation lacks.
+    Unsupported,
+    /// The image was opened read-only, or is dirty or corrupt.
+    ReadOnly,
+    /// The access reaches past the end of the disk.
+    OutOfRange,
+    /// A table points at an unaligned cluster or a refcount is inconsistent.
+    Corrupt,
+}
+
+/// Byte-addressed access to a file holding an image.
+pub trait ImageFile {
+    /// Size in bytes.
+    fn size(&mut self) -> Result<u64, QcowError>;
+    /// Fills `buf` from `offset`; reading past the end is an error.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), QcowError>;
+    /// Writes `data` at `offset`, growing the file as needed.
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), QcowError>;
+    /// Releases the file. The default does nothing.
+    fn close(&mut self) -> Result<(), QcowError> {
+        Ok(())
+    }
+}
+
+/// Whether `file` starts with the qcow2 magic.
+pub fn probe(file: &mut dyn ImageFile) -> Result<bool, QcowError> {
+    if file.size()? < 4 {
+        return Ok(false);
+    }
+    let mut magic = [0u8; 4];
+    file.read_at(0, &mut magic)?;
+    Ok(u32::from_be_bytes(magic) == MAGIC)
+}
+
+/// An open qcow2 image; its disk is accessed through [`ImageFile`].
+pub struct Qcow2<F> {
+    file: F,
+    version: u32,
+    cluster_bits: u32,
+    /// Disk size in bytes.
+    size: u64,
+    l1_offset: u64,
+    l1: Vec<u64>,
+    refcount_order: u32,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    backing_name: Option<String>,
+    /// Image unallocated clusters read from, with its size.
+    backing: Option<(Box<dyn ImageFile>, u64)>,
+    /// Where the next cluster is allocated: the end of the file.
+    next_free: u64,
+    writable: bool,
+}
+
+impl<F: ImageFile> Qcow2<F> {
+    /// Opens the image in `file`. Dirty or corrupt images, whose refcounts
+    /// can't be trusted, refuse to open `writable`.
+    pub fn open(mut file: F, writable: bool) -> Result<Self, QcowError> {
+        let file_size = file.size()?;
+        if file_size < V2_HEADER_LEN as u64 {
+            return Err(QcowError::BadHeader);
+        }
+        let mut header = [0u8; V3_HEADER_LEN];
+        let len = core::cmp::min(file_size, V3_HEADER_LEN as u64) as usize;
+        file.read_at(0, &mut header[..len])?;
+        if be32(&header, 0) != MAGIC {
+            return Err(QcowError::BadHeader);
+        }
+
+        let version = be32(&header, 4);
+        let (incompatible, autoclear, refcount_order) = match version {
+            2 => (0, 0, 4),
+            3 => {
+                if len < V3_HEADER_LEN || (be32(&header, 100) as usize) < V3_HEADER_LEN {
+                    return Err(QcowError::BadHeader);
+                }
+                (be64(&header, 72), be64(&header, 88), be32(&header, 96))
+            }
+            _ => return Err(QcowError::Unsupported),
+        };
+        let cluster_bits = be32(&header, 20);
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits)
+            || refcount_order > MAX_REFCOUNT_ORDER
+        {
+            return Err(QcowError::BadHeader);
+        }
+        if be32(&header, 32) != 0 || incompatible & !INCOMPAT_KNOWN != 0 {
+            return Err(QcowError::Unsupported);
+        }
+        if writable && incompatible & (INCOMPAT_DIRTY | INCOMPAT_CORRUPT) != 0 {
+            return Err(QcowError::ReadOnly);
+        }
+
+        let cluster_size = 1u64 << cluster_bits;
+        let size = be64(&header, 24);
+        let l1_size = be32(&header, 36) as u64;
+        let l1_offset = be64(&header, 40);
+        let refcount_table_offset = be64(&header, 48);
+        let refcount_table_bytes = be32(&header, 56) as u64 * cluster_size;
+        if l1_size < size.div_ceil(cluster_size * (cluster_size / 8))
+            || l1_size * 8 > MAX_L1_BYTES
+            || refcount_table_bytes > MAX_REFCOUNT_TABLE_BYTES
+            || !l1_offset.is_multiple_of(cluster_size)
+            || !refcount_table_offset.is_multiple_of(cluster_size)
+        {
+            return Err(QcowError::BadHeader);
+        }
+        let l1 = read_table(&mut file, l1_offset, l1_size as usize)?;
+        let refcount_table =
+            read_table(&mut file, refcount_table_offset, (refcount_table_bytes / 8) as usize)?;
+
+        let backing_offset = be64(&header, 8);
+        let backing_name = match backing_offset {
+            0 => None,
+            _ => {
+                let len = be32(&header, 16) as usize;
+                if len > MAX_BACKING_NAME {
+                    return Err(QcowError::BadHeader);
+                }
+                let mut name = vec![0u8; len];
+                file.read_at(backing_offset, &mut name)?;
+                Some(String::from_utf8(name).map_err(|_| QcowError::BadHeader)?)
+            }
+        };
+
+        let mut image = Self {
+            file,
+            version,
+            cluster_bits,
+            size,
+            l1_offset,
+            l1,
+            refcount_order,
+            refcount_table_offset,
+            refcount_table,
+            backing_name,
+            backing: None,
+            next_free: file_size.next_multiple_of(cluster_size),
+            writable,
+        };
+        // Extensions guarded by autoclear bits (e.g. dirty bitmaps) go stale
+        // once we write, so drop them as the spec asks
+        if writable && autoclear != 0 {
+            image.write_u64(88, 0)?;
+        }
+        Ok(image)
+    }
+
+    /// Formats `file` as an empty version 3 image of `size` bytes with
+    /// `1 << cluster_bits` byte clusters, over the image named `backing` if
+    /// given. The layout matches what qemu-img creates.
+    pub fn create(
+        file: F,
+        size: u64,
+        cluster_bits: u32,
+        backing: Option<&str>,
+    ) -> Result<Self, QcowError> {
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+            return Err(QcowError::BadHeader);
+        }
+        let cluster_size = 1u64 << cluster_bits;
+        // The backing name follows the header and an empty extension list
+        let backing_offset = V3_HEADER_LEN as u64 + 8;
+        if let Some(name) = backing {
+            if name.len() > MAX_BACKING_NAME || backing_offset + name.len() as u64 > cluster_size {
+                return Err(QcowError::BadHeader);
+            }
+        }
+        let l1_size = size.div_ceil(cluster_size * (cluster_size / 8));
+        if l1_size * 8 > MAX_L1_BYTES {
+            return Err(QcowError::BadHeader);
+        }
+
+        let mut image = Self {
+            file,
+            version: 3,
+            cluster_bits,
+            size,
+            l1_offset: 0,
+            l1: vec![0; l1_size as usize],
+            refcount_order: 4,
+            refcount_table_offset: cluster_size,
+            refcount_table: vec![0; (cluster_size / 8) as usize],
+            backing_name: backing.map(String::from),
+            backing: None,
+            next_free: 2 * cluster_size,
+            writable: true,
+        };
+        // Header, then the refcount table; the first refcount block and the
+        // L1 table are allocated after them
+        image.file.write_at(0, &vec![0u8; 2 * cluster_size as usize])?;
+        image.set_refcount(0, 1)?;
+        image.set_refcount(cluster_size, 1)?;
+        let l1_clusters = core::cmp::max(1, (l1_size * 8).div_ceil(cluster_size));
+        image.l1_offset = image.alloc_clusters(l1_clusters)?;
+        image.file.write_at(image.l1_offset, &vec![0u8; (l1_clusters * cluster_size) as usize])?;
+
+        let mut header = [0u8; V3_HEADER_LEN];
+        put32(&mut header, 0, MAGIC);
+        put32(&mut header, 4, 3);
+        if let Some(name) = backing {
+            put64(&mut header, 8, backing_offset);
+            put32(&mut header, 16, name.len() as u32);
+            image.file.write_at(backing_offset, name.as_bytes())?;
+        }
+        put32(&mut header, 20, cluster_bits);
+        put64(&mut header, 24, size);
+        put32(&mut header, 36, l1_size as u32);
+        put64(&mut header, 40, image.l1_offset);
+        put64(&mut header, 48, image.refcount_table_offset);
+        let refcount_table_clusters = image.refcount_table.len() as u64 * 8 / cluster_size;
+        put32(&mut header, 56, refcount_table_clusters as u32);
+        put32(&mut header, 96, image.refcount_order);
+        put32(&mut header, 100, V3_HEADER_LEN as u32);
+        image.file.write_at(0, &header)?;
+        Ok(image)
+    }
+
+    /// Disk size in bytes.
+    pub fn virtual_size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Name of the backing image as stored in the header, for the caller to
+    /// open and hand to [`Qcow2::set_backing`].
+    pub fn backing_file(&self) -> Option<&str> {
+        self.backing_name.as_deref()
+    }
+
+    /// Reads unallocated clusters from `backing`; past its end they are zeros.
+    /// The image keeps `backing`, and closes it, even if this fails.
+    pub fn set_backing(&mut self, backing: Box<dyn ImageFile>) -> Result<(), QcowError> {
+        let (backing, size) = self.backing.insert((backing, 0));
+        *size = backing.size()?;
+        Ok(())
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    fn check_range(&self, offset: u64, len: usize) -> Result<(), QcowError> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(QcowError::OutOfRange),
+        }
+    }
+
+    /// Validates a host offset taken from a table; 0 (unallocated) passes.
+    fn check_host(&self, host: u64) -> Result<u64, QcowError> {
+        if host & (self.cluster_size() - 1) != 0 {
+            return Err(QcowError::Corrupt);
+        }
+        Ok(host)
+    }
+
+    fn l1_index(&self, vaddr: u64) -> usize {
+        (vaddr >> (2 * self.cluster_bits - 3)) as usize
+    }
+
+    /// Byte offset of the L2 entry for `vaddr` within its table.
+    fn l2_entry_offset(&self, vaddr: u64) -> u64 {
+        ((vaddr >> self.cluster_bits) & (self.cluster_size() / 8 - 1)) * 8
+    }
+
+    /// L2 entry mapping `vaddr`, 0 if its L2 table isn't allocated.
+    fn l2_entry(&mut self, vaddr: u64) -> Result<u64, QcowError> {
+        let l2 = self.check_host(self.l1[self.l1_index(vaddr)] & L1_OFFSET_MASK)?;
+        if l2 == 0 {
+            return Ok(0);
+        }
+        self.read_u64(l2 + self.l2_entry_offset(vaddr))
+    }
+
+    /// Reads from `vaddr` within the cluster `entry` maps.
+    fn read_mapped(&mut self, entry: u64, vaddr: u64, buf: &mut [u8]) -> Result<(), QcowError> {
+        if entry & COMPRESSED != 0 {
+            return Err(QcowError::Unsupported);
+        }
+        if self.version >= 3 && entry & ZERO != 0 {
+            buf.fill(0);
+            return Ok(());
+        }
+        match self.check_host(entry & L2_OFFSET_MASK)? {
+            0 => self.read_backing(vaddr, buf),
+            host => self.file.read_at(host + (vaddr & (self.cluster_size() - 1)), buf),
+        }
+    }
+
+    fn read_backing(&mut self, vaddr: u64, buf: &mut [u8]) -> Result<(), QcowError> {
+        let Some((backing, size)) = self.backing.as_mut() else {
+            buf.fill(0);
+            return Ok(());
+        };
+        let len = core::cmp::min(size.saturating_sub(vaddr), buf.len() as u64) as usize;
+        if len > 0 {
+            backing.read_at(vaddr, &mut buf[..len])?;
+        }
+        buf[len..].fill(0);
+        Ok(())
+    }
+
+    /// Writes `data`, which lies within one cluster, at `vaddr`.
+    fn write_cluster(&mut self, vaddr: u64, data: &[u8]) -> Result<(), QcowError> {
+        let cluster_size = self.cluster_size();
+        let l2 = self.writable_l2(self.l1_index(vaddr))?;
+        let slot = l2 + self.l2_entry_offset(vaddr);
+        let entry = self.read_u64(slot)?;
+        if entry & COMPRESSED != 0 {
+            return Err(QcowError::Unsupported);
+        }
+        let host = self.check_host(entry & L2_OFFSET_MASK)?;
+        let zero = self.version >= 3 && entry & ZERO != 0;
+        if host != 0 && entry & COPIED != 0 && !zero {
+            return self.file.write_at(host + (vaddr & (cluster_size - 1)), data);
+        }
+
+        // Build the whole cluster so the copy we point at is complete
+        let start = vaddr & !(cluster_size - 1);
+        let mut cluster = vec![0u8; cluster_size as usize];
+        if data.len() < cluster.len() {
+            self.read_mapped(entry, start, &mut cluster)?;
+        }
+        let pos = (vaddr - start) as usize;
+        cluster[pos..pos + data.len()].copy_from_slice(data);
+
+        // A preallocated zero cluster we own is reused, anything shared is copied
+        let target = match host {
+            0 => self.alloc_clusters(1)?,
+            _ if entry & COPIED != 0 => host,
+            _ => self.alloc_clusters(1)?,
+        };
+        self.file.write_at(target, &cluster)?;
+        self.write_u64(slot, target | COPIED)?;
+        if host != 0 && target != host {
+            self.decref(host)?;
+        }
+        Ok(())
+    }
+
+    /// Offset of L1 entry `index`'s L2 table, allocating it, or copying it if a
+    /// snapshot shares it.
+    fn writable_l2(&mut self, index: usize) -> Result<u64, QcowError> {
+        let entry = self.l1[index];
+        let old = self.check_host(entry & L1_OFFSET_MASK)?;
+        if old != 0 && entry & COPIED != 0 {
+            return Ok(old);
+        }
+
+        let mut table = vec![0u8; self.cluster_size() as usize];
+        if old != 0 {
+            self.file.read_at(old, &mut table)?;
+        }
+        let new = self.alloc_clusters(1)?;
+        self.file.write_at(new, &table)?;
+        self.write_u64(self.l1_offset + index as u64 * 8, new | COPIED)?;
+        self.l1[index] = new | COPIED;
+        if old != 0 {
+            self.decref(old)?;
+        }
+        Ok(new)
+    }
+
+    /// Takes `count` clusters from the end of the file, with refcount 1.
+    fn alloc_clusters(&mut self, count: u64) -> Result<u64, QcowError> {
+        let cluster_size = self.cluster_size();
+        let start = self.next_free;
+        self.next_free += count * cluster_size;
+        for i in 0..count {
+            self.set_refcount(start + i * cluster_size, 1)?;
+        }
+        Ok(start)
+    }
+
+    fn decref(&mut self, host: u64) -> Result<(), QcowError> {
+        match self.get_refcount(host)? {
+            0 => Err(QcowError::Corrupt),
+            n => self.set_refcount(host, n - 1),
+        }
+    }
+
+    fn refcounts_per_block(&self) -> u64 {
+        (self.cluster_size() * 8) >> self.refcount_order
+    }
+
+    fn get_refcount(&mut self, host: u64) -> Result<u64, QcowError> {
+        let cluster = host >> self.cluster_bits;
+        let per_block = self.refcounts_per_block();
+        let block = match self.refcount_table.get((cluster / per_block) as usize) {
+            Some(&entry) => self.check_host(entry & REFTABLE_OFFSET_MASK)?,
+            None => 0,
+        };
+        if block == 0 {
+            return Ok(0);
+        }
+
+        let bits = 1u64 << self.refcount_order;
+        let pos = block + (cluster % per_block) * bits / 8;
+        if bits < 8 {
+            let mut byte = [0u8];
+            self.file.read_at(pos, &mut byte)?;
+            let shift = (cluster % per_block) * bits % 8;
+            return Ok((byte[0] as u64 >> shift) & ((1 << bits) - 1));
+        }
+        let mut raw = [0u8; 8];
+        self.file.read_at(pos, &mut raw[8 - (bits / 8) as usize..])?;
+        Ok(u64::from_be_bytes(raw))
+    }
+
+    /// Sets the refcount of the cluster at `host`, allocating a refcount block
+    /// (and growing the refcount table) if none covers it yet.
+    fn set_refcount(&mut self, host: u64, value: u64) -> Result<(), QcowError> {
+        let cluster_size = self.cluster_size();
+        let cluster = host >> self.cluster_bits;
+        let per_block = self.refcounts_per_block();
+        let index = (cluster / per_block) as usize;
+        if index >= self.refcount_table.len() {
+            self.grow_refcount_table(index)?;
+        }
+
+        let mut block = self.check_host(self.refcount_table[index] & REFTABLE_OFFSET_MASK)?;
+        if block == 0 {
+            block = self.next_free;
+            self.next_free += cluster_size;
+            self.file.write_at(block, &vec![0u8; cluster_size as usize])?;
+            self.write_u64(self.refcount_table_offset + index as u64 * 8, block)?;
+            self.refcount_table[index] = block;
+            // The block counts itself, or lands in the range of the next one
+            self.set_refcount(block, 1)?;
+        }
+
+        let bits = 1u64 << self.refcount_order;
+        if bits < 64 && value >> bits != 0 {
+            return Err(QcowError::Unsupported);
+        }
+        let pos = block + (cluster % per_block) * bits / 8;
+        if bits < 8 {
+            let mut byte = [0u8];
+            self.file.read_at(pos, &mut byte)?;
+            let shift = (cluster % per_block) * bits % 8;
+            let mask = ((1u64 << bits) - 1) << shift;
+            byte[0] = ((byte[0] as u64 & !mask) | (value << shift)) as u8;
+            return self.file.write_at(pos, &byte);
+        }
+        self.file.write_at(pos, &value.to_be_bytes()[8 - (bits / 8) as usize..])
+    }
+
+    /// Moves the refcount table to the end of the file, with room for entry
+    /// `index` and for the refcounts of the new table itself.
+    fn grow_refcount_table(&mut self, index: usize) -> Result<(), QcowError> {
+        let cluster_size = self.cluster_size();
+        let per_cluster = cluster_size / 8;
+        let per_block = self.refcounts_per_block();
+        let old_offset = self.refcount_table_offset;
+        let old_clusters = self.refcount_table.len() as u64 / per_cluster;
+
+        let start = self.next_free;
+        // Leave two spare entries for the blocks counting the table's clusters
+        let needed = |clusters: u64| {
+            let end = (start >> self.cluster_bits) + clusters;
+            core::cmp::max(index as u64 + 1, end.div_ceil(per_block) + 2)
+        };
+        let mut clusters = core::cmp::max(old_clusters, 1);
+        while clusters * per_cluster < needed(clusters) {
+            clusters *= 2;
+        }
+        if clusters * cluster_size > MAX_REFCOUNT_TABLE_BYTES {
+            return Err(QcowError::Unsupported);
+        }
+        self.next_free += clusters * cluster_size;
+
+        self.refcount_table.resize((clusters * per_cluster) as usize, 0);
+        let raw: Vec<u8> = self.refcount_table.iter().flat_map(|e| e.to_be_bytes()).collect();
+        self.file.write_at(start, &raw)?;
+        let mut header = [0u8; 12];
+        put64(&mut header, 0, start);
+        put32(&mut header, 8, clusters as u32);
+        self.file.write_at(48, &header)?;
+        self.refcount_table_offset = start;
+
+        for i in 0..clusters {
+            self.set_refcount(start + i * cluster_size, 1)?;
+        }
+        for i in 0..old_clusters {
+            self.set_refcount(old_offset + i * cluster_size, 0)?;
+        }
+        Ok(())
+    }
+
+    fn read_u64(&mut self, offset: u64) -> Result<u64, QcowError> {
+        let mut raw = [0u8; 8];
+        self.file.read_at(offset, &mut raw)?;
+        Ok(u64::from_be_bytes(raw))
+    }
+
+    fn write_u64(&mut self, offset: u64, value: u64) -> Result<(), QcowError> {
+        self.file.write_at(offset, &value.to_be_bytes())
+    }
+}
+
+/// The guest-visible disk, so an image can back another.
+impl<F: ImageFile> ImageFile for Qcow2<F> {
+    fn size(&mut self) -> Result<u64, QcowError> {
+        Ok(self.size)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), QcowError> {
+        self.check_range(offset, buf.len())?;
+        let cluster_size = self.cluster_size();
+        let mut done = 0;
+        while done < buf.len() {
+            let vaddr = offset + done as u64;
+            let len = core::cmp::min(
+                (cluster_size - (vaddr & (cluster_size - 1))) as usize,
+                buf.len() - done,
+            );
+            let entry = self.l2_entry(vaddr)?;
+            self.read_mapped(entry, vaddr, &mut buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), QcowError> {
+        if !self.writable {
+            return Err(QcowError::ReadOnly);
+        }
+        self.check_range(offset, data.len())?;
+        let cluster_size = self.cluster_size();
+        let mut done = 0;
+        while done < data.len() {
+            let vaddr = offset + done as u64;
+            let len = core::cmp::min(
+                (cluster_size - (vaddr & (cluster_size - 1))) as usize,
+                data.len() - done,
+            );
+            self.write_cluster(vaddr, &data[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Closes the backing image, then the file.
+    fn close(&mut self) -> Result<(), QcowError> {
+        if let Some((backing, _)) = self.backing.as_mut() {
+            backing.close()?;
+        }
+        self.file.close()
+    }
+}
+
+fn read_table(
+    file: &mut dyn ImageFile,
+    offset: u64,
+    entries: usize,
+) -> Result<Vec<u64>, QcowError> {
+    let mut raw = vec![0u8; entries * 8];
+    file.read_at(offset, &mut raw)?;
+    Ok(raw.chunks_exact(8).map(|e| u64::from_be_bytes(e.try_into().unwrap())).collect())
+}
+
+fn be32(buf: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes(buf[off..off + 4].try_into().unwrap())
+}
+
+fn be64(buf: &[u8], off: usize) -> u64 {
+    u64::from_be_bytes(buf[off..off + 8].try_into().unwrap())
+}
+
+fn put32(buf: &mut [u8], off: usize, value: u32) {
+    buf[off..off + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+fn put64(buf: &mut [u8], off: usize, value: u64) {
+    buf[off..off + 8].copy_from_slice(&value.to_be_bytes());
+}
@@ -0,0 +1,197 @@
+//! Reads and writes images held in memory. The tests checking against qemu-img
+//! are ignored by default; run them with `cargo test -- --ignored` where it is
+//! installed.
+
+use qcow2::*;
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::process::Command;
+use std::rc::Rc;
+
+/// A file whose bytes stay reachable after the image takes it.
+#[derive(Clone, Default)]
+struct MemFile(Rc<RefCell<Vec<u8>>>);
+
+impl MemFile {
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self(Rc::new(RefCell::new(bytes)))
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+
+impl ImageFile for MemFile {
+    fn size(&mut self) -> Result<u64, QcowError> {
+        Ok(self.0.borrow().len() as u64)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), QcowError> {
+        let data = self.0.borrow();
+        let src = data.get(offset as usize..offset as usize + buf.len()).ok_or(QcowError::Io)?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), QcowError> {
+        let mut file = self.0.borrow_mut();
+        let end = offset as usize + data.len();
+        if file.len() < end {
+            file.resize(end, 0);
+        }
+        file[offset as usize..end].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+}
+
+fn read(image: &mut dyn ImageFile, offset: u64, len: usize) -> Vec<u8> {
+    let mut buf = vec![0; len];
+    image.read_at(offset, &mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn create_write_reopen() {
+    let file = MemFile::default();
+    let mut image = Qcow2::create(file.clone(), 1 << 20, 12, None).unwrap();
+    assert_eq!(read(&mut image, 0, 4096), vec![0; 4096]);
+
+    // Spans three clusters, the middle one whole
+    let data = pattern(9000, 7);
+    image.write_at(3000, &data).unwrap();
+    image.write_at(3000, &data[..100]).unwrap();
+    assert_eq!(read(&mut image, 3000, data.len()), data);
+
+    let mut image = Qcow2::open(file, false).unwrap();
+    assert_eq!(image.virtual_size(), 1 << 20);
+    assert_eq!(read(&mut image, 3000, data.len()), data);
+    assert_eq!(read(&mut image, 0, 3000), vec![0; 3000]);
+    assert_eq!(image.write_at(0, &[1]), Err(QcowError::ReadOnly));
+    assert_eq!(image.read_at((1 << 20) - 1, &mut [0; 2]), Err(QcowError::OutOfRange));
+}
+
+#[test]
+fn backing_file_reads_through() {
+    let base = pattern(64 << 10, 3);
+    let mut image = Qcow2::create(MemFile::default(), 128 << 10, 12, Some("base.img")).unwrap();
+    assert_eq!(image.backing_file(), Some("base.img"));
+    let base_file = MemFile::from_bytes(base.clone());
+    image.set_backing(Box::new(base_file.clone())).unwrap();
+
+    assert_eq!(read(&mut image, 100, 5000), base[100..5100]);
+    // Past the end of the backing file reads zeros
+    assert_eq!(read(&mut image, 64 << 10, 10), vec![0; 10]);
+
+    // A partial write copies the rest of the cluster up from the base
+    image.write_at(4096 + 10, &[0xaa; 20]).unwrap();
+    let mut expected = base[4096..8192].to_vec();
+    expected[10..30].fill(0xaa);
+    assert_eq!(read(&mut image, 4096, 4096), expected);
+    assert_eq!(base_file.bytes(), base);
+}
+
+/// Makes a 16 MiB image with 512-byte clusters: one refcount block covers
+/// 128 KiB and the initial table 8 MiB, so filling it forces both to grow.
+fn grown_image() -> MemFile {
+    let file = MemFile::default();
+    let mut image = Qcow2::create(file.clone(), 16 << 20, 9, None).unwrap();
+    for i in 0..(16 << 20) / 65536u64 {
+        image.write_at(i * 65536, &pattern(65536, i as u8)).unwrap();
+    }
+    file
+}
+
+#[test]
+fn small_clusters_grow_refcount_table() {
+    let file = grown_image();
+    let mut image = Qcow2::open(file, true).unwrap();
+    for i in 0..(16 << 20) / 65536u64 {
+        assert_eq!(read(&mut image, i * 65536, 65536), pattern(65536, i as u8));
+    }
+}
+
+#[test]
+#[ignore = "needs qemu-img"]
+fn qemu_img_checks_grown_refcount_table() {
+    let path = scratch_file("grow.qcow2", &grown_image().bytes());
+    qemu_img(&["check", path.to_str().unwrap()]);
+}
+
+#[test]
+fn rejects_foreign_and_unsupported_images() {
+    let raw = MemFile::from_bytes(vec![0; 4096]);
+    assert!(!probe(&mut raw.clone()).unwrap());
+    assert_eq!(Qcow2::open(raw, false).err(), Some(QcowError::BadHeader));
+
+    let file = MemFile::default();
+    Qcow2::create(file.clone(), 1 << 20, 16, None).unwrap();
+    assert!(probe(&mut file.clone()).unwrap());
+    let mut bytes = file.bytes();
+
+    // Dirty: readable, but the refcounts can't be trusted for writing
+    bytes[79] = 1;
+    let dirty = MemFile::from_bytes(bytes.clone());
+    assert_eq!(Qcow2::open(dirty.clone(), true).err(), Some(QcowError::ReadOnly));
+    assert!(Qcow2::open(dirty, false).is_ok());
+
+    // Encrypted
+    bytes[79] = 0;
+    bytes[35] = 2;
+    let encrypted = MemFile::from_bytes(bytes);
+    assert_eq!(Qcow2::open(encrypted, false).err(), Some(QcowError::Unsupported));
+}
+
+#[test]
+#[ignore = "needs qemu-img"]
+fn qemu_img_interop() {
+    let dir = scratch_dir();
+    let raw_path = dir.join("interop.raw");
+    let qcow_path = dir.join("interop.qcow2");
+    let disk = [pattern(1 << 20, 1), vec![0; 1 << 20], pattern(1 << 20, 2)].concat();
+    std::fs::write(&raw_path, &disk).unwrap();
+
+    // An image qemu-img made reads back as the raw disk
+    qemu_img(&["convert", "-O", "qcow2", raw_path.to_str().unwrap(), qcow_path.to_str().unwrap()]);
+    let file = MemFile::from_bytes(std::fs::read(&qcow_path).unwrap());
+    let mut image = Qcow2::open(file.clone(), true).unwrap();
+    assert_eq!(read(&mut image, 0, disk.len()), disk);
+
+    // Our writes to it pass qemu-img check and convert back as expected
+    let update = pattern(200_000, 9);
+    image.write_at(900_000, &update).unwrap();
+    let mut expected = disk;
+    expected[900_000..1_100_000].copy_from_slice(&update);
+    std::fs::write(&qcow_path, file.bytes()).unwrap();
+    qemu_img(&["check", qcow_path.to_str().unwrap()]);
+    qemu_img(&["convert", "-O", "raw", qcow_path.to_str().unwrap(), raw_path.to_str().unwrap()]);
+    assert_eq!(std::fs::read(&raw_path).unwrap(), expected);
+}
+
+fn scratch_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("qcow2-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes `bytes` to a scratch file for qemu-img to look at.
+fn scratch_file(name: &str, bytes: &[u8]) -> PathBuf {
+    let path = scratch_dir().join(name);
+    std::fs::write(&path, bytes).unwrap();
+    path
+}
+
+/// Runs qemu-img and fails the test if it is missing or reports an error.
+fn qemu_img(args: &[&str]) {
+    let output = Command::new("qemu-img").args(args).output().expect("qemu-img not found");
+    assert!(
+        output.status.success(),
+        "qemu-img {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
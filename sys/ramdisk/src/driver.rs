@@ -1,71 +1,174 @@
-use crate::layout::{BUFFER_SLOT, BUFFER_VA, RING_SLOT, RING_VA};
+use crate::layout::{BUFFER_STRIDE, BUFFER_VA, RING_VA};
+use alloc::vec::Vec;
 use glenda::arch::mem::PGSIZE;
 use glenda::cap::{CapType, Endpoint, Page};
 use glenda::client::ResourceClient;
 use glenda::error::Error;
-use glenda::interface::{ResourceService, VSpaceService};
+use glenda::interface::{CSpaceService, ResourceService, VSpaceService};
 use glenda::io::uring::{IOURING_OP_READ, IOURING_OP_SYNC, IOURING_OP_WRITE, IoUringSqe};
 use glenda::io::uring::{IoUringBuffer as IoUring, IoUringServer};
 use glenda::ipc::Badge;
 use glenda::mem::Perms;
 use glenda::mem::shm::SharedMemory;
 use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+use partition::{Extent, Partition, PartitionError, SectorReader, badge_partition};
+
+/// Upper bound on client sessions (one per badge).
+pub const MAX_SESSIONS: usize = 4;
+/// Sector size the partition table is written in, independent of `block_size`.
+const TABLE_SECTOR_SIZE: usize = 512;
+
+/// Per-client state, keyed by the client's badge.
+struct Session {
+    badge: usize,
+    ring: Option<IoUringServer>,
+    buffer: Option<SharedMemory>,
+    /// Partition the badge addresses, in blocks.
+    extent: Option<Extent>,
+    /// Writes are refused.
+    read_only: bool,
+}
 
 pub struct Ramdisk {
     data: &'static mut [u8],
     block_size: u32,
-    ring: Option<IoUringServer>,
-    buffer: Option<SharedMemory>,
+    sessions: Vec<Session>,
+    /// Partition table found at init, with extents in blocks.
+    partitions: Vec<Partition>,
 }
 
 impl Ramdisk {
     pub fn new(data: &'static mut [u8]) -> Self {
-        Self { data, block_size: 512, ring: None, buffer: None }
+        Self { data, block_size: 512, sessions: Vec::new(), partitions: Vec::new() }
+    }
+
+    /// Index of the session for `badge`, opening one on first contact.
+    fn session(&mut self, badge: usize) -> Result<usize, Error> {
+        if let Some(idx) = self.sessions.iter().position(|s| s.badge == badge) {
+            return Ok(idx);
+        }
+        if self.sessions.len() >= MAX_SESSIONS {
+            return Err(Error::OutOfMemory);
+        }
+        let extent = match badge_partition(badge) {
+            Some(number) => {
+                let part = self.partitions.iter().find(|p| p.number == number);
+                Some(part.ok_or(Error::NotFound)?.extent)
+            }
+            None => None,
+        };
+        self.sessions.push(Session { badge, ring: None, buffer: None, extent, read_only: false });
+        log!("Session {} opened for badge {:#x}", self.sessions.len() - 1, badge);
+        Ok(self.sessions.len() - 1)
     }
 
     pub fn capacity(&self) -> usize {
         (self.data.len() as usize) / (self.block_size as usize)
     }
 
+    /// Capacity seen by the client `badge`: its partition's length, or the disk's.
+    pub fn capacity_of(&self, badge: usize) -> usize {
+        match badge_partition(badge) {
+            Some(number) => self
+                .partitions
+                .iter()
+                .find(|p| p.number == number)
+                .map_or(0, |p| p.extent.len as usize),
+            None => self.capacity(),
+        }
+    }
+
     pub fn block_size(&self) -> u32 {
         self.block_size
     }
 
+    /// Makes the session of `badge` read-only; there is no way back.
+    pub fn set_read_only(&mut self, badge: usize) -> Result<(), Error> {
+        let idx = self.session(badge)?;
+        self.sessions[idx].read_only = true;
+        log!("Session {} is read-only", idx);
+        Ok(())
+    }
+
+    /// Whether the client `badge` may only read.
+    pub fn is_read_only_for(&self, badge: usize) -> bool {
+        self.sessions.iter().any(|s| s.badge == badge && s.read_only)
+    }
+
+    pub fn partitions(&self) -> &[Partition] {
+        &self.partitions
+    }
+
+    /// Reads the partition table from the image. Call after `set_block_size`;
+    /// partitions that don't start and end on a block boundary are skipped.
+    pub fn scan_partitions(&mut self) {
+        let parts = match partition::scan(&mut SliceReader(self.data)) {
+            Ok(parts) => parts,
+            Err(PartitionError::NoTable) => {
+                log!("No partition table");
+                return;
+            }
+            Err(e) => {
+                warn!("Partition table unreadable: {:?}", e);
+                return;
+            }
+        };
+        let per_block = (self.block_size as usize / TABLE_SECTOR_SIZE) as u64;
+        for mut p in parts {
+            if per_block == 0 || p.extent.start % per_block != 0 || p.extent.len % per_block != 0 {
+                warn!("Partition {} is not aligned to {} byte blocks", p.number, self.block_size);
+                continue;
+            }
+            p.extent = Extent { start: p.extent.start / per_block, len: p.extent.len / per_block };
+            log!("Partition {}: start={}, blocks={}", p.number, p.extent.start, p.extent.len);
+            self.partitions.push(p);
+        }
+    }
+
     pub fn set_block_size(&mut self, block_size: u32) {
         self.block_size = block_size;
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn setup_buffer(
         &mut self,
         res: &mut ResourceClient,
         vspace_mgr: &mut VSpaceManager,
         cspace_mgr: &mut CSpaceManager,
+        badge: usize,
+        frame: Page,
         client_vaddr: usize,
         size: usize,
         paddr: usize,
     ) -> Result<(), Error> {
-        let frame = Page::from(BUFFER_SLOT);
+        let idx = self.session(badge)?;
         let pages = (size + glenda::arch::mem::PGSIZE - 1) / glenda::arch::mem::PGSIZE;
+        if pages * glenda::arch::mem::PGSIZE > BUFFER_STRIDE {
+            return Err(Error::InvalidArgs);
+        }
+        // Each session's window gets its own slice of the address space
+        let buffer_va = BUFFER_VA + idx * BUFFER_STRIDE;
 
         vspace_mgr.map_page(
             frame.clone(),
-            BUFFER_VA,
+            buffer_va,
             glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
             pages,
             res,
             cspace_mgr,
         )?;
 
-        // We use our own BUFFER_VA for data access, but we need to know the client's vaddr
+        // We use our own mapping for data access, but we need to know the client's vaddr
         // to translate SQE addresses.
-        let mut shm = SharedMemory::from_frame(frame, BUFFER_VA, pages * glenda::arch::mem::PGSIZE);
+        let mut shm = SharedMemory::from_frame(frame, buffer_va, pages * glenda::arch::mem::PGSIZE);
         shm.set_client_vaddr(client_vaddr);
         shm.set_paddr(paddr);
-        self.buffer = Some(shm);
+        self.sessions[idx].buffer = Some(shm);
         log!(
-            "SHM buffer setup: client_vaddr={:#x}, driver_vaddr={:#x}, paddr={:#x}, size={}",
+            "Session {} SHM buffer setup: client_vaddr={:#x}, driver_vaddr={:#x}, paddr={:#x}, size={}",
+            idx,
             client_vaddr,
-            BUFFER_VA,
+            buffer_va,
             paddr,
             size
         );
@@ -77,40 +180,58 @@ impl Ramdisk {
         res: &mut ResourceClient,
         vspace_mgr: &mut VSpaceManager,
         cspace_mgr: &mut CSpaceManager,
+        badge: usize,
         sq_entries: u32,
         cq_entries: u32,
         endpoint: Endpoint,
     ) -> Result<Page, glenda::error::Error> {
-        log!("Setting up ring: SQ={}, CQ={}", sq_entries, cq_entries);
+        let idx = self.session(badge)?;
+        // The ring page sits at a fixed VA per session and can't be mapped twice
+        if self.sessions[idx].ring.is_some() {
+            return Err(Error::InvalidArgs);
+        }
+        log!("Setting up ring for session {}: SQ={}, CQ={}", idx, sq_entries, cq_entries);
+        let ring_va = RING_VA + idx * PGSIZE;
         // 1. Allocate a frame for the ring
         // Each SQE is 64 bytes, CQE is 16 bytes. Header is 64 bytes.
         // For 4 entries, we only need a few hundred bytes, so 1 page is plenty.
-        let frame = Page::from(res.alloc(Badge::null(), CapType::Page, 1, RING_SLOT)?);
+        let slot = cspace_mgr.alloc(res)?;
+        let frame = Page::from(res.alloc(Badge::null(), CapType::Page, 1, slot)?);
         // 2. Map it in our space
         vspace_mgr.map_page(
             frame.clone(),
-            RING_VA,
+            ring_va,
             Perms::READ | Perms::WRITE,
             1,
             res,
             cspace_mgr,
         )?;
         // 3. Init IoUring
-        let ring = unsafe { IoUring::new(RING_VA as *mut u8, PGSIZE, sq_entries, cq_entries) };
+        let ring = unsafe { IoUring::new(ring_va as *mut u8, PGSIZE, sq_entries, cq_entries) };
         let mut server = IoUringServer::new(ring);
         server.set_client_notify(endpoint);
-        self.ring = Some(server);
+        self.sessions[idx].ring = Some(server);
         Ok(frame)
     }
 
     pub fn handle_io(&mut self) -> Result<(), Error> {
+        for idx in 0..self.sessions.len() {
+            self.handle_session_io(idx)?;
+        }
+        Ok(())
+    }
+
+    fn handle_session_io(&mut self, idx: usize) -> Result<(), Error> {
         loop {
-            let sqe =
-                if let Some(ref mut server) = self.ring { server.next_request() } else { None };
+            let sqe = if let Some(ref mut server) = self.sessions[idx].ring {
+                server.next_request()
+            } else {
+                None
+            };
 
             if let Some(sqe) = sqe {
-                let res_val = self.process_sqe(&sqe);
-                if let Some(ref mut server) = self.ring {
+                let res_val = self.process_sqe(idx, &sqe);
+                if let Some(ref mut server) = self.sessions[idx].ring {
                     server.complete(sqe.user_data, res_val)?;
                 }
             } else {
@@ -120,7 +241,7 @@ impl Ramdisk {
         Ok(())
     }
 
-    fn process_sqe(&mut self, sqe: &IoUringSqe) -> i32 {
+    fn process_sqe(&mut self, idx: usize, sqe: &IoUringSqe) -> i32 {
         let block_size = self.block_size();
         if sqe.len % block_size != 0 {
             error!(
@@ -130,29 +251,50 @@ impl Ramdisk {
             return -(Error::InvalidArgs as i32);
         }
 
-        let sector = sqe.off;
+        if sqe.opcode == IOURING_OP_WRITE && self.sessions[idx].read_only {
+            return -(Error::PermissionDenied as i32);
+        }
+
         let len = sqe.len as usize;
-        let addr = if let Some(ref shm) = self.buffer {
-            let buffer_vaddr = shm.vaddr();
-            let client_vaddr = shm.client_vaddr();
-            let buffer_size = shm.size();
-
-            // Translate client address to local address
-            if (sqe.addr as usize) < client_vaddr
-                || (sqe.addr as usize) + len > client_vaddr + buffer_size
-            {
-                error!(
-                    "Error: Client address {:#x} out of SHM boundary [{:#x}, {:#x})",
-                    sqe.addr,
-                    client_vaddr,
-                    client_vaddr + buffer_size
-                );
-                return -(Error::InvalidArgs as i32);
+        let sector = match self.sessions[idx].extent {
+            // Partition clients address blocks from the start of their partition
+            Some(extent) => {
+                match extent.translate(sqe.off as u64, (len / block_size as usize) as u64) {
+                    Some(sector) => sector as usize,
+                    None => {
+                        error!(
+                            "Ramdisk: request beyond end of partition: sector={}, len={}",
+                            sqe.off, len
+                        );
+                        return -(Error::InvalidArgs as i32);
+                    }
+                }
             }
-            (buffer_vaddr + (sqe.addr as usize - client_vaddr)) as *mut u8
-        } else {
-            sqe.addr as *mut u8
+            None => sqe.off,
+        };
+        // Buffers must lie in the session's window; raw addresses aren't taken
+        let Some(ref shm) = self.sessions[idx].buffer else {
+            error!("Error: Session {} has no SHM buffer", idx);
+            return -(Error::NotInitialized as i32);
+        };
+        let buffer_vaddr = shm.vaddr();
+        let client_vaddr = shm.client_vaddr();
+        let buffer_size = shm.size();
+
+        // Translate client address to local address
+        let offset = (sqe.addr as usize)
+            .checked_sub(client_vaddr)
+            .filter(|off| off.checked_add(len).is_some_and(|end| end <= buffer_size));
+        let Some(offset) = offset else {
+            error!(
+                "Error: Client address {:#x} out of SHM boundary [{:#x}, {:#x})",
+                sqe.addr,
+                client_vaddr,
+                client_vaddr + buffer_size
+            );
+            return -(Error::InvalidArgs as i32);
         };
+        let addr = (buffer_vaddr + offset) as *mut u8;
 
         log!(
             "Processing SQE: opcode={}, sector={}, len={}, addr={:?}",
@@ -162,10 +304,14 @@ impl Ramdisk {
             addr
         );
 
-        let byte_offset = sector * block_size as usize;
-        if byte_offset + len as usize > self.data.len() as usize {
+        // `sector` comes straight from the SQE for whole-disk clients
+        let byte_offset = sector.checked_mul(block_size as usize);
+        let Some(byte_offset) = byte_offset
+            .filter(|off| off.checked_add(len).is_some_and(|end| end <= self.data.len()))
+        else {
+            error!("Ramdisk: request beyond end of disk: sector={}, len={}", sector, len);
             return -(Error::InvalidArgs as i32);
-        }
+        };
 
         match sqe.opcode {
             IOURING_OP_READ => {
@@ -193,3 +339,23 @@ impl Ramdisk {
         }
     }
 }
+
+/// Sector access to the image for the partition scan.
+struct SliceReader<'a>(&'a [u8]);
+
+impl SectorReader for SliceReader<'_> {
+    fn sector_size(&self) -> usize {
+        TABLE_SECTOR_SIZE
+    }
+
+    fn num_sectors(&self) -> u64 {
+        (self.0.len() / TABLE_SECTOR_SIZE) as u64
+    }
+
+    fn read_sector(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), PartitionError> {
+        let start = lba as usize * TABLE_SECTOR_SIZE;
+        let data = self.0.get(start..start + buf.len()).ok_or(PartitionError::Io)?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+}
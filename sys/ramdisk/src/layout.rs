@@ -2,10 +2,15 @@ use glenda::cap::CapPtr;
 
 pub const DEVICE_SLOT: CapPtr = CapPtr::from(9);
 pub const MMIO_SLOT: CapPtr = CapPtr::from(10);
-pub const RING_SLOT: CapPtr = CapPtr::from(11);
-pub const BUFFER_SLOT: CapPtr = CapPtr::from(12);
-pub const NOTIFY_SLOT: CapPtr = CapPtr::from(13);
 
 pub const MMIO_VA: usize = 0x6000_0000;
+/// Unpacked images and anonymous ramdisks are mapped here.
+pub const ALLOC_VA: usize = 0x7000_0000;
 pub const RING_VA: usize = 0x5000_0000;
 pub const BUFFER_VA: usize = 0x4000_0000;
+/// Each session's buffer is mapped at BUFFER_VA + session * BUFFER_STRIDE.
+pub const BUFFER_STRIDE: usize = 0x0400_0000;
+
+/// Device property giving the size in bytes of the zero-filled ramdisk made
+/// when the device manager hands out no memory region.
+pub const ANON_SIZE_PROPERTY: &str = "ramdisk-size";
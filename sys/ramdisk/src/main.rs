@@ -32,7 +32,6 @@ pub struct RamdiskService<'a> {
     res: &'a mut ResourceClient,
     vspace_mgr: &'a mut VSpaceManager,
     cspace_mgr: &'a mut CSpaceManager,
-    connected_client: Option<usize>,
 }
 
 impl<'a> RamdiskService<'a> {
@@ -52,7 +51,6 @@ impl<'a> RamdiskService<'a> {
             res,
             vspace_mgr,
             cspace_mgr,
-            connected_client: None,
         }
     }
 }
@@ -1,11 +1,16 @@
 use crate::RamdiskService;
 use crate::driver::Ramdisk;
-use crate::layout::{BUFFER_SLOT, MMIO_SLOT, MMIO_VA, NOTIFY_SLOT};
-use glenda::cap::{CSPACE_CAP, CapPtr, ENDPOINT_SLOT, Endpoint, RECV_SLOT, Reply};
+use crate::layout::{ALLOC_VA, ANON_SIZE_PROPERTY, MMIO_SLOT, MMIO_VA};
+use block_proto::{BLOCK_GET_READ_ONLY, BLOCK_RING_READ_ONLY};
+use decompress::{DecompressError, Format};
+use glenda::arch::mem::PGSIZE;
+use glenda::cap::{CSPACE_CAP, CapPtr, CapType, ENDPOINT_SLOT, Endpoint, Page, RECV_SLOT, Reply};
 use glenda::client::ResourceClient;
 use glenda::drivers::protocol::BLOCK_PROTO;
 use glenda::error::Error;
-use glenda::interface::{DeviceService, SystemService, VSpaceService};
+use glenda::interface::{
+    CSpaceService, DeviceService, ResourceService, SystemService, VSpaceService,
+};
 use glenda::ipc::server::{handle_call, handle_cap_call, handle_notify};
 use glenda::ipc::{Badge, MsgTag, UTCB};
 use glenda::utils::manager::{CSpaceManager, VSpaceManager};
@@ -14,25 +19,44 @@ impl<'a> SystemService for RamdiskService<'a> {
     fn init(&mut self) -> Result<(), Error> {
         log!("Driver init...");
 
-        // 1. Get MMIO Cap (backing store)=
-        let (mmio, paddr, size) = self.dev.get_mmio(Badge::null(), 0, MMIO_SLOT)?;
-        log!("Got memory region: paddr={:#x}, size={:#x}", paddr, size);
+        // 1. Get the backing store: the MMIO region, what it unpacks to, or
+        // fresh zeroed memory when there is none
+        let data = match self.dev.get_mmio(Badge::null(), 0, MMIO_SLOT) {
+            Ok((mmio, paddr, size)) => {
+                log!("Got memory region: paddr={:#x}, size={:#x}", paddr, size);
 
-        // 2. Map MMIO
-        let pages = (size + glenda::arch::mem::PGSIZE - 1) / glenda::arch::mem::PGSIZE;
-        self.vspace_mgr.map_page(
-            glenda::cap::Page::from(mmio.into()),
-            MMIO_VA,
-            glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
-            pages,
-            self.res,
-            self.cspace_mgr,
-        )?;
+                // 2. Map MMIO
+                let pages = (size + glenda::arch::mem::PGSIZE - 1) / glenda::arch::mem::PGSIZE;
+                self.vspace_mgr.map_page(
+                    glenda::cap::Page::from(mmio.into()),
+                    MMIO_VA,
+                    glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
+                    pages,
+                    self.res,
+                    self.cspace_mgr,
+                )?;
+                let image = unsafe { core::slice::from_raw_parts_mut(MMIO_VA as *mut u8, size) };
+                match Format::detect(image) {
+                    Some(format) => self.unpack_image(format, image)?,
+                    None => image,
+                }
+            }
+            Err(Error::NotFound) => {
+                let size = self.anon_size()?;
+                log!("No memory region, creating {} byte anonymous ramdisk", size);
+                let data = self.alloc_region(size)?;
+                // Fresh pages aren't promised to be clear
+                data.fill(0);
+                data
+            }
+            Err(e) => return Err(e),
+        };
 
         // 3. Init hardware (ramdisk logic)
-        let data = unsafe { core::slice::from_raw_parts_mut(MMIO_VA as *mut u8, size) };
         let mut ramdisk = Ramdisk::new(data);
         ramdisk.set_block_size(4096);
+        ramdisk.scan_partitions();
+        let partitions = ramdisk.partitions().to_vec();
         log!(
             "Initialized Ramdisk with {} blocks ({} bytes each)",
             ramdisk.capacity(),
@@ -49,6 +73,17 @@ impl<'a> SystemService for RamdiskService<'a> {
         };
         self.dev.register_logic(Badge::null(), desc, ENDPOINT_SLOT)?;
 
+        // One device per partition; its clients' badges select the partition
+        for p in partitions.iter() {
+            let desc = glenda::protocol::device::LogicDeviceDesc {
+                name: alloc::format!("ramdisk-p{}", p.number),
+                dev_type: glenda::protocol::device::LogicDeviceType::Block,
+                parent_name: alloc::string::String::from("ramdisk"),
+                badge: Some(partition::partition_badge(p.number)),
+            };
+            self.dev.register_logic(Badge::null(), desc, ENDPOINT_SLOT)?;
+        }
+
         log!("Driver initialized!");
         Ok(())
     }
@@ -104,12 +139,6 @@ impl<'a> SystemService for RamdiskService<'a> {
 
     fn dispatch(&mut self, utcb: &mut UTCB) -> Result<(), Error> {
         let badge = utcb.get_badge().bits();
-        if badge != 0 && self.connected_client.is_some() && self.connected_client != Some(badge) {
-            let proto = utcb.get_msg_tag().proto();
-            if proto != glenda::protocol::KERNEL_PROTO {
-                return Err(Error::PermissionDenied);
-            }
-        }
 
         glenda::ipc_dispatch! {
             self, utcb,
@@ -145,14 +174,14 @@ impl<'a> SystemService for RamdiskService<'a> {
                 })
             },
             (BLOCK_PROTO, glenda::drivers::protocol::block::GET_CAPACITY) => |s: &mut Self, u: &mut UTCB| {
-                if badge != 0 && s.connected_client.is_none() {
-                    s.connected_client = Some(badge);
-                }
-                handle_call(u, |_| Ok(s.ramdisk.as_ref().unwrap().capacity() as usize))
+                handle_call(u, |_| Ok(s.ramdisk.as_ref().unwrap().capacity_of(badge)))
             },
             (BLOCK_PROTO, glenda::drivers::protocol::block::GET_BLOCK_SIZE) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |_| Ok(s.ramdisk.as_ref().unwrap().block_size() as usize))
             },
+            (BLOCK_PROTO, BLOCK_GET_READ_ONLY) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_| Ok(s.ramdisk.as_ref().unwrap().is_read_only_for(badge) as usize))
+            },
             (BLOCK_PROTO, glenda::drivers::protocol::block::SETUP_BUFFER) => |s: &mut Self, u: &mut UTCB| {
                 let recv_slot = s.recv;
                 let client_vaddr = u.get_mr(0);
@@ -160,13 +189,15 @@ impl<'a> SystemService for RamdiskService<'a> {
                 let paddr = u.get_mr(2) as usize;
 
                 // Move capabilities after reading registers
-                CSPACE_CAP.transfer_self(recv_slot, BUFFER_SLOT)?;
+                let slot = s.cspace_mgr.alloc(s.res)?;
+                CSPACE_CAP.transfer_self(recv_slot, slot)?;
+                let frame = Page::from(slot);
 
                 handle_call(u, |_| {
                     let res = unsafe { &mut *(s.res as *mut ResourceClient) };
                     let vspace_mgr = unsafe { &mut *(s.vspace_mgr as *mut VSpaceManager) };
                     let cspace_mgr = unsafe { &mut *(s.cspace_mgr as *mut CSpaceManager) };
-                    s.ramdisk.as_mut().unwrap().setup_buffer(res, vspace_mgr, cspace_mgr, client_vaddr, size, paddr)?;
+                    s.ramdisk.as_mut().unwrap().setup_buffer(res, vspace_mgr, cspace_mgr, badge, frame, client_vaddr, size, paddr)?;
                     Ok(0usize)
                 })
             },
@@ -174,19 +205,24 @@ impl<'a> SystemService for RamdiskService<'a> {
                 let recv_slot = s.recv;
                 let sq = u.get_mr(0) as u32;
                 let cq = u.get_mr(1) as u32;
+                let flags = u.get_mr(2);
 
                 // Move capabilities after reading registers
-                CSPACE_CAP.transfer_self(recv_slot, NOTIFY_SLOT)?;
+                let slot = s.cspace_mgr.alloc(s.res)?;
+                CSPACE_CAP.transfer_self(recv_slot, slot)?;
 
                 handle_cap_call(u, |_| {
                     // Transfer notification endpoint
                     let res = unsafe { &mut *(s.res as *mut ResourceClient) };
                     let vspace_mgr = unsafe { &mut *(s.vspace_mgr as *mut VSpaceManager) };
                     let cspace_mgr = unsafe { &mut *(s.cspace_mgr as *mut CSpaceManager) };
-                    let notify_ep = Endpoint::from(NOTIFY_SLOT);
+                    let notify_ep = Endpoint::from(slot);
 
                     let ramdisk = s.ramdisk.as_mut().unwrap();
-                    let frame = ramdisk.setup_ring(res, vspace_mgr, cspace_mgr, sq, cq, notify_ep)?;
+                    if flags & BLOCK_RING_READ_ONLY != 0 {
+                        ramdisk.set_read_only(badge)?;
+                    }
+                    let frame = ramdisk.setup_ring(res, vspace_mgr, cspace_mgr, badge, sq, cq, notify_ep)?;
                     Ok(frame.cap())
                 })
             },
@@ -204,3 +240,65 @@ impl<'a> SystemService for RamdiskService<'a> {
         self.running = false;
     }
 }
+
+impl RamdiskService<'_> {
+    /// Unpacks a compressed image into a region of its own. The image's size
+    /// isn't recorded by every format, so it is decoded twice: once to measure.
+    fn unpack_image(&mut self, format: Format, image: &[u8]) -> Result<&'static mut [u8], Error> {
+        let size = decompress::decompressed_size(image).map_err(decompress_error)?;
+        log!("Unpacking {:?} image: {} bytes to {}", format, image.len(), size);
+        let data = self.alloc_region(size)?;
+        let n = decompress::decompress(image, data).map_err(decompress_error)?;
+        // The region is page-rounded; the tail reads as zeros
+        data[n..].fill(0);
+        Ok(data)
+    }
+
+    /// Size asked for an anonymous ramdisk, in bytes (decimal or 0x-prefixed hex).
+    fn anon_size(&mut self) -> Result<usize, Error> {
+        let Ok(value) = self.dev.get_property(Badge::null(), ANON_SIZE_PROPERTY) else {
+            error!("No memory region and no {} property", ANON_SIZE_PROPERTY);
+            return Err(Error::NotFound);
+        };
+        let value = value.trim();
+        let size = match value.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => value.parse(),
+        };
+        match size {
+            Ok(size) if size > 0 => Ok(size),
+            _ => {
+                error!("Bad {} property: {:?}", ANON_SIZE_PROPERTY, value);
+                Err(Error::InvalidArgs)
+            }
+        }
+    }
+
+    /// Allocates and maps `size` bytes, rounded up to whole pages, at ALLOC_VA.
+    fn alloc_region(&mut self, size: usize) -> Result<&'static mut [u8], Error> {
+        let pages = size.div_ceil(PGSIZE);
+        if pages == 0 {
+            return Err(Error::InvalidArgs);
+        }
+        let slot = self.cspace_mgr.alloc(self.res)?;
+        let frame = Page::from(self.res.alloc(Badge::null(), CapType::Page, pages, slot)?);
+        self.vspace_mgr.map_page(
+            frame,
+            ALLOC_VA,
+            glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
+            pages,
+            self.res,
+            self.cspace_mgr,
+        )?;
+        Ok(unsafe { core::slice::from_raw_parts_mut(ALLOC_VA as *mut u8, pages * PGSIZE) })
+    }
+}
+
+fn decompress_error(e: DecompressError) -> Error {
+    error!("Image unpack failed: {:?}", e);
+    match e {
+        DecompressError::Corrupt | DecompressError::Checksum => Error::InvalidArgs,
+        DecompressError::Unsupported => Error::NotSupported,
+        DecompressError::OutputFull => Error::OutOfMemory,
+    }
+}
@@ -2,7 +2,7 @@ mod device;
 mod driver;
 mod server;
 
-use crate::layout::{RING_VA, SHM_VA};
+use crate::layout::RING_VA;
 use crate::Ns16550a;
 use glenda::cap::{CapPtr, Endpoint, Page, Reply};
 use glenda::client::{DeviceClient, ResourceClient};
@@ -24,7 +24,6 @@ pub struct UartService<'a> {
     pub res: &'a mut ResourceClient,
     pub cspace: &'a mut CSpaceManager,
     pub vspace: &'a mut VSpaceManager,
-    pub connected_client: Option<usize>,
 }
 
 impl<'a> UartService<'a> {
@@ -45,29 +44,47 @@ impl<'a> UartService<'a> {
             cspace,
             vspace,
             running: false,
-            connected_client: None,
         }
     }
-    fn setup_ring(&mut self, sq: u32, cq: u32, notify_ep: Endpoint) -> Result<Page, Error> {
-        log!("Setting up ring: sq={}, cq={}, notify_ep={}", sq, cq, notify_ep.cap());
+    fn setup_ring(
+        &mut self,
+        badge: usize,
+        sq: u32,
+        cq: u32,
+        notify_ep: Endpoint,
+    ) -> Result<Page, Error> {
+        let uart = self.uart.as_mut().ok_or(Error::NotInitialized)?;
+        let session = uart.session(badge)?;
+        // The ring page sits at a fixed VA per session and can't be mapped twice
+        if uart.sessions[session].ring.is_some() {
+            return Err(Error::InvalidArgs);
+        }
+        log!(
+            "Setting up ring for session {}: sq={}, cq={}, notify_ep={}",
+            session,
+            sq,
+            cq,
+            notify_ep.cap()
+        );
+        let ring_va = RING_VA + session * 4096;
         let slot = self.cspace.alloc(self.res)?;
         let (_paddr, frame): (usize, Page) = self.res.dma_alloc(Badge::null(), 1, slot)?;
 
         self.vspace.map_page(
             frame.clone(),
-            RING_VA,
+            ring_va,
             glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
             1,
             self.res,
             self.cspace,
         )?;
 
-        let ring = unsafe { IoUringBuffer::new(RING_VA as *mut u8, 4096, sq, cq) };
+        let ring = unsafe { IoUringBuffer::new(ring_va as *mut u8, 4096, sq, cq) };
         let mut server = IoUringServer::new(ring);
         server.set_client_notify(notify_ep);
 
         if let Some(uart) = self.uart.as_mut() {
-            uart.ring = Some(server);
+            uart.set_ring_server(badge, server)?;
         }
 
         Ok(frame)
@@ -75,14 +92,16 @@ impl<'a> UartService<'a> {
 
     fn setup_shm(
         &mut self,
+        badge: usize,
         frame: Page,
         vaddr: usize,
         paddr: usize,
         size: usize,
     ) -> Result<(), Error> {
+        let session = self.uart.as_mut().ok_or(Error::NotInitialized)?.session(badge)?;
         self.vspace.map_page(
             frame.clone(),
-            SHM_VA,
+            Ns16550a::shm_va(session),
             glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
             1,
             self.res,
@@ -90,7 +109,7 @@ impl<'a> UartService<'a> {
         )?;
 
         if let Some(uart) = self.uart.as_mut() {
-            uart.setup_shm(frame, vaddr, paddr, size)?;
+            uart.setup_shm(badge, frame, vaddr, paddr, size)?;
         }
         Ok(())
     }
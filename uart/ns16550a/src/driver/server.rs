@@ -4,7 +4,7 @@ use glenda::cap::{CapPtr, Endpoint, Page, Reply, CSPACE_CAP};
 use glenda::drivers::interface::{DriverService, UartDriver};
 use glenda::drivers::protocol;
 use glenda::error::Error;
-use glenda::interface::SystemService;
+use glenda::interface::{CSpaceService, SystemService};
 use glenda::ipc::server::{handle_call, handle_cap_call, handle_notify};
 use glenda::ipc::{MsgTag, UTCB};
 
@@ -62,19 +62,10 @@ impl<'a> SystemService for UartService<'a> {
 
     fn dispatch(&mut self, utcb: &mut UTCB) -> Result<(), Error> {
         let badge = utcb.get_badge().bits();
-        if badge != 0 && self.connected_client.is_some() && self.connected_client != Some(badge) {
-            let proto = utcb.get_msg_tag().proto();
-            if proto != glenda::protocol::KERNEL_PROTO {
-                return Err(Error::PermissionDenied);
-            }
-        }
 
         let res = glenda::ipc_dispatch! {
             self, utcb,
             (protocol::UART_PROTO, protocol::uart::WRITE) => |s: &mut Self, u: &mut UTCB| {
-                if badge != 0 && s.connected_client.is_none() {
-                    s.connected_client = Some(badge);
-                }
                 handle_call(u, |u| {
                     if let Some(uart) = s.uart.as_mut() {
                         let len = u.get_size();
@@ -111,26 +102,25 @@ impl<'a> SystemService for UartService<'a> {
             (protocol::UART_PROTO, protocol::uart::SETUP_RING) => |s: &mut Self, u: &mut UTCB| {
                 handle_cap_call(u, |u| {
                     let recv_slot = s.recv;
-                    let slot = RING_SLOT;
+                    let slot = s.cspace.alloc(s.res)?;
                     let sq = u.get_mr(0) as u32;
                     let cq = u.get_mr(1) as u32;
-                    // Move cap to predefined slot
                     CSPACE_CAP.transfer_self(recv_slot, slot)?;
                     let notify_ep = Endpoint::from(slot);
-                    let frame = s.setup_ring(sq, cq, notify_ep)?;
+                    let frame = s.setup_ring(badge, sq, cq, notify_ep)?;
                     Ok(frame.cap())
                 })
             },
             (protocol::UART_PROTO, protocol::uart::SETUP_BUFFER) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |u| {
                     let recv_slot = s.recv;
-                    let slot = SHM_SLOT;
+                    let slot = s.cspace.alloc(s.res)?;
                     let vaddr = u.get_mr(0);
                     let size = u.get_mr(1);
                     let paddr = u.get_mr(2) as usize;
                     CSPACE_CAP.transfer_self(recv_slot, slot)?;
                     let frame = Page::from(slot);
-                    s.setup_shm(frame, vaddr, paddr, size)?;
+                    s.setup_shm(badge, frame, vaddr, paddr, size)?;
                     Ok(())
                 })
             },
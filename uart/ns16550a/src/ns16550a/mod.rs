@@ -3,8 +3,10 @@ mod consts;
 
 use crate::layout::SHM_VA;
 use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 use config::*;
 use consts::*;
+use glenda::arch::mem::PGSIZE;
 use glenda::cap::{IrqHandler, Page};
 use glenda::drivers::interface::UartDriver;
 use glenda::error::Error;
@@ -30,15 +32,26 @@ fn should_log_sparse(count: usize) -> bool {
     count == 1 || count.is_power_of_two() || count % LOG_THROTTLE_EVERY == 0
 }
 
-pub struct Ns16550a {
-    pub base: usize,
-    pub irq: IrqHandler,
+/// Upper bound on client sessions (one per badge).
+pub const MAX_SESSIONS: usize = 4;
+
+/// Per-client state, keyed by the client's badge.
+pub struct UartSession {
+    pub badge: usize,
     pub ring: Option<IoUringServer>,
     pub shm: Option<SharedMemory>,
     pub rx_ring: Option<&'static mut ShmRingBuffer>,
     pub tx_ring: Option<&'static mut ShmRingBuffer>,
-    pub rx_buffer: VecDeque<u8>,
     pub pending_read: Option<usize>,
+}
+
+pub struct Ns16550a {
+    pub base: usize,
+    pub irq: IrqHandler,
+    pub sessions: Vec<UartSession>,
+    /// Session that receives RX data: the last one to post a READ.
+    pub foreground: Option<usize>,
+    pub rx_buffer: VecDeque<u8>,
     pub stats: UartStats,
 }
 
@@ -47,28 +60,55 @@ impl Ns16550a {
         Self {
             base,
             irq,
+            sessions: Vec::new(),
+            foreground: None,
+            rx_buffer: VecDeque::with_capacity(RX_BUFFER_SOFT_LIMIT),
+            stats: UartStats::default(),
+        }
+    }
+
+    /// Index of the session for `badge`, opening one on first contact.
+    pub fn session(&mut self, badge: usize) -> Result<usize, Error> {
+        if let Some(idx) = self.sessions.iter().position(|s| s.badge == badge) {
+            return Ok(idx);
+        }
+        if self.sessions.len() >= MAX_SESSIONS {
+            return Err(Error::OutOfMemory);
+        }
+        self.sessions.push(UartSession {
+            badge,
             ring: None,
             shm: None,
             rx_ring: None,
             tx_ring: None,
-            rx_buffer: VecDeque::with_capacity(RX_BUFFER_SOFT_LIMIT),
             pending_read: None,
-            stats: UartStats::default(),
-        }
+        });
+        log!("Session {} opened for badge {:#x}", self.sessions.len() - 1, badge);
+        Ok(self.sessions.len() - 1)
     }
 
-    pub fn set_ring_server(&mut self, ring: IoUringServer) {
-        self.ring = Some(ring);
+    /// Where the SHM page of session `idx` is mapped.
+    pub fn shm_va(idx: usize) -> usize {
+        SHM_VA + idx * PGSIZE
+    }
+
+    pub fn set_ring_server(&mut self, badge: usize, ring: IoUringServer) -> Result<(), Error> {
+        let idx = self.session(badge)?;
+        self.sessions[idx].ring = Some(ring);
+        Ok(())
     }
 
     pub fn setup_shm(
         &mut self,
+        badge: usize,
         frame: Page,
         client_vaddr: usize,
         paddr: usize,
         size: usize,
     ) -> Result<(), Error> {
-        let mut shm = SharedMemory::new(frame, SHM_VA, size);
+        let idx = self.session(badge)?;
+        let shm_va = Self::shm_va(idx);
+        let mut shm = SharedMemory::new(frame, shm_va, size);
         shm.set_client_vaddr(client_vaddr);
         shm.set_paddr(paddr);
 
@@ -76,15 +116,16 @@ impl Ns16550a {
         // 0 - 2KB: TX Ring Buffer (Input to UART)
         // 2KB - 4KB: RX Ring Buffer (Output from UART)
 
-        let tx_ring_ptr = SHM_VA as *mut u8;
-        let rx_ring_ptr = (SHM_VA + 2048) as *mut u8;
+        let tx_ring_ptr = shm_va as *mut u8;
+        let rx_ring_ptr = (shm_va + 2048) as *mut u8;
 
+        let session = &mut self.sessions[idx];
         unsafe {
-            self.tx_ring = Some(ShmRingBuffer::init(tx_ring_ptr, 2048));
-            self.rx_ring = Some(ShmRingBuffer::init(rx_ring_ptr, 2048));
+            session.tx_ring = Some(ShmRingBuffer::init(tx_ring_ptr, 2048));
+            session.rx_ring = Some(ShmRingBuffer::init(rx_ring_ptr, 2048));
         }
 
-        self.shm = Some(shm);
+        session.shm = Some(shm);
         Ok(())
     }
 
@@ -169,7 +210,9 @@ impl Ns16550a {
                     self.stats.irq_rx_batches += 1;
                     has_data |= self.drain_rx_fifo_to_sw_buffer(RX_IRQ_BUDGET_BYTES) > 0;
                 } else if id == IIR_THR_EMPTY {
-                    let _ = self.process_tx_ring(TX_IRQ_BUDGET_BYTES);
+                    for idx in 0..self.sessions.len() {
+                        let _ = self.process_tx_ring(idx, TX_IRQ_BUDGET_BYTES);
+                    }
                 } else {
                     self.record_lsr_errors();
                 }
@@ -247,8 +290,11 @@ impl Ns16550a {
     }
 
     fn process_rx_ring(&mut self) {
+        let Some(session) = self.foreground.and_then(|idx| self.sessions.get_mut(idx)) else {
+            return;
+        };
         // Try to push to SHM Ring Buffer first
-        if let Some(ring) = &mut self.rx_ring {
+        if let Some(ring) = &mut session.rx_ring {
             let mut pushed_total = 0;
             while !self.rx_buffer.is_empty() {
                 let (front_len, pushed) = {
@@ -296,8 +342,8 @@ impl Ns16550a {
             }
 
             if pushed_total > 0 {
-                if let Some(ud) = self.pending_read {
-                    if let Some(uring) = &mut self.ring {
+                if let Some(ud) = session.pending_read {
+                    if let Some(uring) = &mut session.ring {
                         let _ = uring.complete(ud, pushed_total as i32);
                     }
                 }
@@ -306,39 +352,49 @@ impl Ns16550a {
     }
 
     pub fn handle_sq(&mut self) {
+        for idx in 0..self.sessions.len() {
+            self.handle_session_sq(idx);
+        }
+
+        if !self.rx_buffer.is_empty() {
+            self.process_rx_ring();
+        }
+    }
+
+    fn handle_session_sq(&mut self, idx: usize) {
         loop {
-            let sqe = if let Some(ring) = &mut self.ring { ring.next_request() } else { None };
+            let sqe = if let Some(ring) = &mut self.sessions[idx].ring {
+                ring.next_request()
+            } else {
+                None
+            };
             if let Some(sqe) = sqe {
-                match sqe.opcode {
+                let res = match sqe.opcode {
                     glenda::io::uring::IOURING_OP_WRITE => {
-                        let written = self.process_tx_ring(TX_SQ_BUDGET_BYTES);
-                        let res = if sqe.addr == 0 { written as i32 } else { sqe.len as i32 };
-                        if let Some(ring) = &mut self.ring {
-                            let _ = ring.complete(sqe.user_data, res);
+                        let written = self.process_tx_ring(idx, TX_SQ_BUDGET_BYTES);
+                        if sqe.addr == 0 {
+                            written as i32
+                        } else {
+                            sqe.len as i32
                         }
                     }
                     glenda::io::uring::IOURING_OP_READ => {
-                        self.pending_read = Some(sqe.user_data);
+                        // The reading session takes over the RX stream
+                        self.sessions[idx].pending_read = Some(sqe.user_data);
+                        self.foreground = Some(idx);
                         self.process_rx_ring();
                         // Trigger an initial fake CQE to ensure the client checks existing data
-                        if let Some(ring) = &mut self.ring {
-                            let _ = ring.complete(sqe.user_data, 0);
-                        }
-                    }
-                    _ => {
-                        if let Some(ring) = &mut self.ring {
-                            let _ = ring.complete(sqe.user_data, -(Error::NotSupported as i32));
-                        }
+                        0
                     }
+                    _ => -(Error::NotSupported as i32),
+                };
+                if let Some(ring) = &mut self.sessions[idx].ring {
+                    let _ = ring.complete(sqe.user_data, res);
                 }
             } else {
                 break;
             }
         }
-
-        if !self.rx_buffer.is_empty() {
-            self.process_rx_ring();
-        }
     }
 
     pub fn handle_cq(&mut self) {
@@ -349,7 +405,7 @@ impl Ns16550a {
         }
     }
 
-    fn process_tx_ring(&mut self, budget: usize) -> usize {
+    fn process_tx_ring(&mut self, idx: usize, budget: usize) -> usize {
         let mut data_to_write = [0u8; TX_RING_CHUNK];
         let mut total_written = 0;
         let mut remaining_budget = budget;
@@ -360,7 +416,7 @@ impl Ns16550a {
 
         while remaining_budget > 0 {
             let chunk_len = core::cmp::min(data_to_write.len(), remaining_budget);
-            let total_read = if let Some(tx_ring) = &mut self.tx_ring {
+            let total_read = if let Some(tx_ring) = &mut self.sessions[idx].tx_ring {
                 tx_ring.pop_slice(&mut data_to_write[..chunk_len])
             } else {
                 0
@@ -385,7 +441,8 @@ impl Ns16550a {
         }
 
         if remaining_budget == 0 {
-            let has_pending_tx = self.tx_ring.as_ref().map(|r| r.len() > 0).unwrap_or(false);
+            let tx_ring = self.sessions[idx].tx_ring.as_ref();
+            let has_pending_tx = tx_ring.map(|r| r.len() > 0).unwrap_or(false);
             if has_pending_tx {
                 self.stats.tx_budget_hit += 1;
                 if should_log_sparse(self.stats.tx_budget_hit) {
@@ -393,7 +450,7 @@ impl Ns16550a {
                         "TX budget hit count={} (budget={}, pending_tx={})",
                         self.stats.tx_budget_hit,
                         budget,
-                        self.tx_ring.as_ref().map(|r| r.len()).unwrap_or(0)
+                        tx_ring.map(|r| r.len()).unwrap_or(0)
                     );
                 }
             }
@@ -1,75 +1,304 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use glenda::arch::mem::PGSIZE;
 use glenda::cap::{Endpoint, Page};
 use glenda::error::Error;
 use glenda::io::uring::{self as io_uring, IoUringServer};
 use glenda::mem::shm::SharedMemory;
+use partition::{badge_partition, Extent, Partition, PartitionError, SectorReader};
+use virtio_common::blk::*;
 use virtio_common::consts::*;
+use virtio_common::indirect::IndirectPool;
 use virtio_common::queue::*;
-use virtio_common::VirtIOTransport;
-
-#[repr(C)]
-#[derive(Debug, Default, Clone, Copy)]
-pub struct VirtIOBlkReq {
-    pub type_: u32,
-    pub reserved: u32,
-    pub sector: usize,
+use virtio_common::Transport;
+
+pub const QUEUE_SIZE: u16 = 128;
+pub const MAX_PENDING: usize = QUEUE_SIZE as usize;
+/// Descriptors per indirect table: header, up to MAX_DATA_SEGMENTS of data, status.
+pub const INDIRECT_TABLE_LEN: u16 = MAX_DATA_SEGMENTS as u16 + 2;
+/// Upper bound on request queues used with VIRTIO_BLK_F_MQ.
+pub const MAX_QUEUES: usize = 4;
+/// Upper bound on client rings; rings are spread across the request queues.
+pub const MAX_RINGS: usize = 8;
+/// Upper bound on client sessions (one per badge).
+pub const MAX_SESSIONS: usize = MAX_RINGS;
+/// Data segments per request when the device doesn't report VIRTIO_BLK_F_SEG_MAX.
+pub const MAX_DATA_SEGMENTS: usize = 8;
+/// Used-ring polls before a synchronous read at init gives up.
+const SYNC_POLL_LIMIT: usize = 10_000_000;
+/// Device features the driver handles; the rest of what the device offers is declined.
+const BLK_FEATURES: u64 = VIRTIO_BLK_F_SIZE_MAX
+    | VIRTIO_BLK_F_SEG_MAX
+    | VIRTIO_BLK_F_GEOMETRY
+    | VIRTIO_BLK_F_RO
+    | VIRTIO_BLK_F_BLK_SIZE
+    | VIRTIO_BLK_F_FLUSH
+    | VIRTIO_BLK_F_TOPOLOGY
+    | VIRTIO_BLK_F_CONFIG_WCE
+    | VIRTIO_BLK_F_MQ
+    | VIRTIO_BLK_F_DISCARD
+    | VIRTIO_BLK_F_WRITE_ZEROES;
+
+// DMA layout, per request queue: page 0 request headers and status bytes, page 1
+// discard / write-zeroes ranges, pages 2-3 the ring (two pages so the legacy layout
+// fits), then one indirect table per ring token.
+const QUEUE_DMA_PAGES: usize = INDIRECT_OFFSET / 4096
+    + IndirectPool::size_in_bytes(QUEUE_SIZE, INDIRECT_TABLE_LEN).div_ceil(4096);
+pub const DMA_PAGES: usize = QUEUE_DMA_PAGES * MAX_QUEUES;
+const STATUS_OFFSET: usize = MAX_PENDING * core::mem::size_of::<VirtIOBlkReq>();
+const RANGE_OFFSET: usize = 4096;
+const QUEUE_OFFSET: usize = 8192;
+const INDIRECT_OFFSET: usize = 16384;
+
+/// One request virtqueue with its own request slots.
+pub struct BlkQueue {
+    pub vq: Box<dyn Queue>,
+    pub dma_vaddr: *mut u8,
+    pub dma_paddr: usize,
+    /// In-flight requests, indexed by request slot.
+    pub pending: [Option<BlkPending>; MAX_PENDING],
+    /// SQEs that were issued as several requests.
+    pub splits: [Option<BlkSplit>; MAX_PENDING],
+    /// (ring, sqe) taken off a ring while the queue was full, resubmitted in order
+    /// as completions free slots and descriptors.
+    pub deferred: VecDeque<(usize, io_uring::IoUringSqe)>,
+    /// Token of a partition-scan read that timed out. Its header and buffer stay
+    /// the device's, so the queue takes nothing new until it completes.
+    pub stalled: Option<u16>,
 }
 
-pub const VIRTIO_BLK_T_IN: u32 = 0;
-pub const VIRTIO_BLK_T_OUT: u32 = 1;
-pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+impl BlkQueue {
+    /// Fills the header and status byte of request slot `idx`; returns their
+    /// physical addresses.
+    fn write_header(&mut self, idx: usize, type_: u32, sector: usize) -> (usize, usize) {
+        let req_ptr = unsafe { (self.dma_vaddr as *mut VirtIOBlkReq).add(idx) };
+        let status_ptr = unsafe { self.dma_vaddr.add(STATUS_OFFSET + idx) };
+        unsafe {
+            core::ptr::addr_of_mut!((*req_ptr).type_).write_volatile(type_);
+            core::ptr::addr_of_mut!((*req_ptr).reserved).write_volatile(0);
+            core::ptr::addr_of_mut!((*req_ptr).sector).write_volatile(sector);
+            status_ptr.write_volatile(0xFF);
+        }
+        glenda::arch::sync::fence();
 
-pub const VIRTIO_BLK_S_OK: u8 = 0;
-pub const VIRTIO_BLK_S_IOERR: u8 = 1;
-pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+        let req_paddr = self.dma_paddr + idx * core::mem::size_of::<VirtIOBlkReq>();
+        (req_paddr, self.dma_paddr + STATUS_OFFSET + idx)
+    }
+}
 
-pub const VIRTIO_F_RING_PACKED: u64 = 1_u64 << 34;
-pub const VIRTIO_F_EVENT_IDX: u64 = 1_u64 << 29;
+#[derive(Debug, Clone, Copy)]
+pub struct BlkPending {
+    pub ring: usize,
+    pub user_data: usize,
+    pub token: u16,
+    /// Completion result on success: bytes transferred.
+    pub len: u32,
+    /// Slot of the `BlkSplit` this request is part of.
+    pub split: Option<usize>,
+}
 
-pub const VIRTIO_BLK_F_BLK_SIZE: u64 = 1_u64 << 6;
+/// Completion state of an SQE issued as several requests.
+#[derive(Debug, Clone, Copy)]
+pub struct BlkSplit {
+    pub ring: usize,
+    pub user_data: usize,
+    pub remaining: usize,
+    /// Total bytes, or the first error seen.
+    pub result: i32,
+}
+
+pub struct BlkRing {
+    pub server: IoUringServer,
+    /// Request queue this ring's submissions go to.
+    pub queue: usize,
+    /// Session that owns the ring.
+    pub session: usize,
+}
+
+/// Per-client state, keyed by the client's badge.
+pub struct BlkSession {
+    pub badge: usize,
+    /// The client's data window; SQE addresses are checked against it.
+    pub buffer: Option<SharedMemory>,
+    /// Maximum SQEs in flight, `None` for no limit.
+    pub quota: Option<usize>,
+    pub inflight: usize,
+    /// Partition the badge addresses; sectors are relative to it and bounded by it.
+    pub extent: Option<Extent>,
+    /// Writes, discards and write-zeroes are refused.
+    pub read_only: bool,
+}
 
 pub struct VirtIOBlk {
-    pub transport: VirtIOTransport,
-    pub queue: Option<VirtQueue>,
-    pub dma_vaddr: *mut u8,
-    pub dma_paddr: usize,
-    pub pending_info: [Option<(usize, u16)>; 64],
-    pub ring_server: Option<IoUringServer>,
+    pub transport: Box<dyn Transport>,
+    pub queues: Vec<BlkQueue>,
+    pub rings: Vec<BlkRing>,
     pub endpoint: Option<Endpoint>,
-    pub buffer: Option<SharedMemory>,
-    pub blk_size: u32,
+    pub sessions: Vec<BlkSession>,
+    /// Negotiated feature bits.
+    pub features: u64,
+    /// Config space as read at init, refreshed when the device reports a change.
+    pub config: VirtioBlkConfig,
+    /// Partition table found at init.
+    pub partitions: Vec<Partition>,
 }
 
 impl VirtIOBlk {
-    pub fn new(transport: VirtIOTransport) -> Self {
+    pub fn new(transport: Box<dyn Transport>) -> Self {
         Self {
             transport,
-            queue: None,
-            dma_vaddr: core::ptr::null_mut(),
-            dma_paddr: 0,
-            pending_info: [None; 64],
-            ring_server: None,
+            queues: Vec::new(),
+            rings: Vec::new(),
             endpoint: None,
+            sessions: Vec::new(),
+            features: 0,
+            config: VirtioBlkConfig { blk_size: 512, ..VirtioBlkConfig::default() },
+            partitions: Vec::new(),
+        }
+    }
+
+    /// Index of the session for `badge`, opening one on first contact.
+    pub fn session(&mut self, badge: usize) -> Result<usize, Error> {
+        if let Some(idx) = self.sessions.iter().position(|s| s.badge == badge) {
+            return Ok(idx);
+        }
+        if self.sessions.len() >= MAX_SESSIONS {
+            return Err(Error::OutOfMemory);
+        }
+        let extent = self.partition_extent(badge)?;
+        self.sessions.push(BlkSession {
+            badge,
             buffer: None,
-            blk_size: 512,
+            quota: None,
+            inflight: 0,
+            extent,
+            read_only: false,
+        });
+        log!("Session {} opened for badge {:#x}", self.sessions.len() - 1, badge);
+        Ok(self.sessions.len() - 1)
+    }
+
+    /// Extent of the partition `badge` addresses, `None` for the whole disk.
+    pub fn partition_extent(&self, badge: usize) -> Result<Option<Extent>, Error> {
+        let Some(number) = badge_partition(badge) else { return Ok(None) };
+        let part = self.partitions.iter().find(|p| p.number == number).ok_or(Error::NotFound)?;
+        Ok(Some(part.extent))
+    }
+
+    /// Reads the partition table through request queue 0. Must run before any
+    /// ring is set up, as it borrows request slot 0 and the range page.
+    pub fn scan_partitions(&mut self) {
+        match partition::scan(&mut SyncReader(self)) {
+            Ok(mut parts) => {
+                // The tables count logical blocks; requests count 512-byte sectors
+                let ratio = self.sectors_per_block();
+                for p in parts.iter_mut() {
+                    p.extent.start *= ratio;
+                    p.extent.len *= ratio;
+                    log!(
+                        "Partition {}: start={}, sectors={}, {:?}",
+                        p.number,
+                        p.extent.start,
+                        p.extent.len,
+                        p.kind
+                    );
+                }
+                self.partitions = parts;
+            }
+            Err(PartitionError::NoTable) => log!("No partition table"),
+            Err(e) => warn!("Partition table unreadable: {:?}", e),
         }
     }
 
+    /// Reads `buf.len()` bytes from `sector` a page at a time, polling for
+    /// each completion.
+    fn read_sync(&mut self, sector: usize, buf: &mut [u8]) -> Result<(), Error> {
+        if buf.len() % 512 != 0 {
+            return Err(Error::InvalidArgs);
+        }
+        for (i, chunk) in buf.chunks_mut(PGSIZE).enumerate() {
+            self.read_sync_page(sector + i * (PGSIZE / 512), chunk)?;
+        }
+        Ok(())
+    }
+
+    fn read_sync_page(&mut self, sector: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let queue = self.queues.first_mut().ok_or(Error::NotInitialized)?;
+        if queue.stalled.is_some() {
+            return Err(Error::IoError);
+        }
+        let (req_paddr, status_paddr) = queue.write_header(0, VIRTIO_BLK_T_IN, sector);
+        let data_paddr = queue.dma_paddr + RANGE_OFFSET;
+        let segs = blk_rw_chain(req_paddr, data_paddr, buf.len() as u32, false, status_paddr);
+        let token = queue.vq.submit_sg(&segs).ok_or(Error::OutOfMemory)?;
+        self.kick(0);
+
+        let queue = &mut self.queues[0];
+        let mut done = false;
+        for _ in 0..SYNC_POLL_LIMIT {
+            if let Some((used, _len)) = queue.vq.pop_used() {
+                if used == token {
+                    done = true;
+                    break;
+                }
+            }
+            core::hint::spin_loop();
+        }
+        if !done {
+            // The device may still write the header slot and buffer; hold the
+            // queue until the chain comes back
+            error!("Read of sector {} timed out, holding queue 0 until it completes", sector);
+            queue.stalled = Some(token);
+            return Err(Error::IoError);
+        }
+
+        glenda::arch::sync::fence();
+        let status = unsafe { core::ptr::read_volatile(queue.dma_vaddr.add(STATUS_OFFSET)) };
+        if status != VIRTIO_BLK_S_OK {
+            return Err(Error::IoError);
+        }
+        let data =
+            unsafe { core::slice::from_raw_parts(queue.dma_vaddr.add(RANGE_OFFSET), buf.len()) };
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+
+    pub fn set_quota(&mut self, badge: usize, quota: Option<usize>) -> Result<(), Error> {
+        let idx = self.session(badge)?;
+        self.sessions[idx].quota = quota;
+        Ok(())
+    }
+
+    /// Makes the session of `badge` read-only; there is no way back.
+    pub fn set_read_only(&mut self, badge: usize) -> Result<(), Error> {
+        let idx = self.session(badge)?;
+        self.sessions[idx].read_only = true;
+        log!("Session {} is read-only", idx);
+        Ok(())
+    }
+
     pub fn setup_shm(
         &mut self,
+        badge: usize,
         frame: Page,
         vaddr: usize,
         paddr: usize,
         size: usize,
     ) -> Result<(), Error> {
-        // Since we don't map it here (it's already handled by the service layer if needed),
-        // we just store the addresses.
-        // Actually, the service layer didn't map it for us yet.
-        // But for VirtIO, we mainly need the paddr.
+        // The device only needs the paddr; the window isn't mapped in the driver.
+        let idx = self.session(badge)?;
         let mut shm = SharedMemory::from_frame(frame, vaddr, size);
         shm.set_client_vaddr(vaddr);
         shm.set_paddr(paddr);
-        self.buffer = Some(shm);
-        log!("SHM setup: client_vaddr={:#x}, paddr={:#x}, size={}", vaddr, paddr, size);
+        self.sessions[idx].buffer = Some(shm);
+        log!(
+            "Session {} SHM setup: client_vaddr={:#x}, paddr={:#x}, size={}",
+            idx,
+            vaddr,
+            paddr,
+            size
+        );
         Ok(())
     }
 
@@ -79,33 +308,104 @@ impl VirtIOBlk {
         dma_paddr: usize,
         endpoint: Endpoint,
     ) -> Result<(), Error> {
-        self.dma_vaddr = dma_vaddr;
-        self.dma_paddr = dma_paddr;
         self.endpoint = Some(endpoint);
 
         self.transport.set_status(0);
         self.transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
 
-        let mut features = self.transport.get_device_features();
-        features &= !(VIRTIO_F_EVENT_IDX | VIRTIO_F_RING_PACKED);
+        let features =
+            self.transport.get_device_features() & (VIRTIO_TRANSPORT_FEATURES | BLK_FEATURES);
         self.transport.set_driver_features(features);
-        self.transport.set_status(self.transport.get_status() | STATUS_FEATURES_OK);
+        if !self.transport.set_features_ok() {
+            error!("Device rejected features {:#x}", features);
+            return Err(Error::NotSupported);
+        }
+        self.features = features;
+
+        let config = VirtioBlkConfig::read(self.transport.as_ref(), features);
+        self.config = config;
+        log!(
+            "Block size {}, physical block size {}",
+            config.blk_size,
+            config.physical_block_size()
+        );
+        if features & VIRTIO_BLK_F_RO != 0 {
+            log!("Device is read-only");
+        }
+        if features & VIRTIO_BLK_F_DISCARD != 0 {
+            log!(
+                "Discard: max {} sectors, {} segments",
+                config.max_discard_sectors,
+                config.max_discard_seg
+            );
+        }
+        if features & VIRTIO_BLK_F_WRITE_ZEROES != 0 {
+            log!(
+                "Write zeroes: max {} sectors, {} segments",
+                config.max_write_zeroes_sectors,
+                config.max_write_zeroes_seg
+            );
+        }
+        if config.writeback != 0 {
+            log!("Device has a volatile write cache");
+        }
 
-        if features & VIRTIO_BLK_F_BLK_SIZE != 0 {
-            unsafe {
-                let ptr = self.transport.config_ptr() as *const u32;
-                // blk_size is at offset 20 in the config space for virtio-blk
-                self.blk_size = ptr.add(5).read_volatile();
-                log!("Detected block size {}", self.blk_size);
-            }
+        let num_queues = if features & VIRTIO_BLK_F_MQ != 0 {
+            let n = (config.num_queues as usize).clamp(1, MAX_QUEUES);
+            log!("Device offers {} request queues, using {}", config.num_queues, n);
+            n
+        } else {
+            1
+        };
+
+        if features & VIRTIO_F_RING_PACKED != 0 {
+            log!("Using packed virtqueue");
+        }
+        if features & VIRTIO_F_INDIRECT_DESC != 0 {
+            log!("Using indirect descriptors");
+        }
+        if features & VIRTIO_F_EVENT_IDX != 0 {
+            log!("Using event index notification suppression");
         }
 
-        let queue_paddr = dma_paddr + 8192;
-        let queue_vaddr = unsafe { dma_vaddr.add(8192) };
-        let queue = unsafe { VirtQueue::new(0, 128, queue_paddr, queue_vaddr) };
+        for i in 0..num_queues {
+            let base = i * QUEUE_DMA_PAGES * PGSIZE;
+            let vaddr = unsafe { dma_vaddr.add(base) };
+            let paddr = dma_paddr + base;
+            let Some(size) = self.transport.queue_size(i as u32, QUEUE_SIZE) else {
+                error!("Request queue {} is not available", i);
+                return Err(Error::NotSupported);
+            };
+
+            let mut vq = unsafe {
+                self.transport.create_queue(
+                    features,
+                    i as u32,
+                    size,
+                    paddr + QUEUE_OFFSET,
+                    vaddr.add(QUEUE_OFFSET),
+                )
+            };
+            if features & VIRTIO_F_INDIRECT_DESC != 0 {
+                let pool = IndirectPool::new(
+                    unsafe { vaddr.add(INDIRECT_OFFSET) },
+                    paddr + INDIRECT_OFFSET,
+                    INDIRECT_TABLE_LEN,
+                );
+                vq.set_indirect_pool(pool);
+            }
 
-        unsafe { self.transport.setup_queue(&queue) };
-        self.queue = Some(queue);
+            unsafe { self.transport.setup_queue(vq.as_ref()) };
+            self.queues.push(BlkQueue {
+                vq,
+                dma_vaddr: vaddr,
+                dma_paddr: paddr,
+                pending: [None; MAX_PENDING],
+                splits: [None; MAX_PENDING],
+                deferred: VecDeque::new(),
+                stalled: None,
+            });
+        }
 
         glenda::arch::sync::fence();
 
@@ -114,182 +414,507 @@ impl VirtIOBlk {
     }
 
     pub fn handle_irq(&mut self) {
-        if self.transport.interrupt_ack() {
-            self.pop_completions();
+        let status = self.transport.ack_interrupt();
+        if status & INTERRUPT_CONFIG_CHANGE != 0 {
+            self.config_changed();
+        }
+        if status != 0 {
+            let backed_up =
+                self.queues.iter().any(|q| !q.deferred.is_empty() || q.stalled.is_some())
+                    || self.sessions.iter().any(|s| s.quota.map_or(false, |q| s.inflight >= q));
+            for q in 0..self.queues.len() {
+                self.pop_completions(q);
+            }
+            if backed_up {
+                // Rings skipped while their queue or session was full may have requests waiting
+                self.handle_ring();
+            }
+        }
+    }
+
+    /// Re-reads config space so that capacity queries and end-of-device
+    /// checks agree after a resize.
+    fn config_changed(&mut self) {
+        let config = VirtioBlkConfig::read(self.transport.as_ref(), self.features);
+        if config.capacity != self.config.capacity {
+            log!("Capacity changed: {} -> {} sectors", self.config.capacity, config.capacity);
         }
+        self.config = config;
     }
 
     pub fn handle_ring(&mut self) {
+        for ring in 0..self.rings.len() {
+            self.handle_one_ring(ring);
+        }
+    }
+
+    fn handle_one_ring(&mut self, ring: usize) {
         let mut sqes = [io_uring::IoUringSqe::default(); 16];
         let mut count = 0;
 
-        if let Some(server) = self.ring_server.as_mut() {
-            while count < 16 {
-                if let Some(sqe) = server.next_request() {
-                    sqes[count] = sqe;
-                    count += 1;
-                } else {
-                    break;
-                }
-            }
-        } else {
-            error!("Handle_ring called but ring_server is None!");
+        let q = self.rings[ring].queue;
+        if !self.queues[q].deferred.is_empty() || self.queues[q].stalled.is_some() {
+            // Queue is still backed up; leave new requests in the ring until it drains
             return;
         }
 
+        let session = &mut self.sessions[self.rings[ring].session];
+        // Past its quota a session's requests stay in its ring until completions come in
+        let budget = match session.quota {
+            Some(quota) => core::cmp::min(quota.saturating_sub(session.inflight), 16),
+            None => 16,
+        };
+        let server = &mut self.rings[ring].server;
+        while count < budget {
+            if let Some(sqe) = server.next_request() {
+                sqes[count] = sqe;
+                count += 1;
+            } else {
+                break;
+            }
+        }
+        session.inflight += count;
+
         for i in 0..count {
             let sqe = sqes[i];
-            if let Err(e) = self.submit_virtio_request(sqe) {
-                error!("Submit_virtio_request failed: {:?}", e);
-                if let Some(server) = self.ring_server.as_mut() {
-                    let _ = server.complete(sqe.user_data, -1);
-                }
+            if sqe.opcode == io_uring::IOURING_OP_SYNC && self.features & VIRTIO_BLK_F_FLUSH == 0 {
+                // Without VIRTIO_BLK_F_FLUSH the device has no volatile write cache
+                self.complete(ring, sqe.user_data, 0);
+                continue;
+            }
+            if !self.queues[q].deferred.is_empty() {
+                // Keep submission order behind requests already waiting
+                self.queues[q].deferred.push_back((ring, sqe));
+                continue;
             }
+            self.submit_or_defer(ring, q, sqe);
         }
 
         if count > 0 {
-            self.pop_completions();
+            self.kick(q);
+            self.pop_completions(q);
+        }
+    }
+
+    /// Submits `sqe`, parking it on the queue's deferred list if the queue is full.
+    fn submit_or_defer(&mut self, ring: usize, q: usize, sqe: io_uring::IoUringSqe) {
+        match self.submit_virtio_request(ring, q, sqe) {
+            Ok(()) => {}
+            Err(Error::OutOfMemory) => self.queues[q].deferred.push_back((ring, sqe)),
+            Err(e) => self.fail_request(ring, sqe, e),
+        }
+    }
+
+    fn fail_request(&mut self, ring: usize, sqe: io_uring::IoUringSqe, e: Error) {
+        error!("Submit_virtio_request failed: {:?}", e);
+        self.complete(ring, sqe.user_data, -(e as i32));
+    }
+
+    /// Posts the CQE for an SQE taken off `ring`.
+    fn complete(&mut self, ring: usize, user_data: usize, result: i32) {
+        complete_sqe(&mut self.rings, &mut self.sessions, ring, user_data, result);
+    }
+
+    /// Resubmits deferred requests of `q` in order until the queue fills up again.
+    fn submit_deferred(&mut self, q: usize) {
+        let mut submitted = false;
+        while let Some(&(ring, sqe)) = self.queues[q].deferred.front() {
+            match self.submit_virtio_request(ring, q, sqe) {
+                Ok(()) => submitted = true,
+                Err(Error::OutOfMemory) => break,
+                Err(e) => self.fail_request(ring, sqe, e),
+            }
+            self.queues[q].deferred.pop_front();
+        }
+        if submitted {
+            self.kick(q);
         }
     }
 
-    fn submit_virtio_request(&mut self, sqe: io_uring::IoUringSqe) -> Result<(), Error> {
+    /// Rings the doorbell once for everything queued on `q`, if the device wants it.
+    fn kick(&mut self, q: usize) {
+        let queue = &mut self.queues[q].vq;
+        if queue.should_notify() {
+            self.transport.notify(queue.index());
+        }
+    }
+
+    /// Translates an address in the buffer of `ring`'s session into a physical
+    /// address for the device; the window is physically contiguous.
+    fn data_paddr(&self, ring: usize, addr: usize, len: u32) -> Result<usize, Error> {
+        let session = &self.sessions[self.rings[ring].session];
+        // Without a window there is nothing the client may name; raw addresses aren't taken
+        let shm = session.buffer.as_ref().ok_or(Error::NotInitialized)?;
+        let offset = addr
+            .checked_sub(shm.client_vaddr())
+            .filter(|off| off.checked_add(len as usize).is_some_and(|end| end <= shm.size()));
+        let Some(offset) = offset else {
+            error!("Address {:#x} out of SHM boundary", addr);
+            return Err(Error::InvalidArgs);
+        };
+        Ok(shm.paddr() + offset)
+    }
+
+    /// Builds and queues the descriptor chain for `sqe` on request queue `q`.
+    /// `OutOfMemory` means no request slot or descriptors were free; nothing was
+    /// queued and the request can be retried once completions come in.
+    fn submit_virtio_request(
+        &mut self,
+        ring: usize,
+        q: usize,
+        mut sqe: io_uring::IoUringSqe,
+    ) -> Result<(), Error> {
         let block_size = self.block_size();
-        let sector = sqe.off;
         let len = sqe.len;
 
-        if len % block_size != 0 {
+        let (virtio_type, sector) = match sqe.opcode {
+            io_uring::IOURING_OP_READ => (VIRTIO_BLK_T_IN, sqe.off),
+            io_uring::IOURING_OP_WRITE => (VIRTIO_BLK_T_OUT, sqe.off),
+            io_uring::IOURING_OP_SYNC => (VIRTIO_BLK_T_FLUSH, 0),
+            IOURING_OP_GET_ID => (VIRTIO_BLK_T_GET_ID, 0),
+            IOURING_OP_TRIM if self.features & VIRTIO_BLK_F_DISCARD != 0 => {
+                (VIRTIO_BLK_T_DISCARD, 0)
+            }
+            IOURING_OP_WRITE_ZEROES if self.features & VIRTIO_BLK_F_WRITE_ZEROES != 0 => {
+                (VIRTIO_BLK_T_WRITE_ZEROES, 0)
+            }
+            _ => return Err(Error::NotSupported),
+        };
+
+        let modifies = matches!(
+            virtio_type,
+            VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES
+        );
+        if modifies && (self.is_read_only() || self.sessions[self.rings[ring].session].read_only) {
+            return Err(Error::PermissionDenied);
+        }
+
+        let transfers = matches!(
+            virtio_type,
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES
+        );
+        if transfers && len % block_size != 0 {
             error!("Request length not aligned to block size ({}): len={}", block_size, len);
             return Err(Error::InvalidArgs);
         }
-        let queue = self.queue.as_mut().ok_or(Error::NotInitialized)?;
+        if transfers {
+            if let Some(extent) = self.sessions[self.rings[ring].session].extent {
+                // Partition clients address sectors from the start of their partition
+                let Some(sector) = extent.translate(sqe.off as u64, len as u64 / 512) else {
+                    error!("Request beyond end of partition: sector={}, len={}", sqe.off, len);
+                    return Err(Error::InvalidArgs);
+                };
+                sqe.off = sector as usize;
+            }
+            let end = (sqe.off as u64).checked_add(len as u64 / 512);
+            if end.map_or(true, |end| end > self.config.capacity) {
+                error!("Request beyond end of device: sector={}, len={}", sqe.off, len);
+                return Err(Error::InvalidArgs);
+            }
+        }
 
-        let req_idx =
-            self.pending_info.iter().position(|x| x.is_none()).ok_or(Error::OutOfMemory)?;
+        let range = match virtio_type {
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                let max = if virtio_type == VIRTIO_BLK_T_DISCARD {
+                    self.config.max_discard_sectors
+                } else {
+                    self.config.max_write_zeroes_sectors
+                };
+                let num_sectors = len / 512;
+                if num_sectors == 0 || num_sectors > max {
+                    error!("Range of {} sectors exceeds device limit {}", num_sectors, max);
+                    return Err(Error::InvalidArgs);
+                }
+                Some(VirtIOBlkDiscardWriteZeroes { sector: sqe.off as u64, num_sectors, flags: 0 })
+            }
+            _ => None,
+        };
 
-        if self.dma_vaddr.is_null() {
-            error!("Dma_vaddr is NULL!");
-            return Err(Error::NotInitialized);
+        if matches!(virtio_type, VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT) {
+            return self.submit_rw(ring, q, sqe, virtio_type);
         }
 
-        let req_ptr = unsafe { (self.dma_vaddr as *mut VirtIOBlkReq).add(req_idx) };
-        let status_ptr =
-            unsafe { self.dma_vaddr.add(64 * core::mem::size_of::<VirtIOBlkReq>() + req_idx) };
-
-        let (virtio_type, is_write) = match sqe.opcode {
-            io_uring::IOURING_OP_READ => (VIRTIO_BLK_T_IN, false),
-            io_uring::IOURING_OP_WRITE => (VIRTIO_BLK_T_OUT, true),
-            _ => return Err(Error::NotSupported),
+        let data = match virtio_type {
+            VIRTIO_BLK_T_GET_ID => {
+                if len < VIRTIO_BLK_ID_BYTES {
+                    return Err(Error::InvalidArgs);
+                }
+                Some(self.data_paddr(ring, sqe.addr, VIRTIO_BLK_ID_BYTES)?)
+            }
+            _ => None,
         };
 
-        unsafe {
-            core::ptr::addr_of_mut!((*req_ptr).type_).write_volatile(virtio_type);
-            core::ptr::addr_of_mut!((*req_ptr).reserved).write_volatile(0);
-            core::ptr::addr_of_mut!((*req_ptr).sector).write_volatile(sector);
-            status_ptr.write_volatile(0xFF);
+        let queue = self.queues.get_mut(q).ok_or(Error::NotInitialized)?;
+        let req_idx = queue.pending.iter().position(|x| x.is_none()).ok_or(Error::OutOfMemory)?;
+        let range_size = core::mem::size_of::<VirtIOBlkDiscardWriteZeroes>();
+
+        if let Some(range) = range {
+            unsafe {
+                let range_ptr = queue.dma_vaddr.add(RANGE_OFFSET + req_idx * range_size);
+                (range_ptr as *mut VirtIOBlkDiscardWriteZeroes).write_volatile(range);
+            }
+        }
+        let (req_paddr, status_paddr) = queue.write_header(req_idx, virtio_type, sector);
+
+        let token = match virtio_type {
+            VIRTIO_BLK_T_FLUSH => queue.vq.submit_sg(&blk_flush_chain(req_paddr, status_paddr)),
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                let range_paddr = queue.dma_paddr + RANGE_OFFSET + req_idx * range_size;
+                let segs =
+                    blk_rw_chain(req_paddr, range_paddr, range_size as u32, true, status_paddr);
+                queue.vq.submit_sg(&segs)
+            }
+            _ => {
+                let data = data.unwrap_or_default();
+                let segs = blk_rw_chain(req_paddr, data, VIRTIO_BLK_ID_BYTES, false, status_paddr);
+                queue.vq.submit_sg(&segs)
+            }
         }
+        .ok_or(Error::OutOfMemory)?;
 
         glenda::arch::sync::fence();
+        let len = if virtio_type == VIRTIO_BLK_T_GET_ID { VIRTIO_BLK_ID_BYTES } else { 0 };
+        let user_data = sqe.user_data;
+        queue.pending[req_idx] = Some(BlkPending { ring, user_data, token, len, split: None });
 
-        let req_paddr = self.dma_paddr + (req_idx * core::mem::size_of::<VirtIOBlkReq>()) as usize;
-        let status_paddr =
-            self.dma_paddr + (64 * core::mem::size_of::<VirtIOBlkReq>() + req_idx) as usize;
-
-        let data_paddr = if let Some(ref shm) = self.buffer {
-            let client_vaddr = shm.client_vaddr();
-            let paddr = shm.paddr();
-            let size = shm.size();
-            if (sqe.addr as usize) < client_vaddr
-                || (sqe.addr as usize) + sqe.len as usize > client_vaddr + size
-            {
-                error!("Address {:#x} out of SHM boundary", sqe.addr);
-                return Err(Error::InvalidArgs);
-            }
-            paddr + (sqe.addr as usize - client_vaddr as usize)
-        } else {
-            // Fallback to absolute paddr if no SHM (risky, but was previous behavior)
-            sqe.addr
-        };
+        Ok(())
+    }
 
-        let d1 = queue.alloc_desc().ok_or(Error::OutOfMemory)?;
-        let d2 = queue.alloc_desc().ok_or(Error::OutOfMemory)?;
-        let d3 = queue.alloc_desc().ok_or(Error::OutOfMemory)?;
-
-        queue.write_desc(
-            d1,
-            Descriptor {
-                addr: req_paddr,
-                len: core::mem::size_of::<VirtIOBlkReq>() as u32,
-                flags: DESC_F_NEXT,
-                next: d2,
-            },
-        );
+    /// Cuts the client buffer of a read or write into segments no longer than
+    /// the device's size_max. The session's window is one frame handed over with
+    /// a single physical address, so the buffer is physically contiguous.
+    fn data_segments(
+        &self,
+        ring: usize,
+        addr: usize,
+        len: u32,
+        write: bool,
+    ) -> Result<Vec<Segment>, Error> {
+        let max = match self.config.size_max {
+            0 => u32::MAX,
+            size_max => core::cmp::max(size_max & !511, 512),
+        } as usize;
+
+        let paddr = self.data_paddr(ring, addr, len)?;
+        let mut segs: Vec<Segment> = Vec::new();
+        let mut done = 0;
+        while done < len as usize {
+            let take = core::cmp::min(len as usize - done, max);
+            segs.push(Segment { addr: paddr + done, len: take as u32, write });
+            done += take;
+        }
+        Ok(segs)
+    }
 
-        let flags = if is_write { DESC_F_NEXT } else { DESC_F_NEXT | DESC_F_WRITE };
-        queue.write_desc(d2, Descriptor { addr: data_paddr, len: sqe.len, flags, next: d3 });
+    /// Issues a read or write, split into as many requests as seg_max and size_max
+    /// demand. Either every request is queued or none is.
+    fn submit_rw(
+        &mut self,
+        ring: usize,
+        q: usize,
+        sqe: io_uring::IoUringSqe,
+        virtio_type: u32,
+    ) -> Result<(), Error> {
+        let is_write = virtio_type == VIRTIO_BLK_T_OUT;
+        let segs = self.data_segments(ring, sqe.addr, sqe.len, !is_write)?;
+        let max_segs = match self.config.seg_max as usize {
+            0 => MAX_DATA_SEGMENTS,
+            seg_max => core::cmp::min(seg_max, MAX_DATA_SEGMENTS),
+        };
+        let groups = blk_split_segments(&segs, max_segs).ok_or(Error::InvalidArgs)?;
 
-        queue.write_desc(
-            d3,
-            Descriptor { addr: status_paddr, len: 1, flags: DESC_F_WRITE, next: 0 },
-        );
+        let queue = self.queues.get_mut(q).ok_or(Error::NotInitialized)?;
+        // Worst case without indirect tables: header and status around every group
+        let descs: usize = groups.iter().map(|g| g.len() + 2).sum();
+        if groups.len() > MAX_PENDING || descs > queue.vq.size() as usize {
+            error!("Request of {} bytes needs too many descriptors ({})", sqe.len, descs);
+            return Err(Error::InvalidArgs);
+        }
+        let free_slots = queue.pending.iter().filter(|x| x.is_none()).count();
+        if free_slots < groups.len() || descs > queue.vq.num_free() as usize {
+            return Err(Error::OutOfMemory);
+        }
 
-        glenda::arch::sync::fence();
-        queue.submit(d1);
+        let mut sector = sqe.off;
+        let mut split = None;
+        if groups.len() > 1 {
+            // Each live split still holds a request slot, so one of these is free
+            let slot = queue.splits.iter().position(|x| x.is_none()).ok_or(Error::OutOfMemory)?;
+            queue.splits[slot] = Some(BlkSplit {
+                ring,
+                user_data: sqe.user_data,
+                remaining: groups.len(),
+                result: sqe.len as i32,
+            });
+            split = Some(slot);
+        }
 
+        for group in groups.iter() {
+            let req_idx = queue.pending.iter().position(|x| x.is_none()).unwrap();
+            let (req_paddr, status_paddr) = queue.write_header(req_idx, virtio_type, sector);
+            let token = queue
+                .vq
+                .submit_sg(&blk_sg_chain(req_paddr, group, status_paddr))
+                .ok_or(Error::OutOfMemory)?;
+
+            let len: u32 = group.iter().map(|s| s.len).sum();
+            let user_data = sqe.user_data;
+            queue.pending[req_idx] = Some(BlkPending { ring, user_data, token, len, split });
+            sector += len as usize / 512;
+        }
         glenda::arch::sync::fence();
-        self.pending_info[req_idx] = Some((sqe.user_data, d1));
-        self.transport.notify_queue(0);
 
         Ok(())
     }
 
-    fn pop_completions(&mut self) {
-        if let Some(queue) = self.queue.as_mut() {
-            while let Some((id, _len)) = queue.pop() {
-                if let Some(pos) = self
-                    .pending_info
-                    .iter()
-                    .position(|info| info.map_or(false, |(_, head)| head == id as u16))
-                {
-                    let (user_data, head) = self.pending_info[pos].take().unwrap();
-
-                    let mut curr = head;
-                    loop {
-                        let next = queue.desc_table()[curr as usize].next;
-                        let flags = queue.desc_table()[curr as usize].flags;
-                        queue.free_desc(curr);
-                        if flags & DESC_F_NEXT == 0 {
-                            break;
-                        }
-                        curr = next;
-                    }
-
-                    let status_ptr = unsafe {
-                        self.dma_vaddr.add(64 * core::mem::size_of::<VirtIOBlkReq>() + pos)
-                    };
-                    let status = unsafe { core::ptr::read_volatile(status_ptr) };
+    fn pop_completions(&mut self, q: usize) {
+        self.reap_completions(q);
+        if !self.queues[q].deferred.is_empty() {
+            self.submit_deferred(q);
+        }
+    }
 
-                    let result = if status == VIRTIO_BLK_S_OK { 0 } else { -1 };
+    fn reap_completions(&mut self, q: usize) {
+        let queue = &mut self.queues[q];
+        loop {
+            queue.vq.disable_cb();
+            while let Some((token, _len)) = queue.vq.pop_used() {
+                if queue.stalled == Some(token) {
+                    log!("Timed-out read completed, queue {} is free again", q);
+                    queue.stalled = None;
+                    continue;
+                }
+                let Some(pos) =
+                    queue.pending.iter().position(|info| info.map_or(false, |p| p.token == token))
+                else {
+                    continue;
+                };
+                let req = queue.pending[pos].take().unwrap();
+
+                let status_ptr = unsafe { queue.dma_vaddr.add(STATUS_OFFSET + pos) };
+                let status = unsafe { core::ptr::read_volatile(status_ptr) };
+
+                let result = match status {
+                    VIRTIO_BLK_S_OK => req.len as i32,
+                    VIRTIO_BLK_S_IOERR => -(Error::IoError as i32),
+                    VIRTIO_BLK_S_UNSUPP => -(Error::NotSupported as i32),
+                    _ => {
+                        error!("Unknown virtio-blk status {}", status);
+                        -(Error::IoError as i32)
+                    }
+                };
 
-                    if let Some(server) = self.ring_server.as_mut() {
-                        let _ = server.complete(user_data, result);
+                let (ring, user_data, result) = match req.split {
+                    Some(slot) => {
+                        let Some(split) = queue.splits[slot].as_mut() else { continue };
+                        if result < 0 && split.result >= 0 {
+                            split.result = result;
+                        }
+                        split.remaining -= 1;
+                        if split.remaining > 0 {
+                            continue;
+                        }
+                        let split = queue.splits[slot].take().unwrap();
+                        (split.ring, split.user_data, split.result)
                     }
-                }
+                    None => (req.ring, req.user_data, result),
+                };
+
+                complete_sqe(&mut self.rings, &mut self.sessions, ring, user_data, result);
+            }
+            // Each request's client waits on its own completion, so interrupt for the next one
+            if queue.vq.enable_cb() {
+                break;
             }
         }
     }
 
-    pub fn set_ring_server(&mut self, server: IoUringServer) {
-        self.ring_server = Some(server);
+    /// Opens the session of `badge` and checks a ring is free, so that the
+    /// ring can be mapped knowing add_ring_server will take it.
+    pub fn reserve_ring(&mut self, badge: usize) -> Result<usize, Error> {
+        if self.rings.len() >= MAX_RINGS {
+            return Err(Error::OutOfMemory);
+        }
+        self.session(badge)
+    }
+
+    /// Adds a ring for the client `badge`, spreading rings round-robin over the
+    /// request queues. Returns the ring's index.
+    pub fn add_ring_server(&mut self, badge: usize, server: IoUringServer) -> Result<usize, Error> {
+        let session = self.reserve_ring(badge)?;
+        let idx = self.rings.len();
+        let queue = idx % self.queues.len().max(1);
+        self.rings.push(BlkRing { server, queue, session });
+        log!("Ring {} (session {}) uses request queue {}", idx, session, queue);
+        Ok(idx)
     }
 
     pub fn capacity(&self) -> usize {
-        unsafe {
-            let ptr = self.transport.config_ptr() as *const usize;
-            ptr.read_volatile()
+        self.config.capacity as usize
+    }
+
+    /// Capacity seen by the client `badge`: its partition's length, or the disk's.
+    pub fn capacity_of(&self, badge: usize) -> usize {
+        match self.partition_extent(badge) {
+            Ok(Some(extent)) => extent.len as usize,
+            Ok(None) => self.capacity(),
+            Err(_) => 0,
         }
     }
 
     pub fn block_size(&self) -> u32 {
-        self.blk_size
+        self.config.blk_size
+    }
+
+    /// 512-byte sectors per logical block.
+    fn sectors_per_block(&self) -> u64 {
+        (self.config.blk_size as u64 / 512).max(1)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.features & VIRTIO_BLK_F_RO != 0
+    }
+
+    /// Whether the client `badge` may only read, by device or session.
+    pub fn is_read_only_for(&self, badge: usize) -> bool {
+        self.is_read_only() || self.sessions.iter().any(|s| s.badge == badge && s.read_only)
+    }
+
+    pub fn config(&self) -> &VirtioBlkConfig {
+        &self.config
+    }
+}
+
+/// Posts a CQE on `ring` and returns the SQE's slot to its session's quota.
+fn complete_sqe(
+    rings: &mut [BlkRing],
+    sessions: &mut [BlkSession],
+    ring: usize,
+    user_data: usize,
+    result: i32,
+) {
+    if let Some(r) = rings.get_mut(ring) {
+        let _ = r.server.complete(user_data, result);
+        if let Some(session) = sessions.get_mut(r.session) {
+            session.inflight = session.inflight.saturating_sub(1);
+        }
+    }
+}
+
+/// Sector access for the partition scan at init.
+struct SyncReader<'a>(&'a mut VirtIOBlk);
+
+impl SectorReader for SyncReader<'_> {
+    fn sector_size(&self) -> usize {
+        self.0.sectors_per_block() as usize * 512
+    }
+
+    fn num_sectors(&self) -> u64 {
+        self.0.config.capacity / self.0.sectors_per_block()
+    }
+
+    fn read_sector(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), PartitionError> {
+        let sector = lba * self.0.sectors_per_block();
+        self.0.read_sync(sector as usize, buf).map_err(|_| PartitionError::Io)
     }
 }
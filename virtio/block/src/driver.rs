@@ -1,11 +1,12 @@
+use crate::blk::DMA_PAGES;
 use crate::layout::IRQ_BADGE;
 use crate::layout::{
-    DMA_SLOT, DMA_VA, IRQ_NOTIFY_CAP, IRQ_NOTIFY_SLOT, IRQ_SLOT, MMIO_SLOT, MMIO_VA,
+    BAR_VA, DMA_SLOT, DMA_VA, IRQ_NOTIFY_CAP, IRQ_NOTIFY_SLOT, IRQ_SLOT, MMIO_SLOT, MMIO_VA,
 };
 use crate::BlockService;
 use crate::VirtIOBlk;
+use alloc::format;
 use alloc::string::String;
-use core::ptr::NonNull;
 use glenda::arch::mem::PGSIZE;
 use glenda::cap::{Rights, CSPACE_CAP};
 use glenda::drivers::interface::DriverService;
@@ -13,23 +14,20 @@ use glenda::error::Error;
 use glenda::interface::{DeviceService, ResourceService, VSpaceService};
 use glenda::ipc::Badge;
 use glenda::protocol::device::LogicDeviceDesc;
-use virtio_common::VirtIOTransport;
+use partition::partition_badge;
 
 impl DriverService for BlockService<'_> {
     fn init(&mut self) -> Result<(), Error> {
         log!("Driver init...");
-        let (mmio, pa, size) = self.dev.get_mmio(Badge::null(), 0, MMIO_SLOT)?;
-        log!("Got MMIO cap: addr={:#x}, size={:#x}", pa, size);
-
-        self.vspace_mgr.map_page(
-            mmio,
-            MMIO_VA,
-            glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
-            1,
+        let transport = virtio_common::map_device(
+            self.dev,
             self.res,
             self.cspace_mgr,
+            self.vspace_mgr,
+            MMIO_SLOT,
+            MMIO_VA,
+            BAR_VA,
         )?;
-        glenda::arch::sync::fence();
         let irq_handler = self.dev.get_irq(Badge::null(), 0, IRQ_SLOT)?;
         log!("Got IRQ cap: {:?}", irq_handler);
 
@@ -46,21 +44,17 @@ impl DriverService for BlockService<'_> {
         self.irq = Some(irq_handler);
 
         // 5. Init Hardware / Construct VirtIOBlk
-        let transport = unsafe {
-            VirtIOTransport::new(NonNull::new(MMIO_VA as *mut u8).expect("MMIO_VA is null"))
-                .expect("Failed to init transport")
-        };
         let mut blk = VirtIOBlk::new(transport);
 
-        // 6. Allocate DMA memory (4 pages)
-        log!("Allocating 4 pages of DMA memory...");
-        let (paddr, frame) = self.res.dma_alloc(Badge::null(), 4, DMA_SLOT)?;
-        log!("Mapping DMA: paddr={:#x}, len={:#x}", paddr, 4 * PGSIZE);
+        // 6. Allocate DMA memory
+        log!("Allocating {} pages of DMA memory...", DMA_PAGES);
+        let (paddr, frame) = self.res.dma_alloc(Badge::null(), DMA_PAGES, DMA_SLOT)?;
+        log!("Mapping DMA: paddr={:#x}, len={:#x}", paddr, DMA_PAGES * PGSIZE);
         self.vspace_mgr.map_page(
             frame,
             DMA_VA,
             glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
-            4,
+            DMA_PAGES,
             self.res,
             self.cspace_mgr,
         )?;
@@ -72,6 +66,8 @@ impl DriverService for BlockService<'_> {
 
         let cap = blk.capacity();
         log!("Capacity: {} sectors ({} MB)", cap, (cap * 512) / (1024 * 1024));
+        blk.scan_partitions();
+        let partitions = blk.partitions.clone();
 
         self.blk = Some(blk);
         log!("Registering block device with capacity {} sectors", cap);
@@ -83,6 +79,17 @@ impl DriverService for BlockService<'_> {
             badge: None,
         };
         self.dev.register_logic(Badge::null(), desc, self.endpoint.cap())?;
+
+        // One device per partition; its clients' badges select the partition
+        for p in partitions.iter() {
+            let desc = LogicDeviceDesc {
+                name: format!("virtio-blk-p{}", p.number),
+                parent_name: String::from("virtio-blk"),
+                dev_type: glenda::protocol::device::LogicDeviceType::Block,
+                badge: Some(partition_badge(p.number)),
+            };
+            self.dev.register_logic(Badge::null(), desc, self.endpoint.cap())?;
+        }
         Ok(())
     }
 
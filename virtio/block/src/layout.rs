@@ -19,5 +19,6 @@ pub const RING_CAP: Page = Page::from(RING_SLOT);
 pub const IRQ_NOTIFY_CAP: Endpoint = Endpoint::from(IRQ_NOTIFY_SLOT);
 
 pub const MMIO_VA: usize = 0x4000_0000;
+pub const BAR_VA: usize = 0x4100_0000;
 pub const DMA_VA: usize = 0x5000_0000;
 pub const RING_VA: usize = 0x6000_0000;
@@ -1,5 +1,7 @@
 use crate::blk::*;
 use crate::layout::{IRQ_BADGE, RING_VA};
+use block_proto::*;
+use glenda::arch::mem::PGSIZE;
 use glenda::cap::{CapPtr, Endpoint, IrqHandler, Page, Reply, CSPACE_CAP};
 use glenda::client::{DeviceClient, ResourceClient};
 use glenda::drivers::interface::DriverService;
@@ -10,6 +12,7 @@ use glenda::io::uring::{IoUringBuffer as IoUring, IoUringServer};
 use glenda::ipc::server::{handle_call, handle_cap_call, handle_notify};
 use glenda::ipc::{Badge, MsgTag, UTCB};
 use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+use virtio_common::blk::*;
 
 pub struct BlockService<'a> {
     pub blk: Option<VirtIOBlk>,
@@ -21,7 +24,6 @@ pub struct BlockService<'a> {
     pub res: &'a mut ResourceClient,
     pub cspace_mgr: &'a mut CSpaceManager,
     pub vspace_mgr: &'a mut VSpaceManager,
-    pub connected_client: Option<usize>,
 }
 
 impl<'a> BlockService<'a> {
@@ -41,17 +43,24 @@ impl<'a> BlockService<'a> {
             res,
             cspace_mgr,
             vspace_mgr,
-            connected_client: None,
         }
     }
 
     pub fn setup_ring(
         &mut self,
+        badge: usize,
         sq_entries: u32,
         cq_entries: u32,
+        flags: usize,
         notify_ep: Endpoint,
         _recv: CapPtr,
     ) -> Result<Page, Error> {
+        let blk = self.blk.as_mut().ok_or(Error::NotInitialized)?;
+        // Everything that can turn the ring down is checked before its page is mapped
+        blk.reserve_ring(badge)?;
+        // Each client ring gets its own page after RING_VA
+        let ring_va = RING_VA + blk.rings.len() * PGSIZE;
+
         let slot = self.cspace_mgr.alloc(self.res)?;
         // For 4 entries, we only need a few hundred bytes, so 1 page is plenty.
         let (paddr, frame) = self.res.dma_alloc(Badge::null(), 1, slot)?;
@@ -60,7 +69,7 @@ impl<'a> BlockService<'a> {
         // Map the DMA frame to our virtual address space
         self.vspace_mgr.map_page(
             frame.clone(),
-            RING_VA,
+            ring_va,
             glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
             1,
             self.res,
@@ -68,17 +77,16 @@ impl<'a> BlockService<'a> {
         )?;
         glenda::arch::sync::fence();
 
-        let ring = unsafe {
-            IoUring::new(RING_VA as *mut u8, glenda::arch::mem::PGSIZE, sq_entries, cq_entries)
-        };
+        let ring = unsafe { IoUring::new(ring_va as *mut u8, PGSIZE, sq_entries, cq_entries) };
         let mut server = IoUringServer::new(ring);
 
         server.set_client_notify(notify_ep);
 
         if let Some(blk) = self.blk.as_mut() {
-            blk.set_ring_server(server);
-        } else {
-            error!("setup_ring: blk is None!");
+            blk.add_ring_server(badge, server)?;
+            if flags & BLOCK_RING_READ_ONLY != 0 {
+                blk.set_read_only(badge)?;
+            }
         }
 
         Ok(frame)
@@ -86,13 +94,14 @@ impl<'a> BlockService<'a> {
 
     pub fn setup_shm(
         &mut self,
+        badge: usize,
         frame: Page,
         vaddr: usize,
         paddr: usize,
         size: usize,
     ) -> Result<(), Error> {
         if let Some(blk) = self.blk.as_mut() {
-            blk.setup_shm(frame, vaddr, paddr, size)
+            blk.setup_shm(badge, frame, vaddr, paddr, size)
         } else {
             Err(Error::NotInitialized)
         }
@@ -100,13 +109,36 @@ impl<'a> BlockService<'a> {
 }
 
 impl<'a> BlockService<'a> {
-    pub fn capacity(&self) -> usize {
-        self.blk.as_ref().map(|b| b.capacity()).unwrap_or(0)
+    pub fn capacity(&self, badge: usize) -> usize {
+        self.blk.as_ref().map(|b| b.capacity_of(badge)).unwrap_or(0)
     }
 
     pub fn block_size(&self) -> u32 {
         self.blk.as_ref().map(|b| b.block_size()).unwrap_or(0)
     }
+
+    /// Answers the device property queries from the config read at init.
+    pub fn query(&self, badge: usize, label: usize) -> Result<usize, Error> {
+        let blk = self.blk.as_ref().ok_or(Error::NotInitialized)?;
+        let cfg = blk.config();
+        let topo = cfg.topology;
+        let value = match label {
+            BLOCK_GET_READ_ONLY => blk.is_read_only_for(badge) as usize,
+            BLOCK_GET_WRITEBACK => cfg.writeback as usize,
+            BLOCK_GET_GEOMETRY => {
+                let geo = cfg.geometry;
+                (geo.cylinders as usize) << 16 | (geo.heads as usize) << 8 | geo.sectors as usize
+            }
+            BLOCK_GET_PHYS_BLOCK_SIZE => cfg.physical_block_size() as usize,
+            BLOCK_GET_ALIGNMENT_OFFSET => topo.alignment_offset as usize,
+            BLOCK_GET_MIN_IO_SIZE => topo.min_io_size as usize * cfg.blk_size as usize,
+            BLOCK_GET_OPT_IO_SIZE => topo.opt_io_size as usize * cfg.blk_size as usize,
+            BLOCK_GET_MAX_SEGMENT_SIZE => cfg.size_max as usize,
+            BLOCK_GET_MAX_SEGMENTS => cfg.seg_max as usize,
+            _ => return Err(Error::NotSupported),
+        };
+        Ok(value)
+    }
 }
 
 impl<'a> SystemService for BlockService<'a> {
@@ -163,12 +195,6 @@ impl<'a> SystemService for BlockService<'a> {
 
     fn dispatch(&mut self, utcb: &mut UTCB) -> Result<(), Error> {
         let badge = utcb.get_badge().bits();
-        if badge != 0 && self.connected_client.is_some() && self.connected_client != Some(badge) {
-            let proto = utcb.get_msg_tag().proto();
-            if proto != glenda::protocol::KERNEL_PROTO {
-                return Err(Error::PermissionDenied);
-            }
-        }
 
         glenda::ipc_dispatch! {
             self, utcb,
@@ -222,14 +248,15 @@ impl<'a> SystemService for BlockService<'a> {
                 })
             },
             (BLOCK_PROTO, block::GET_CAPACITY) => |s: &mut Self, u: &mut UTCB| {
-                if badge != 0 && s.connected_client.is_none() {
-                    s.connected_client = Some(badge);
-                }
-                handle_call(u, |_| Ok(s.capacity() as usize))
+                handle_call(u, |_| Ok(s.capacity(badge)))
             },
             (BLOCK_PROTO, block::GET_BLOCK_SIZE) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |_| Ok(s.block_size() as usize))
             },
+            (BLOCK_PROTO, BLOCK_GET_READ_ONLY | BLOCK_GET_WRITEBACK | BLOCK_GET_GEOMETRY | BLOCK_GET_PHYS_BLOCK_SIZE | BLOCK_GET_ALIGNMENT_OFFSET | BLOCK_GET_MIN_IO_SIZE | BLOCK_GET_OPT_IO_SIZE | BLOCK_GET_MAX_SEGMENT_SIZE | BLOCK_GET_MAX_SEGMENTS) => |s: &mut Self, u: &mut UTCB| {
+                let label = u.get_msg_tag().label();
+                handle_call(u, |_| s.query(badge, label))
+            },
             (BLOCK_PROTO, block::SETUP_BUFFER) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |u| {
                     let recv_slot = s.recv;
@@ -242,7 +269,7 @@ impl<'a> SystemService for BlockService<'a> {
                     CSPACE_CAP.transfer_self(recv_slot, slot)?;
 
                     let frame = Page::from(slot);
-                    s.setup_shm(frame, vaddr, paddr, size)?;
+                    s.setup_shm(badge, frame, vaddr, paddr, size)?;
                     Ok(())
                 })
             },
@@ -253,6 +280,7 @@ impl<'a> SystemService for BlockService<'a> {
                     // Read args into local variables before transfer
                     let sq = u.get_mr(0) as u32;
                     let cq = u.get_mr(1) as u32;
+                    let flags = u.get_mr(2);
 
                     CSPACE_CAP.transfer_self(recv_slot, slot)?;
 
@@ -260,7 +288,7 @@ impl<'a> SystemService for BlockService<'a> {
                     // It was moved to slot.
                     let notify_ep = Endpoint::from(slot);
 
-                    let frame = s.setup_ring(sq, cq, notify_ep, CapPtr::null())?;
+                    let frame = s.setup_ring(badge, sq, cq, flags, notify_ep, CapPtr::null())?;
                     Ok(frame.cap())
                 })
             },
@@ -0,0 +1,232 @@
+//! virtio-blk request layout (VirtIO 1.1, Section 5.2.6)
+
+use crate::queue::Segment;
+use crate::transport::{read_config_consistent, Transport};
+use alloc::vec::Vec;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VirtIOBlkReq {
+    pub type_: u32,
+    pub reserved: u32,
+    pub sector: usize,
+}
+
+pub const VIRTIO_BLK_T_IN: u32 = 0;
+pub const VIRTIO_BLK_T_OUT: u32 = 1;
+pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
+pub const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+pub const VIRTIO_BLK_F_SIZE_MAX: u64 = 1_u64 << 1;
+pub const VIRTIO_BLK_F_SEG_MAX: u64 = 1_u64 << 2;
+pub const VIRTIO_BLK_F_GEOMETRY: u64 = 1_u64 << 4;
+pub const VIRTIO_BLK_F_RO: u64 = 1_u64 << 5;
+pub const VIRTIO_BLK_F_BLK_SIZE: u64 = 1_u64 << 6;
+pub const VIRTIO_BLK_F_FLUSH: u64 = 1_u64 << 9;
+pub const VIRTIO_BLK_F_TOPOLOGY: u64 = 1_u64 << 10;
+pub const VIRTIO_BLK_F_CONFIG_WCE: u64 = 1_u64 << 11;
+pub const VIRTIO_BLK_F_MQ: u64 = 1_u64 << 12;
+pub const VIRTIO_BLK_F_DISCARD: u64 = 1_u64 << 13;
+pub const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 1_u64 << 14;
+
+/// Length of the serial string returned by VIRTIO_BLK_T_GET_ID.
+pub const VIRTIO_BLK_ID_BYTES: u32 = 20;
+
+// io_uring opcodes for block requests beyond read / write / sync
+pub const IOURING_OP_TRIM: u8 = 0x20;
+pub const IOURING_OP_WRITE_ZEROES: u8 = 0x21;
+/// Fills the buffer with the device serial (up to `VIRTIO_BLK_ID_BYTES`).
+pub const IOURING_OP_GET_ID: u8 = 0x22;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VirtioBlkGeometry {
+    pub cylinders: u16,
+    pub heads: u8,
+    pub sectors: u8,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VirtioBlkTopology {
+    /// log2 of logical blocks per physical block.
+    pub physical_block_exp: u8,
+    /// Offset of the first aligned logical block.
+    pub alignment_offset: u8,
+    /// Suggested minimum and optimal I/O sizes, in logical blocks.
+    pub min_io_size: u16,
+    pub opt_io_size: u32,
+}
+
+/// struct virtio_blk_config; fields behind features that weren't negotiated are left at 0.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VirtioBlkConfig {
+    /// In 512-byte sectors.
+    pub capacity: u64,
+    pub size_max: u32,
+    pub seg_max: u32,
+    pub geometry: VirtioBlkGeometry,
+    pub blk_size: u32,
+    pub topology: VirtioBlkTopology,
+    pub writeback: u8,
+    pub num_queues: u16,
+    pub max_discard_sectors: u32,
+    pub max_discard_seg: u32,
+    pub discard_sector_alignment: u32,
+    pub max_write_zeroes_sectors: u32,
+    pub max_write_zeroes_seg: u32,
+    pub write_zeroes_may_unmap: bool,
+}
+
+impl VirtioBlkConfig {
+    /// Reads the config fields backed by `features`, retrying across config changes.
+    pub fn read(transport: &dyn Transport, features: u64) -> Self {
+        read_config_consistent(transport, |base| unsafe {
+            let u8_at = |off: usize| base.add(off).read_volatile();
+            let u16_at = |off: usize| (base.add(off) as *const u16).read_volatile();
+            let u32_at = |off: usize| (base.add(off) as *const u32).read_volatile();
+            let has = |f: u64| features & f != 0;
+
+            let mut cfg = Self {
+                // 64-bit fields are read as two halves, which the generation check covers
+                capacity: (u32_at(0) as u64) | (u32_at(4) as u64) << 32,
+                blk_size: 512,
+                ..Self::default()
+            };
+            if has(VIRTIO_BLK_F_SIZE_MAX) {
+                cfg.size_max = u32_at(8);
+            }
+            if has(VIRTIO_BLK_F_SEG_MAX) {
+                cfg.seg_max = u32_at(12);
+            }
+            if has(VIRTIO_BLK_F_GEOMETRY) {
+                cfg.geometry = VirtioBlkGeometry {
+                    cylinders: u16_at(16),
+                    heads: u8_at(18),
+                    sectors: u8_at(19),
+                };
+            }
+            if has(VIRTIO_BLK_F_BLK_SIZE) {
+                cfg.blk_size = u32_at(20);
+            }
+            if has(VIRTIO_BLK_F_TOPOLOGY) {
+                cfg.topology = VirtioBlkTopology {
+                    physical_block_exp: u8_at(24),
+                    alignment_offset: u8_at(25),
+                    min_io_size: u16_at(26),
+                    opt_io_size: u32_at(28),
+                };
+            }
+            if has(VIRTIO_BLK_F_CONFIG_WCE) {
+                cfg.writeback = u8_at(32);
+            } else if has(VIRTIO_BLK_F_FLUSH) {
+                cfg.writeback = 1;
+            }
+            if has(VIRTIO_BLK_F_MQ) {
+                cfg.num_queues = u16_at(34);
+            }
+            if has(VIRTIO_BLK_F_DISCARD) {
+                cfg.max_discard_sectors = u32_at(36);
+                cfg.max_discard_seg = u32_at(40);
+                cfg.discard_sector_alignment = u32_at(44);
+            }
+            if has(VIRTIO_BLK_F_WRITE_ZEROES) {
+                cfg.max_write_zeroes_sectors = u32_at(48);
+                cfg.max_write_zeroes_seg = u32_at(52);
+                cfg.write_zeroes_may_unmap = u8_at(56) != 0;
+            }
+            cfg
+        })
+    }
+
+    pub fn physical_block_size(&self) -> u32 {
+        self.blk_size << self.topology.physical_block_exp
+    }
+}
+
+/// Payload of DISCARD / WRITE_ZEROES requests, one per range.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VirtIOBlkDiscardWriteZeroes {
+    pub sector: u64,
+    pub num_sectors: u32,
+    pub flags: u32,
+}
+
+pub const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
+
+/// Chain for a read or write: header, data (device-writable for reads), status byte.
+pub fn blk_rw_chain(
+    req_paddr: usize,
+    data_paddr: usize,
+    len: u32,
+    is_write: bool,
+    status_paddr: usize,
+) -> [Segment; 3] {
+    [
+        Segment::readable(req_paddr, core::mem::size_of::<VirtIOBlkReq>() as u32),
+        Segment { addr: data_paddr, len, write: !is_write },
+        Segment::writable(status_paddr, 1),
+    ]
+}
+
+/// Chain for a request without payload, such as FLUSH: header and status byte.
+pub fn blk_flush_chain(req_paddr: usize, status_paddr: usize) -> [Segment; 2] {
+    [
+        Segment::readable(req_paddr, core::mem::size_of::<VirtIOBlkReq>() as u32),
+        Segment::writable(status_paddr, 1),
+    ]
+}
+
+/// Chain for a scattered read or write: header, each data segment, status byte.
+pub fn blk_sg_chain(req_paddr: usize, data: &[Segment], status_paddr: usize) -> Vec<Segment> {
+    let mut chain = Vec::with_capacity(data.len() + 2);
+    chain.push(Segment::readable(req_paddr, core::mem::size_of::<VirtIOBlkReq>() as u32));
+    chain.extend_from_slice(data);
+    chain.push(Segment::writable(status_paddr, 1));
+    chain
+}
+
+/// Groups data segments into requests of at most `max_segs` segments each.
+///
+/// Every group but the last is cut back to a whole number of 512-byte sectors, so
+/// consecutive groups can be issued at consecutive sectors. Returns `None` if a
+/// group can't be made sector aligned within `max_segs` segments.
+pub fn blk_split_segments(segs: &[Segment], max_segs: usize) -> Option<Vec<Vec<Segment>>> {
+    let mut groups = Vec::new();
+    let mut group: Vec<Segment> = Vec::new();
+    let mut queue: Vec<Segment> = segs.iter().rev().copied().collect();
+
+    while let Some(seg) = queue.pop() {
+        group.push(seg);
+        if group.len() < max_segs || queue.is_empty() {
+            continue;
+        }
+        // Push the unaligned tail back so this group ends on a sector boundary
+        let mut excess = group.iter().map(|s| s.len as usize).sum::<usize>() % 512;
+        while excess > 0 {
+            let last = group.last_mut()?;
+            if last.len as usize > excess {
+                last.len -= excess as u32;
+                let addr = last.addr + last.len as usize;
+                queue.push(Segment { addr, len: excess as u32, write: last.write });
+                excess = 0;
+            } else {
+                excess -= last.len as usize;
+                queue.push(group.pop()?);
+            }
+        }
+        if group.is_empty() {
+            return None;
+        }
+        groups.push(core::mem::take(&mut group));
+    }
+    if !group.is_empty() {
+        groups.push(group);
+    }
+    Some(groups)
+}
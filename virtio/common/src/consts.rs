@@ -19,9 +19,12 @@ pub const OFF_DEVICE_FEATURES: usize = 0x010;
 pub const OFF_DEVICE_FEATURES_SEL: usize = 0x014;
 pub const OFF_DRIVER_FEATURES: usize = 0x020;
 pub const OFF_DRIVER_FEATURES_SEL: usize = 0x024;
+pub const OFF_GUEST_PAGE_SIZE: usize = 0x028; // Legacy only
 pub const OFF_QUEUE_SEL: usize = 0x030;
 pub const OFF_QUEUE_NUM_MAX: usize = 0x034;
 pub const OFF_QUEUE_NUM: usize = 0x038;
+pub const OFF_QUEUE_ALIGN: usize = 0x03c; // Legacy only
+pub const OFF_QUEUE_PFN: usize = 0x040; // Legacy only
 pub const OFF_QUEUE_READY: usize = 0x044;
 pub const OFF_QUEUE_NOTIFY: usize = 0x050;
 pub const OFF_INTERRUPT_STATUS: usize = 0x060;
@@ -36,6 +39,10 @@ pub const OFF_QUEUE_DEVICE_HIGH: usize = 0x0a4;
 pub const OFF_CONFIG_GENERATION: usize = 0x0fc;
 pub const OFF_CONFIG: usize = 0x100;
 
+// Legacy (version 1) rings live in one area with the used ring on its own page
+pub const LEGACY_PAGE_SIZE: usize = 4096;
+pub const LEGACY_QUEUE_ALIGN: usize = 4096;
+
 // Status bits
 pub const STATUS_ACKNOWLEDGE: u32 = 1;
 pub const STATUS_DRIVER: u32 = 2;
@@ -43,3 +50,17 @@ pub const STATUS_DRIVER_OK: u32 = 4;
 pub const STATUS_FEATURES_OK: u32 = 8;
 pub const STATUS_DEVICE_NEEDS_RESET: u32 = 64;
 pub const STATUS_FAILED: u32 = 128;
+
+// Interrupt status bits
+pub const INTERRUPT_USED_BUFFER: u32 = 1;
+pub const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+// Reserved feature bits (VirtIO 1.1, Section 6)
+pub const VIRTIO_F_INDIRECT_DESC: u64 = 1_u64 << 28;
+pub const VIRTIO_F_EVENT_IDX: u64 = 1_u64 << 29;
+pub const VIRTIO_F_VERSION_1: u64 = 1_u64 << 32;
+pub const VIRTIO_F_RING_PACKED: u64 = 1_u64 << 34;
+/// Reserved features the transports and queues implement; drivers mask the
+/// offered reserved bits with this rather than taking them as offered.
+pub const VIRTIO_TRANSPORT_FEATURES: u64 =
+    VIRTIO_F_VERSION_1 | VIRTIO_F_INDIRECT_DESC | VIRTIO_F_EVENT_IDX | VIRTIO_F_RING_PACKED;
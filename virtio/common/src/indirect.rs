@@ -0,0 +1,40 @@
+//! Indirect descriptor tables (VirtIO 1.1, Section 2.6.5.3 / 2.7.7)
+
+/// Driver-owned DMA area holding one indirect table per ring token.
+///
+/// A token is only ever in flight once, so the table for a chain is simply
+/// the one indexed by its token; no separate allocator is needed.
+#[derive(Debug, Clone, Copy)]
+pub struct IndirectPool {
+    pub vaddr: *mut u8,
+    pub paddr: usize,
+    /// Descriptors per table.
+    pub table_len: u16,
+}
+
+impl IndirectPool {
+    pub const fn size_in_bytes(num: u16, table_len: u16) -> usize {
+        num as usize * table_len as usize * 16
+    }
+
+    pub fn new(vaddr: *mut u8, paddr: usize, table_len: u16) -> Self {
+        Self { vaddr, paddr, table_len }
+    }
+
+    /// Whether a chain of `len` segments should go through an indirect table.
+    pub fn fits(&self, len: usize) -> bool {
+        len > 1 && len <= self.table_len as usize
+    }
+
+    fn table_offset(&self, token: u16) -> usize {
+        token as usize * self.table_len as usize * 16
+    }
+
+    pub fn table_vaddr(&self, token: u16) -> *mut u8 {
+        unsafe { self.vaddr.add(self.table_offset(token)) }
+    }
+
+    pub fn table_paddr(&self, token: u16) -> usize {
+        self.paddr + self.table_offset(token)
+    }
+}
@@ -1,10 +1,25 @@
 #![no_std]
 
+#[macro_use]
+extern crate glenda;
+
+extern crate alloc;
+
+pub mod blk;
 pub mod consts;
+pub mod indirect;
+pub mod net;
+pub mod packed;
+pub mod pci;
 pub mod queue;
+#[cfg(feature = "sim")]
+pub mod sim;
 pub mod transport;
 
 pub use consts::*;
+pub use indirect::*;
+pub use packed::*;
+pub use pci::*;
 pub use queue::*;
 pub use transport::*;
 
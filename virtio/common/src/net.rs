@@ -0,0 +1,374 @@
+//! virtio-net packet layout (VirtIO 1.1, Section 5.1.6)
+
+use crate::consts::VIRTIO_F_VERSION_1;
+use crate::queue::Segment;
+use crate::transport::{read_config_consistent, Transport};
+
+pub const VIRTIO_NET_F_CSUM: u64 = 1_u64 << 0;
+pub const VIRTIO_NET_F_GUEST_CSUM: u64 = 1_u64 << 1;
+pub const VIRTIO_NET_F_MTU: u64 = 1_u64 << 3;
+pub const VIRTIO_NET_F_MAC: u64 = 1_u64 << 5;
+pub const VIRTIO_NET_F_GUEST_TSO4: u64 = 1_u64 << 7;
+pub const VIRTIO_NET_F_GUEST_TSO6: u64 = 1_u64 << 8;
+pub const VIRTIO_NET_F_GUEST_ECN: u64 = 1_u64 << 9;
+pub const VIRTIO_NET_F_GUEST_UFO: u64 = 1_u64 << 10;
+pub const VIRTIO_NET_F_HOST_TSO4: u64 = 1_u64 << 11;
+pub const VIRTIO_NET_F_HOST_TSO6: u64 = 1_u64 << 12;
+pub const VIRTIO_NET_F_HOST_ECN: u64 = 1_u64 << 13;
+pub const VIRTIO_NET_F_HOST_UFO: u64 = 1_u64 << 14;
+pub const VIRTIO_NET_F_MRG_RXBUF: u64 = 1_u64 << 15;
+pub const VIRTIO_NET_F_STATUS: u64 = 1_u64 << 16;
+pub const VIRTIO_NET_F_CTRL_VQ: u64 = 1_u64 << 17;
+pub const VIRTIO_NET_F_CTRL_RX: u64 = 1_u64 << 18;
+pub const VIRTIO_NET_F_CTRL_VLAN: u64 = 1_u64 << 19;
+pub const VIRTIO_NET_F_GUEST_ANNOUNCE: u64 = 1_u64 << 21;
+pub const VIRTIO_NET_F_MQ: u64 = 1_u64 << 22;
+pub const VIRTIO_NET_F_CTRL_MAC_ADDR: u64 = 1_u64 << 23;
+pub const VIRTIO_NET_F_RSS: u64 = 1_u64 << 60;
+
+/// Checksum and segmentation offloads, the features `NET_GET_OFFLOADS` reports.
+pub const VIRTIO_NET_OFFLOADS: u64 = VIRTIO_NET_F_CSUM
+    | VIRTIO_NET_F_GUEST_CSUM
+    | VIRTIO_NET_F_HOST_TSO4
+    | VIRTIO_NET_F_HOST_TSO6
+    | VIRTIO_NET_F_GUEST_TSO4
+    | VIRTIO_NET_F_GUEST_TSO6;
+
+// Config `status` bits
+pub const VIRTIO_NET_S_LINK_UP: u16 = 1;
+/// The driver should announce its addresses, then send VIRTIO_NET_CTRL_ANNOUNCE_ACK.
+pub const VIRTIO_NET_S_ANNOUNCE: u16 = 2;
+
+/// MTU assumed when the device doesn't offer VIRTIO_NET_F_MTU.
+pub const VIRTIO_NET_DEFAULT_MTU: u16 = 1500;
+
+/// `flags`: only `csum_start`..end is summed; the sum goes at `csum_start + csum_offset`.
+pub const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+/// `flags`: the device checked the packet's checksums.
+pub const VIRTIO_NET_HDR_F_DATA_VALID: u8 = 2;
+
+pub const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+pub const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+pub const VIRTIO_NET_HDR_GSO_UDP: u8 = 3;
+pub const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+pub const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+// Control queue commands (Section 5.1.6.5): classes, then the commands in each
+pub const VIRTIO_NET_CTRL_RX: u8 = 0;
+pub const VIRTIO_NET_CTRL_RX_PROMISC: u8 = 0;
+pub const VIRTIO_NET_CTRL_RX_ALLMULTI: u8 = 1;
+pub const VIRTIO_NET_CTRL_MAC: u8 = 1;
+pub const VIRTIO_NET_CTRL_MAC_TABLE_SET: u8 = 0;
+pub const VIRTIO_NET_CTRL_MAC_ADDR_SET: u8 = 1;
+pub const VIRTIO_NET_CTRL_VLAN: u8 = 2;
+pub const VIRTIO_NET_CTRL_VLAN_ADD: u8 = 0;
+pub const VIRTIO_NET_CTRL_VLAN_DEL: u8 = 1;
+pub const VIRTIO_NET_CTRL_ANNOUNCE: u8 = 3;
+pub const VIRTIO_NET_CTRL_ANNOUNCE_ACK: u8 = 0;
+pub const VIRTIO_NET_CTRL_MQ: u8 = 4;
+pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+pub const VIRTIO_NET_CTRL_MQ_RSS_CONFIG: u8 = 1;
+
+// Packet fields RSS hashes on (Section 5.1.6.4.3.1)
+pub const VIRTIO_NET_RSS_HASH_TYPE_IPV4: u32 = 1 << 0;
+pub const VIRTIO_NET_RSS_HASH_TYPE_TCPV4: u32 = 1 << 1;
+pub const VIRTIO_NET_RSS_HASH_TYPE_UDPV4: u32 = 1 << 2;
+pub const VIRTIO_NET_RSS_HASH_TYPE_IPV6: u32 = 1 << 3;
+pub const VIRTIO_NET_RSS_HASH_TYPE_TCPV6: u32 = 1 << 4;
+pub const VIRTIO_NET_RSS_HASH_TYPE_UDPV6: u32 = 1 << 5;
+
+/// Acks the device writes back for a control command.
+pub const VIRTIO_NET_OK: u8 = 0;
+pub const VIRTIO_NET_ERR: u8 = 1;
+
+// NET_PROTO labels beyond GET_MAC and the setup calls
+/// Negotiated `VIRTIO_NET_OFFLOADS` bits; a ring may only ask for these.
+pub const NET_GET_OFFLOADS: usize = 0x100;
+/// MR 0: NET_RX_* bits. Needs VIRTIO_NET_F_CTRL_RX.
+pub const NET_SET_RX_MODE: usize = 0x101;
+/// MR 0-5: the address octets, laid out as GET_MAC returns them. Needs
+/// VIRTIO_NET_F_CTRL_MAC_ADDR.
+pub const NET_SET_MAC: usize = 0x102;
+/// MR 0: VLAN id whose tagged frames are received, or no longer received.
+/// Needs VIRTIO_NET_F_CTRL_VLAN.
+pub const NET_ADD_VLAN: usize = 0x103;
+pub const NET_DEL_VLAN: usize = 0x104;
+/// IPC buffer: 6-byte addresses to receive for, the first MR 0 of them unicast
+/// and the rest multicast. Needs VIRTIO_NET_F_CTRL_RX.
+pub const NET_SET_MAC_TABLE: usize = 0x105;
+/// Returns NET_LINK_* bits in MR 0 and the MTU in MR 1.
+pub const NET_GET_LINK: usize = 0x106;
+/// Returns the queue pairs the driver can use in MR 0 and those enabled in MR 1.
+/// Each ring set up enables and takes the next pair until they run out; later
+/// rings share them round-robin.
+pub const NET_GET_QUEUE_PAIRS: usize = 0x107;
+/// MR 0: VIRTIO_NET_RSS_HASH_TYPE_* bits to hash on; IPC buffer: the hash key.
+/// Received flows are spread over the enabled pairs. Needs VIRTIO_NET_F_RSS.
+pub const NET_SET_RSS: usize = 0x108;
+
+/// NET_SET_RX_MODE: receive every frame.
+pub const NET_RX_PROMISC: usize = 1 << 0;
+/// NET_SET_RX_MODE: receive every multicast frame.
+pub const NET_RX_ALLMULTI: usize = 1 << 1;
+
+/// The link is up.
+pub const NET_LINK_UP: usize = 1 << 0;
+/// The network changed under the device (e.g. after migration); the client
+/// should announce its addresses with gratuitous ARPs. Only in link events.
+pub const NET_LINK_ANNOUNCE: usize = 1 << 1;
+
+/// SETUP_RING flag (MR 2): the ring uses the offload encodings below.
+pub const NET_RING_OFFLOAD: usize = 1 << 0;
+/// SETUP_RING flag (MR 2): the ring also gets link events, CQEs with user_data
+/// NET_EVENT_LINK and NET_LINK_* bits as result. They take CQ space like any CQE.
+pub const NET_RING_EVENTS: usize = 1 << 1;
+pub const NET_EVENT_LINK: usize = usize::MAX;
+
+// On offload rings a WRITE SQE's `off` asks for offloads: `csum_start` in bits
+// 0-15, `csum_offset` in 16-31, `gso_size` in 32-47, a VIRTIO_NET_HDR_GSO_* type
+// in 48-55 and NET_TX_NEEDS_CSUM in bit 56. 0 asks for nothing.
+pub const NET_TX_NEEDS_CSUM: u64 = 1 << 56;
+
+// On offload rings a READ CQE's result is the frame length ORed with these flags
+pub const NET_CQE_LEN_MASK: i32 = 0xf_ffff;
+/// The frame's checksums are known good.
+pub const NET_CQE_CSUM_VALID: i32 = 1 << 20;
+/// The frame is several TCP segments merged; its headers describe the first.
+pub const NET_CQE_GSO_TCPV4: i32 = 1 << 21;
+pub const NET_CQE_GSO_TCPV6: i32 = 1 << 22;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VirtioNetHdr {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+}
+
+impl VirtioNetHdr {
+    /// Header for a transmitted packet from the `off` of its SQE.
+    pub fn from_tx_offload(off: u64) -> Self {
+        Self {
+            flags: if off & NET_TX_NEEDS_CSUM != 0 { VIRTIO_NET_HDR_F_NEEDS_CSUM } else { 0 },
+            gso_type: (off >> 48) as u8,
+            hdr_len: 0,
+            gso_size: (off >> 32) as u16,
+            csum_start: off as u16,
+            csum_offset: (off >> 16) as u16,
+        }
+    }
+
+    /// Features the device must have agreed to for it to act on this header.
+    pub fn required_features(&self) -> u64 {
+        let mut features = 0;
+        if self.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+            features |= VIRTIO_NET_F_CSUM;
+        }
+        if self.gso_type & VIRTIO_NET_HDR_GSO_ECN != 0 {
+            features |= VIRTIO_NET_F_HOST_ECN;
+        }
+        features
+            | match self.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+                VIRTIO_NET_HDR_GSO_NONE => 0,
+                VIRTIO_NET_HDR_GSO_TCPV4 => VIRTIO_NET_F_HOST_TSO4,
+                VIRTIO_NET_HDR_GSO_TCPV6 => VIRTIO_NET_F_HOST_TSO6,
+                _ => VIRTIO_NET_F_HOST_UFO,
+            }
+    }
+}
+
+/// struct virtio_net_config; fields behind features that weren't negotiated hold
+/// what the device is assumed to have without them.
+#[derive(Debug, Clone, Copy)]
+pub struct VirtioNetConfig {
+    pub mac: [u8; 6],
+    /// VIRTIO_NET_S_* bits.
+    pub status: u16,
+    pub max_virtqueue_pairs: u16,
+    pub mtu: u16,
+    pub rss_max_key_size: u8,
+    pub rss_max_indirection_table_length: u16,
+    /// VIRTIO_NET_RSS_HASH_TYPE_* bits.
+    pub supported_hash_types: u32,
+}
+
+impl VirtioNetConfig {
+    /// Reads the config fields backed by `features`, retrying across config changes.
+    pub fn read(transport: &dyn Transport, features: u64) -> Self {
+        read_config_consistent(transport, |base| unsafe {
+            let u16_at = |off: usize| (base.add(off) as *const u16).read_volatile();
+            let u32_at = |off: usize| (base.add(off) as *const u32).read_volatile();
+            let has = |f: u64| features & f != 0;
+
+            let mut cfg = Self {
+                mac: [0; 6],
+                // Without a status field the link counts as up
+                status: VIRTIO_NET_S_LINK_UP,
+                max_virtqueue_pairs: 1,
+                mtu: VIRTIO_NET_DEFAULT_MTU,
+                rss_max_key_size: 0,
+                rss_max_indirection_table_length: 0,
+                supported_hash_types: 0,
+            };
+            for (i, octet) in cfg.mac.iter_mut().enumerate() {
+                *octet = base.add(i).read_volatile();
+            }
+            if has(VIRTIO_NET_F_STATUS) {
+                cfg.status = u16_at(6);
+            }
+            if has(VIRTIO_NET_F_MQ) || has(VIRTIO_NET_F_RSS) {
+                cfg.max_virtqueue_pairs = u16_at(8);
+            }
+            if has(VIRTIO_NET_F_MTU) {
+                cfg.mtu = u16_at(10);
+            }
+            if has(VIRTIO_NET_F_RSS) {
+                cfg.rss_max_key_size = base.add(17).read_volatile();
+                cfg.rss_max_indirection_table_length = u16_at(18);
+                cfg.supported_hash_types = u32_at(20);
+            }
+            cfg
+        })
+    }
+}
+
+/// The header with the `num_buffers` field it carries under VIRTIO_F_VERSION_1 or
+/// VIRTIO_NET_F_MRG_RXBUF: a received frame fills that many buffers, this header
+/// opening the first.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VirtioNetHdrMrgRxbuf {
+    pub hdr: VirtioNetHdr,
+    pub num_buffers: u16,
+}
+
+/// Length of the header in front of each packet with `features` negotiated.
+pub fn net_hdr_len(features: u64) -> usize {
+    if features & (VIRTIO_F_VERSION_1 | VIRTIO_NET_F_MRG_RXBUF) != 0 {
+        core::mem::size_of::<VirtioNetHdrMrgRxbuf>()
+    } else {
+        core::mem::size_of::<VirtioNetHdr>()
+    }
+}
+
+/// Receive buffers the frame opened by `hdr` fills; always one without mergeable buffers.
+pub fn rx_num_buffers(hdr: &VirtioNetHdrMrgRxbuf, features: u64) -> usize {
+    if features & VIRTIO_NET_F_MRG_RXBUF == 0 {
+        1
+    } else {
+        hdr.num_buffers.max(1) as usize
+    }
+}
+
+/// Chain for one packet: header then payload, device-writable on the receive queue.
+pub fn net_chain(
+    hdr_paddr: usize,
+    hdr_len: usize,
+    data_paddr: usize,
+    len: u32,
+    is_rx: bool,
+) -> [Segment; 2] {
+    [
+        Segment { addr: hdr_paddr, len: hdr_len as u32, write: is_rx },
+        Segment { addr: data_paddr, len, write: is_rx },
+    ]
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VirtioNetCtrlHdr {
+    pub class: u8,
+    pub command: u8,
+}
+
+/// Chain for a control command: header and data for the device, then its ack byte.
+pub fn ctrl_chain(
+    hdr_paddr: usize,
+    data_paddr: usize,
+    data_len: u32,
+    ack_paddr: usize,
+) -> [Segment; 3] {
+    [
+        Segment::readable(hdr_paddr, core::mem::size_of::<VirtioNetCtrlHdr>() as u32),
+        Segment::readable(data_paddr, data_len),
+        Segment::writable(ack_paddr, 1),
+    ]
+}
+
+/// Writes the VIRTIO_NET_CTRL_MAC_TABLE_SET data to `out`: `macs` holds 6-byte
+/// addresses, the first `unicast` of them unicast. Returns the data length, or
+/// None if the addresses are malformed or `out` is too small.
+pub fn mac_table(out: &mut [u8], macs: &[u8], unicast: usize) -> Option<usize> {
+    if !macs.len().is_multiple_of(6) || unicast > macs.len() / 6 {
+        return None;
+    }
+    let len = 8 + macs.len();
+    if len > out.len() {
+        return None;
+    }
+    let (uni, multi) = macs.split_at(unicast * 6);
+    let mut pos = 0;
+    for table in [uni, multi] {
+        out[pos..pos + 4].copy_from_slice(&((table.len() / 6) as u32).to_le_bytes());
+        out[pos + 4..pos + 4 + table.len()].copy_from_slice(table);
+        pos += 4 + table.len();
+    }
+    Some(len)
+}
+
+/// Writes the VIRTIO_NET_CTRL_MQ_RSS_CONFIG data to `out`: flows hashed on
+/// `hash_types` go to the receive queue `table` names for their hash, the rest
+/// to the first. Returns the data length, or None if the table length isn't a
+/// power of two or `out` is too small.
+pub fn rss_config(
+    out: &mut [u8],
+    hash_types: u32,
+    table: &[u16],
+    max_tx_vq: u16,
+    key: &[u8],
+) -> Option<usize> {
+    if !table.len().is_power_of_two() || key.len() > u8::MAX as usize {
+        return None;
+    }
+    let len = 8 + table.len() * 2 + 3 + key.len();
+    if len > out.len() {
+        return None;
+    }
+    out[0..4].copy_from_slice(&hash_types.to_le_bytes());
+    out[4..6].copy_from_slice(&((table.len() - 1) as u16).to_le_bytes());
+    // unclassified_queue
+    out[6..8].copy_from_slice(&0u16.to_le_bytes());
+    let mut pos = 8;
+    for &queue in table {
+        out[pos..pos + 2].copy_from_slice(&queue.to_le_bytes());
+        pos += 2;
+    }
+    out[pos..pos + 2].copy_from_slice(&max_tx_vq.to_le_bytes());
+    out[pos + 2] = key.len() as u8;
+    out[pos + 3..len].copy_from_slice(key);
+    Some(len)
+}
+
+/// Fills in a checksum the device left partial (`VIRTIO_NET_HDR_F_NEEDS_CSUM`):
+/// the Internet checksum of `frame[start..]`, whose field at `start + offset`
+/// holds the pseudo-header sum. Returns false if the field is out of bounds.
+pub fn complete_checksum(frame: &mut [u8], start: usize, offset: usize) -> bool {
+    let field = start + offset;
+    if start >= frame.len() || field + 2 > frame.len() {
+        return false;
+    }
+    let mut sum: u32 = 0;
+    for word in frame[start..].chunks(2) {
+        let hi = word[0] as u32;
+        let lo = word.get(1).copied().unwrap_or(0) as u32;
+        sum += hi << 8 | lo;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    frame[field..field + 2].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+    true
+}
@@ -0,0 +1,358 @@
+//! Packed virtqueue (VirtIO 1.1, Section 2.7)
+
+use crate::indirect::IndirectPool;
+use crate::queue::{need_event, Queue, Segment};
+
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy)]
+pub struct PackedDescriptor {
+    pub addr: u64,
+    pub len: u32,
+    pub id: u16,
+    pub flags: u16,
+}
+
+pub const PACKED_DESC_F_NEXT: u16 = 1;
+pub const PACKED_DESC_F_WRITE: u16 = 2;
+pub const PACKED_DESC_F_INDIRECT: u16 = 4;
+pub const PACKED_DESC_F_AVAIL: u16 = 1 << 7;
+pub const PACKED_DESC_F_USED: u16 = 1 << 15;
+
+/// Driver / device event suppression area.
+#[repr(C, align(4))]
+#[derive(Debug, Clone, Copy)]
+pub struct EventSuppress {
+    pub off_wrap: u16,
+    pub flags: u16,
+}
+
+pub const RING_EVENT_FLAGS_ENABLE: u16 = 0;
+pub const RING_EVENT_FLAGS_DISABLE: u16 = 1;
+pub const RING_EVENT_FLAGS_DESC: u16 = 2;
+
+/// Largest ring we keep per-buffer bookkeeping for.
+pub const PACKED_QUEUE_MAX: usize = 256;
+
+pub fn packed_queue_size_in_bytes(num: u16) -> usize {
+    16 * num as usize + 2 * core::mem::size_of::<EventSuppress>()
+}
+
+pub struct PackedVirtQueue {
+    pub index: u32,
+    pub num: u16,
+    pub paddr: usize,
+    pub vaddr: *mut u8,
+
+    next_avail: u16,
+    avail_wrap: bool,
+    last_used: u16,
+    used_wrap: bool,
+    num_free: u16,
+
+    event_idx: bool,
+    /// Descriptors made available since the last `should_notify`.
+    num_added: u16,
+    indirect: Option<IndirectPool>,
+
+    // Buffer ids are handed out independently of ring slots
+    free_id: u16,
+    id_next: [u16; PACKED_QUEUE_MAX],
+    id_count: [u16; PACKED_QUEUE_MAX],
+}
+
+impl PackedVirtQueue {
+    pub unsafe fn new(index: u32, num: u16, paddr: usize, vaddr: *mut u8) -> Self {
+        assert!(num as usize <= PACKED_QUEUE_MAX, "packed queue too large");
+        let mut v = Self {
+            index,
+            num,
+            paddr,
+            vaddr,
+            next_avail: 0,
+            avail_wrap: true,
+            last_used: 0,
+            used_wrap: true,
+            num_free: num,
+            event_idx: false,
+            num_added: 0,
+            indirect: None,
+            free_id: 0,
+            id_next: [0; PACKED_QUEUE_MAX],
+            id_count: [0; PACKED_QUEUE_MAX],
+        };
+
+        for i in 0..num {
+            v.id_next[i as usize] = i + 1;
+        }
+        core::ptr::write_bytes(v.vaddr, 0, packed_queue_size_in_bytes(num));
+
+        v
+    }
+
+    pub fn desc_ring(&self) -> *mut PackedDescriptor {
+        self.vaddr as *mut PackedDescriptor
+    }
+
+    pub fn driver_event(&self) -> *mut EventSuppress {
+        unsafe { self.vaddr.add(16 * self.num as usize) as *mut EventSuppress }
+    }
+
+    pub fn device_event(&self) -> *mut EventSuppress {
+        unsafe { self.driver_event().add(1) }
+    }
+
+    fn wrap_flags(&self) -> u16 {
+        if self.avail_wrap {
+            PACKED_DESC_F_AVAIL
+        } else {
+            PACKED_DESC_F_USED
+        }
+    }
+
+    fn read_flags(&self, idx: u16) -> u16 {
+        unsafe {
+            let desc = self.desc_ring().add(idx as usize);
+            core::ptr::addr_of!((*desc).flags).read_volatile()
+        }
+    }
+
+    fn is_used(&self, idx: u16, wrap: bool) -> bool {
+        let flags = self.read_flags(idx);
+        let avail = flags & PACKED_DESC_F_AVAIL != 0;
+        let used = flags & PACKED_DESC_F_USED != 0;
+        avail == used && used == wrap
+    }
+
+    /// Publishes `n` ring descriptors as one buffer; `desc_at` yields (addr, len, flags)
+    /// for each, without the NEXT and wrap bits.
+    fn push(&mut self, n: usize, desc_at: impl Fn(usize) -> (u64, u32, u16)) -> Option<u16> {
+        if n == 0 || n > self.num_free as usize || self.free_id >= self.num {
+            return None;
+        }
+
+        let id = self.free_id;
+        self.free_id = self.id_next[id as usize];
+        self.id_count[id as usize] = n as u16;
+
+        let head = self.next_avail;
+        let mut head_flags = 0;
+        for i in 0..n {
+            let (addr, len, mut flags) = desc_at(i);
+            flags |= self.wrap_flags();
+            if i + 1 != n {
+                flags |= PACKED_DESC_F_NEXT;
+            }
+
+            unsafe {
+                let desc = self.desc_ring().add(self.next_avail as usize);
+                core::ptr::addr_of_mut!((*desc).addr).write_volatile(addr);
+                core::ptr::addr_of_mut!((*desc).len).write_volatile(len);
+                core::ptr::addr_of_mut!((*desc).id).write_volatile(id);
+                // The head is published last so the device never sees a partial chain
+                if i == 0 {
+                    head_flags = flags;
+                } else {
+                    core::ptr::addr_of_mut!((*desc).flags).write_volatile(flags);
+                }
+            }
+
+            self.next_avail += 1;
+            if self.next_avail == self.num {
+                self.next_avail = 0;
+                self.avail_wrap = !self.avail_wrap;
+            }
+        }
+        self.num_free -= n as u16;
+        self.num_added = self.num_added.wrapping_add(n as u16);
+
+        glenda::arch::sync::fence();
+        unsafe {
+            let desc = self.desc_ring().add(head as usize);
+            core::ptr::addr_of_mut!((*desc).flags).write_volatile(head_flags);
+        }
+        Some(id)
+    }
+
+    fn write_driver_event(&self, off_wrap: Option<u16>, flags: u16) {
+        let event = self.driver_event();
+        unsafe {
+            if let Some(off_wrap) = off_wrap {
+                core::ptr::addr_of_mut!((*event).off_wrap).write_volatile(off_wrap);
+                // off_wrap must land before the device can observe RING_EVENT_FLAGS_DESC
+                glenda::arch::sync::fence();
+            }
+            core::ptr::addr_of_mut!((*event).flags).write_volatile(flags);
+        }
+    }
+}
+
+impl Queue for PackedVirtQueue {
+    fn index(&self) -> u32 {
+        self.index
+    }
+
+    fn size(&self) -> u16 {
+        self.num
+    }
+
+    fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    fn desc_paddr(&self) -> usize {
+        self.paddr
+    }
+
+    fn driver_paddr(&self) -> usize {
+        self.paddr + 16 * self.num as usize
+    }
+
+    fn device_paddr(&self) -> usize {
+        self.driver_paddr() + core::mem::size_of::<EventSuppress>()
+    }
+
+    fn add_chain(&mut self, segs: &[Segment]) -> Option<u16> {
+        self.push(segs.len(), |i| {
+            let seg = &segs[i];
+            (seg.addr as u64, seg.len, if seg.write { PACKED_DESC_F_WRITE } else { 0 })
+        })
+    }
+
+    fn submit_sg(&mut self, segs: &[Segment]) -> Option<u16> {
+        let pool = match self.indirect {
+            Some(pool) if pool.fits(segs.len()) => pool,
+            _ => return self.add_chain(segs),
+        };
+        if self.num_free == 0 || self.free_id >= self.num {
+            return None;
+        }
+
+        // The id push() hands out next is free_id, so the table can be filled beforehand
+        let table = pool.table_vaddr(self.free_id) as *mut PackedDescriptor;
+        for (i, seg) in segs.iter().enumerate() {
+            let flags = if seg.write { PACKED_DESC_F_WRITE } else { 0 };
+            unsafe {
+                table.add(i).write_volatile(PackedDescriptor {
+                    addr: seg.addr as u64,
+                    len: seg.len,
+                    id: 0,
+                    flags,
+                })
+            };
+        }
+
+        let table_paddr = pool.table_paddr(self.free_id) as u64;
+        let len = (segs.len() * core::mem::size_of::<PackedDescriptor>()) as u32;
+        self.push(1, |_| (table_paddr, len, PACKED_DESC_F_INDIRECT))
+    }
+
+    fn can_pop(&self) -> bool {
+        self.is_used(self.last_used, self.used_wrap)
+    }
+
+    fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.can_pop() {
+            return None;
+        }
+        // Order the flags read before reading id / len
+        glenda::arch::sync::fence();
+
+        let (id, len) = unsafe {
+            let desc = self.desc_ring().add(self.last_used as usize);
+            (
+                core::ptr::addr_of!((*desc).id).read_volatile(),
+                core::ptr::addr_of!((*desc).len).read_volatile(),
+            )
+        };
+        if id >= self.num {
+            error!("Packed queue {}: device returned bad buffer id {}", self.index, id);
+            return None;
+        }
+
+        let count = self.id_count[id as usize];
+        self.last_used += count;
+        if self.last_used >= self.num {
+            self.last_used -= self.num;
+            self.used_wrap = !self.used_wrap;
+        }
+        self.num_free += count;
+
+        self.id_next[id as usize] = self.free_id;
+        self.free_id = id;
+        Some((id, len))
+    }
+
+    fn set_indirect_pool(&mut self, pool: IndirectPool) {
+        self.indirect = Some(pool);
+    }
+
+    fn set_event_idx(&mut self, enabled: bool) {
+        self.event_idx = enabled;
+    }
+
+    fn should_notify(&mut self) -> bool {
+        // Descriptor flags must be visible before we sample the device's suppression state
+        glenda::arch::sync::fence();
+
+        let new = self.next_avail;
+        let old = new.wrapping_sub(self.num_added);
+        self.num_added = 0;
+
+        let event = self.device_event();
+        let (off_wrap, flags) = unsafe {
+            (
+                core::ptr::addr_of!((*event).off_wrap).read_volatile(),
+                core::ptr::addr_of!((*event).flags).read_volatile(),
+            )
+        };
+
+        match flags {
+            RING_EVENT_FLAGS_DISABLE => false,
+            RING_EVENT_FLAGS_DESC if self.event_idx => {
+                let mut event_idx = off_wrap & 0x7fff;
+                let wrap = off_wrap & 0x8000 != 0;
+                if wrap != self.avail_wrap {
+                    event_idx = event_idx.wrapping_sub(self.num);
+                }
+                need_event(event_idx, new, old)
+            }
+            _ => true,
+        }
+    }
+
+    fn disable_cb(&mut self) {
+        self.write_driver_event(None, RING_EVENT_FLAGS_DISABLE);
+    }
+
+    fn enable_cb(&mut self) -> bool {
+        if self.event_idx {
+            let off_wrap = self.last_used | (self.used_wrap as u16) << 15;
+            self.write_driver_event(Some(off_wrap), RING_EVENT_FLAGS_DESC);
+        } else {
+            self.write_driver_event(None, RING_EVENT_FLAGS_ENABLE);
+        }
+        glenda::arch::sync::fence();
+        !self.can_pop()
+    }
+
+    fn enable_cb_delayed(&mut self) -> bool {
+        if !self.event_idx {
+            self.write_driver_event(None, RING_EVENT_FLAGS_ENABLE);
+            glenda::arch::sync::fence();
+            return !self.can_pop();
+        }
+
+        let bufs = (self.num - self.num_free) * 3 / 4;
+        let mut used_idx = self.last_used + bufs;
+        let mut wrap = self.used_wrap;
+        if used_idx >= self.num {
+            used_idx -= self.num;
+            wrap = !wrap;
+        }
+
+        self.write_driver_event(Some(used_idx | (wrap as u16) << 15), RING_EVENT_FLAGS_DESC);
+        glenda::arch::sync::fence();
+
+        !self.is_used(used_idx, wrap)
+    }
+}
@@ -0,0 +1,331 @@
+//! Virtio over PCI, modern interface (VirtIO 1.1, Section 4.1)
+
+use super::consts::*;
+use super::transport::Transport;
+use super::{Result, VirtIOError};
+use crate::queue::Queue;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::ptr::NonNull;
+use glenda::arch::mem::PGSIZE;
+use glenda::cap::{Page, CSPACE_CAP, RECV_SLOT};
+use glenda::client::{DeviceClient, ResourceClient};
+use glenda::error::Error;
+use glenda::interface::{DeviceService, VSpaceService};
+use glenda::ipc::Badge;
+use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+
+pub const PCI_VENDOR_VIRTIO: u16 = 0x1af4;
+
+const PCI_STATUS: usize = 0x06;
+const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+const PCI_BAR0: usize = 0x10;
+const PCI_CAP_PTR: usize = 0x34;
+const PCI_CAP_ID_VNDR: u8 = 0x09;
+
+// Vendor capability layout
+const CAP_CFG_TYPE: usize = 3;
+const CAP_BAR: usize = 4;
+const CAP_OFFSET: usize = 8;
+const CAP_LENGTH: usize = 12;
+const CAP_NOTIFY_MULTIPLIER: usize = 16;
+
+pub const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+pub const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+pub const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+pub const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+// Common configuration structure
+const COMMON_DFSELECT: usize = 0x00;
+const COMMON_DF: usize = 0x04;
+const COMMON_GFSELECT: usize = 0x08;
+const COMMON_GF: usize = 0x0c;
+const COMMON_MSIX_CONFIG: usize = 0x10;
+const COMMON_STATUS: usize = 0x14;
+const COMMON_CFG_GENERATION: usize = 0x15;
+const COMMON_Q_SELECT: usize = 0x16;
+const COMMON_Q_SIZE: usize = 0x18;
+const COMMON_Q_MSIX_VECTOR: usize = 0x1a;
+const COMMON_Q_ENABLE: usize = 0x1c;
+const COMMON_Q_NOTIFY_OFF: usize = 0x1e;
+const COMMON_Q_DESC: usize = 0x20;
+const COMMON_Q_DRIVER: usize = 0x28;
+const COMMON_Q_DEVICE: usize = 0x30;
+
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+/// Maps a PCI device id to a virtio device id; transitional ids predate the 0x1040 range.
+pub fn virtio_device_id(pci_device_id: u16) -> Option<u32> {
+    match pci_device_id {
+        0x1040..=0x107f => Some((pci_device_id - 0x1040) as u32),
+        0x1000 => Some(DEV_ID_NET),
+        0x1001 => Some(DEV_ID_BLOCK),
+        0x1003 => Some(DEV_ID_CONSOLE),
+        0x1005 => Some(DEV_ID_ENTROPY),
+        _ => None,
+    }
+}
+
+/// A BAR the driver has already mapped, identified by its physical base.
+#[derive(Debug, Clone, Copy)]
+pub struct BarMapping {
+    pub paddr: usize,
+    pub vaddr: usize,
+    pub size: usize,
+}
+
+/// Maps the function's BARs, which the device manager hands out as regions 1
+/// onwards after its config space, one after another from `va`.
+pub fn map_bars(
+    dev: &mut DeviceClient,
+    res: &mut ResourceClient,
+    cspace_mgr: &mut CSpaceManager,
+    vspace_mgr: &mut VSpaceManager,
+    mut va: usize,
+) -> core::result::Result<Vec<BarMapping>, Error> {
+    let mut bars = Vec::new();
+    for idx in 1.. {
+        // Received into the scratch slot so asking past the last BAR costs no slot
+        let (_, paddr, size) = match dev.get_mmio(Badge::null(), idx, RECV_SLOT) {
+            Ok(region) => region,
+            Err(Error::NotFound) => break,
+            Err(e) => return Err(e),
+        };
+        let slot = cspace_mgr.alloc(res)?;
+        CSPACE_CAP.transfer_self(RECV_SLOT, slot)?;
+        let pages = size.div_ceil(PGSIZE);
+        vspace_mgr.map_page(
+            Page::from(slot),
+            va,
+            glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
+            pages,
+            res,
+            cspace_mgr,
+        )?;
+        bars.push(BarMapping { paddr, vaddr: va, size });
+        va += pages * PGSIZE;
+    }
+    Ok(bars)
+}
+
+pub struct VirtIOPciTransport {
+    device_id: u32,
+    common: NonNull<u8>,
+    notify: NonNull<u8>,
+    notify_off_multiplier: u32,
+    /// Doorbell of each queue set up so far, indexed by queue.
+    notify_addrs: RefCell<Vec<Option<NonNull<u16>>>>,
+    isr: NonNull<u8>,
+    device: NonNull<u8>,
+}
+
+unsafe fn cfg_read_u32(cfg: NonNull<u8>, reg: usize) -> u32 {
+    (cfg.as_ptr().add(reg & !0x3) as *const u32).read_volatile()
+}
+
+unsafe fn cfg_read_u16(cfg: NonNull<u8>, reg: usize) -> u16 {
+    (cfg_read_u32(cfg, reg) >> ((reg & 0x2) * 8)) as u16
+}
+
+unsafe fn cfg_read_u8(cfg: NonNull<u8>, reg: usize) -> u8 {
+    (cfg_read_u32(cfg, reg) >> ((reg & 0x3) * 8)) as u8
+}
+
+/// Physical base of memory BAR `bar`, 0 if it is unassigned or an I/O BAR.
+unsafe fn bar_paddr(cfg: NonNull<u8>, bar: u8) -> usize {
+    if bar > 5 {
+        return 0;
+    }
+    let off = PCI_BAR0 + bar as usize * 4;
+    let lo = cfg_read_u32(cfg, off);
+    if lo & 0x1 != 0 {
+        return 0;
+    }
+    let mut base = (lo & 0xffff_fff0) as u64;
+    if (lo >> 1) & 0x3 == 0x2 && bar < 5 {
+        base |= (cfg_read_u32(cfg, off + 4) as u64) << 32;
+    }
+    base as usize
+}
+
+impl VirtIOPciTransport {
+    /// Walks the vendor capabilities in the function's configuration space `cfg`
+    /// and resolves each structure inside the already mapped `bars`.
+    pub unsafe fn new(cfg: NonNull<u8>, bars: &[BarMapping]) -> Result<Self> {
+        let vendor = cfg_read_u16(cfg, 0x00);
+        let pci_device_id = cfg_read_u16(cfg, 0x02);
+        if vendor != PCI_VENDOR_VIRTIO {
+            return Err(VirtIOError::InvalidHeader);
+        }
+        let device_id = virtio_device_id(pci_device_id).ok_or(VirtIOError::DeviceNotFound)?;
+        if cfg_read_u16(cfg, PCI_STATUS) & PCI_STATUS_CAP_LIST == 0 {
+            error!("Virtio PCI device {:04x} has no capability list", pci_device_id);
+            return Err(VirtIOError::InvalidHeader);
+        }
+
+        let resolve = |cap: usize| -> Option<NonNull<u8>> {
+            let bar = cfg_read_u8(cfg, cap + CAP_BAR);
+            let offset = cfg_read_u32(cfg, cap + CAP_OFFSET) as usize;
+            let length = cfg_read_u32(cfg, cap + CAP_LENGTH) as usize;
+            let paddr = bar_paddr(cfg, bar);
+            let map = bars.iter().find(|m| {
+                paddr != 0
+                    && m.paddr == paddr
+                    && offset.checked_add(length).is_some_and(|end| end <= m.size)
+            })?;
+            NonNull::new((map.vaddr + offset) as *mut u8)
+        };
+
+        let mut common = None;
+        let mut notify = None;
+        let mut notify_off_multiplier = 0;
+        let mut isr = None;
+        let mut device = None;
+
+        let mut cap = (cfg_read_u8(cfg, PCI_CAP_PTR) & !0x3) as usize;
+        // Bounded walk so a looping list can't hang the driver
+        for _ in 0..48 {
+            if cap == 0 {
+                break;
+            }
+            if cfg_read_u8(cfg, cap) == PCI_CAP_ID_VNDR {
+                // Only the first capability of each type is used (Section 4.1.4)
+                match cfg_read_u8(cfg, cap + CAP_CFG_TYPE) {
+                    VIRTIO_PCI_CAP_COMMON_CFG if common.is_none() => common = resolve(cap),
+                    VIRTIO_PCI_CAP_NOTIFY_CFG if notify.is_none() => {
+                        notify = resolve(cap);
+                        notify_off_multiplier = cfg_read_u32(cfg, cap + CAP_NOTIFY_MULTIPLIER);
+                    }
+                    VIRTIO_PCI_CAP_ISR_CFG if isr.is_none() => isr = resolve(cap),
+                    VIRTIO_PCI_CAP_DEVICE_CFG if device.is_none() => device = resolve(cap),
+                    _ => {}
+                }
+            }
+            cap = (cfg_read_u8(cfg, cap + 1) & !0x3) as usize;
+        }
+
+        match (common, notify, isr, device) {
+            (Some(common), Some(notify), Some(isr), Some(device)) => {
+                log!("Virtio PCI device {:04x}: modern interface", pci_device_id);
+                Ok(Self {
+                    device_id,
+                    common,
+                    notify,
+                    notify_off_multiplier,
+                    notify_addrs: RefCell::new(Vec::new()),
+                    isr,
+                    device,
+                })
+            }
+            _ => {
+                error!("Virtio PCI device {:04x}: required capability not mapped", pci_device_id);
+                Err(VirtIOError::DeviceNotFound)
+            }
+        }
+    }
+
+    unsafe fn read_common<T: Copy>(&self, offset: usize) -> T {
+        (self.common.as_ptr().add(offset) as *const T).read_volatile()
+    }
+
+    unsafe fn write_common<T: Copy>(&self, offset: usize, val: T) {
+        (self.common.as_ptr().add(offset) as *mut T).write_volatile(val)
+    }
+
+    /// 64-bit fields are written as two halves; not every bus handles 8-byte accesses.
+    unsafe fn write_common_u64(&self, offset: usize, val: u64) {
+        self.write_common(offset, val as u32);
+        self.write_common(offset + 4, (val >> 32) as u32);
+    }
+}
+
+impl Transport for VirtIOPciTransport {
+    fn device_id(&self) -> u32 {
+        self.device_id
+    }
+
+    fn get_device_features(&self) -> u64 {
+        unsafe {
+            self.write_common::<u32>(COMMON_DFSELECT, 0);
+            let low = self.read_common::<u32>(COMMON_DF) as u64;
+            self.write_common::<u32>(COMMON_DFSELECT, 1);
+            let high = self.read_common::<u32>(COMMON_DF) as u64;
+            (high << 32) | low
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        unsafe {
+            self.write_common::<u32>(COMMON_GFSELECT, 0);
+            self.write_common(COMMON_GF, features as u32);
+            self.write_common::<u32>(COMMON_GFSELECT, 1);
+            self.write_common(COMMON_GF, (features >> 32) as u32);
+        }
+    }
+
+    fn get_status(&self) -> u32 {
+        unsafe { self.read_common::<u8>(COMMON_STATUS) as u32 }
+    }
+
+    fn set_status(&self, status: u32) {
+        unsafe {
+            self.write_common(COMMON_STATUS, status as u8);
+            if status == 0 {
+                // Interrupts are delivered over INTx; keep MSI-X out of the picture
+                self.write_common(COMMON_MSIX_CONFIG, VIRTIO_MSI_NO_VECTOR);
+            }
+        }
+    }
+
+    fn max_queue_size(&self, idx: u32) -> u16 {
+        unsafe {
+            self.write_common(COMMON_Q_SELECT, idx as u16);
+            self.read_common(COMMON_Q_SIZE)
+        }
+    }
+
+    unsafe fn setup_queue(&self, vq: &dyn Queue) {
+        self.write_common(COMMON_Q_SELECT, vq.index() as u16);
+        self.write_common(COMMON_Q_SIZE, vq.size());
+        self.write_common(COMMON_Q_MSIX_VECTOR, VIRTIO_MSI_NO_VECTOR);
+        self.write_common_u64(COMMON_Q_DESC, vq.desc_paddr() as u64);
+        self.write_common_u64(COMMON_Q_DRIVER, vq.driver_paddr() as u64);
+        self.write_common_u64(COMMON_Q_DEVICE, vq.device_paddr() as u64);
+        glenda::arch::sync::fence_io();
+        self.write_common::<u16>(COMMON_Q_ENABLE, 1);
+
+        // The doorbell doesn't move once the queue is enabled, so look it up once
+        let off = self.read_common::<u16>(COMMON_Q_NOTIFY_OFF) as usize;
+        let addr = self.notify.as_ptr().add(off * self.notify_off_multiplier as usize);
+        let idx = vq.index() as usize;
+        let mut addrs = self.notify_addrs.borrow_mut();
+        if addrs.len() <= idx {
+            addrs.resize(idx + 1, None);
+        }
+        addrs[idx] = NonNull::new(addr as *mut u16);
+    }
+
+    fn notify(&self, queue_idx: u32) {
+        let Some(addr) = self.notify_addrs.borrow().get(queue_idx as usize).copied().flatten()
+        else {
+            error!("Notify on queue {} before it was set up", queue_idx);
+            return;
+        };
+        // Memory barrier between DRAM (Avail Ring update) and MMIO (Notification)
+        glenda::arch::sync::fence();
+        unsafe { addr.as_ptr().write_volatile(queue_idx as u16) };
+    }
+
+    fn ack_interrupt(&self) -> u32 {
+        // Reading the ISR status clears it
+        unsafe { self.isr.as_ptr().read_volatile() as u32 }
+    }
+
+    fn config_generation(&self) -> u32 {
+        unsafe { self.read_common::<u8>(COMMON_CFG_GENERATION) as u32 }
+    }
+
+    unsafe fn config_ptr(&self) -> *mut u8 {
+        self.device.as_ptr()
+    }
+}
@@ -1,3 +1,8 @@
+use crate::consts::{LEGACY_QUEUE_ALIGN, VIRTIO_F_EVENT_IDX, VIRTIO_F_RING_PACKED};
+use crate::indirect::IndirectPool;
+use crate::packed::PackedVirtQueue;
+use alloc::boxed::Box;
+
 #[repr(C, align(16))]
 #[derive(Debug, Clone, Copy)]
 pub struct Descriptor {
@@ -11,6 +16,14 @@ pub const DESC_F_NEXT: u16 = 1;
 pub const DESC_F_WRITE: u16 = 2;
 pub const DESC_F_INDIRECT: u16 = 4;
 
+pub const AVAIL_F_NO_INTERRUPT: u16 = 1;
+pub const USED_F_NO_NOTIFY: u16 = 1;
+
+/// `vring_need_event` from the spec: true if `event` lies in `[old, new)`.
+pub fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
 #[repr(C, align(2))]
 pub struct Available {
     pub flags: u16,
@@ -31,6 +44,14 @@ pub struct Used {
     pub ring: [UsedElem; 0], // Flexible array
 }
 
+/// Bytes needed for a legacy ring of `num` entries, used ring page aligned.
+pub fn legacy_queue_size_in_bytes(num: u16) -> usize {
+    let num = num as usize;
+    let avail_end = 16 * num + 6 + 2 * num;
+    let used_offset = (avail_end + LEGACY_QUEUE_ALIGN - 1) & !(LEGACY_QUEUE_ALIGN - 1);
+    used_offset + 6 + 8 * num
+}
+
 // Helper to calculate queue size
 pub fn queue_size_in_bytes(num: u16) -> usize {
     let num = num as usize;
@@ -43,6 +64,82 @@ pub fn queue_size_in_bytes(num: u16) -> usize {
     desc_size + avail_size + used_size
 }
 
+/// One element of a descriptor chain handed to [`Queue::add_chain`].
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub addr: usize,
+    pub len: u32,
+    /// Device-writable buffer (DESC_F_WRITE).
+    pub write: bool,
+}
+
+impl Segment {
+    pub const fn readable(addr: usize, len: u32) -> Self {
+        Self { addr, len, write: false }
+    }
+
+    pub const fn writable(addr: usize, len: u32) -> Self {
+        Self { addr, len, write: true }
+    }
+}
+
+/// Ring-layout independent view of a virtqueue.
+///
+/// Drivers submit whole chains and get back an opaque token, which is
+/// returned by `pop_used` once the device is done with the chain. The
+/// descriptors of a popped chain are already recycled.
+pub trait Queue {
+    fn index(&self) -> u32;
+    fn size(&self) -> u16;
+    fn num_free(&self) -> u16;
+
+    /// Physical addresses programmed into QueueDesc / QueueDriver / QueueDevice.
+    fn desc_paddr(&self) -> usize;
+    fn driver_paddr(&self) -> usize;
+    fn device_paddr(&self) -> usize;
+
+    /// Makes `segs` available to the device as a single chain.
+    /// Returns `None` without touching the ring if there aren't enough free descriptors.
+    fn add_chain(&mut self, segs: &[Segment]) -> Option<u16>;
+    /// Like `add_chain`, but places multi-segment chains in an indirect table when a
+    /// pool has been attached, so the whole chain costs a single ring descriptor.
+    fn submit_sg(&mut self, segs: &[Segment]) -> Option<u16>;
+    fn can_pop(&self) -> bool;
+    fn pop_used(&mut self) -> Option<(u16, u32)>;
+
+    /// Attaches the indirect table pool; only valid if VIRTIO_F_INDIRECT_DESC was negotiated.
+    fn set_indirect_pool(&mut self, pool: IndirectPool);
+    /// Switches notification suppression to the VIRTIO_F_EVENT_IDX scheme.
+    fn set_event_idx(&mut self, enabled: bool);
+    /// Whether the device wants a doorbell for the chains added since the last call.
+    fn should_notify(&mut self) -> bool;
+    /// Asks the device not to interrupt for used buffers on this queue.
+    fn disable_cb(&mut self);
+    /// Re-arms used-buffer interrupts for the next chain the device completes.
+    /// Returns false if chains are already used, in which case the caller must drain again.
+    fn enable_cb(&mut self) -> bool;
+    /// Re-arms used-buffer interrupts, deferred until ~3/4 of the outstanding chains complete.
+    /// Returns false if that many are already used, in which case the caller must drain again.
+    fn enable_cb_delayed(&mut self) -> bool;
+}
+
+/// Creates a packed ring if `VIRTIO_F_RING_PACKED` was negotiated, a split ring otherwise.
+pub unsafe fn new_queue(
+    features: u64,
+    index: u32,
+    num: u16,
+    paddr: usize,
+    vaddr: *mut u8,
+) -> Box<dyn Queue> {
+    let mut queue: Box<dyn Queue> = if features & VIRTIO_F_RING_PACKED != 0 {
+        Box::new(PackedVirtQueue::new(index, num, paddr, vaddr))
+    } else {
+        Box::new(VirtQueue::new(index, num, paddr, vaddr))
+    };
+    queue.set_event_idx(features & VIRTIO_F_EVENT_IDX != 0);
+    queue
+}
+
 pub struct VirtQueue {
     pub index: u32,
     pub num: u16,
@@ -52,11 +149,45 @@ pub struct VirtQueue {
     pub last_used_idx: u16,
     pub free_head: u16,
     pub num_free: u16,
+
+    pub event_idx: bool,
+    /// Chains made available since the last `should_notify`.
+    num_added: u16,
+    indirect: Option<IndirectPool>,
+    /// Alignment of the used ring: 4 for modern devices, a page for legacy ones.
+    used_align: usize,
 }
 
 impl VirtQueue {
     pub unsafe fn new(index: u32, num: u16, paddr: usize, vaddr: *mut u8) -> Self {
-        let v = Self { index, num, paddr, vaddr, last_used_idx: 0, free_head: 0, num_free: num };
+        Self::with_used_align(index, num, paddr, vaddr, 4)
+    }
+
+    /// Single-area layout required by legacy (version 1) virtio-mmio devices.
+    pub unsafe fn new_legacy(index: u32, num: u16, paddr: usize, vaddr: *mut u8) -> Self {
+        Self::with_used_align(index, num, paddr, vaddr, LEGACY_QUEUE_ALIGN)
+    }
+
+    unsafe fn with_used_align(
+        index: u32,
+        num: u16,
+        paddr: usize,
+        vaddr: *mut u8,
+        used_align: usize,
+    ) -> Self {
+        let v = Self {
+            index,
+            num,
+            paddr,
+            vaddr,
+            last_used_idx: 0,
+            free_head: 0,
+            num_free: num,
+            event_idx: false,
+            num_added: 0,
+            indirect: None,
+            used_align,
+        };
 
         // Initialize descriptor chain
         let descs = v.desc_table();
@@ -74,19 +205,31 @@ impl VirtQueue {
         unsafe { core::slice::from_raw_parts_mut(self.vaddr as *mut Descriptor, self.num as usize) }
     }
 
+    fn avail_offset(&self) -> usize {
+        16 * self.num as usize
+    }
+
+    fn used_offset(&self) -> usize {
+        let avail_end = 16 * self.num as usize + 6 + 2 * self.num as usize;
+        (avail_end + self.used_align - 1) & !(self.used_align - 1)
+    }
+
+    /// `used_event`, trailing the available ring.
+    fn used_event(&self) -> *mut u16 {
+        unsafe { self.vaddr.add(self.avail_offset() + 4 + 2 * self.num as usize) as *mut u16 }
+    }
+
+    /// `avail_event`, trailing the used ring.
+    fn avail_event(&self) -> *mut u16 {
+        unsafe { self.vaddr.add(self.used_offset() + 4 + 8 * self.num as usize) as *mut u16 }
+    }
+
     pub fn avail_ring(&self) -> &mut Available {
-        unsafe {
-            let offset = 16 * self.num as usize;
-            &mut *(self.vaddr.add(offset) as *mut Available)
-        }
+        unsafe { &mut *(self.vaddr.add(self.avail_offset()) as *mut Available) }
     }
 
     pub fn used_ring(&self) -> &mut Used {
-        unsafe {
-            // Align to 4 bytes for Used ring features
-            let offset = (16 * self.num as usize + 6 + 2 * self.num as usize + 3) & !3;
-            &mut *(self.vaddr.add(offset) as *mut Used)
-        }
+        unsafe { &mut *(self.vaddr.add(self.used_offset()) as *mut Used) }
     }
 
     pub fn alloc_desc(&mut self) -> Option<u16> {
@@ -135,6 +278,7 @@ impl VirtQueue {
 
             idx_ptr.write_volatile(idx.wrapping_add(1));
         }
+        self.num_added = self.num_added.wrapping_add(1);
     }
 
     pub fn can_pop(&self) -> bool {
@@ -162,3 +306,166 @@ impl VirtQueue {
         Some((elem.id, elem.len))
     }
 }
+
+impl Queue for VirtQueue {
+    fn index(&self) -> u32 {
+        self.index
+    }
+
+    fn size(&self) -> u16 {
+        self.num
+    }
+
+    fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    fn desc_paddr(&self) -> usize {
+        self.paddr
+    }
+
+    fn driver_paddr(&self) -> usize {
+        self.paddr + self.avail_offset()
+    }
+
+    fn device_paddr(&self) -> usize {
+        self.paddr + self.used_offset()
+    }
+
+    fn add_chain(&mut self, segs: &[Segment]) -> Option<u16> {
+        if segs.is_empty() || segs.len() > self.num_free as usize {
+            return None;
+        }
+
+        let head = self.free_head;
+        for (i, seg) in segs.iter().enumerate() {
+            let id = self.alloc_desc()?;
+            let last = i + 1 == segs.len();
+            let mut flags = if seg.write { DESC_F_WRITE } else { 0 };
+            if !last {
+                flags |= DESC_F_NEXT;
+            }
+            // alloc_desc already advanced free_head to the descriptor we take next
+            let next = if last { 0 } else { self.free_head };
+            self.write_desc(id, Descriptor { addr: seg.addr, len: seg.len, flags, next });
+        }
+
+        self.submit(head);
+        Some(head)
+    }
+
+    fn submit_sg(&mut self, segs: &[Segment]) -> Option<u16> {
+        let pool = match self.indirect {
+            Some(pool) if pool.fits(segs.len()) => pool,
+            _ => return self.add_chain(segs),
+        };
+
+        let id = self.alloc_desc()?;
+        let table = pool.table_vaddr(id) as *mut Descriptor;
+        for (i, seg) in segs.iter().enumerate() {
+            let last = i + 1 == segs.len();
+            let mut flags = if seg.write { DESC_F_WRITE } else { 0 };
+            if !last {
+                flags |= DESC_F_NEXT;
+            }
+            let next = if last { 0 } else { i as u16 + 1 };
+            unsafe {
+                table.add(i).write_volatile(Descriptor {
+                    addr: seg.addr,
+                    len: seg.len,
+                    flags,
+                    next,
+                })
+            };
+        }
+
+        let len = (segs.len() * core::mem::size_of::<Descriptor>()) as u32;
+        self.write_desc(
+            id,
+            Descriptor { addr: pool.table_paddr(id), len, flags: DESC_F_INDIRECT, next: 0 },
+        );
+        self.submit(id);
+        Some(id)
+    }
+
+    fn can_pop(&self) -> bool {
+        VirtQueue::can_pop(self)
+    }
+
+    fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let (id, len) = self.pop()?;
+        let head = id as u16;
+
+        let mut curr = head;
+        loop {
+            let desc = self.desc_table()[curr as usize];
+            self.free_desc(curr);
+            if desc.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            curr = desc.next;
+        }
+        Some((head, len))
+    }
+
+    fn set_indirect_pool(&mut self, pool: IndirectPool) {
+        self.indirect = Some(pool);
+    }
+
+    fn set_event_idx(&mut self, enabled: bool) {
+        self.event_idx = enabled;
+    }
+
+    fn should_notify(&mut self) -> bool {
+        // The avail.idx store must be visible before we sample the device's suppression state
+        glenda::arch::sync::fence();
+
+        let new = unsafe { core::ptr::addr_of!(self.avail_ring().idx).read_volatile() };
+        let old = new.wrapping_sub(self.num_added);
+        self.num_added = 0;
+
+        if self.event_idx {
+            let event = unsafe { self.avail_event().read_volatile() };
+            need_event(event, new, old)
+        } else {
+            let flags = unsafe { core::ptr::addr_of!(self.used_ring().flags).read_volatile() };
+            flags & USED_F_NO_NOTIFY == 0
+        }
+    }
+
+    fn disable_cb(&mut self) {
+        // With EVENT_IDX the device ignores the flag; used_event is simply left behind
+        if !self.event_idx {
+            unsafe {
+                core::ptr::addr_of_mut!(self.avail_ring().flags)
+                    .write_volatile(AVAIL_F_NO_INTERRUPT)
+            };
+        }
+    }
+
+    fn enable_cb(&mut self) -> bool {
+        if self.event_idx {
+            unsafe { self.used_event().write_volatile(self.last_used_idx) };
+        } else {
+            unsafe { core::ptr::addr_of_mut!(self.avail_ring().flags).write_volatile(0) };
+        }
+        glenda::arch::sync::fence();
+        !VirtQueue::can_pop(self)
+    }
+
+    fn enable_cb_delayed(&mut self) -> bool {
+        if !self.event_idx {
+            unsafe { core::ptr::addr_of_mut!(self.avail_ring().flags).write_volatile(0) };
+            glenda::arch::sync::fence();
+            return !VirtQueue::can_pop(self);
+        }
+
+        let avail_idx = unsafe { core::ptr::addr_of!(self.avail_ring().idx).read_volatile() };
+        let bufs = avail_idx.wrapping_sub(self.last_used_idx) as u32 * 3 / 4;
+        unsafe { self.used_event().write_volatile(self.last_used_idx.wrapping_add(bufs as u16)) };
+        glenda::arch::sync::fence();
+
+        let used_idx = unsafe { core::ptr::addr_of!(self.used_ring().idx).read_volatile() };
+        used_idx.wrapping_sub(self.last_used_idx) as u32 <= bufs
+    }
+}
@@ -0,0 +1,314 @@
+//! Software virtio device for host-side tests.
+//!
+//! `SimDevice` keeps its registers in memory and plays the device role on split
+//! rings, so queues, feature negotiation and request layouts can be checked
+//! under `cargo test` with scripted device behaviour. There is no IOMMU here:
+//! "physical" addresses are plain host pointers, so tests hand the driver the
+//! same value for `vaddr` and `paddr` (see [`SimDma`]).
+
+use crate::consts::*;
+use crate::queue::{
+    need_event, Descriptor, Segment, AVAIL_F_NO_INTERRUPT, DESC_F_INDIRECT, DESC_F_NEXT,
+    DESC_F_WRITE,
+};
+use crate::transport::Transport;
+use crate::Queue;
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell, UnsafeCell};
+
+/// Page-aligned, zeroed host memory standing in for a DMA allocation.
+pub struct SimDma {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl SimDma {
+    pub fn new(pages: usize) -> Self {
+        let layout = Layout::from_size_align(pages * 4096, 4096).expect("bad DMA layout");
+        let ptr = unsafe { alloc_zeroed(layout) };
+        assert!(!ptr.is_null(), "DMA allocation failed");
+        Self { ptr, layout }
+    }
+
+    pub fn vaddr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// Same as `vaddr`; the simulated device dereferences it directly.
+    pub fn paddr(&self) -> usize {
+        self.ptr as usize
+    }
+}
+
+impl Drop for SimDma {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr, self.layout) }
+    }
+}
+
+/// Device-side state of one virtqueue as programmed through the transport.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SimQueue {
+    pub num: u16,
+    pub desc: usize,
+    pub driver: usize,
+    pub device: usize,
+    pub ready: bool,
+    last_avail: u16,
+}
+
+/// A chain the device took off the available ring.
+#[derive(Debug, Clone)]
+pub struct SimChain {
+    pub head: u16,
+    pub segments: Vec<Segment>,
+}
+
+impl SimChain {
+    /// Bytes of the device-readable segments, in order.
+    pub fn readable(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for seg in self.segments.iter().filter(|s| !s.write) {
+            let data =
+                unsafe { core::slice::from_raw_parts(seg.addr as *const u8, seg.len as usize) };
+            out.extend_from_slice(data);
+        }
+        out
+    }
+
+    /// Fills the device-writable segments from `data`; returns the bytes written.
+    pub fn write(&self, data: &[u8]) -> u32 {
+        let mut done = 0;
+        for seg in self.segments.iter().filter(|s| s.write) {
+            let n = core::cmp::min(seg.len as usize, data.len() - done);
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[done..].as_ptr(), seg.addr as *mut u8, n)
+            };
+            done += n;
+        }
+        done as u32
+    }
+}
+
+/// In-memory virtio device implementing [`Transport`]; only split rings are served.
+pub struct SimDevice {
+    device_id: u32,
+    device_features: u64,
+    max_queue_size: u16,
+    driver_features: Cell<u64>,
+    status: Cell<u32>,
+    isr: Cell<u32>,
+    generation: Cell<u32>,
+    config: UnsafeCell<Vec<u8>>,
+    queues: RefCell<Vec<SimQueue>>,
+    notifications: RefCell<Vec<u32>>,
+    interrupts: Cell<u32>,
+}
+
+impl SimDevice {
+    pub fn new(device_id: u32, device_features: u64, config: &[u8]) -> Self {
+        Self {
+            device_id,
+            device_features,
+            max_queue_size: 256,
+            driver_features: Cell::new(0),
+            status: Cell::new(0),
+            isr: Cell::new(0),
+            generation: Cell::new(0),
+            config: UnsafeCell::new(config.to_vec()),
+            queues: RefCell::new(Vec::new()),
+            notifications: RefCell::new(Vec::new()),
+            interrupts: Cell::new(0),
+        }
+    }
+
+    pub fn driver_features(&self) -> u64 {
+        self.driver_features.get()
+    }
+
+    pub fn queue(&self, idx: u32) -> Option<SimQueue> {
+        self.queues.borrow().get(idx as usize).copied()
+    }
+
+    /// Queue indices the driver rang the doorbell for since the last call.
+    pub fn take_notifications(&self) -> Vec<u32> {
+        core::mem::take(&mut *self.notifications.borrow_mut())
+    }
+
+    /// Used-buffer interrupts raised so far, after suppression.
+    pub fn interrupts(&self) -> u32 {
+        self.interrupts.get()
+    }
+
+    /// Rewrites device config space as the device would, bumping the generation.
+    pub fn update_config(&self, offset: usize, bytes: &[u8]) {
+        let config = unsafe { &mut *self.config.get() };
+        config[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self.generation.set(self.generation.get().wrapping_add(1));
+        self.isr.set(self.isr.get() | 2);
+    }
+
+    fn negotiated(&self, feature: u64) -> bool {
+        self.driver_features.get() & feature != 0
+    }
+
+    /// Takes the next available chain of queue `idx`, following indirect tables.
+    pub fn pop_avail(&self, idx: u32) -> Option<SimChain> {
+        let mut queues = self.queues.borrow_mut();
+        let q = queues.get_mut(idx as usize).filter(|q| q.ready)?;
+
+        let avail_idx = unsafe { ((q.driver + 2) as *const u16).read_volatile() };
+        if avail_idx == q.last_avail {
+            return None;
+        }
+        let slot = (q.last_avail % q.num) as usize;
+        let head = unsafe { ((q.driver + 4) as *const u16).add(slot).read_volatile() };
+        q.last_avail = q.last_avail.wrapping_add(1);
+
+        if self.negotiated(VIRTIO_F_EVENT_IDX) {
+            // avail_event: ask to be notified for whatever comes next
+            let avail_event = (q.device + 4 + 8 * q.num as usize) as *mut u16;
+            unsafe { avail_event.write_volatile(q.last_avail) };
+        }
+
+        let mut segments = Vec::new();
+        let mut table = q.desc as *const Descriptor;
+        let mut limit = q.num as usize;
+        let mut curr = head as usize;
+        loop {
+            assert!(curr < limit, "descriptor index {} out of range", curr);
+            let desc = unsafe { table.add(curr).read_volatile() };
+            if desc.flags & DESC_F_INDIRECT != 0 {
+                table = desc.addr as *const Descriptor;
+                limit = desc.len as usize / core::mem::size_of::<Descriptor>();
+                curr = 0;
+                continue;
+            }
+            segments.push(Segment {
+                addr: desc.addr,
+                len: desc.len,
+                write: desc.flags & DESC_F_WRITE != 0,
+            });
+            if desc.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            curr = desc.next as usize;
+        }
+
+        Some(SimChain { head, segments })
+    }
+
+    /// Returns chain `head` to the driver with `len` bytes written, raising an
+    /// interrupt unless the driver suppressed it.
+    pub fn push_used(&self, idx: u32, head: u16, len: u32) {
+        let queues = self.queues.borrow();
+        let q = &queues[idx as usize];
+
+        let used_idx_ptr = (q.device + 2) as *mut u16;
+        let old = unsafe { used_idx_ptr.read_volatile() };
+        let slot = (old % q.num) as usize;
+        unsafe {
+            let elem = ((q.device + 4) as *mut u32).add(slot * 2);
+            elem.write_volatile(head as u32);
+            elem.add(1).write_volatile(len);
+            used_idx_ptr.write_volatile(old.wrapping_add(1));
+        }
+
+        let notify = if self.negotiated(VIRTIO_F_EVENT_IDX) {
+            let used_event = unsafe { ((q.driver + 4) as *const u16).add(q.num as usize).read() };
+            need_event(used_event, old.wrapping_add(1), old)
+        } else {
+            let flags = unsafe { (q.driver as *const u16).read_volatile() };
+            flags & AVAIL_F_NO_INTERRUPT == 0
+        };
+        if notify {
+            self.isr.set(self.isr.get() | 1);
+            self.interrupts.set(self.interrupts.get() + 1);
+        }
+    }
+
+    /// Serves every available chain on queue `idx` with `f`, which returns the
+    /// number of bytes it wrote. Returns how many chains were completed.
+    pub fn process(&self, idx: u32, mut f: impl FnMut(&SimChain) -> u32) -> usize {
+        let mut count = 0;
+        while let Some(chain) = self.pop_avail(idx) {
+            let len = f(&chain);
+            self.push_used(idx, chain.head, len);
+            count += 1;
+        }
+        count
+    }
+}
+
+impl Transport for SimDevice {
+    fn device_id(&self) -> u32 {
+        self.device_id
+    }
+
+    fn get_device_features(&self) -> u64 {
+        self.device_features
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        self.driver_features.set(features);
+    }
+
+    fn get_status(&self) -> u32 {
+        self.status.get()
+    }
+
+    fn set_status(&self, status: u32) {
+        if status == 0 {
+            self.driver_features.set(0);
+            self.isr.set(0);
+            self.queues.borrow_mut().clear();
+            self.status.set(0);
+            return;
+        }
+        let mut status = status;
+        // Refuse FEATURES_OK if the driver picked something we never offered
+        if status & STATUS_FEATURES_OK != 0
+            && self.driver_features.get() & !self.device_features != 0
+        {
+            status &= !STATUS_FEATURES_OK;
+        }
+        self.status.set(status);
+    }
+
+    fn max_queue_size(&self, _idx: u32) -> u16 {
+        self.max_queue_size
+    }
+
+    unsafe fn setup_queue(&self, vq: &dyn Queue) {
+        let idx = vq.index() as usize;
+        let mut queues = self.queues.borrow_mut();
+        if queues.len() <= idx {
+            queues.resize(idx + 1, SimQueue::default());
+        }
+        queues[idx] = SimQueue {
+            num: vq.size(),
+            desc: vq.desc_paddr(),
+            driver: vq.driver_paddr(),
+            device: vq.device_paddr(),
+            ready: true,
+            last_avail: 0,
+        };
+    }
+
+    fn notify(&self, queue_idx: u32) {
+        self.notifications.borrow_mut().push(queue_idx);
+    }
+
+    fn ack_interrupt(&self) -> u32 {
+        self.isr.replace(0)
+    }
+
+    fn config_generation(&self) -> u32 {
+        self.generation.get()
+    }
+
+    unsafe fn config_ptr(&self) -> *mut u8 {
+        (*self.config.get()).as_mut_ptr()
+    }
+}
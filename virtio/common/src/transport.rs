@@ -1,10 +1,131 @@
 use super::consts::*;
 use super::{Result, VirtIOError};
-use crate::queue::VirtQueue;
+use crate::pci::{map_bars, BarMapping, VirtIOPciTransport};
+use crate::queue::{new_queue, Queue, VirtQueue};
+use alloc::boxed::Box;
 use core::ptr::NonNull;
+use glenda::cap::CapPtr;
+use glenda::client::{DeviceClient, ResourceClient};
+use glenda::error::Error;
+use glenda::interface::{DeviceService, VSpaceService};
+use glenda::ipc::Badge;
+use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+
+/// Bus-independent view of a virtio device, implemented by the MMIO and PCI transports.
+pub trait Transport {
+    fn device_id(&self) -> u32;
+    fn get_device_features(&self) -> u64;
+    fn set_driver_features(&self, features: u64);
+    fn get_status(&self) -> u32;
+    fn set_status(&self, status: u32);
+
+    fn add_status(&self, status: u32) {
+        self.set_status(self.get_status() | status);
+    }
+
+    /// Sets FEATURES_OK and checks that the device kept it.
+    fn set_features_ok(&self) -> bool {
+        self.add_status(STATUS_FEATURES_OK);
+        self.get_status() & STATUS_FEATURES_OK != 0
+    }
+
+    /// Largest ring the device supports for queue `idx`, 0 if the queue doesn't exist.
+    fn max_queue_size(&self, idx: u32) -> u16;
+
+    /// Ring size to use for queue `idx`: `wanted`, capped at the device's
+    /// maximum. `None` if the device doesn't have the queue.
+    fn queue_size(&self, idx: u32, wanted: u16) -> Option<u16> {
+        match self.max_queue_size(idx) {
+            0 => None,
+            max => Some(wanted.min(max)),
+        }
+    }
+
+    /// Builds a ring with the layout this device expects.
+    unsafe fn create_queue(
+        &self,
+        features: u64,
+        index: u32,
+        num: u16,
+        paddr: usize,
+        vaddr: *mut u8,
+    ) -> Box<dyn Queue> {
+        new_queue(features, index, num, paddr, vaddr)
+    }
+
+    unsafe fn setup_queue(&self, vq: &dyn Queue);
+    fn notify(&self, queue_idx: u32);
+
+    /// Reads and acknowledges the interrupt status.
+    /// Bit 0 signals used buffers, bit 1 a configuration change.
+    fn ack_interrupt(&self) -> u32;
+
+    fn interrupt_ack(&self) -> bool {
+        self.ack_interrupt() != 0
+    }
+
+    fn config_generation(&self) -> u32;
+    unsafe fn config_ptr(&self) -> *mut u8;
+}
+
+/// Reads device config space with `read`, retrying until the config generation is
+/// unchanged across the read so multi-field values are never torn.
+pub fn read_config_consistent<T>(transport: &dyn Transport, read: impl Fn(*const u8) -> T) -> T {
+    loop {
+        let before = transport.config_generation();
+        let value = read(unsafe { transport.config_ptr() });
+        if transport.config_generation() == before {
+            return value;
+        }
+    }
+}
+
+/// Picks the transport for a device whose first region is mapped at `region0`:
+/// virtio-mmio registers, or the configuration space of a virtio PCI function
+/// whose BARs are already mapped as `bars`.
+pub unsafe fn attach(region0: NonNull<u8>, bars: &[BarMapping]) -> Result<Box<dyn Transport>> {
+    if (region0.as_ptr() as *const u32).read_volatile() == MAGIC_VALUE {
+        return Ok(Box::new(VirtIOTransport::new(region0)?));
+    }
+    Ok(Box::new(VirtIOPciTransport::new(region0, bars)?))
+}
+
+/// Maps the device's first region into `slot` at `va` and any BARs from
+/// `bar_va`, then picks its transport.
+pub fn map_device(
+    dev: &mut DeviceClient,
+    res: &mut ResourceClient,
+    cspace_mgr: &mut CSpaceManager,
+    vspace_mgr: &mut VSpaceManager,
+    slot: CapPtr,
+    va: usize,
+    bar_va: usize,
+) -> core::result::Result<Box<dyn Transport>, Error> {
+    let (region0, paddr, size) = dev.get_mmio(Badge::null(), 0, slot)?;
+    log!("Got MMIO cap: addr={:#x}, size={:#x}", paddr, size);
+    vspace_mgr.map_page(
+        region0,
+        va,
+        glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
+        1,
+        res,
+        cspace_mgr,
+    )?;
+    // Virtio PCI functions hand out their config space first and the BARs after it
+    let bars = map_bars(dev, res, cspace_mgr, vspace_mgr, bar_va)?;
+    glenda::arch::sync::fence();
+
+    let region0 = NonNull::new(va as *mut u8).ok_or(Error::InvalidArgs)?;
+    unsafe { attach(region0, &bars) }.map_err(|e| {
+        error!("No virtio transport: {:?}", e);
+        Error::NotSupported
+    })
+}
 
 pub struct VirtIOTransport {
     base: NonNull<u8>,
+    /// Version 1 register layout (GuestPageSize / QueuePFN, no FEATURES_OK).
+    legacy: bool,
 }
 
 impl VirtIOTransport {
@@ -39,20 +160,62 @@ impl VirtIOTransport {
         }
     }
     pub unsafe fn new(base: NonNull<u8>) -> Result<Self> {
-        let transport = Self { base };
+        let mut transport = Self { base, legacy: false };
 
         if transport.read_reg(OFF_MAGIC) != MAGIC_VALUE {
             return Err(VirtIOError::InvalidHeader);
         }
 
-        if transport.read_reg(OFF_VERSION) != VERSION_2 {
-            // For simplicity, we only support V2 (VirtIO 1.0) for now, or handle legacy if needed.
-            // But QEMU virt usually provides V2.
+        match transport.read_reg(OFF_VERSION) {
+            VERSION_2 => {}
+            VERSION_1 => {
+                log!("Legacy virtio-mmio device");
+                transport.legacy = true;
+            }
+            version => {
+                error!("Unsupported virtio-mmio version {}", version);
+                return Err(VirtIOError::InvalidHeader);
+            }
         }
 
         Ok(transport)
     }
 
+    pub fn is_legacy(&self) -> bool {
+        self.legacy
+    }
+
+    /// Sets FEATURES_OK and checks that the device kept it.
+    /// Legacy devices have no such handshake, so this always succeeds for them.
+    pub fn set_features_ok(&self) -> bool {
+        if self.legacy {
+            return true;
+        }
+        self.add_status(STATUS_FEATURES_OK);
+        self.get_status() & STATUS_FEATURES_OK != 0
+    }
+
+    /// Builds a ring with the layout this device expects.
+    ///
+    /// Legacy rings must start on a page and need two pages for 128 entries.
+    pub unsafe fn create_queue(
+        &self,
+        features: u64,
+        index: u32,
+        num: u16,
+        paddr: usize,
+        vaddr: *mut u8,
+    ) -> Box<dyn Queue> {
+        if self.legacy {
+            let mut queue: Box<dyn Queue> =
+                Box::new(VirtQueue::new_legacy(index, num, paddr, vaddr));
+            queue.set_event_idx(features & VIRTIO_F_EVENT_IDX != 0);
+            queue
+        } else {
+            new_queue(features, index, num, paddr, vaddr)
+        }
+    }
+
     pub unsafe fn read_reg(&self, offset: usize) -> u32 {
         let ptr = self.base.as_ptr().add(offset) as *const u32;
         core::ptr::read_volatile(ptr)
@@ -169,19 +332,31 @@ impl VirtIOTransport {
         self.write_reg(OFF_QUEUE_READY, ready);
     }
 
-    pub unsafe fn setup_queue(&self, vq: &VirtQueue) {
-        self.write_queue_sel(vq.index);
-        self.write_queue_num(vq.num as u32);
-        self.write_queue_desc(vq.paddr as u64);
+    pub unsafe fn setup_queue(&self, vq: &dyn Queue) {
+        if self.legacy {
+            self.setup_legacy_queue(vq);
+            return;
+        }
 
-        let avail_offset = 16 * vq.num as usize;
-        self.write_queue_driver((vq.paddr + avail_offset) as u64);
+        self.write_queue_sel(vq.index());
+        self.write_queue_num(vq.size() as u32);
+        self.write_queue_desc(vq.desc_paddr() as u64);
+        self.write_queue_driver(vq.driver_paddr() as u64);
+        self.write_queue_device(vq.device_paddr() as u64);
+        self.write_queue_ready(1);
+    }
 
-        // Used ring is aligned to 4 bytes
-        let used_offset = (16 * vq.num as usize + 6 + 2 * vq.num as usize + 3) & !3;
-        self.write_queue_device((vq.paddr + used_offset) as u64);
+    unsafe fn setup_legacy_queue(&self, vq: &dyn Queue) {
+        if vq.desc_paddr() % LEGACY_PAGE_SIZE != 0 {
+            error!("Legacy queue {} at {:#x} is not page aligned", vq.index(), vq.desc_paddr());
+        }
 
-        self.write_queue_ready(1);
+        self.write_reg(OFF_GUEST_PAGE_SIZE, LEGACY_PAGE_SIZE as u32);
+        self.write_queue_sel(vq.index());
+        self.write_queue_num(vq.size() as u32);
+        self.write_reg(OFF_QUEUE_ALIGN, LEGACY_QUEUE_ALIGN as u32);
+        // A non-zero PFN activates the queue; there is no QueueReady register
+        self.write_reg(OFF_QUEUE_PFN, (vq.desc_paddr() / LEGACY_PAGE_SIZE) as u32);
     }
 
     pub unsafe fn config_ptr(&self) -> *mut u8 {
@@ -194,3 +369,71 @@ impl VirtIOTransport {
         unsafe { self.write_reg(OFF_QUEUE_NOTIFY, idx) }
     }
 }
+
+impl Transport for VirtIOTransport {
+    fn device_id(&self) -> u32 {
+        self.get_device_id()
+    }
+
+    fn get_device_features(&self) -> u64 {
+        VirtIOTransport::get_device_features(self)
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        VirtIOTransport::set_driver_features(self, features)
+    }
+
+    fn get_status(&self) -> u32 {
+        VirtIOTransport::get_status(self)
+    }
+
+    fn set_status(&self, status: u32) {
+        VirtIOTransport::set_status(self, status)
+    }
+
+    fn set_features_ok(&self) -> bool {
+        VirtIOTransport::set_features_ok(self)
+    }
+
+    fn max_queue_size(&self, idx: u32) -> u16 {
+        unsafe {
+            self.write_queue_sel(idx);
+            self.read_queue_max() as u16
+        }
+    }
+
+    unsafe fn create_queue(
+        &self,
+        features: u64,
+        index: u32,
+        num: u16,
+        paddr: usize,
+        vaddr: *mut u8,
+    ) -> Box<dyn Queue> {
+        VirtIOTransport::create_queue(self, features, index, num, paddr, vaddr)
+    }
+
+    unsafe fn setup_queue(&self, vq: &dyn Queue) {
+        VirtIOTransport::setup_queue(self, vq)
+    }
+
+    fn notify(&self, queue_idx: u32) {
+        VirtIOTransport::notify(self, queue_idx)
+    }
+
+    fn ack_interrupt(&self) -> u32 {
+        VirtIOTransport::ack_interrupt(self)
+    }
+
+    fn interrupt_ack(&self) -> bool {
+        VirtIOTransport::interrupt_ack(self)
+    }
+
+    fn config_generation(&self) -> u32 {
+        unsafe { self.read_reg(OFF_CONFIG_GENERATION) }
+    }
+
+    unsafe fn config_ptr(&self) -> *mut u8 {
+        VirtIOTransport::config_ptr(self)
+    }
+}
@@ -0,0 +1,421 @@
+//! Host-side tests driving the queues against the simulated device.
+
+use virtio_common::blk::*;
+use virtio_common::net::*;
+use virtio_common::sim::{SimDevice, SimDma};
+use virtio_common::*;
+
+const QUEUE_SIZE: u16 = 8;
+
+/// Brings the device up to DRIVER_OK with one queue, the way the drivers do.
+fn bring_up(dev: &SimDevice, dma: &SimDma, wanted: u64) -> Box<dyn Queue> {
+    dev.set_status(0);
+    dev.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+    let features = dev.get_device_features() & wanted;
+    dev.set_driver_features(features);
+    assert!(dev.set_features_ok());
+
+    let queue = unsafe { dev.create_queue(features, 0, QUEUE_SIZE, dma.paddr(), dma.vaddr()) };
+    unsafe { dev.setup_queue(queue.as_ref()) };
+    dev.add_status(STATUS_DRIVER_OK);
+    queue
+}
+
+#[test]
+fn features_ok_rejects_unoffered_bits() {
+    let dev = SimDevice::new(DEV_ID_BLOCK, VIRTIO_F_VERSION_1, &[]);
+    dev.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+    dev.set_driver_features(VIRTIO_F_VERSION_1 | VIRTIO_F_EVENT_IDX);
+    assert!(!dev.set_features_ok());
+
+    dev.set_driver_features(VIRTIO_F_VERSION_1);
+    assert!(dev.set_features_ok());
+    assert_eq!(dev.driver_features(), VIRTIO_F_VERSION_1);
+}
+
+#[test]
+fn queue_size_is_capped_at_device_maximum() {
+    let dev = SimDevice::new(DEV_ID_BLOCK, VIRTIO_F_VERSION_1, &[]);
+    assert_eq!(dev.queue_size(0, QUEUE_SIZE), Some(QUEUE_SIZE));
+    assert_eq!(dev.queue_size(0, 1024), Some(dev.max_queue_size(0)));
+}
+
+#[test]
+fn chains_round_trip_and_free_descriptors() {
+    let dev = SimDevice::new(DEV_ID_BLOCK, VIRTIO_F_VERSION_1, &[]);
+    let dma = SimDma::new(2);
+    let mut queue = bring_up(&dev, &dma, !0);
+    let buf = SimDma::new(1);
+
+    let segs = [Segment::readable(buf.paddr(), 16), Segment::writable(buf.paddr() + 16, 16)];
+    let a = queue.add_chain(&segs).unwrap();
+    let b = queue.add_chain(&segs).unwrap();
+    assert_ne!(a, b);
+    assert_eq!(queue.num_free(), QUEUE_SIZE - 4);
+    assert!(queue.should_notify());
+    dev.notify(queue.index());
+    assert_eq!(dev.take_notifications(), [0]);
+
+    let mut seen = Vec::new();
+    assert_eq!(
+        dev.process(0, |chain| {
+            assert_eq!(chain.segments.len(), 2);
+            seen.push(chain.head);
+            16
+        }),
+        2
+    );
+    assert_eq!(seen, [a, b]);
+
+    assert_eq!(queue.pop_used(), Some((a, 16)));
+    assert_eq!(queue.pop_used(), Some((b, 16)));
+    assert_eq!(queue.pop_used(), None);
+    assert_eq!(queue.num_free(), QUEUE_SIZE);
+}
+
+#[test]
+fn full_queue_refuses_chain() {
+    let dev = SimDevice::new(DEV_ID_BLOCK, VIRTIO_F_VERSION_1, &[]);
+    let dma = SimDma::new(2);
+    let mut queue = bring_up(&dev, &dma, !0);
+
+    let segs = [Segment::readable(0x1000, 1); 3];
+    assert!(queue.add_chain(&segs).is_some());
+    assert!(queue.add_chain(&segs).is_some());
+    assert_eq!(queue.num_free(), 2);
+    assert!(queue.add_chain(&segs).is_none());
+    // A refused chain must leave the ring untouched
+    assert_eq!(queue.num_free(), 2);
+    assert_eq!(dev.process(0, |_| 0), 2);
+}
+
+#[test]
+fn indirect_chain_uses_one_descriptor() {
+    let dev = SimDevice::new(DEV_ID_BLOCK, VIRTIO_F_VERSION_1 | VIRTIO_F_INDIRECT_DESC, &[]);
+    let dma = SimDma::new(2);
+    let tables = SimDma::new(1);
+    let mut queue = bring_up(&dev, &dma, !0);
+    queue.set_indirect_pool(IndirectPool::new(tables.vaddr(), tables.paddr(), 4));
+
+    let segs = [Segment::readable(0x1000, 16), Segment::writable(0x2000, 512)];
+    let token = queue.submit_sg(&segs).unwrap();
+    assert_eq!(queue.num_free(), QUEUE_SIZE - 1);
+
+    let chain = dev.pop_avail(0).unwrap();
+    assert_eq!(chain.head, token);
+    assert_eq!(chain.segments.len(), 2);
+    assert!(chain.segments[1].write);
+    dev.push_used(0, chain.head, 512);
+
+    assert_eq!(queue.pop_used(), Some((token, 512)));
+    assert_eq!(queue.num_free(), QUEUE_SIZE);
+}
+
+#[test]
+fn event_idx_suppresses_notifications() {
+    let dev = SimDevice::new(DEV_ID_BLOCK, VIRTIO_F_VERSION_1 | VIRTIO_F_EVENT_IDX, &[]);
+    let dma = SimDma::new(2);
+    let mut queue = bring_up(&dev, &dma, !0);
+
+    let segs = [Segment::readable(0x1000, 1)];
+    queue.add_chain(&segs).unwrap();
+    assert!(queue.should_notify());
+
+    // Device hasn't consumed anything, so avail_event still points at the first chain
+    queue.add_chain(&segs).unwrap();
+    assert!(!queue.should_notify());
+
+    dev.process(0, |_| 0);
+    queue.add_chain(&segs).unwrap();
+    assert!(queue.should_notify());
+}
+
+#[test]
+fn disabled_callbacks_raise_no_interrupt() {
+    let dev = SimDevice::new(DEV_ID_BLOCK, VIRTIO_F_VERSION_1, &[]);
+    let dma = SimDma::new(2);
+    let mut queue = bring_up(&dev, &dma, !0);
+
+    queue.disable_cb();
+    queue.add_chain(&[Segment::readable(0x1000, 1)]).unwrap();
+    dev.process(0, |_| 0);
+    assert_eq!(dev.interrupts(), 0);
+    assert!(!queue.enable_cb_delayed());
+    assert!(queue.pop_used().is_some());
+    assert!(queue.enable_cb_delayed());
+
+    queue.add_chain(&[Segment::readable(0x1000, 1)]).unwrap();
+    dev.process(0, |_| 0);
+    assert_eq!(dev.interrupts(), 1);
+    assert_eq!(dev.ack_interrupt() & 1, 1);
+    assert_eq!(dev.ack_interrupt(), 0);
+}
+
+#[test]
+fn enable_cb_interrupts_on_next_used_chain() {
+    let dev = SimDevice::new(DEV_ID_NET, VIRTIO_F_VERSION_1 | VIRTIO_F_EVENT_IDX, &[]);
+    let dma = SimDma::new(2);
+    let mut queue = bring_up(&dev, &dma, !0);
+    let complete_one = || {
+        let chain = dev.pop_avail(0).unwrap();
+        dev.push_used(0, chain.head, 0);
+    };
+
+    // A full pool of receive buffers, as the net driver keeps posted
+    for _ in 0..QUEUE_SIZE {
+        queue.add_chain(&[Segment::writable(0x1000, 64)]).unwrap();
+    }
+    complete_one();
+    assert_eq!(dev.interrupts(), 1);
+    assert!(queue.pop_used().is_some());
+
+    // The delayed form waits for 3/4 of the outstanding chains
+    assert!(queue.enable_cb_delayed());
+    complete_one();
+    assert_eq!(dev.interrupts(), 1);
+    assert!(queue.pop_used().is_some());
+
+    assert!(queue.enable_cb());
+    complete_one();
+    assert_eq!(dev.interrupts(), 2);
+    assert!(!queue.enable_cb());
+}
+
+#[test]
+fn blk_write_request_layout() {
+    let dev = SimDevice::new(DEV_ID_BLOCK, VIRTIO_F_VERSION_1, &[]);
+    let dma = SimDma::new(2);
+    let mut queue = bring_up(&dev, &dma, !0);
+    let mem = SimDma::new(1);
+
+    let req = VirtIOBlkReq { type_: VIRTIO_BLK_T_OUT, reserved: 0, sector: 42 };
+    unsafe { (mem.vaddr() as *mut VirtIOBlkReq).write(req) };
+    let data = mem.paddr() + 512;
+    let status = mem.paddr() + 1024;
+    let token = queue.submit_sg(&blk_rw_chain(mem.paddr(), data, 512, true, status)).unwrap();
+
+    dev.process(0, |chain| {
+        let [hdr, payload, st] = &chain.segments[..] else { panic!("expected 3 segments") };
+        assert_eq!(hdr.len as usize, core::mem::size_of::<VirtIOBlkReq>());
+        assert!(!hdr.write && !payload.write && st.write);
+        let req = unsafe { (hdr.addr as *const VirtIOBlkReq).read() };
+        assert_eq!((req.type_, req.sector), (VIRTIO_BLK_T_OUT, 42));
+        unsafe { (st.addr as *mut u8).write(VIRTIO_BLK_S_OK) };
+        1
+    });
+
+    assert_eq!(queue.pop_used(), Some((token, 1)));
+    assert_eq!(unsafe { *(mem.vaddr().add(1024)) }, VIRTIO_BLK_S_OK);
+}
+
+#[test]
+fn net_rx_chain_is_device_writable() {
+    let dev = SimDevice::new(DEV_ID_NET, VIRTIO_F_VERSION_1, &[0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+    let dma = SimDma::new(2);
+    let mut queue = bring_up(&dev, &dma, !0);
+    let mem = SimDma::new(1);
+
+    let hdr_len = net_hdr_len(VIRTIO_F_VERSION_1);
+    let token =
+        queue.add_chain(&net_chain(mem.paddr(), hdr_len, mem.paddr() + 64, 1514, true)).unwrap();
+    let packet = [0xabu8; 70];
+    dev.process(0, |chain| {
+        assert!(chain.segments.iter().all(|s| s.write));
+        assert_eq!(chain.segments[0].len as usize, hdr_len);
+        let mut frame = vec![0u8; hdr_len];
+        frame.extend_from_slice(&packet);
+        chain.write(&frame)
+    });
+
+    let (popped, len) = queue.pop_used().unwrap();
+    assert_eq!(popped, token);
+    assert_eq!(len as usize, hdr_len + packet.len());
+    assert_eq!(unsafe { *mem.vaddr().add(64) }, 0xab);
+    assert_eq!(unsafe { *dev.config_ptr().add(5) }, 0x56);
+}
+
+#[test]
+fn config_update_bumps_generation() {
+    let dev = SimDevice::new(DEV_ID_BLOCK, VIRTIO_F_VERSION_1, &[0; 8]);
+    let before = dev.config_generation();
+    dev.update_config(0, &[1, 0, 0, 0]);
+    assert_ne!(dev.config_generation(), before);
+    assert_eq!(dev.ack_interrupt() & 2, 2);
+}
+
+#[test]
+fn blk_config_reads_negotiated_fields() {
+    let mut raw = [0u8; 60];
+    raw[0..8].copy_from_slice(&2048u64.to_le_bytes());
+    raw[12..16].copy_from_slice(&126u32.to_le_bytes());
+    raw[20..24].copy_from_slice(&4096u32.to_le_bytes());
+    raw[24] = 1;
+    raw[26..28].copy_from_slice(&2u16.to_le_bytes());
+    let dev = SimDevice::new(DEV_ID_BLOCK, VIRTIO_F_VERSION_1, &raw);
+
+    let cfg = VirtioBlkConfig::read(&dev, VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_TOPOLOGY);
+    assert_eq!(cfg.capacity, 2048);
+    assert_eq!(cfg.blk_size, 4096);
+    assert_eq!(cfg.physical_block_size(), 8192);
+    assert_eq!(cfg.topology.min_io_size, 2);
+    // SEG_MAX wasn't negotiated, so the field is not trusted
+    assert_eq!(cfg.seg_max, 0);
+
+    dev.update_config(0, &4096u64.to_le_bytes());
+    assert_eq!(VirtioBlkConfig::read(&dev, 0).capacity, 4096);
+}
+
+#[test]
+fn blk_split_keeps_groups_sector_aligned() {
+    // Two pages and an unaligned tail, two data segments per request
+    let segs = [
+        Segment::writable(0x10000, 4096),
+        Segment::writable(0x30000, 700),
+        Segment::writable(0x50000, 324),
+    ];
+    let groups = blk_split_segments(&segs, 2).unwrap();
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[0].iter().map(|s| s.len).sum::<u32>(), 4096 + 512);
+    assert_eq!(groups[1][0].addr, 0x30000 + 512);
+    assert_eq!(groups[1].iter().map(|s| s.len).sum::<u32>(), 188 + 324);
+
+    let chain = blk_sg_chain(0x1000, &groups[1], 0x2000);
+    assert_eq!(chain.len(), 4);
+    assert!(!chain[0].write && chain[3].write);
+}
+
+#[test]
+fn net_tx_offload_header_and_features() {
+    // TSO4 for a frame whose TCP header starts at 34, checksum field at +16
+    let off = 34 | 16 << 16 | 1448 << 32 | (VIRTIO_NET_HDR_GSO_TCPV4 as u64) << 48;
+    let hdr = VirtioNetHdr::from_tx_offload(off | NET_TX_NEEDS_CSUM);
+    assert_eq!(hdr.flags, VIRTIO_NET_HDR_F_NEEDS_CSUM);
+    assert_eq!((hdr.csum_start, hdr.csum_offset, hdr.gso_size), (34, 16, 1448));
+    assert_eq!(hdr.required_features(), VIRTIO_NET_F_CSUM | VIRTIO_NET_F_HOST_TSO4);
+
+    let hdr = VirtioNetHdr::from_tx_offload(0);
+    assert_eq!((hdr.flags, hdr.gso_type, hdr.required_features()), (0, 0, 0));
+}
+
+#[test]
+fn net_partial_checksum_completion() {
+    // A UDP header and payload after Ethernet and IPv4; the checksum field
+    // starts out holding the pseudo-header sum
+    let pseudo = 0x1234u32;
+    let mut frame = vec![0u8; 14 + 20];
+    frame.extend_from_slice(&[0x30, 0x39, 0x00, 0x35, 0x00, 0x0d, 0x12, 0x34]);
+    frame.extend_from_slice(b"hello");
+    let start = 34;
+    assert!(complete_checksum(&mut frame, start, 6));
+
+    // With the finished checksum, segment and pseudo-header sum to all ones
+    let mut sum = pseudo;
+    for word in frame[start..].chunks(2) {
+        sum += (word[0] as u32) << 8 | word.get(1).copied().unwrap_or(0) as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    assert_eq!(sum, 0xffff);
+    assert!(!complete_checksum(&mut frame, start, 100));
+}
+
+#[test]
+fn net_header_length_follows_features() {
+    assert_eq!(net_hdr_len(0), 10);
+    assert_eq!(net_hdr_len(VIRTIO_F_VERSION_1), 12);
+    assert_eq!(net_hdr_len(VIRTIO_NET_F_MRG_RXBUF), 12);
+
+    let hdr = VirtioNetHdrMrgRxbuf { num_buffers: 3, ..Default::default() };
+    assert_eq!(rx_num_buffers(&hdr, VIRTIO_F_VERSION_1), 1);
+    assert_eq!(rx_num_buffers(&hdr, VIRTIO_NET_F_MRG_RXBUF), 3);
+    let hdr = VirtioNetHdrMrgRxbuf::default();
+    assert_eq!(rx_num_buffers(&hdr, VIRTIO_NET_F_MRG_RXBUF), 1);
+}
+
+#[test]
+fn net_ctrl_mac_table_command() {
+    let dev = SimDevice::new(DEV_ID_NET, VIRTIO_F_VERSION_1 | VIRTIO_NET_F_CTRL_VQ, &[0; 6]);
+    let dma = SimDma::new(2);
+    let mut queue = bring_up(&dev, &dma, !0);
+    let mem = SimDma::new(1);
+
+    let uni = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+    let multi = [0x01, 0x00, 0x5e, 0, 0, 0xfb];
+    let data = unsafe { core::slice::from_raw_parts_mut(mem.vaddr().add(16), 64) };
+    assert_eq!(mac_table(data, &[uni, multi].concat(), 1), Some(20));
+    assert_eq!(mac_table(data, &uni[..5], 0), None);
+    assert_eq!(mac_table(data, &uni, 2), None);
+    assert_eq!(mac_table(&mut [0; 8], &uni, 1), None);
+
+    let hdr =
+        VirtioNetCtrlHdr { class: VIRTIO_NET_CTRL_MAC, command: VIRTIO_NET_CTRL_MAC_TABLE_SET };
+    unsafe { (mem.vaddr() as *mut VirtioNetCtrlHdr).write(hdr) };
+    let chain = ctrl_chain(mem.paddr(), mem.paddr() + 16, 20, mem.paddr() + 8);
+    let token = queue.add_chain(&chain).unwrap();
+
+    dev.process(0, |chain| {
+        let [hdr, data, ack] = &chain.segments[..] else { panic!("expected 3 segments") };
+        assert!(!hdr.write && !data.write && ack.write);
+        let bytes = chain.readable();
+        assert_eq!(&bytes[..2], &[VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET]);
+        assert_eq!(&bytes[2..6], &1u32.to_le_bytes());
+        assert_eq!(&bytes[6..12], &uni);
+        assert_eq!(&bytes[12..16], &1u32.to_le_bytes());
+        assert_eq!(&bytes[16..22], &multi);
+        chain.write(&[VIRTIO_NET_OK])
+    });
+
+    assert_eq!(queue.pop_used(), Some((token, 1)));
+    assert_eq!(unsafe { *mem.vaddr().add(8) }, VIRTIO_NET_OK);
+}
+
+#[test]
+fn net_config_reads_link_and_mtu() {
+    let mut raw = [0u8; 12];
+    raw[..6].copy_from_slice(&[0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+    raw[6..8].copy_from_slice(&VIRTIO_NET_S_ANNOUNCE.to_le_bytes());
+    raw[10..12].copy_from_slice(&9000u16.to_le_bytes());
+    let dev = SimDevice::new(DEV_ID_NET, VIRTIO_F_VERSION_1, &raw);
+
+    let cfg = VirtioNetConfig::read(&dev, VIRTIO_NET_F_STATUS | VIRTIO_NET_F_MTU);
+    assert_eq!(cfg.mac[5], 0x56);
+    assert_eq!((cfg.status, cfg.mtu), (VIRTIO_NET_S_ANNOUNCE, 9000));
+    // Without the features the link is up and the MTU the Ethernet default
+    let cfg = VirtioNetConfig::read(&dev, 0);
+    assert_eq!((cfg.status, cfg.mtu), (VIRTIO_NET_S_LINK_UP, VIRTIO_NET_DEFAULT_MTU));
+
+    dev.update_config(6, &VIRTIO_NET_S_LINK_UP.to_le_bytes());
+    assert_eq!(dev.ack_interrupt() & INTERRUPT_CONFIG_CHANGE, INTERRUPT_CONFIG_CHANGE);
+    assert_eq!(VirtioNetConfig::read(&dev, VIRTIO_NET_F_STATUS).status, VIRTIO_NET_S_LINK_UP);
+}
+
+#[test]
+fn net_multiqueue_config_and_rss_layout() {
+    let mut raw = [0u8; 24];
+    raw[8..10].copy_from_slice(&4u16.to_le_bytes());
+    raw[17] = 40;
+    raw[18..20].copy_from_slice(&128u16.to_le_bytes());
+    raw[20..24].copy_from_slice(&0x3fu32.to_le_bytes());
+    let dev = SimDevice::new(DEV_ID_NET, VIRTIO_F_VERSION_1, &raw);
+
+    let cfg = VirtioNetConfig::read(&dev, VIRTIO_NET_F_MQ | VIRTIO_NET_F_RSS);
+    assert_eq!(cfg.max_virtqueue_pairs, 4);
+    assert_eq!((cfg.rss_max_key_size, cfg.rss_max_indirection_table_length), (40, 128));
+    assert_eq!(cfg.supported_hash_types, 0x3f);
+    assert_eq!(VirtioNetConfig::read(&dev, 0).max_virtqueue_pairs, 1);
+
+    let hash = VIRTIO_NET_RSS_HASH_TYPE_TCPV4 | VIRTIO_NET_RSS_HASH_TYPE_TCPV6;
+    let mut out = [0u8; 64];
+    let len = rss_config(&mut out, hash, &[0, 1, 0, 1], 2, &[0xaa; 4]).unwrap();
+    assert_eq!(len, 8 + 8 + 3 + 4);
+    assert_eq!(&out[..4], &hash.to_le_bytes());
+    // indirection_table_mask, unclassified_queue, then the table
+    assert_eq!(&out[4..8], &[3, 0, 0, 0]);
+    assert_eq!(&out[8..16], &[0, 0, 1, 0, 0, 0, 1, 0]);
+    assert_eq!(&out[16..19], &[2, 0, 4]);
+    assert_eq!(&out[19..23], &[0xaa; 4]);
+
+    assert_eq!(rss_config(&mut out, hash, &[0, 1, 0], 2, &[]), None);
+    assert_eq!(rss_config(&mut [0; 8], hash, &[0], 1, &[]), None);
+}
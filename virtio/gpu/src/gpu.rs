@@ -1,22 +1,33 @@
 use crate::protocol::*;
+use alloc::boxed::Box;
 use glenda::drivers::protocol::fb::IOURING_OP_FB_FLUSH;
 use glenda::error::Error;
 use glenda::io::uring::IoUringServer;
 use virtio_common::consts::*;
-use virtio_common::{Descriptor, VirtIOTransport, VirtQueue, DESC_F_NEXT, DESC_F_WRITE};
+use virtio_common::{Queue, Segment, Transport};
+
+// DMA layout: pages 0-1 the control ring, pages 2-3 the cursor ring (two pages so
+// the legacy layout fits), page 4 the command, page 5 the response.
+pub const DMA_PAGES: usize = RESP_OFFSET / 4096 + 1;
+const QUEUE_SIZE: u16 = 16;
+const RING_PAGES: usize = 2;
+const CURSOR_RING_OFFSET: usize = RING_PAGES * 4096;
+const CMD_OFFSET: usize = 2 * RING_PAGES * 4096;
+const RESP_OFFSET: usize = CMD_OFFSET + 4096;
+
 pub struct VirtIOGpu {
-    transport: VirtIOTransport,
+    transport: Box<dyn Transport>,
     width: usize,
     height: usize,
     ring_server: Option<IoUringServer>,
-    control_vq: Option<VirtQueue>,
-    cursor_vq: Option<VirtQueue>,
+    control_vq: Option<Box<dyn Queue>>,
+    cursor_vq: Option<Box<dyn Queue>>,
     cmd_buf_pa: usize,
     cmd_buf_va: *mut u8,
 }
 
 impl VirtIOGpu {
-    pub fn new(transport: VirtIOTransport, cmd_buf_va: *mut u8, cmd_buf_pa: usize) -> Self {
+    pub fn new(transport: Box<dyn Transport>, cmd_buf_va: *mut u8, cmd_buf_pa: usize) -> Self {
         Self {
             transport,
             width: 0,
@@ -64,28 +75,36 @@ impl VirtIOGpu {
 
         // 1. Feature negotiation
         let features = self.transport.get_device_features();
-        // We only need basic VirtIO 1.0 features
-        self.transport.set_driver_features(features & (1 << 32)); // VIRTIO_F_VERSION_1
-        self.transport.add_status(STATUS_FEATURES_OK);
-        if (self.transport.get_status() & STATUS_FEATURES_OK) == 0 {
+        // We only need basic VirtIO 1.0 features, plus packed rings when offered
+        let features = features & (VIRTIO_F_VERSION_1 | VIRTIO_F_RING_PACKED);
+        self.transport.set_driver_features(features);
+        if !self.transport.set_features_ok() {
+            error!("Device rejected features {:#x}", features);
             return Err(Error::NotSupported);
         }
 
         // 2. Setup VirtQueues (0: controlvq, 1: cursorvq)
-        // We use the cmd_buf to host queues.
-        // Queue size 16 * 16 (desc) + 6 + 32 (avail) + 6 + 128 (used) = ~200 bytes.
-        // We can place them at the start of cmd_buf.
-        unsafe {
-            let vq0 = VirtQueue::new(0, 16, self.cmd_buf_pa, self.cmd_buf_va);
-            self.transport.setup_queue(&vq0);
-            self.control_vq = Some(vq0);
-
-            let q1_offset = 4096; // Offset for cursor vq
-            let vq1 =
-                VirtQueue::new(1, 16, self.cmd_buf_pa + q1_offset, self.cmd_buf_va.add(q1_offset));
-            self.transport.setup_queue(&vq1);
-            self.cursor_vq = Some(vq1);
+        let mut sizes = [0; 2];
+        for (idx, size) in sizes.iter_mut().enumerate() {
+            *size = self.transport.queue_size(idx as u32, QUEUE_SIZE).ok_or_else(|| {
+                error!("Virtqueue {} is not available", idx);
+                Error::NotSupported
+            })?;
         }
+        let [control_vq, cursor_vq] =
+            [(0, 0), (1, CURSOR_RING_OFFSET)].map(|(idx, offset)| unsafe {
+                let vq = self.transport.create_queue(
+                    features,
+                    idx,
+                    sizes[idx as usize],
+                    self.cmd_buf_pa + offset,
+                    self.cmd_buf_va.add(offset),
+                );
+                self.transport.setup_queue(vq.as_ref());
+                vq
+            });
+        self.control_vq = Some(control_vq);
+        self.cursor_vq = Some(cursor_vq);
 
         self.transport.add_status(STATUS_DRIVER_OK);
 
@@ -114,51 +133,26 @@ impl VirtIOGpu {
     {
         let vq = self.control_vq.as_mut().ok_or(Error::NotInitialized)?;
 
-        // Use cmd_buf offset 8192 for command data and 12288 for response
-        let cmd_offset = 8192;
-        let resp_offset = 12288;
-
         unsafe {
-            core::ptr::write_volatile(self.cmd_buf_va.add(cmd_offset) as *mut T, cmd);
-            core::ptr::write_volatile(self.cmd_buf_va.add(resp_offset) as *mut R, R::default());
+            core::ptr::write_volatile(self.cmd_buf_va.add(CMD_OFFSET) as *mut T, cmd);
+            core::ptr::write_volatile(self.cmd_buf_va.add(RESP_OFFSET) as *mut R, R::default());
         }
 
-        let head = vq.alloc_desc().ok_or(Error::OutOfMemory)?;
-        let resp_desc_id = vq.alloc_desc().ok_or(Error::OutOfMemory)?;
-
-        vq.write_desc(
-            head,
-            Descriptor {
-                addr: self.cmd_buf_pa + cmd_offset,
-                len: core::mem::size_of::<T>() as u32,
-                flags: DESC_F_NEXT,
-                next: resp_desc_id,
-            },
-        );
-
-        vq.write_desc(
-            resp_desc_id,
-            Descriptor {
-                addr: self.cmd_buf_pa + resp_offset,
-                len: core::mem::size_of::<R>() as u32,
-                flags: DESC_F_WRITE,
-                next: 0,
-            },
-        );
-
-        vq.submit(head);
+        let segs = [
+            Segment::readable(self.cmd_buf_pa + CMD_OFFSET, core::mem::size_of::<T>() as u32),
+            Segment::writable(self.cmd_buf_pa + RESP_OFFSET, core::mem::size_of::<R>() as u32),
+        ];
+        vq.add_chain(&segs).ok_or(Error::OutOfMemory)?;
         self.transport.notify(0);
 
         while !vq.can_pop() {
             core::hint::spin_loop();
         }
 
-        vq.pop();
-        vq.free_desc(head);
-        vq.free_desc(resp_desc_id);
+        vq.pop_used();
 
         let resp =
-            unsafe { core::ptr::read_volatile(self.cmd_buf_va.add(resp_offset) as *const R) };
+            unsafe { core::ptr::read_volatile(self.cmd_buf_va.add(RESP_OFFSET) as *const R) };
         Ok(resp)
     }
 
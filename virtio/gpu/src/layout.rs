@@ -2,6 +2,7 @@ use glenda::cap::{CapPtr, Endpoint};
 
 pub const MMIO_VA: usize = 0x7000_0000;
 pub const RING_VA: usize = 0x7200_0000;
+pub const BAR_VA: usize = 0x7300_0000;
 pub const DMA_VA: usize = 0x7100_0000;
 
 pub const DEVICE_SLOT: CapPtr = CapPtr::from(0x10);
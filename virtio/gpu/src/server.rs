@@ -1,10 +1,9 @@
-use crate::gpu::VirtIOGpu;
+use crate::gpu::{VirtIOGpu, DMA_PAGES};
 use crate::layout::{
-    DMA_SLOT, DMA_VA, IRQ_BADGE, IRQ_NOTIFY_CAP, IRQ_NOTIFY_SLOT, IRQ_SLOT, MMIO_SLOT, MMIO_VA,
-    RING_SLOT, RING_VA,
+    BAR_VA, DMA_SLOT, DMA_VA, IRQ_BADGE, IRQ_NOTIFY_CAP, IRQ_NOTIFY_SLOT, IRQ_SLOT, MMIO_SLOT,
+    MMIO_VA, RING_SLOT, RING_VA,
 };
 use alloc::string::String;
-use core::ptr::NonNull;
 use glenda::cap::{CapPtr, CapType, Endpoint, IrqHandler, Page, Reply, Rights, CSPACE_CAP};
 use glenda::client::{DeviceClient, ResourceClient};
 use glenda::drivers::protocol::{fb, FB_PROTO};
@@ -17,7 +16,6 @@ use glenda::ipc::server::{handle_call, handle_cap_call, handle_notify};
 use glenda::ipc::{Badge, MsgTag, UTCB};
 use glenda::protocol::device::LogicDeviceDesc;
 use glenda::utils::manager::{CSpaceManager, VSpaceManager};
-use virtio_common::VirtIOTransport;
 
 pub struct GpuService<'a> {
     pub dev: &'a mut DeviceClient,
@@ -94,17 +92,16 @@ impl<'a> GpuService<'a> {
 impl<'a> SystemService for GpuService<'a> {
     fn init(&mut self) -> Result<(), Error> {
         log!("Driver init...");
-        // 1. Get and map MMIO
-        let (mmio, _pa, _size) = self.dev.get_mmio(Badge::null(), 0, MMIO_SLOT)?;
-        self.vspace_mgr.map_page(
-            mmio,
-            MMIO_VA,
-            glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
-            1,
+        // 1. Map the device and set up its VirtIO transport
+        let transport = virtio_common::map_device(
+            self.dev,
             self.res,
             self.cspace_mgr,
+            self.vspace_mgr,
+            MMIO_SLOT,
+            MMIO_VA,
+            BAR_VA,
         )?;
-        glenda::arch::sync::fence();
 
         // 2. Get and setup IRQ
         let irq_handler = self.dev.get_irq(Badge::null(), 0, IRQ_SLOT)?;
@@ -117,19 +114,13 @@ impl<'a> SystemService for GpuService<'a> {
         irq_handler.set_notification(IRQ_NOTIFY_CAP)?;
         self.irq = Some(irq_handler);
 
-        // 3. Setup VirtIO transport
-        let transport = unsafe {
-            VirtIOTransport::new(NonNull::new(MMIO_VA as *mut u8).expect("MMIO_VA is null"))
-                .expect("Failed to init transport")
-        };
-
-        // 4. Allocate and map DMA memory for command buffers and queues
-        let (paddr, frame) = self.res.dma_alloc(Badge::null(), 4, DMA_SLOT)?;
+        // 3. Allocate and map DMA memory for command buffers and queues
+        let (paddr, frame) = self.res.dma_alloc(Badge::null(), DMA_PAGES, DMA_SLOT)?;
         self.vspace_mgr.map_page(
             frame,
             DMA_VA,
             glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
-            4,
+            DMA_PAGES,
             self.res,
             self.cspace_mgr,
         )?;
@@ -137,7 +128,7 @@ impl<'a> SystemService for GpuService<'a> {
 
         let mut gpu = VirtIOGpu::new(transport, DMA_VA as *mut u8, paddr as usize);
 
-        // 5. Initialize GPU hardware
+        // 4. Initialize GPU hardware
         gpu.init()?;
         glenda::arch::sync::fence();
 
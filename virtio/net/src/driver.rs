@@ -1,5 +1,5 @@
-use crate::layout::{DMA_SLOT, DMA_VA, IRQ_EP, IRQ_EP_SLOT, IRQ_SLOT, MMIO_SLOT, MMIO_VA};
-use crate::net::VirtIONet;
+use crate::layout::{BAR_VA, DMA_SLOT, DMA_VA, IRQ_EP, IRQ_EP_SLOT, IRQ_SLOT, MMIO_SLOT, MMIO_VA};
+use crate::net::{VirtIONet, DMA_PAGES};
 use crate::NetService;
 use alloc::string::String;
 use glenda::cap::{Rights, CSPACE_CAP};
@@ -13,16 +13,14 @@ impl DriverService for NetService<'_> {
     fn init(&mut self) -> Result<(), Error> {
         log!("Driver init...");
 
-        let (mmio, pa, size) = self.dev.get_mmio(Badge::null(), 0, MMIO_SLOT)?;
-        log!("Got MMIO cap: addr={:#x}, size={:#x}", pa, size);
-
-        self.vspace_mgr.map_page(
-            mmio,
-            MMIO_VA,
-            glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
-            1,
+        let transport = virtio_common::map_device(
+            self.dev,
             self.res,
             self.cspace_mgr,
+            self.vspace_mgr,
+            MMIO_SLOT,
+            MMIO_VA,
+            BAR_VA,
         )?;
 
         let irq_badge = Badge::new(1);
@@ -33,17 +31,17 @@ impl DriverService for NetService<'_> {
         CSPACE_CAP.mint_self(self.endpoint.cap(), IRQ_EP_SLOT, irq_badge, Rights::ALL)?;
         irq.set_notification(IRQ_EP)?;
 
-        let (paddr, frame) = self.res.dma_alloc(Badge::null(), 4, DMA_SLOT)?;
+        let (paddr, frame) = self.res.dma_alloc(Badge::null(), DMA_PAGES, DMA_SLOT)?;
         self.vspace_mgr.map_page(
             frame,
             DMA_VA,
             glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
-            4,
+            DMA_PAGES,
             self.res,
             self.cspace_mgr,
         )?;
 
-        let mut net = unsafe { VirtIONet::new(MMIO_VA).map_err(|_| Error::Generic)? };
+        let mut net = VirtIONet::new(transport).map_err(|_| Error::Generic)?;
 
         net.init(DMA_VA as *mut u8, paddr as usize, self.endpoint).map_err(|_| Error::Generic)?;
         glenda::arch::sync::fence();
@@ -13,6 +13,9 @@ pub const IRQ_CAP: IrqHandler = IrqHandler::from(IRQ_SLOT);
 pub const IRQ_EP: Endpoint = Endpoint::from(IRQ_EP_SLOT);
 
 pub const MMIO_VA: usize = 0x4000_0000;
+pub const BAR_VA: usize = 0x4100_0000;
 pub const DMA_VA: usize = 0x5000_0000;
 pub const RING_VA: usize = 0x6000_0000;
 pub const SHM_VA: usize = 0x7000_0000;
+/// Each session's packet window is mapped at SHM_VA + session * SHM_STRIDE.
+pub const SHM_STRIDE: usize = 0x0100_0000;
@@ -1,76 +1,432 @@
-use core::ptr::NonNull;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
 use glenda::cap::{Endpoint, Page};
 use glenda::io::uring::{self as io_uring, IoUringServer};
 use glenda::mem::shm::SharedMemory;
 use virtio_common::consts::*;
-use virtio_common::queue::{Descriptor, VirtQueue, DESC_F_NEXT, DESC_F_WRITE};
-use virtio_common::{Result, VirtIOError, VirtIOTransport};
-
-pub const VIRTIO_NET_F_MAC: usize = 5;
-pub const VIRTIO_NET_F_MRG_RXBUF: usize = 15;
-
-#[repr(C)]
-#[derive(Debug, Default, Clone, Copy)]
-pub struct VirtioNetHdr {
-    pub flags: u8,
-    pub gso_type: u8,
-    pub hdr_len: u16,
-    pub gso_size: u16,
-    pub csum_start: u16,
-    pub csum_offset: u16,
+use virtio_common::indirect::IndirectPool;
+use virtio_common::net::*;
+use virtio_common::queue::{Queue, Segment};
+use virtio_common::{Result, Transport, VirtIOError};
+
+// DMA layout, per queue pair: page 0 TX headers, pages 1-2/3-4 RX/TX rings (two pages
+// each so the legacy layout fits), page 5 TX indirect tables, then the receive buffer
+// pool. After the pairs come the control ring and a page for the control command in
+// flight.
+const PAIR_DMA_PAGES: usize = 6 + RX_BUFFERS * RX_BUF_SIZE / 4096;
+pub const DMA_PAGES: usize = CTRL_BUF_OFFSET / 4096 + 1;
+const RING_PAGES: usize = 2;
+const RX_RING_OFFSET: usize = 4096;
+const INDIRECT_OFFSET: usize = 5 * 4096;
+const RX_POOL_OFFSET: usize = 6 * 4096;
+const CTRL_RING_OFFSET: usize = MAX_PAIRS * PAIR_DMA_PAGES * 4096;
+const CTRL_BUF_OFFSET: usize = CTRL_RING_OFFSET + RING_PAGES * 4096;
+/// Offsets of the ack and data in the control command page; the header leads.
+const CTRL_ACK: usize = 8;
+const CTRL_DATA: usize = 16;
+const CTRL_QUEUE_SIZE: u16 = 16;
+/// Used-ring polls before a control command is given up on.
+const CTRL_POLL_LIMIT: usize = 10_000_000;
+/// Receive buffers the driver keeps posted, so frames arriving between reads aren't lost.
+const RX_BUFFERS: usize = 64;
+/// Fits a 1514-byte frame and its header; larger frames need mergeable buffers.
+const RX_BUF_SIZE: usize = 2048;
+/// Descriptors per indirect table: header and payload.
+const INDIRECT_TABLE_LEN: u16 = 2;
+/// Upper bound on queue pairs used with VIRTIO_NET_F_MQ.
+pub const MAX_PAIRS: usize = 4;
+/// Upper bound on client rings; rings are spread across the queue pairs.
+pub const MAX_RINGS: usize = 8;
+/// Upper bound on client sessions (one per badge).
+pub const MAX_SESSIONS: usize = 4;
+/// Longest RSS indirection table sent, if the device takes one that long.
+const RSS_TABLE_LEN: usize = 128;
+/// Device features the driver handles; the rest of what the device offers is declined.
+const NET_FEATURES: u64 = VIRTIO_NET_F_MAC
+    | VIRTIO_NET_F_MRG_RXBUF
+    | VIRTIO_NET_OFFLOADS
+    | VIRTIO_NET_F_CTRL_VQ
+    | VIRTIO_NET_F_CTRL_RX
+    | VIRTIO_NET_F_CTRL_VLAN
+    | VIRTIO_NET_F_CTRL_MAC_ADDR
+    | VIRTIO_NET_F_STATUS
+    | VIRTIO_NET_F_MTU
+    | VIRTIO_NET_F_GUEST_ANNOUNCE
+    | VIRTIO_NET_F_MQ
+    | VIRTIO_NET_F_RSS;
+
+/// Per-client state, keyed by the client's badge.
+pub struct NetSession {
+    pub badge: usize,
+    /// The client's packet window; SQE addresses are checked against it.
+    pub buffer: Option<SharedMemory>,
+    /// Maximum SQEs in flight, `None` for no limit.
+    pub quota: Option<usize>,
+    pub inflight: usize,
+    /// The rings use the NET_RING_OFFLOAD encodings of SQE `off` and CQE results.
+    pub offload: bool,
+    /// The first ring gets NET_EVENT_LINK CQEs.
+    pub events: bool,
+}
+
+pub struct NetRing {
+    pub server: IoUringServer,
+    /// Queue pair this ring's reads and writes go to.
+    pub pair: usize,
+    /// Session that owns the ring.
+    pub session: usize,
+}
+
+/// A buffer the device holds for a ring's SQE.
+#[derive(Clone, Copy)]
+pub struct Pending {
+    pub ring: usize,
+    pub user_data: usize,
+    pub token: u16,
+    /// Client address of the packet.
+    pub addr: usize,
+}
+
+/// A READ SQE waiting for a received frame.
+#[derive(Clone, Copy)]
+pub struct RxRead {
+    pub ring: usize,
+    pub user_data: usize,
+    /// Client address and length of the read buffer.
+    pub addr: usize,
+    pub len: usize,
+}
+
+/// One receive/transmit virtqueue pair with its receive buffer pool.
+pub struct NetPair {
+    pub rx: Box<dyn Queue>,
+    pub tx: Box<dyn Queue>,
+    pub dma_vaddr: *mut u8,
+    pub dma_paddr: usize,
+    /// In-flight transmits; the index is also the packet's header slot.
+    pub pending_tx: [Option<Pending>; 128],
+    /// Token of each pool buffer the device holds.
+    pub rx_posted: [Option<u16>; RX_BUFFERS],
+    /// Pool buffers the device filled and their used lengths, in the order it used them.
+    pub rx_filled: VecDeque<(usize, usize)>,
+    /// READ SQEs waiting for a frame, oldest first.
+    pub rx_waiting: VecDeque<RxRead>,
+}
+
+impl NetPair {
+    /// Hands pool buffer `index` to the device.
+    fn post_rx(&mut self, index: usize) -> Result<()> {
+        let paddr = self.dma_paddr + RX_POOL_OFFSET + index * RX_BUF_SIZE;
+        let token = self
+            .rx
+            .submit_sg(&[Segment::writable(paddr, RX_BUF_SIZE as u32)])
+            .ok_or(VirtIOError::OOM)?;
+        self.rx_posted[index] = Some(token);
+        Ok(())
+    }
+
+    fn rx_buffer(&self, index: usize) -> *mut u8 {
+        unsafe { self.dma_vaddr.add(RX_POOL_OFFSET + index * RX_BUF_SIZE) }
+    }
 }
 
 pub struct VirtIONet {
-    transport: VirtIOTransport,
+    transport: Box<dyn Transport>,
     mac: [u8; 6],
-    pub rx_queue: Option<VirtQueue>,
-    pub tx_queue: Option<VirtQueue>,
+    /// VIRTIO_NET_S_LINK_UP as last reported to clients.
+    link: u16,
+    /// Config space as read at init.
+    pub config: Option<VirtioNetConfig>,
+    pub features: u64,
+    pub pairs: Vec<NetPair>,
+    /// Pairs the device delivers to and takes from; the first ones of `pairs`.
+    pub active_pairs: usize,
+    pub rings: Vec<NetRing>,
+    pub ctrl_queue: Option<Box<dyn Queue>>,
+    /// Hash types and key of the RSS configuration a client asked for.
+    pub rss: Option<(u32, Vec<u8>)>,
     pub dma_vaddr: *mut u8,
     pub dma_paddr: usize,
-    pub pending_rx: [Option<(usize, u16)>; 128],
-    pub pending_tx: [Option<(usize, u16)>; 128],
-    pub ring_server: Option<IoUringServer>,
+    /// Length of the header in front of every packet.
+    pub hdr_len: usize,
     pub endpoint: Option<Endpoint>,
-    pub buffer: Option<SharedMemory>,
+    pub sessions: Vec<NetSession>,
 }
 
 impl VirtIONet {
-    pub unsafe fn new(base_addr: usize) -> Result<Self> {
-        let base = NonNull::new(base_addr as *mut u8).ok_or(VirtIOError::DeviceNotFound)?;
-        let transport = VirtIOTransport::new(base)?;
-
-        if transport.get_device_id() != DEV_ID_NET {
-            log!("Unmatched device ID: {:#x}", transport.get_device_id());
+    pub fn new(transport: Box<dyn Transport>) -> Result<Self> {
+        if transport.device_id() != DEV_ID_NET {
+            log!("Unmatched device ID: {:#x}", transport.device_id());
             return Err(VirtIOError::DeviceNotFound);
         }
 
         Ok(Self {
             transport,
             mac: [0u8; 6],
-            rx_queue: None,
-            tx_queue: None,
+            link: 0,
+            config: None,
+            features: 0,
+            pairs: Vec::new(),
+            active_pairs: 1,
+            rings: Vec::new(),
+            ctrl_queue: None,
+            rss: None,
             dma_vaddr: core::ptr::null_mut(),
             dma_paddr: 0,
-            pending_rx: [None; 128],
-            pending_tx: [None; 128],
-            ring_server: None,
+            hdr_len: 0,
             endpoint: None,
-            buffer: None,
+            sessions: Vec::new(),
         })
     }
 
+    /// Index of the session for `badge`, opening one on first contact.
+    pub fn session(&mut self, badge: usize) -> core::result::Result<usize, glenda::error::Error> {
+        if let Some(idx) = self.sessions.iter().position(|s| s.badge == badge) {
+            return Ok(idx);
+        }
+        if self.sessions.len() >= MAX_SESSIONS {
+            return Err(glenda::error::Error::OutOfMemory);
+        }
+        self.sessions.push(NetSession {
+            badge,
+            buffer: None,
+            quota: None,
+            inflight: 0,
+            offload: false,
+            events: false,
+        });
+        log!("Session {} opened for badge {:#x}", self.sessions.len() - 1, badge);
+        Ok(self.sessions.len() - 1)
+    }
+
+    pub fn set_quota(
+        &mut self,
+        badge: usize,
+        quota: Option<usize>,
+    ) -> core::result::Result<(), glenda::error::Error> {
+        let idx = self.session(badge)?;
+        self.sessions[idx].quota = quota;
+        Ok(())
+    }
+
+    /// Switches the session of `badge` to the offload encodings.
+    pub fn set_offload(&mut self, badge: usize) -> core::result::Result<(), glenda::error::Error> {
+        let idx = self.session(badge)?;
+        self.sessions[idx].offload = true;
+        Ok(())
+    }
+
+    /// Has the session of `badge` told about link changes.
+    pub fn set_events(&mut self, badge: usize) -> core::result::Result<(), glenda::error::Error> {
+        let idx = self.session(badge)?;
+        self.sessions[idx].events = true;
+        Ok(())
+    }
+
+    /// Current NET_LINK_* bits and the MTU.
+    pub fn link(&self) -> (usize, usize) {
+        let status = VirtioNetConfig::read(self.transport.as_ref(), self.features).status;
+        let link = if status & VIRTIO_NET_S_LINK_UP != 0 { NET_LINK_UP } else { 0 };
+        let mtu = self.config.map_or(VIRTIO_NET_DEFAULT_MTU, |c| c.mtu);
+        (link, mtu as usize)
+    }
+
+    /// Queue pairs the driver set up and how many of them the device uses.
+    pub fn queue_pairs(&self) -> (usize, usize) {
+        (self.pairs.len(), self.active_pairs)
+    }
+
+    /// Turns promiscuous and all-multicast reception on or off, as NET_RX_* bits.
+    pub fn set_rx_mode(&mut self, mode: usize) -> core::result::Result<(), glenda::error::Error> {
+        self.require(VIRTIO_NET_F_CTRL_RX)?;
+        let promisc = (mode & NET_RX_PROMISC != 0) as u8;
+        let allmulti = (mode & NET_RX_ALLMULTI != 0) as u8;
+        self.ctrl(VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_PROMISC, &[promisc])?;
+        self.ctrl(VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_ALLMULTI, &[allmulti])?;
+        log!("RX mode: promisc={}, allmulti={}", promisc, allmulti);
+        Ok(())
+    }
+
+    /// Changes the address the device sends from and filters for.
+    pub fn set_mac(&mut self, mac: [u8; 6]) -> core::result::Result<(), glenda::error::Error> {
+        self.require(VIRTIO_NET_F_CTRL_MAC_ADDR)?;
+        self.ctrl(VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_ADDR_SET, &mac)?;
+        self.mac = mac;
+        log!("MAC set to {:02x?}", mac);
+        Ok(())
+    }
+
+    /// Starts or stops receiving frames tagged with VLAN `vid`.
+    pub fn set_vlan(
+        &mut self,
+        vid: u16,
+        add: bool,
+    ) -> core::result::Result<(), glenda::error::Error> {
+        self.require(VIRTIO_NET_F_CTRL_VLAN)?;
+        if vid >= 4096 {
+            return Err(glenda::error::Error::InvalidArgs);
+        }
+        let command = if add { VIRTIO_NET_CTRL_VLAN_ADD } else { VIRTIO_NET_CTRL_VLAN_DEL };
+        self.ctrl(VIRTIO_NET_CTRL_VLAN, command, &vid.to_le_bytes())
+    }
+
+    /// Replaces the extra addresses received for: `macs` holds 6-byte addresses,
+    /// the first `unicast` of them unicast and the rest multicast.
+    pub fn set_mac_table(
+        &mut self,
+        macs: &[u8],
+        unicast: usize,
+    ) -> core::result::Result<(), glenda::error::Error> {
+        self.require(VIRTIO_NET_F_CTRL_RX)?;
+        let mut data = vec![0u8; 8 + macs.len()];
+        let len = mac_table(&mut data, macs, unicast).ok_or(glenda::error::Error::InvalidArgs)?;
+        self.ctrl(VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET, &data[..len])
+    }
+
+    /// Spreads received flows across the active pairs by hashing the headers
+    /// `hash_types` names with `key`, as VIRTIO_NET_RSS_HASH_TYPE_* bits.
+    pub fn set_rss(
+        &mut self,
+        hash_types: u32,
+        key: &[u8],
+    ) -> core::result::Result<(), glenda::error::Error> {
+        self.require(VIRTIO_NET_F_RSS)?;
+        let config = self.config.ok_or(glenda::error::Error::NotInitialized)?;
+        if hash_types & !config.supported_hash_types != 0
+            || key.len() > config.rss_max_key_size as usize
+        {
+            return Err(glenda::error::Error::InvalidArgs);
+        }
+        self.send_rss(hash_types, key, self.active_pairs)?;
+        self.rss = Some((hash_types, key.to_vec()));
+        log!("RSS: hash types {:#x} over {} pairs", hash_types, self.active_pairs);
+        Ok(())
+    }
+
+    /// Has the device use the first `pairs` queue pairs.
+    fn set_active_pairs(&mut self, pairs: usize) -> core::result::Result<(), glenda::error::Error> {
+        self.require(VIRTIO_NET_F_MQ)?;
+        // Under RSS the indirection table and max_tx_vq decide the pairs in use
+        if let Some((hash_types, key)) = self.rss.take() {
+            let res = self.send_rss(hash_types, &key, pairs);
+            self.rss = Some((hash_types, key));
+            res?;
+        } else {
+            self.ctrl(
+                VIRTIO_NET_CTRL_MQ,
+                VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET,
+                &(pairs as u16).to_le_bytes(),
+            )?;
+        }
+        self.active_pairs = pairs;
+        log!("{} queue pairs active", pairs);
+        Ok(())
+    }
+
+    /// Sends an RSS configuration spreading flows evenly over the first `pairs` pairs.
+    fn send_rss(
+        &mut self,
+        hash_types: u32,
+        key: &[u8],
+        pairs: usize,
+    ) -> core::result::Result<(), glenda::error::Error> {
+        let config = self.config.ok_or(glenda::error::Error::NotInitialized)?;
+        // The table length must be a power of two the device can hold
+        let max_len = (config.rss_max_indirection_table_length as usize).clamp(1, RSS_TABLE_LEN);
+        let table_len = 1 << max_len.ilog2();
+        let table: Vec<u16> = (0..table_len).map(|i| (i % pairs.max(1)) as u16).collect();
+        let mut data = vec![0u8; 11 + table_len * 2 + key.len()];
+        let len = rss_config(&mut data, hash_types, &table, pairs as u16, key)
+            .ok_or(glenda::error::Error::InvalidArgs)?;
+        self.ctrl(VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_RSS_CONFIG, &data[..len])
+    }
+
+    fn require(&self, feature: u64) -> core::result::Result<(), glenda::error::Error> {
+        if self.features & feature == 0 {
+            return Err(glenda::error::Error::NotSupported);
+        }
+        Ok(())
+    }
+
+    /// Runs a control command and waits for the device to ack it.
+    fn ctrl(
+        &mut self,
+        class: u8,
+        command: u8,
+        data: &[u8],
+    ) -> core::result::Result<(), glenda::error::Error> {
+        let queue = self.ctrl_queue.as_mut().ok_or(glenda::error::Error::NotSupported)?;
+        if CTRL_DATA + data.len() > 4096 {
+            return Err(glenda::error::Error::InvalidArgs);
+        }
+        let buf = unsafe { self.dma_vaddr.add(CTRL_BUF_OFFSET) };
+        unsafe {
+            (buf as *mut VirtioNetCtrlHdr).write_volatile(VirtioNetCtrlHdr { class, command });
+            core::ptr::copy_nonoverlapping(data.as_ptr(), buf.add(CTRL_DATA), data.len());
+            buf.add(CTRL_ACK).write_volatile(VIRTIO_NET_ERR);
+        }
+
+        let paddr = self.dma_paddr + CTRL_BUF_OFFSET;
+        let chain = ctrl_chain(paddr, paddr + CTRL_DATA, data.len() as u32, paddr + CTRL_ACK);
+        // Commands without data leave out the empty segment
+        let no_data = [chain[0], chain[2]];
+        let segs: &[Segment] = if data.is_empty() { &no_data } else { &chain };
+        let token = queue.add_chain(segs).ok_or(glenda::error::Error::OutOfMemory)?;
+        if queue.should_notify() {
+            self.transport.notify(queue.index());
+        }
+        // Devices answer control commands right away, so spinning beats a round trip via the IRQ
+        let mut done = false;
+        for _ in 0..CTRL_POLL_LIMIT {
+            match queue.pop_used() {
+                Some((used, _)) if used == token => {
+                    done = true;
+                    break;
+                }
+                Some(_) => {}
+                None => core::hint::spin_loop(),
+            }
+        }
+        if !done {
+            error!("Control command {}/{} timed out", class, command);
+            return Err(glenda::error::Error::IoError);
+        }
+
+        let ack = unsafe { buf.add(CTRL_ACK).read_volatile() };
+        if ack != VIRTIO_NET_OK {
+            warn!("Control command {}/{} refused", class, command);
+            return Err(glenda::error::Error::InvalidArgs);
+        }
+        Ok(())
+    }
+
+    /// Negotiated checksum and segmentation offloads, as VIRTIO_NET_F_* bits.
+    pub fn offloads(&self) -> u64 {
+        self.features & VIRTIO_NET_OFFLOADS
+    }
+
     pub fn setup_shm(
         &mut self,
+        badge: usize,
         frame: Page,
         vaddr: usize,
         paddr: usize,
         size: usize,
     ) -> core::result::Result<(), glenda::error::Error> {
+        let idx = self.session(badge)?;
         let mut shm = SharedMemory::from_frame(frame, vaddr, size);
         shm.set_client_vaddr(vaddr);
         shm.set_paddr(paddr);
-        self.buffer = Some(shm);
-        log!("SHM setup: client_vaddr={:#x}, paddr={:#x}, size={}", vaddr, paddr, size);
+        self.sessions[idx].buffer = Some(shm);
+        log!(
+            "Session {} SHM setup: client_vaddr={:#x}, paddr={:#x}, size={}",
+            idx,
+            vaddr,
+            paddr,
+            size
+        );
         Ok(())
     }
 
@@ -81,202 +437,549 @@ impl VirtIONet {
         self.transport.set_status(0);
         self.transport.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
 
-        let mut device_features = self.transport.get_features();
-        device_features &= !(1 << VIRTIO_NET_F_MRG_RXBUF);
-        self.transport.set_features(device_features);
+        let device_features = self.transport.get_device_features();
+        let mut features = device_features & (VIRTIO_TRANSPORT_FEATURES | NET_FEATURES);
+        // Offloads in either direction build on checksumming in that direction
+        if features & VIRTIO_NET_F_CSUM == 0 {
+            features &= !(VIRTIO_NET_F_HOST_TSO4 | VIRTIO_NET_F_HOST_TSO6);
+        }
+        if features & VIRTIO_NET_F_GUEST_CSUM == 0 {
+            features &= !(VIRTIO_NET_F_GUEST_TSO4 | VIRTIO_NET_F_GUEST_TSO6);
+        }
+        // Merged segments only fit the pool when a frame may span several buffers
+        if features & VIRTIO_NET_F_MRG_RXBUF == 0 {
+            features &= !(VIRTIO_NET_F_GUEST_TSO4 | VIRTIO_NET_F_GUEST_TSO6);
+        }
+        if features & VIRTIO_NET_F_CTRL_VQ == 0 {
+            features &= !(VIRTIO_NET_F_CTRL_RX
+                | VIRTIO_NET_F_CTRL_VLAN
+                | VIRTIO_NET_F_CTRL_MAC_ADDR
+                | VIRTIO_NET_F_GUEST_ANNOUNCE
+                | VIRTIO_NET_F_MQ
+                | VIRTIO_NET_F_RSS);
+        }
+        // RSS is only set up over the pairs VIRTIO_NET_F_MQ brings
+        if features & VIRTIO_NET_F_MQ == 0 {
+            features &= !VIRTIO_NET_F_RSS;
+        }
+        self.transport.set_driver_features(features);
 
-        self.transport.add_status(STATUS_FEATURES_OK);
-        if (self.transport.get_status() & STATUS_FEATURES_OK) == 0 {
+        if !self.transport.set_features_ok() {
+            error!("Device rejected features {:#x}", features);
             return Err(VirtIOError::InvalidHeader);
         }
+        self.features = features;
+        self.hdr_len = net_hdr_len(features);
+        log!("Offloads: {:#x}", self.offloads());
+
+        let config = VirtioNetConfig::read(self.transport.as_ref(), features);
+        self.config = Some(config);
+        let device_pairs = config.max_virtqueue_pairs.max(1) as usize;
+        let pairs = if features & VIRTIO_NET_F_MQ != 0 { device_pairs.min(MAX_PAIRS) } else { 1 };
+
+        for k in 0..pairs {
+            let pair_paddr = dma_paddr + k * PAIR_DMA_PAGES * 4096;
+            let pair_vaddr = unsafe { dma_vaddr.add(k * PAIR_DMA_PAGES * 4096) };
+
+            let rx_paddr = pair_paddr + RX_RING_OFFSET;
+            let rx_vaddr = unsafe { pair_vaddr.add(RX_RING_OFFSET) };
+            let (rx_size, tx_size) = match (
+                self.transport.queue_size(2 * k as u32, 128),
+                self.transport.queue_size(2 * k as u32 + 1, 128),
+            ) {
+                (Some(rx), Some(tx)) => (rx, tx),
+                _ => {
+                    error!("Queue pair {} is not available", k);
+                    return Err(VirtIOError::DeviceNotFound);
+                }
+            };
+            let rx = unsafe {
+                self.transport.create_queue(features, 2 * k as u32, rx_size, rx_paddr, rx_vaddr)
+            };
 
-        // Use Page 2+ for VirtQueues
-        let rx_paddr = dma_paddr + 8192;
-        let rx_vaddr = unsafe { dma_vaddr.add(8192) };
-        let rx_queue = unsafe { VirtQueue::new(0, 128, rx_paddr, rx_vaddr) };
-        unsafe { self.transport.setup_queue(&rx_queue) };
-        self.rx_queue = Some(rx_queue);
+            let tx_paddr = rx_paddr + RING_PAGES * 4096;
+            let tx_vaddr = unsafe { rx_vaddr.add(RING_PAGES * 4096) };
+            let mut tx = unsafe {
+                self.transport.create_queue(features, 2 * k as u32 + 1, tx_size, tx_paddr, tx_vaddr)
+            };
 
-        let tx_paddr = rx_paddr + 4096;
-        let tx_vaddr = unsafe { rx_vaddr.add(4096) };
-        let tx_queue = unsafe { VirtQueue::new(1, 128, tx_paddr, tx_vaddr) };
-        unsafe { self.transport.setup_queue(&tx_queue) };
-        self.tx_queue = Some(tx_queue);
+            // Receive buffers are single descriptors; only transmits go indirect
+            if features & VIRTIO_F_INDIRECT_DESC != 0 {
+                let pool = IndirectPool::new(
+                    unsafe { pair_vaddr.add(INDIRECT_OFFSET) },
+                    pair_paddr + INDIRECT_OFFSET,
+                    INDIRECT_TABLE_LEN,
+                );
+                tx.set_indirect_pool(pool);
+            }
 
-        let mac_ptr = unsafe { self.transport.config_ptr() };
-        for i in 0..6 {
-            self.mac[i] = unsafe { core::ptr::read_volatile(mac_ptr.add(i)) };
+            unsafe { self.transport.setup_queue(rx.as_ref()) };
+            unsafe { self.transport.setup_queue(tx.as_ref()) };
+            self.pairs.push(NetPair {
+                rx,
+                tx,
+                dma_vaddr: pair_vaddr,
+                dma_paddr: pair_paddr,
+                pending_tx: [None; 128],
+                rx_posted: [None; RX_BUFFERS],
+                rx_filled: VecDeque::new(),
+                rx_waiting: VecDeque::new(),
+            });
+        }
+        if features & VIRTIO_F_INDIRECT_DESC != 0 {
+            log!("Using indirect descriptors");
+        }
+        if features & VIRTIO_F_RING_PACKED != 0 {
+            log!("Using packed virtqueues");
+        }
+
+        if features & VIRTIO_NET_F_CTRL_VQ != 0 {
+            // The control queue follows all the pairs the device has, used or not
+            let index = if features & VIRTIO_NET_F_MQ != 0 { 2 * device_pairs as u32 } else { 2 };
+            let Some(size) = self.transport.queue_size(index, CTRL_QUEUE_SIZE) else {
+                error!("Control queue {} is not available", index);
+                return Err(VirtIOError::DeviceNotFound);
+            };
+            let mut ctrl_queue = unsafe {
+                self.transport.create_queue(
+                    features,
+                    index,
+                    size,
+                    dma_paddr + CTRL_RING_OFFSET,
+                    dma_vaddr.add(CTRL_RING_OFFSET),
+                )
+            };
+            // Commands are waited for by polling
+            ctrl_queue.disable_cb();
+            unsafe { self.transport.setup_queue(ctrl_queue.as_ref()) };
+            self.ctrl_queue = Some(ctrl_queue);
+            log!("Control queue ready");
+        }
+
+        self.mac = config.mac;
+        self.link = config.status & VIRTIO_NET_S_LINK_UP;
+        log!("Link {}, MTU {}", if self.link != 0 { "up" } else { "down" }, config.mtu);
+        if features & VIRTIO_NET_F_MRG_RXBUF == 0
+            && 14 + config.mtu as usize > RX_BUF_SIZE - self.hdr_len
+        {
+            warn!("MTU {} frames don't fit the receive buffers", config.mtu);
         }
         self.transport.add_status(STATUS_DRIVER_OK);
+
+        // Every pair gets its pool now; the device only fills those it was told to use
+        for pair in self.pairs.iter_mut() {
+            // A ring smaller than the pool leaves the rest of it unused
+            for index in 0..RX_BUFFERS.min(pair.rx.size() as usize) {
+                pair.post_rx(index)?;
+            }
+            kick(self.transport.as_ref(), pair.rx.as_mut());
+        }
+        // The device starts out on the first pair alone
+        self.active_pairs = 1;
+        if features & VIRTIO_NET_F_MRG_RXBUF != 0 {
+            log!("Using mergeable receive buffers");
+        }
+        if features & VIRTIO_NET_F_MQ != 0 {
+            log!("{} of {} queue pairs set up", pairs, device_pairs);
+        }
         Ok(())
     }
 
     pub fn mac(&self) -> [u8; 6] {
         self.mac
     }
-    pub fn set_ring_server(&mut self, server: IoUringServer) {
-        self.ring_server = Some(server);
+
+    /// Opens the session of `badge` and brings up the queue pair its next ring will use,
+    /// so that the ring can be mapped knowing add_ring will take it.
+    pub fn reserve_ring(&mut self, badge: usize) -> core::result::Result<(), glenda::error::Error> {
+        if self.rings.len() >= MAX_RINGS {
+            return Err(glenda::error::Error::OutOfMemory);
+        }
+        self.session(badge)?;
+        let pair = self.rings.len() % self.pairs.len().max(1);
+        if pair >= self.active_pairs {
+            self.set_active_pairs(pair + 1)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a client ring; rings take the queue pairs in turn, enabling each
+    /// pair as its first ring arrives.
+    pub fn add_ring(
+        &mut self,
+        badge: usize,
+        server: IoUringServer,
+    ) -> core::result::Result<usize, glenda::error::Error> {
+        self.reserve_ring(badge)?;
+        let session = self.session(badge)?;
+        let idx = self.rings.len();
+        let pair = idx % self.pairs.len().max(1);
+        self.rings.push(NetRing { server, pair, session });
+        log!("Ring {} (session {}) uses queue pair {}", idx, session, pair);
+        // An announce nobody could be told about is passed to the first events ring
+        if self.sessions[session].events {
+            self.config_changed();
+        }
+        Ok(idx)
     }
     pub fn set_endpoint(&mut self, endpoint: Endpoint) {
         self.endpoint = Some(endpoint);
     }
 
     pub fn handle_ring(&mut self) {
+        let mut submitted = [false; MAX_PAIRS];
+        for ring in 0..self.rings.len() {
+            if self.handle_client_ring(ring) {
+                submitted[self.rings[ring].pair] = true;
+            }
+        }
+        for pair in 0..self.pairs.len() {
+            if submitted[pair] {
+                // New reads may take frames already waiting in the pool
+                self.deliver_rx(pair);
+                kick(self.transport.as_ref(), self.pairs[pair].tx.as_mut());
+            }
+        }
+    }
+
+    /// Takes the pending SQEs of one ring; returns whether any were taken.
+    fn handle_client_ring(&mut self, ring: usize) -> bool {
         let mut sqes = [io_uring::IoUringSqe::default(); 16];
         let mut count = 0;
 
-        if let Some(server) = self.ring_server.as_mut() {
-            while count < 16 {
-                if let Some(sqe) = server.next_request() {
-                    sqes[count] = sqe;
-                    count += 1;
-                } else {
-                    break;
-                }
+        let r = &mut self.rings[ring];
+        let sess = &mut self.sessions[r.session];
+        // Past its quota a session's requests stay in its rings until completions come in
+        let budget = match sess.quota {
+            Some(quota) => core::cmp::min(quota.saturating_sub(sess.inflight), 16),
+            None => 16,
+        };
+        while count < budget {
+            if let Some(sqe) = r.server.next_request() {
+                sqes[count] = sqe;
+                count += 1;
+            } else {
+                break;
             }
-        } else {
-            return;
         }
+        sess.inflight += count;
 
-        for i in 0..count {
-            let sqe = sqes[i];
+        for &sqe in &sqes[..count] {
             let res = match sqe.opcode {
-                io_uring::IOURING_OP_READ => self.submit(0, sqe),
-                io_uring::IOURING_OP_WRITE => self.submit(1, sqe),
+                io_uring::IOURING_OP_READ => self.queue_read(ring, sqe),
+                io_uring::IOURING_OP_WRITE => self.submit(ring, sqe),
                 _ => Err(VirtIOError::DeviceNotFound),
             };
             if res.is_err() {
-                if let Some(server) = self.ring_server.as_mut() {
-                    let _ = server.complete(sqe.user_data, -1);
-                }
+                complete_sqe(&mut self.rings, &mut self.sessions, ring, sqe.user_data, -1);
             }
         }
+
+        count > 0
     }
 
-    fn submit(&mut self, qidx: u32, sqe: io_uring::IoUringSqe) -> Result<()> {
-        let queue = if qidx == 0 { self.rx_queue.as_mut() } else { self.tx_queue.as_mut() }
-            .ok_or(VirtIOError::DeviceNotFound)?;
+    /// Queues a READ SQE for the next frame received on the ring's pair.
+    fn queue_read(&mut self, ring: usize, sqe: io_uring::IoUringSqe) -> Result<()> {
+        let NetRing { session, pair, .. } = self.rings[ring];
+        // Frames are copied out of the pool, so the buffer must lie in the packet window
+        if local_frame(&mut self.sessions[session], sqe.addr, sqe.len as usize).is_none() {
+            error!("Address {:#x} out of SHM boundary", sqe.addr);
+            return Err(VirtIOError::InvalidHeader);
+        }
+        self.pairs[pair].rx_waiting.push_back(RxRead {
+            ring,
+            user_data: sqe.user_data,
+            addr: sqe.addr,
+            len: sqe.len as usize,
+        });
+        Ok(())
+    }
 
-        let d1 = queue.alloc_desc().ok_or(VirtIOError::OOM)?;
-        let d2 = queue.alloc_desc().ok_or(VirtIOError::OOM)?;
+    /// Copies frames received on `pair` into its waiting READ buffers, oldest
+    /// first, and gives the emptied pool buffers back to the device.
+    fn deliver_rx(&mut self, pair: usize) {
+        let p = &mut self.pairs[pair];
+        let mut reposted = false;
+        while let Some(&(first, _)) = p.rx_filled.front() {
+            let hdr =
+                unsafe { (p.rx_buffer(first) as *const VirtioNetHdrMrgRxbuf).read_volatile() };
+            // The device marks all buffers of a frame used together
+            let count = rx_num_buffers(&hdr, self.features).min(RX_BUFFERS);
+            if p.rx_filled.len() < count {
+                break;
+            }
+            let Some(read) = p.rx_waiting.pop_front() else {
+                break;
+            };
+            let session = self.rings.get(read.ring).map(|r| r.session);
+            let result = match session.and_then(|s| self.sessions.get_mut(s)) {
+                Some(sess) => copy_frame(p, sess, self.hdr_len, &read, count, &hdr.hdr),
+                None => -1,
+            };
+            complete_sqe(&mut self.rings, &mut self.sessions, read.ring, read.user_data, result);
 
-        let data_paddr = if let Some(ref shm) = self.buffer {
-            let client_vaddr = shm.client_vaddr();
-            let paddr = shm.paddr();
-            let size = shm.size();
-            if (sqe.addr as usize) < client_vaddr
-                || (sqe.addr as usize) + sqe.len as usize > client_vaddr + size
-            {
-                error!("Address {:#x} out of SHM boundary", sqe.addr);
-                return Err(VirtIOError::InvalidHeader);
+            for _ in 0..count {
+                let (index, _) = p.rx_filled.pop_front().unwrap();
+                if p.post_rx(index).is_err() {
+                    error!("Failed to repost receive buffer {} of pair {}", index, pair);
+                }
             }
-            paddr + (sqe.addr as usize - client_vaddr as usize)
+            reposted = true;
+        }
+        if reposted {
+            kick(self.transport.as_ref(), p.rx.as_mut());
+        }
+    }
+
+    fn submit(&mut self, ring: usize, sqe: io_uring::IoUringSqe) -> Result<()> {
+        let NetRing { session, pair, .. } = self.rings[ring];
+        let p = &mut self.pairs[pair];
+        let slot = p.pending_tx.iter().position(|p| p.is_none()).ok_or(VirtIOError::OOM)?;
+
+        let hdr = if self.sessions[session].offload {
+            tx_header(&mut self.sessions[session], self.features, &sqe)?
         } else {
-            sqe.addr
+            VirtioNetHdr::default()
         };
 
-        // Page 0 (DMA_VA) for RX headers, Page 1 for TX headers.
-        // Each page can hold up to 128 headers (approx 12 bytes each).
-        let hdr_paddr = self.dma_paddr + (qidx as usize * 4096) + (d1 as usize * 16);
-        let hdr_vaddr = unsafe { self.dma_vaddr.add(qidx as usize * 4096).add(d1 as usize * 16) };
+        // Packets must lie in the session's window; raw addresses aren't taken
+        let shm = self.sessions[session].buffer.as_ref().ok_or(VirtIOError::InvalidHeader)?;
+        let offset = sqe
+            .addr
+            .checked_sub(shm.client_vaddr())
+            .filter(|off| off.checked_add(sqe.len as usize).is_some_and(|end| end <= shm.size()));
+        let Some(offset) = offset else {
+            error!("Address {:#x} out of SHM boundary", sqe.addr);
+            return Err(VirtIOError::InvalidHeader);
+        };
+        let data_paddr = shm.paddr() + offset;
 
-        // Initialize header
+        // The pair's first page holds the TX headers, one 16-byte slot per pending packet
+        let hdr_paddr = p.dma_paddr + slot * 16;
+        let hdr_vaddr = unsafe { p.dma_vaddr.add(slot * 16) };
         unsafe {
-            let hdr = hdr_vaddr as *mut VirtioNetHdr;
-            hdr.write_volatile(VirtioNetHdr::default());
-        }
-
-        // Desc 1: Header
-        queue.write_desc(
-            d1,
-            Descriptor {
-                addr: hdr_paddr,
-                len: core::mem::size_of::<VirtioNetHdr>() as u32,
-                flags: if qidx == 0 { DESC_F_NEXT | DESC_F_WRITE } else { DESC_F_NEXT },
-                next: d2,
-            },
-        );
-
-        // Desc 2: Payload
-        queue.write_desc(
-            d2,
-            Descriptor {
-                addr: data_paddr,
-                len: sqe.len,
-                flags: if qidx == 0 { DESC_F_WRITE } else { 0 },
-                next: 0,
-            },
-        );
-
-        if qidx == 0 {
-            self.pending_rx[d1 as usize] = Some((sqe.user_data, d1));
-        } else {
-            self.pending_tx[d1 as usize] = Some((sqe.user_data, d1));
+            (hdr_vaddr as *mut VirtioNetHdrMrgRxbuf)
+                .write_volatile(VirtioNetHdrMrgRxbuf { hdr, num_buffers: 0 });
         }
 
-        glenda::arch::sync::fence();
-        queue.submit(d1);
-        self.transport.notify(qidx);
+        let segs = net_chain(hdr_paddr, self.hdr_len, data_paddr, sqe.len, false);
+        let token = p.tx.submit_sg(&segs).ok_or(VirtIOError::OOM)?;
+        p.pending_tx[slot] =
+            Some(Pending { ring, user_data: sqe.user_data, token, addr: sqe.addr });
         Ok(())
     }
 
     pub fn handle_irq(&mut self) {
-        if !self.transport.interrupt_ack() {
+        let status = self.transport.ack_interrupt();
+        if status == 0 {
             return;
         }
-
-        let server = match self.ring_server.as_mut() {
-            Some(s) => s,
-            None => return,
-        };
-
-        if let Some(rx) = self.rx_queue.as_mut() {
-            while let Some((idx, len)) = rx.pop() {
-                if let Some((data, head)) = self.pending_rx[idx as usize].take() {
-                    // len includes the header size in mergeable rx buffer or similar?
-                    // Actually, virtio-net-hdr is part of the chain length.
-                    let result_len = if len as usize > core::mem::size_of::<VirtioNetHdr>() {
-                        len - core::mem::size_of::<VirtioNetHdr>() as u32
-                    } else {
-                        0
-                    };
-                    let _ = server.complete(data, result_len as i32);
-
-                    // Free chains
-                    let mut curr = head;
-                    loop {
-                        let row = &rx.desc_table()[curr as usize];
-                        let flags = row.flags;
-                        let next = row.next;
-                        rx.free_desc(curr);
-                        if flags & DESC_F_NEXT == 0 {
-                            break;
-                        }
-                        curr = next;
+        if status & INTERRUPT_CONFIG_CHANGE != 0 {
+            self.config_changed();
+        }
+        let throttled = self.sessions.iter().any(|s| s.quota.map_or(false, |q| s.inflight >= q));
+
+        for pair in 0..self.pairs.len() {
+            let p = &mut self.pairs[pair];
+            loop {
+                p.rx.disable_cb();
+                while let Some((token, len)) = p.rx.pop_used() {
+                    if let Some(index) = p.rx_posted.iter().position(|&t| t == Some(token)) {
+                        p.rx_posted[index] = None;
+                        p.rx_filled.push_back((index, len as usize));
                     }
                 }
+                // The whole pool stays posted, so a delayed re-arm would hold frames back
+                // until most of it filled
+                if p.rx.enable_cb() {
+                    break;
+                }
             }
-        }
-
-        if let Some(tx) = self.tx_queue.as_mut() {
-            while let Some((idx, _)) = tx.pop() {
-                if let Some((data, head)) = self.pending_tx[idx as usize].take() {
-                    let _ = server.complete(data, 0);
-
-                    // Free chains
-                    let mut curr = head;
-                    loop {
-                        let row = &tx.desc_table()[curr as usize];
-                        let flags = row.flags;
-                        let next = row.next;
-                        tx.free_desc(curr);
-                        if flags & DESC_F_NEXT == 0 {
-                            break;
-                        }
-                        curr = next;
+            self.deliver_rx(pair);
+
+            let p = &mut self.pairs[pair];
+            loop {
+                p.tx.disable_cb();
+                while let Some((token, _)) = p.tx.pop_used() {
+                    if let Some(pos) =
+                        p.pending_tx.iter().position(|p| p.map_or(false, |p| p.token == token))
+                    {
+                        let pending = p.pending_tx[pos].take().unwrap();
+                        complete_sqe(
+                            &mut self.rings,
+                            &mut self.sessions,
+                            pending.ring,
+                            pending.user_data,
+                            0,
+                        );
                     }
                 }
+                if p.tx.enable_cb_delayed() {
+                    break;
+                }
+            }
+        }
+
+        if throttled {
+            // Sessions held back by their quota may have requests waiting
+            self.handle_ring();
+        }
+    }
+
+    /// Tells the sessions that asked for events about a link change or an
+    /// announce request, and acks the latter.
+    fn config_changed(&mut self) {
+        let status = VirtioNetConfig::read(self.transport.as_ref(), self.features).status;
+        let link = status & VIRTIO_NET_S_LINK_UP;
+        let announce =
+            self.features & VIRTIO_NET_F_GUEST_ANNOUNCE != 0 && status & VIRTIO_NET_S_ANNOUNCE != 0;
+        if link == self.link && !announce {
+            return;
+        }
+        if link != self.link {
+            log!("Link {}", if link != 0 { "up" } else { "down" });
+            self.link = link;
+        }
+
+        let mut event = if link != 0 { NET_LINK_UP } else { 0 };
+        if announce {
+            event |= NET_LINK_ANNOUNCE;
+        }
+        // One event per session, on its first ring
+        let mut delivered = false;
+        for (idx, _) in self.sessions.iter().enumerate().filter(|(_, s)| s.events) {
+            if let Some(ring) = self.rings.iter_mut().find(|r| r.session == idx) {
+                delivered |= ring.server.complete(NET_EVENT_LINK, event as i32).is_ok();
+            }
+        }
+        // The clients send the gratuitous ARPs; the device only needs to hear it was passed on.
+        // With no one to pass it to it stays pending until an events ring is added.
+        if announce && !delivered {
+            warn!("Announce pending: no events session to pass it to");
+        } else if announce
+            && self.ctrl(VIRTIO_NET_CTRL_ANNOUNCE, VIRTIO_NET_CTRL_ANNOUNCE_ACK, &[]).is_err()
+        {
+            warn!("Announce not acknowledged");
+        }
+    }
+}
+
+/// Rings the doorbell for `queue` if chains were added and the device wants a notification.
+fn kick(transport: &dyn Transport, queue: &mut dyn Queue) {
+    if queue.should_notify() {
+        transport.notify(queue.index());
+    }
+}
+
+/// Posts a CQE on the ring and returns the SQE's slot to its session's quota.
+fn complete_sqe(
+    rings: &mut [NetRing],
+    sessions: &mut [NetSession],
+    ring: usize,
+    user_data: usize,
+    result: i32,
+) {
+    if let Some(r) = rings.get_mut(ring) {
+        let _ = r.server.complete(user_data, result);
+        if let Some(sess) = sessions.get_mut(r.session) {
+            sess.inflight = sess.inflight.saturating_sub(1);
+        }
+    }
+}
+
+/// Copies the frame filling the first `count` filled buffers of `pair` into
+/// `read`'s buffer and returns the CQE result, -1 if it doesn't fit.
+fn copy_frame(
+    pair: &NetPair,
+    sess: &mut NetSession,
+    hdr_len: usize,
+    read: &RxRead,
+    count: usize,
+    hdr: &VirtioNetHdr,
+) -> i32 {
+    let Some(dst) = local_frame(sess, read.addr, read.len) else {
+        return -1;
+    };
+    let mut copied = 0;
+    for (i, &(index, len)) in pair.rx_filled.iter().take(count).enumerate() {
+        // Only the first buffer opens with the header
+        let skip = if i == 0 { hdr_len } else { 0 };
+        let len = len.min(RX_BUF_SIZE).saturating_sub(skip);
+        if copied + len > dst.len() {
+            warn!("Frame dropped: larger than the {}-byte read buffer", read.len);
+            return -1;
+        }
+        let src = unsafe { core::slice::from_raw_parts(pair.rx_buffer(index).add(skip), len) };
+        dst[copied..copied + len].copy_from_slice(src);
+        copied += len;
+    }
+    rx_result(sess, read.addr, copied, hdr)
+}
+
+/// The session's local mapping of `len` bytes at client address `addr`, if it
+/// has a packet window covering them.
+fn local_frame(sess: &mut NetSession, addr: usize, len: usize) -> Option<&mut [u8]> {
+    let shm = sess.buffer.as_ref()?;
+    let offset = addr.checked_sub(shm.client_vaddr())?;
+    if offset.checked_add(len)? > shm.size() {
+        return None;
+    }
+    Some(unsafe { core::slice::from_raw_parts_mut((shm.vaddr() + offset) as *mut u8, len) })
+}
+
+/// Header asking the device for the offloads in a WRITE SQE's `off`.
+fn tx_header(
+    sess: &mut NetSession,
+    features: u64,
+    sqe: &io_uring::IoUringSqe,
+) -> Result<VirtioNetHdr> {
+    let mut hdr = VirtioNetHdr::from_tx_offload(sqe.off as u64);
+    if hdr.required_features() & !features != 0 {
+        error!("Offload {:#x} not negotiated", sqe.off);
+        return Err(VirtIOError::InvalidHeader);
+    }
+    let needs_csum = hdr.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0;
+    let csum_end = hdr.csum_start as usize + hdr.csum_offset as usize + 2;
+    if needs_csum && csum_end > sqe.len as usize {
+        error!("Checksum field at {} beyond packet of {} bytes", csum_end, sqe.len);
+        return Err(VirtIOError::InvalidHeader);
+    }
+    if hdr.gso_type != VIRTIO_NET_HDR_GSO_NONE {
+        // Segmentation rewrites the TCP checksum of every segment
+        if !needs_csum || hdr.gso_size == 0 {
+            return Err(VirtIOError::InvalidHeader);
+        }
+        // hdr_len is a hint: the headers end after the TCP header at csum_start
+        let tcp = hdr.csum_start as usize;
+        if let Some(frame) = local_frame(sess, sqe.addr, sqe.len as usize) {
+            if let Some(&doff) = frame.get(tcp + 12) {
+                hdr.hdr_len = (tcp + (doff >> 4) as usize * 4) as u16;
             }
         }
     }
+    Ok(hdr)
+}
+
+/// CQE result for a received frame. Checksums the device left partial are
+/// completed here, so clients only ever see whole ones.
+fn rx_result(sess: &mut NetSession, addr: usize, frame_len: usize, hdr: &VirtioNetHdr) -> i32 {
+    let mut valid = hdr.flags & VIRTIO_NET_HDR_F_DATA_VALID != 0;
+    if hdr.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+        valid = local_frame(sess, addr, frame_len).is_some_and(|frame| {
+            complete_checksum(frame, hdr.csum_start as usize, hdr.csum_offset as usize)
+        });
+    }
+    if !sess.offload {
+        return frame_len as i32;
+    }
+    let mut result = frame_len as i32 & NET_CQE_LEN_MASK;
+    if valid {
+        result |= NET_CQE_CSUM_VALID;
+    }
+    match hdr.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+        VIRTIO_NET_HDR_GSO_TCPV4 => result |= NET_CQE_GSO_TCPV4,
+        VIRTIO_NET_HDR_GSO_TCPV6 => result |= NET_CQE_GSO_TCPV6,
+        _ => {}
+    }
+    result
 }
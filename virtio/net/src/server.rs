@@ -1,4 +1,4 @@
-use crate::layout::SHM_VA;
+use crate::layout::{RING_VA, SHM_STRIDE, SHM_VA};
 use crate::net::VirtIONet;
 use glenda::cap::{CapPtr, Endpoint, Page, Reply, CSPACE_CAP};
 use glenda::client::{DeviceClient, ResourceClient};
@@ -9,8 +9,13 @@ use glenda::error::Error;
 use glenda::interface::{CSpaceService, ResourceService, SystemService, VSpaceService};
 use glenda::io::uring::{IoUringBuffer as IoUring, IoUringServer};
 use glenda::ipc::server::{handle_call, handle_cap_call, handle_notify};
-use glenda::ipc::{Badge, MsgTag, UTCB};
+use glenda::ipc::{Badge, MsgTag, IPC_BUFFER_SIZE, UTCB};
 use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+use virtio_common::net::{
+    NET_ADD_VLAN, NET_DEL_VLAN, NET_GET_LINK, NET_GET_OFFLOADS, NET_GET_QUEUE_PAIRS,
+    NET_RING_EVENTS, NET_RING_OFFLOAD, NET_SET_MAC, NET_SET_MAC_TABLE, NET_SET_RSS,
+    NET_SET_RX_MODE,
+};
 
 pub struct NetService<'a> {
     pub net: Option<VirtIONet>,
@@ -21,11 +26,23 @@ pub struct NetService<'a> {
     pub cspace_mgr: &'a mut CSpaceManager,
     pub vspace_mgr: &'a mut VSpaceManager,
     pub recv: CapPtr,
-    pub connected_client: Option<usize>,
 }
 
 pub const IRQ_BADGE: Badge = Badge::new(0x1);
 
+/// Badge of the driver's own endpoint. Clients reach it badged through the
+/// device manager, so only the driver's owner calls in unbadged.
+const CONTROL_BADGE: usize = 0;
+
+/// Receive filters, the MAC and RSS apply to every session, so only the
+/// control endpoint may change them.
+fn require_control(badge: usize) -> Result<(), Error> {
+    if badge != CONTROL_BADGE {
+        return Err(Error::PermissionDenied);
+    }
+    Ok(())
+}
+
 impl<'a> NetService<'a> {
     pub fn new(
         dev: &'a mut DeviceClient,
@@ -42,17 +59,23 @@ impl<'a> NetService<'a> {
             cspace_mgr,
             vspace_mgr,
             recv: CapPtr::null(),
-            connected_client: None,
         }
     }
 
     pub fn setup_ring(
         &mut self,
+        badge: usize,
         sq_entries: u32,
         cq_entries: u32,
         notify_ep: Endpoint,
-        _recv: CapPtr,
+        flags: usize,
     ) -> Result<Page, Error> {
+        let net = self.net.as_mut().ok_or(Error::NotInitialized)?;
+        // Everything that can turn the ring down is checked before its page is mapped
+        net.reserve_ring(badge)?;
+        // Each client ring gets its own page after RING_VA
+        let ring_va = RING_VA + net.rings.len() * glenda::arch::mem::PGSIZE;
+
         let slot = self.cspace_mgr.alloc(self.res)?;
         // For 4 entries, we only need a few hundred bytes, so 1 page is plenty.
         let (_, frame) = self.res.dma_alloc(Badge::null(), 1, slot)?;
@@ -60,7 +83,7 @@ impl<'a> NetService<'a> {
         // Map the DMA frame to our virtual address space
         self.vspace_mgr.map_page(
             frame.clone(),
-            crate::layout::RING_VA,
+            ring_va,
             glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
             1,
             self.res,
@@ -69,19 +92,20 @@ impl<'a> NetService<'a> {
         glenda::arch::sync::fence();
 
         let ring = unsafe {
-            IoUring::new(
-                crate::layout::RING_VA as *mut u8,
-                glenda::arch::mem::PGSIZE,
-                sq_entries,
-                cq_entries,
-            )
+            IoUring::new(ring_va as *mut u8, glenda::arch::mem::PGSIZE, sq_entries, cq_entries)
         };
         let mut server = IoUringServer::new(ring);
 
         server.set_client_notify(notify_ep);
 
         if let Some(net) = self.net.as_mut() {
-            net.set_ring_server(server);
+            if flags & NET_RING_OFFLOAD != 0 {
+                net.set_offload(badge)?;
+            }
+            if flags & NET_RING_EVENTS != 0 {
+                net.set_events(badge)?;
+            }
+            net.add_ring(badge, server)?;
         }
 
         Ok(frame)
@@ -89,14 +113,20 @@ impl<'a> NetService<'a> {
 
     pub fn setup_shm(
         &mut self,
+        badge: usize,
         frame: Page,
         vaddr: usize,
         paddr: usize,
         size: usize,
     ) -> Result<(), Error> {
+        let session = self.net.as_mut().ok_or(Error::NotInitialized)?.session(badge)?;
+        if size > SHM_STRIDE {
+            return Err(Error::InvalidArgs);
+        }
+        let shm_va = SHM_VA + session * SHM_STRIDE;
         self.vspace_mgr.map_page(
             frame.clone(),
-            SHM_VA,
+            shm_va,
             glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
             size / 4096,
             self.res,
@@ -104,10 +134,10 @@ impl<'a> NetService<'a> {
         )?;
 
         if let Some(net) = self.net.as_mut() {
-            // Note: net-internal shm will use local SHM_VA for access
-            // but keep the Gopher's vaddr as the client_vaddr to match incoming SQEs
-            net.setup_shm(frame, crate::layout::SHM_VA, paddr, size)?;
-            if let Some(shm) = net.buffer.as_mut() {
+            // Note: net-internal shm will use the local mapping for access
+            // but keep the client's vaddr as the client_vaddr to match incoming SQEs
+            net.setup_shm(badge, frame, shm_va, paddr, size)?;
+            if let Some(shm) = net.sessions[session].buffer.as_mut() {
                 shm.set_client_vaddr(vaddr);
             }
         } else {
@@ -179,12 +209,6 @@ impl<'a> SystemService for NetService<'a> {
 
     fn dispatch(&mut self, utcb: &mut UTCB) -> Result<(), Error> {
         let badge = utcb.get_badge().bits();
-        if badge != 0 && self.connected_client.is_some() && self.connected_client != Some(badge) {
-            let proto = utcb.get_msg_tag().proto();
-            if proto != glenda::protocol::KERNEL_PROTO {
-                return Err(Error::PermissionDenied);
-            }
-        }
 
         glenda::ipc_dispatch! {
             self, utcb,
@@ -225,9 +249,6 @@ impl<'a> SystemService for NetService<'a> {
                 })
             },
             (NET_PROTO, net::GET_MAC) => |s: &mut Self, u: &mut UTCB| {
-                if badge != 0 && s.connected_client.is_none() {
-                    s.connected_client = Some(badge);
-                }
                 let mac = s.mac_address();
                 handle_call(u, |u| {
                     for i in 0..6 {
@@ -236,6 +257,74 @@ impl<'a> SystemService for NetService<'a> {
                     Ok(())
                 })
             },
+            (NET_PROTO, NET_GET_OFFLOADS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_| Ok(s.net.as_ref().map_or(0, |n| n.offloads()) as usize))
+            },
+            (NET_PROTO, NET_GET_LINK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u| {
+                    let (link, mtu) = s.net.as_ref().ok_or(Error::NotInitialized)?.link();
+                    u.set_mr(0, link);
+                    u.set_mr(1, mtu);
+                    Ok(())
+                })
+            },
+            (NET_PROTO, NET_GET_QUEUE_PAIRS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u| {
+                    let (pairs, active) = s.net.as_ref().ok_or(Error::NotInitialized)?.queue_pairs();
+                    u.set_mr(0, pairs);
+                    u.set_mr(1, active);
+                    Ok(())
+                })
+            },
+            (NET_PROTO, NET_SET_RSS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u| {
+                    require_control(badge)?;
+                    let hash_types = u32::try_from(u.get_mr(0)).map_err(|_| Error::InvalidArgs)?;
+                    let len = core::cmp::min(u.get_size(), IPC_BUFFER_SIZE);
+                    let net = s.net.as_mut().ok_or(Error::NotInitialized)?;
+                    net.set_rss(hash_types, &u.ipc_buffer()[..len])
+                })
+            },
+            (NET_PROTO, NET_SET_RX_MODE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u| {
+                    require_control(badge)?;
+                    let mode = u.get_mr(0);
+                    s.net.as_mut().ok_or(Error::NotInitialized)?.set_rx_mode(mode)
+                })
+            },
+            (NET_PROTO, NET_SET_MAC) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u| {
+                    require_control(badge)?;
+                    let mut mac = [0u8; 6];
+                    for (i, octet) in mac.iter_mut().enumerate() {
+                        *octet = u.get_mr(i) as u8;
+                    }
+                    s.net.as_mut().ok_or(Error::NotInitialized)?.set_mac(mac)
+                })
+            },
+            (NET_PROTO, NET_ADD_VLAN) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u| {
+                    require_control(badge)?;
+                    let vid = u16::try_from(u.get_mr(0)).map_err(|_| Error::InvalidArgs)?;
+                    s.net.as_mut().ok_or(Error::NotInitialized)?.set_vlan(vid, true)
+                })
+            },
+            (NET_PROTO, NET_DEL_VLAN) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u| {
+                    require_control(badge)?;
+                    let vid = u16::try_from(u.get_mr(0)).map_err(|_| Error::InvalidArgs)?;
+                    s.net.as_mut().ok_or(Error::NotInitialized)?.set_vlan(vid, false)
+                })
+            },
+            (NET_PROTO, NET_SET_MAC_TABLE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u| {
+                    require_control(badge)?;
+                    let unicast = u.get_mr(0);
+                    let len = core::cmp::min(u.get_size(), IPC_BUFFER_SIZE);
+                    let net = s.net.as_mut().ok_or(Error::NotInitialized)?;
+                    net.set_mac_table(&u.ipc_buffer()[..len], unicast)
+                })
+            },
             (NET_PROTO, net::SETUP_BUFFER) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |u| {
                     let vaddr = u.get_mr(0);
@@ -245,7 +334,7 @@ impl<'a> SystemService for NetService<'a> {
                     let slot = s.cspace_mgr.alloc(s.res)?;
                     CSPACE_CAP.transfer_self(s.recv, slot)?;
                     let frame = Page::from(slot);
-                    s.setup_shm(frame, vaddr, paddr, size)?;
+                    s.setup_shm(badge, frame, vaddr, paddr, size)?;
                     Ok(())
                 })
             },
@@ -253,12 +342,13 @@ impl<'a> SystemService for NetService<'a> {
                  handle_cap_call(u, |u| {
                     let sq = u.get_mr(0) as u32;
                     let cq = u.get_mr(1) as u32;
+                    let flags = u.get_mr(2);
 
                     let slot = s.cspace_mgr.alloc(s.res)?;
                     CSPACE_CAP.transfer_self(s.recv, slot)?;
                     let notify_ep = Endpoint::from(slot);
 
-                    let frame = s.setup_ring(sq, cq, notify_ep, CapPtr::null())?;
+                    let frame = s.setup_ring(badge, sq, cq, notify_ep, flags)?;
                     Ok(frame.cap())
                  })
             },